@@ -0,0 +1,75 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use shared::{Encoding, Frame, SequencedMessage, WebSocketMessage};
+use std::hint::black_box;
+
+const SDP: &str = "v=0\r\no=- 4215775240449105457 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\na=group:BUNDLE 0 1 2\r\na=extmap-allow-mixed\r\na=msid-semantic: WMS\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\nc=IN IP4 0.0.0.0\r\na=rtcp:9 IN IP4 0.0.0.0\r\na=ice-ufrag:E6lD\r\na=ice-pwd:Qa3Hc0KxfsJN2yDcWBvDWrIu\r\na=ice-options:trickle\r\na=fingerprint:sha-256 9C:2B:7A:05:6E:1D:41:B3:6F:56:0A:6C:3E:CB:12:77:08:0D:3F:8A:65:2C:E9:A4:8B:5E:D2:1A:71:0C:3B:44\r\na=setup:actpass\r\na=mid:0\r\na=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r\na=sendrecv\r\na=msid:- 2b6f1e0c-7e1b-4b6a-9a4e-0f3c2d1e5a77\r\na=rtcp-mux\r\na=rtpmap:111 opus/48000/2\r\na=fmtp:111 minptime=10;useinbandfec=1\r\na=ssrc:3735928559 cname:Yq9bJ3aXq6z1yK0f\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\nc=IN IP4 0.0.0.0\r\na=mid:1\r\na=sendonly\r\na=rtcp-mux\r\na=rtpmap:111 opus/48000/2\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\nc=IN IP4 0.0.0.0\r\na=mid:2\r\na=sendonly\r\na=rtcp-mux\r\na=rtpmap:111 opus/48000/2\r\n";
+
+/**
+ * Messages that are sent often or are large, as the server would send them.
+ */
+fn samples() -> Vec<(&'static str, SequencedMessage)> {
+    let offer = serde_json::json!({ "WebRTCOffer": { "type": "offer", "sdp": SDP } });
+    let candidate = serde_json::json!({ "IceCandidate": {
+        "candidate": "candidate:1467250027 1 udp 2122260223 192.168.0.196 46243 typ host generation 0 ufrag E6lD network-id 1",
+        "sdpMid": "0",
+        "sdpMLineIndex": 0,
+        "usernameFragment": "E6lD"
+    }});
+    let speaking = serde_json::json!({ "SpeakingStarted": {
+        "user_id": "6b1d9f4e-8c4a-4f61-9f0a-3c8e2f1d7b55"
+    }});
+    [("offer", offer), ("ice_candidate", candidate), ("speaking_started", speaking)]
+        .into_iter()
+        .map(|(name, message)| {
+            let message = serde_json::from_value::<WebSocketMessage>(message).unwrap();
+            (name, SequencedMessage { seq: 42, message })
+        })
+        .collect()
+}
+
+fn frame_len(frame: &Frame) -> usize {
+    match frame {
+        Frame::Text(text) => text.len(),
+        Frame::Binary(bytes) => bytes.len(),
+    }
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for (name, message) in samples() {
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            let len = frame_len(&encoding.encode(&message).unwrap());
+            group.throughput(Throughput::Bytes(len as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("{:?}", encoding), name),
+                &message,
+                |b, message| b.iter(|| encoding.encode(black_box(message)).unwrap()),
+            );
+        }
+    }
+    group.finish();
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for (name, message) in samples() {
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            let frame = encoding.encode(&message).unwrap();
+            group.throughput(Throughput::Bytes(frame_len(&frame) as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("{:?}", encoding), name),
+                &frame,
+                |b, frame| {
+                    b.iter(|| match black_box(frame) {
+                        Frame::Text(text) => SequencedMessage::from_json(text).unwrap(),
+                        Frame::Binary(bytes) => SequencedMessage::from_msgpack(bytes).unwrap(),
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode, bench_decode);
+criterion_main!(benches);
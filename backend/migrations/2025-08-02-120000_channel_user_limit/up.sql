@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS user_limit INTEGER DEFAULT NULL;
\ No newline at end of file
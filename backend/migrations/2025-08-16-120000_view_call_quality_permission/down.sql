@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- Postgres can not remove a value from an enum, the value stays unused
+SELECT 1;
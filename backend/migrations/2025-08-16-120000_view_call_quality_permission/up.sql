@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TYPE permission_type ADD VALUE IF NOT EXISTS 'ViewCallQuality';
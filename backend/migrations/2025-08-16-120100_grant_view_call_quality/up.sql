@@ -0,0 +1,4 @@
+-- Your SQL goes here
+INSERT INTO permissions (role_id, type)
+SELECT id, 'ViewCallQuality' FROM roles WHERE name = 'owner'
+ON CONFLICT DO NOTHING;
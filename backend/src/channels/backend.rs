@@ -1,8 +1,9 @@
 #![allow(unused_imports)]
 use crate::{
-    channels::{VoiceRooms, VOICE_ROOMS}, models::Backend, servers::UsersActiveServers, Error
+    channels::{VoiceRooms, VOICE_ROOMS}, models::{Backend, BackendUser}, servers::UsersActiveServers, Error
 };
-use shared::{models::{Channel, ChannelType, ChannelWithUsers, NewChannel, PermissionType, Server, VoiceUser}, schema};
+use futures_util::future::join_all;
+use shared::{models::{Channel, ChannelType, ChannelWithUsers, NewChannel, PermissionType, Server, VoiceUser}, schema, PublishedTrack};
 use diesel::prelude::*;
 use rand::{Rng, distr::Alphanumeric};
 use strum::IntoEnumIterator;
@@ -27,6 +28,29 @@ impl Backend {
         Ok(channels)
     }
 
+    /**
+     * Channels of the server the user is allowed to see, with the people in their voice rooms.
+     */
+    pub async fn list_visible_channels(
+        &self,
+        user: &BackendUser,
+        server_id: Uuid,
+    ) -> Result<Vec<ChannelWithUsers>, Error> {
+        let channels = self.list_channels(server_id)?;
+        let user_can_see_channel =
+            self.has_permission(user, server_id, PermissionType::ListChannels, None)?;
+        let user_can_see_hidden_channels =
+            self.has_permission(user, server_id, PermissionType::ListHiddenChannels, None)?;
+        let channels = channels
+            .into_iter()
+            .filter(|channel| {
+                (channel.hidden && user_can_see_hidden_channels)
+                    || (!channel.hidden && user_can_see_channel)
+            })
+            .map(Backend::convert_channel_to_with_users);
+        Ok(join_all(channels).await)
+    }
+
     pub fn create_channel(&self, new_channel: &NewChannel) -> Result<Channel, Error> {
         let mut conn = self.get_connection()?;
         let channel = diesel::insert_into(schema::channels::dsl::channels)
@@ -50,6 +74,11 @@ impl Backend {
                             username: person.name.clone().unwrap(),
                             slot,
                             boost: None,
+                            locally_muted: false,
+                            camera: person.published.contains(&PublishedTrack::Camera),
+                            screen_share: person.published.contains(&PublishedTrack::Screen),
+                            muted: person.muted,
+                            deafened: person.deafened,
                         });
                     }
                 }
@@ -1,10 +1,17 @@
-use std::sync::{Arc, OnceLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
 
 use dashmap::DashMap;
 use shared::{
-    models::{AudioChannelMemberUpdate, Channel, Server, Users, VoiceUser}, TrackLocalStaticRTP, ROOM_SIZE
+    models::{AudioChannelMemberUpdate, CameraState, Channel, ConnectionQuality, DominantSpeaker, PersonQuality, RoomQuality, ScreenShareState, Server, Users, VoiceState, VoiceUser}, ActiveSpeakers, ForwardedTrack, PublishedForwards, PublishedTrack, RTCRtpSender, TrackLocalStaticRTP, WebRTCConnection, WebSocketMessage
 };
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc::Sender, watch, Mutex};
 use uuid::Uuid;
 
 use crate::{utils::SubscribableOnce, Error};
@@ -12,6 +19,12 @@ use crate::{utils::SubscribableOnce, Error};
 pub mod backend;
 pub mod web;
 
+/// Number of the loudest speakers forwarded to each person in a voice room
+const MAX_FORWARDED_SPEAKERS: usize = 3;
+/// How often the speaking state of the people in a voice room is checked
+const SPEAKING_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the quality of the connection of each person in a voice room is sampled
+const QUALITY_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct VoiceRooms {
     pub voice_rooms: DashMap<Uuid, VoiceRoom>,
@@ -40,88 +53,545 @@ impl VoiceRooms {
             .get(&channel.id)
             .map(|entry| entry.value().clone())
     }
+
+    fn remove_room(&self, room: &VoiceRoom) {
+        self.voice_rooms.remove_if(&room.channel.id, |_, other| {
+            Arc::ptr_eq(&other.people, &room.people)
+        });
+    }
 }
 
 #[derive(Clone)]
 pub struct VoiceRoom {
     pub server: Server,
     pub channel: Channel,
-    pub people: Arc<Mutex<[MaybeVoicePerson; ROOM_SIZE]>>,
+    pub people: Arc<Mutex<Vec<MaybeVoicePerson>>>,
+    pub active_speakers: Arc<ActiveSpeakers>,
+    /// Set when the room is empty and removed from `VoiceRooms`, nobody can join it anymore
+    closed: Arc<AtomicBool>,
 }
 
 impl VoiceRoom {
     pub fn new(server: Server, channel: Channel) -> Self {
-        VoiceRoom {
+        let (active_speakers, dominant_rx) = ActiveSpeakers::new(MAX_FORWARDED_SPEAKERS);
+        let room = VoiceRoom {
             server,
             channel,
-            people: Arc::new(Mutex::new(std::array::from_fn(|_| MaybeVoicePerson::new()))),
-        }
+            people: Arc::new(Mutex::new(Vec::new())),
+            active_speakers,
+            closed: Arc::new(AtomicBool::new(false)),
+        };
+        room.notify_speakers(dominant_rx);
+        room
+    }
+
+    /**
+     * Tells the subscribers of the server whenever the dominant speaker of the room changes
+     * and whenever someone starts or stops speaking.
+     * The task ends once the room and its speaker ranking are dropped.
+     */
+    fn notify_speakers(&self, mut dominant_rx: watch::Receiver<Option<usize>>) {
+        let server = self.server.clone();
+        let channel_id = self.channel.id;
+        let people = self.people.clone();
+        let active_speakers = Arc::downgrade(&self.active_speakers);
+        tokio::spawn(async move {
+            // Slots that are speaking, with the user in the slot when they started
+            let mut speaking = HashMap::<usize, Uuid>::new();
+            let mut interval = tokio::time::interval(SPEAKING_POLL_INTERVAL);
+            loop {
+                tokio::select! {
+                    changed = dominant_rx.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                        let slot = *dominant_rx.borrow_and_update();
+                        let user_id = match slot {
+                            Some(slot) => people.lock().await.get(slot).and_then(|person| person.id),
+                            None => None,
+                        };
+                        server
+                            .notify_subscribers(WebSocketMessage::DominantSpeakerChanged {
+                                data: DominantSpeaker { channel_id, user_id },
+                            })
+                            .await;
+                    }
+                    _ = interval.tick() => {
+                        let Some(active_speakers) = active_speakers.upgrade() else {
+                            break;
+                        };
+                        let now_speaking = active_speakers.speaking();
+                        drop(active_speakers);
+                        let stopped = speaking
+                            .iter()
+                            .filter(|(slot, _)| !now_speaking.contains(slot))
+                            .map(|(slot, user_id)| (*slot, *user_id))
+                            .collect::<Vec<_>>();
+                        for (slot, user_id) in stopped {
+                            speaking.remove(&slot);
+                            server
+                                .notify_subscribers(WebSocketMessage::SpeakingStopped { user_id })
+                                .await;
+                        }
+                        for slot in now_speaking {
+                            if speaking.contains_key(&slot) {
+                                continue;
+                            }
+                            let Some(user_id) = people.lock().await.get(slot).and_then(|person| person.id) else {
+                                continue;
+                            };
+                            speaking.insert(slot, user_id);
+                            server
+                                .notify_subscribers(WebSocketMessage::SpeakingStarted { user_id })
+                                .await;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Most people the room takes, a limit of 0 or less counts as no limit
+    fn user_limit(&self) -> Option<usize> {
+        self.channel
+            .user_limit
+            .filter(|user_limit| *user_limit > 0)
+            .map(|user_limit| user_limit as usize)
+    }
+
+    /**
+     * Whether the user could join the room now, the slot of another session of theirs
+     * is taken over rather than counted.
+     */
+    pub async fn has_room_for(&self, user_id: Uuid) -> bool {
+        let Some(user_limit) = self.user_limit() else {
+            return true;
+        };
+        let people = self.people.lock().await;
+        people
+            .iter()
+            .filter(|person| person.id.is_some_and(|id| id != user_id))
+            .count()
+            < user_limit
     }
 
+    /**
+     * Puts the user into the first free slot and wires up the audio forwarding
+     * between the new connection and everyone already in the room.
+     *
+     * Returns the slot of the user, the tracks its audio should be forwarded to,
+     * the forwarding of its published tracks and the session of the user it replaced.
+     * The caller tells that session that it was taken over.
+     * Fails with `Error::RoomClosed` if the room was removed in the meantime,
+     * the caller should get the room from `VoiceRooms` again.
+     * If the forwarding cannot be set up, the user leaves the room again before the error is returned.
+     */
     pub async fn join_person(
         &self,
         user: &Users,
-        recv_tracks: Vec<Arc<TrackLocalStaticRTP>>,
-    ) -> Result<usize, Error> {
+        session_id: Uuid,
+        connection: Arc<WebRTCConnection>,
+        websocket: Sender<WebSocketMessage>,
+    ) -> Result<(usize, Arc<Mutex<HashMap<usize, Arc<TrackLocalStaticRTP>>>>, Arc<PublishedForwards>, Option<Uuid>), Error> {
         let mut people = self.people.lock().await;
-        for (i, slot) in people.iter_mut().enumerate() {
-            if slot.id.is_none() {
-                self.server.notify_subscribers(
-                    shared::WebSocketMessage::SomeoneJoinedAudioChannel {
-                        data: AudioChannelMemberUpdate {
-                            channel: self.channel.clone(),
-                            user: VoiceUser {
-                                id: user.id,
-                                username: user.username.clone(),
-                                slot: i,
-                                boost: None,
-                            },
-                        },
+        if self.closed.load(Ordering::Acquire) {
+            return Err(Error::RoomClosed);
+        }
+        // Another session of the user that joined at the same time, only the newest one stays.
+        // The slot stays in the room, which would be closed if that session was alone in it
+        let mut replaced_session = None;
+        if let Some(slot) = people.iter().position(|slot| slot.id == Some(user.id)) {
+            replaced_session = people[slot].session_id;
+            self.clear_slot(&mut people, slot).await;
+        }
+        let slot = match people.iter().position(|slot| slot.id.is_none()) {
+            Some(slot) => slot,
+            None => {
+                if self.user_limit().is_some_and(|user_limit| people.len() >= user_limit) {
+                    return Err(Error::RoomFull);
+                }
+                people.push(MaybeVoicePerson::new());
+                people.len() - 1
+            }
+        };
+        self.server.notify_subscribers(
+            shared::WebSocketMessage::SomeoneJoinedAudioChannel {
+                data: AudioChannelMemberUpdate {
+                    channel: self.channel.clone(),
+                    user: VoiceUser {
+                        id: user.id,
+                        username: user.username.clone(),
+                        slot,
+                        boost: None,
+                        locally_muted: false,
+                        camera: false,
+                        screen_share: false,
+                        muted: false,
+                        deafened: false,
                     },
-                ).await;
-                slot.set_person(user, recv_tracks).await;
+                },
+            },
+        ).await;
 
-                return Ok(i);
+        let forward_tracks = Arc::new(Mutex::new(HashMap::new()));
+        people[slot].set_person(user, session_id, connection.clone(), websocket, forward_tracks.clone(), HashMap::new());
+        if let Err(err) = Self::wire_person(&mut people, slot, &connection).await {
+            // Removes the tracks added so far and frees the slot again
+            self.leave_slot(&mut people, slot).await;
+            return Err(err);
+        }
+        people[slot].quality = Some(connection.monitor_quality(QUALITY_POLL_INTERVAL));
+
+        Ok((slot, forward_tracks, people[slot].forwards.clone(), replaced_session))
+    }
+
+    /**
+     * Forwards the audio of the person in `slot` to everyone else in the room and theirs to it.
+     */
+    async fn wire_person(
+        people: &mut [MaybeVoicePerson],
+        slot: usize,
+        connection: &WebRTCConnection,
+    ) -> Result<(), Error> {
+        for other_slot in 0..people.len() {
+            if other_slot == slot {
+                continue;
             }
+            let Some(other_connection) = people[other_slot].connection.clone() else {
+                continue;
+            };
+            // Audio of the other person to the new connection
+            let (track, sender) = connection.add_forward_track(other_slot).await?;
+            people[other_slot].forward_tracks.lock().await.insert(slot, track);
+            people[slot].recv_senders.insert(other_slot, sender);
+            // Audio of the new person to the other connection
+            let (track, sender) = other_connection.add_forward_track(slot).await?;
+            people[slot].forward_tracks.lock().await.insert(other_slot, track);
+            people[other_slot].recv_senders.insert(slot, sender);
         }
-        Err(Error::RoomFull)
+        Ok(())
     }
 
-    pub async fn leave_person(&self, person_id: Uuid) -> Result<(), Error> {
+    /**
+     * Turns the camera of the person that joined through `session_id` on or off
+     * and tells the subscribers of the server.
+     */
+    pub async fn set_camera(&self, session_id: Uuid, enabled: bool) -> Result<(), Error> {
         let mut people = self.people.lock().await;
-        for (i, slot) in people.iter_mut().enumerate() {
-            if slot.id == Some(person_id) {
-                self.server.notify_subscribers(
-                    shared::WebSocketMessage::SomeoneLeftAudioChannel {
-                        data: AudioChannelMemberUpdate {
-                            channel: self.channel.clone(),
-                            user: VoiceUser {
-                                id: person_id,
-                                username: slot.name.clone().unwrap_or_default(),
-                                slot: i,
-                                boost: None,
-                            },
-                        },
-                    },
-                ).await;
-                slot.reset_person().await;
-                return Ok(());
-            }
+        let person = Self::find_session(&mut people, session_id)?;
+        if !person.set_published(PublishedTrack::Camera, enabled) {
+            return Ok(());
+        }
+        let user_id = person.id.unwrap_or_default();
+        self.server
+            .notify_subscribers(WebSocketMessage::CameraChanged {
+                data: CameraState {
+                    channel_id: self.channel.id,
+                    user_id,
+                    enabled,
+                },
+            })
+            .await;
+        Self::sync_published(&mut people).await;
+        Ok(())
+    }
+
+    /**
+     * Records whether the person that joined through `session_id` is muted and deafened
+     * and tells the subscribers of the server.
+     */
+    pub async fn set_voice_state(&self, session_id: Uuid, muted: bool, deafened: bool) -> Result<(), Error> {
+        let mut people = self.people.lock().await;
+        let person = Self::find_session(&mut people, session_id)?;
+        if person.muted == muted && person.deafened == deafened {
+            return Ok(());
+        }
+        person.muted = muted;
+        person.deafened = deafened;
+        let user_id = person.id.unwrap_or_default();
+        self.server
+            .notify_subscribers(WebSocketMessage::VoiceStateChanged {
+                data: VoiceState {
+                    channel_id: self.channel.id,
+                    user_id,
+                    muted,
+                    deafened,
+                },
+            })
+            .await;
+        Ok(())
+    }
+
+    /**
+     * Starts or stops the screen share of the person that joined through `session_id`,
+     * `audio` tells whether the audio of the shared applications is forwarded with it.
+     */
+    pub async fn set_screen_share(&self, session_id: Uuid, sharing: bool, audio: bool) -> Result<(), Error> {
+        let mut people = self.people.lock().await;
+        let person = Self::find_session(&mut people, session_id)?;
+        let audio = sharing && audio;
+        let screen_changed = person.set_published(PublishedTrack::Screen, sharing);
+        let audio_changed = person.set_published(PublishedTrack::ScreenAudio, audio);
+        if !screen_changed && !audio_changed {
+            return Ok(());
+        }
+        let user_id = person.id.unwrap_or_default();
+        self.server
+            .notify_subscribers(WebSocketMessage::ScreenShareChanged {
+                data: ScreenShareState {
+                    channel_id: self.channel.id,
+                    user_id,
+                    sharing,
+                    audio,
+                },
+            })
+            .await;
+        Self::sync_published(&mut people).await;
+        Ok(())
+    }
+
+    /**
+     * Replaces the users whose camera the person that joined through `session_id` receives.
+     */
+    pub async fn set_video_subscriptions(
+        &self,
+        session_id: Uuid,
+        user_ids: HashSet<Uuid>,
+    ) -> Result<(), Error> {
+        let mut people = self.people.lock().await;
+        Self::find_session(&mut people, session_id)?.camera_subscriptions = user_ids;
+        Self::sync_published(&mut people).await;
+        Ok(())
+    }
+
+    /**
+     * Starts or stops forwarding the screen share of `user_id`
+     * to the person that joined through `session_id`.
+     */
+    pub async fn watch_screen_share(&self, session_id: Uuid, user_id: Uuid, watch: bool) -> Result<(), Error> {
+        let mut people = self.people.lock().await;
+        let person = Self::find_session(&mut people, session_id)?;
+        if watch {
+            person.screen_subscriptions.insert(user_id);
+        } else {
+            person.screen_subscriptions.remove(&user_id);
         }
-        Err(Error::UserNotFoundInRoom)
+        Self::sync_published(&mut people).await;
+        Ok(())
     }
 
-    pub async fn get_track_i_of_all(
+    /**
+     * Stores the height the person that joined through `session_id` shows the published
+     * video of `user_id` at, which picks the simulcast layer they receive.
+     */
+    pub async fn set_video_size(
         &self,
-        track_i: usize,
-    ) -> Vec<Arc<Mutex<Option<Arc<TrackLocalStaticRTP>>>>> {
+        session_id: Uuid,
+        user_id: Uuid,
+        published: PublishedTrack,
+        height: u32,
+    ) -> Result<(), Error> {
+        let mut people = self.people.lock().await;
+        let subscriber = people
+            .iter()
+            .position(|slot| slot.session_id == Some(session_id))
+            .ok_or(Error::UserNotFoundInRoom)?;
+        // Kept for when the video is subscribed to later
+        people[subscriber].video_sizes.insert((user_id, published), height);
+        let Some(publisher) = people.iter().position(|slot| slot.id == Some(user_id)) else {
+            return Ok(());
+        };
+        let forward = people[publisher].forwards.get(published).clone();
+        let tracks = forward.tracks.lock().await;
+        if let Some(forwarded) = tracks.get(&subscriber) {
+            forwarded.selector.lock().unwrap().set_requested_height(height);
+            forward.retarget(&forwarded.selector);
+        }
+        Ok(())
+    }
+
+    /**
+     * Latest quality of the connection of everyone in the room.
+     */
+    pub async fn quality(&self) -> RoomQuality {
         let people = self.people.lock().await;
+        let people = people
+            .iter()
+            .filter_map(|person| {
+                Some(PersonQuality {
+                    user_id: person.id?,
+                    username: person.name.clone().unwrap_or_default(),
+                    quality: person.quality.as_ref()?.borrow().clone(),
+                })
+            })
+            .collect();
+        RoomQuality::new(self.channel.id, people)
+    }
+
+    fn find_session(people: &mut [MaybeVoicePerson], session_id: Uuid) -> Result<&mut MaybeVoicePerson, Error> {
         people
+            .iter_mut()
+            .find(|slot| slot.session_id == Some(session_id))
+            .ok_or(Error::UserNotFoundInRoom)
+    }
+
+    /**
+     * Adds and removes the forwarded tracks so that everyone receives exactly the published
+     * tracks they subscribed to that are being sent. Tracks are only added and removed
+     * when something changed, which keeps renegotiations to a minimum.
+     */
+    async fn sync_published(people: &mut [MaybeVoicePerson]) {
+        for subscriber in 0..people.len() {
+            let Some(connection) = people[subscriber].connection.clone() else {
+                continue;
+            };
+            for publisher in 0..people.len() {
+                if publisher == subscriber {
+                    continue;
+                }
+                for published in PublishedTrack::ALL {
+                    let wanted = people[publisher].published.contains(&published)
+                        && people[publisher].id.is_some_and(|id| {
+                            people[subscriber].subscriptions(published).contains(&id)
+                        });
+                    let key = (publisher, published);
+                    let forwarded = people[subscriber].subscribed_senders.contains_key(&key);
+                    if wanted && !forwarded {
+                        let Some(publisher_connection) = people[publisher].connection.clone() else {
+                            continue;
+                        };
+                        let Some(codec) = publisher_connection.negotiated_codec(published).await else {
+                            tracing::warn!("No codec negotiated for {:?} of slot {}", published, publisher);
+                            continue;
+                        };
+                        let (track, sender) = match connection
+                            .add_published_forward_track(published, publisher, codec)
+                            .await
+                        {
+                            Ok(forward) => forward,
+                            Err(err) => {
+                                tracing::error!("Failed to add forwarded {:?} track: {}", published, err);
+                                continue;
+                            }
+                        };
+                        let forward = people[publisher].forwards.get(published).clone();
+                        let forwarded = ForwardedTrack::new(track);
+                        let selector = forwarded.selector.clone();
+                        let size = people[publisher]
+                            .id
+                            .and_then(|id| people[subscriber].video_sizes.get(&(id, published)));
+                        if let Some(height) = size {
+                            selector.lock().unwrap().set_requested_height(*height);
+                        }
+                        forward.tracks.lock().await.insert(subscriber, forwarded);
+                        forward.retarget(&selector);
+                        if published.is_video() {
+                            WebRTCConnection::relay_keyframe_requests(sender.clone(), forward, selector);
+                        }
+                        people[subscriber].subscribed_senders.insert(key, sender);
+                    } else if !wanted && forwarded {
+                        people[publisher].forwards.get(published).tracks.lock().await.remove(&subscriber);
+                        if let Some(sender) = people[subscriber].subscribed_senders.remove(&key) {
+                            if let Err(err) = connection.remove_forward_track(&sender).await {
+                                tracing::error!("Failed to remove forwarded {:?} track: {}", published, err);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /**
+     * Removes the person that joined through the websocket session `session_id`.
+     */
+    pub async fn leave_session(&self, session_id: Uuid) -> Result<(), Error> {
+        let mut people = self.people.lock().await;
+        let slot = people
             .iter()
-            .enumerate()
-            .filter(|(i, _)| *i != track_i)
-            .map(|(_, person)| person.recv_tracks[track_i].clone())
-            .collect::<Vec<_>>()
+            .position(|slot| slot.session_id == Some(session_id))
+            .ok_or(Error::UserNotFoundInRoom)?;
+        self.leave_slot(&mut people, slot).await;
+        Ok(())
+    }
+
+    /**
+     * Removes the person only if they are still in the room through `connection`,
+     * so that a stale connection can not kick out a newer one.
+     *
+     * Returns whether the person was removed.
+     */
+    pub async fn leave_connection(&self, person_id: Uuid, connection: &Arc<WebRTCConnection>) -> bool {
+        let mut people = self.people.lock().await;
+        let Some(slot) = people.iter().position(|slot| {
+            slot.id == Some(person_id)
+                && slot
+                    .connection
+                    .as_ref()
+                    .is_some_and(|other| Arc::ptr_eq(other, connection))
+        }) else {
+            return false;
+        };
+        self.leave_slot(&mut people, slot).await;
+        true
+    }
+
+    async fn leave_slot(&self, people: &mut Vec<MaybeVoicePerson>, slot: usize) {
+        self.clear_slot(people, slot).await;
+        // Shrink the room so that trailing empty slots do not count against the limit
+        while people.last().is_some_and(|slot| slot.id.is_none()) {
+            people.pop();
+        }
+        if people.is_empty() {
+            // Still holding the lock, so nobody can join between closing and removing
+            self.closed.store(true, Ordering::Release);
+            VoiceRooms::get_or_init().remove_room(self);
+            tracing::info!("Removed empty voice room {}", self.channel.id);
+        }
+    }
+
+    /**
+     * Empties the slot and stops the forwarding from and to it, telling the subscribers.
+     */
+    async fn clear_slot(&self, people: &mut [MaybeVoicePerson], slot: usize) {
+        let person_id = people[slot].id.unwrap_or_default();
+        self.server.notify_subscribers(
+            shared::WebSocketMessage::SomeoneLeftAudioChannel {
+                data: AudioChannelMemberUpdate {
+                    channel: self.channel.clone(),
+                    user: VoiceUser {
+                        id: person_id,
+                        username: people[slot].name.clone().unwrap_or_default(),
+                        slot,
+                        boost: None,
+                        locally_muted: false,
+                        camera: people[slot].published.contains(&PublishedTrack::Camera),
+                        screen_share: people[slot].published.contains(&PublishedTrack::Screen),
+                        muted: people[slot].muted,
+                        deafened: people[slot].deafened,
+                    },
+                },
+            },
+        ).await;
+        people[slot].reset_person().await;
+        self.active_speakers.remove(slot);
+        for other in people.iter_mut() {
+            other.forward_tracks.lock().await.remove(&slot);
+            for published in PublishedTrack::ALL {
+                other.forwards.get(published).tracks.lock().await.remove(&slot);
+            }
+            let Some(other_connection) = other.connection.as_ref() else {
+                continue;
+            };
+            let published_senders = PublishedTrack::ALL
+                .map(|published| other.subscribed_senders.remove(&(slot, published)));
+            let senders = std::iter::once(other.recv_senders.remove(&slot)).chain(published_senders);
+            for sender in senders.flatten() {
+                if let Err(err) = other_connection.remove_forward_track(&sender).await {
+                    tracing::error!("Failed to remove forwarded track: {}", err);
+                }
+            }
+        }
     }
 }
 
@@ -129,7 +599,31 @@ impl VoiceRoom {
 pub struct MaybeVoicePerson {
     pub id: Option<Uuid>,
     pub name: Option<String>,
-    pub recv_tracks: [Arc<Mutex<Option<Arc<TrackLocalStaticRTP>>>>; ROOM_SIZE],
+    /// Websocket session of the user that is in the call
+    pub session_id: Option<Uuid>,
+    pub connection: Option<Arc<WebRTCConnection>>,
+    pub websocket: Option<Sender<WebSocketMessage>>,
+    /// Tracks that carry the audio of this person, keyed by the slot of the receiver
+    pub forward_tracks: Arc<Mutex<HashMap<usize, Arc<TrackLocalStaticRTP>>>>,
+    /// Senders on the connection of this person, keyed by the slot whose audio they carry
+    pub recv_senders: HashMap<usize, Arc<RTCRtpSender>>,
+    /// Optional tracks this person is sending, the camera and the screen share
+    pub published: HashSet<PublishedTrack>,
+    /// Forwarding of the published tracks of this person to its subscribers
+    pub forwards: Arc<PublishedForwards>,
+    /// Users whose camera this person wants to receive
+    pub camera_subscriptions: HashSet<Uuid>,
+    /// Users whose screen share this person watches
+    pub screen_subscriptions: HashSet<Uuid>,
+    /// Senders on the connection of this person, keyed by the slot and the published track they carry
+    pub subscribed_senders: HashMap<(usize, PublishedTrack), Arc<RTCRtpSender>>,
+    /// Heights this person shows the published videos of other users at
+    pub video_sizes: HashMap<(Uuid, PublishedTrack), u32>,
+    /// Quality of the connection of this person, sampled until the connection is dropped
+    pub quality: Option<watch::Receiver<ConnectionQuality>>,
+    /// Whether this person muted their microphone or deafened themselves
+    pub muted: bool,
+    pub deafened: bool,
 }
 
 impl MaybeVoicePerson {
@@ -137,19 +631,62 @@ impl MaybeVoicePerson {
         Self::default()
     }
 
-    pub async fn set_person(&mut self, user: &Users, recv_tracks: Vec<Arc<TrackLocalStaticRTP>>) {
+    pub fn set_person(
+        &mut self,
+        user: &Users,
+        session_id: Uuid,
+        connection: Arc<WebRTCConnection>,
+        websocket: Sender<WebSocketMessage>,
+        forward_tracks: Arc<Mutex<HashMap<usize, Arc<TrackLocalStaticRTP>>>>,
+        recv_senders: HashMap<usize, Arc<RTCRtpSender>>,
+    ) {
         self.id = Some(user.id);
         self.name = Some(user.username.clone());
-        for (track, recv_track) in self.recv_tracks.iter_mut().zip(recv_tracks) {
-            *track.lock().await = Some(recv_track);
-        }
+        self.session_id = Some(session_id);
+        self.connection = Some(connection);
+        self.websocket = Some(websocket);
+        self.forward_tracks = forward_tracks;
+        self.recv_senders = recv_senders;
     }
 
     pub async fn reset_person(&mut self) {
         self.id = None;
         self.name = None;
-        for track in self.recv_tracks.iter_mut() {
-            *track.lock().await = None;
+        self.session_id = None;
+        self.connection = None;
+        self.websocket = None;
+        self.forward_tracks.lock().await.clear();
+        self.forward_tracks = Arc::new(Mutex::new(HashMap::new()));
+        self.recv_senders.clear();
+        self.published.clear();
+        for published in PublishedTrack::ALL {
+            self.forwards.get(published).tracks.lock().await.clear();
+        }
+        self.forwards = Arc::new(PublishedForwards::default());
+        self.camera_subscriptions.clear();
+        self.screen_subscriptions.clear();
+        self.subscribed_senders.clear();
+        self.video_sizes.clear();
+        self.quality = None;
+        self.muted = false;
+        self.deafened = false;
+    }
+
+    /**
+     * Returns whether the track was not already in that state.
+     */
+    fn set_published(&mut self, published: PublishedTrack, enabled: bool) -> bool {
+        if enabled {
+            self.published.insert(published)
+        } else {
+            self.published.remove(&published)
+        }
+    }
+
+    fn subscriptions(&self, published: PublishedTrack) -> &HashSet<Uuid> {
+        match published {
+            PublishedTrack::Camera => &self.camera_subscriptions,
+            PublishedTrack::Screen | PublishedTrack::ScreenAudio => &self.screen_subscriptions,
         }
     }
 }
@@ -7,7 +7,7 @@ use shared::models::PermissionType;
 use crate::models::{AuthSession, Backend};
 use axum::Json;
 use axum::Router;
-use axum::extract::Path;
+use axum::extract::{Path, Query};
 use axum::response::IntoResponse;
 use axum::{routing::get, routing::post};
 use axum_login::login_required;
@@ -60,16 +60,23 @@ mod post {
     }
 }
 mod get {
-    use futures_util::future::join_all;
+    use serde::Deserialize;
     use shared::models::Server;
 
     use crate::{models::user::OnlineUsers, utils::SubscribableOnce};
 
     use super::*;
 
+    #[derive(Deserialize)]
+    pub struct SessionQuery {
+        /// Websocket session to subscribe, the newest session of the user if missing
+        pub session_id: Option<Uuid>,
+    }
+
     pub async fn list_channels(
         session: AuthSession,
         Path(server_id): Path<Uuid>,
+        Query(query): Query<SessionQuery>,
     ) -> impl IntoResponse {
         let user = session.user.unwrap();
         let internal_err =
@@ -80,46 +87,17 @@ mod get {
                 return internal_err(e);
             }
         };
-        let online_user = OnlineUsers::get().get_user(user.0.id);
-        let channels = match session.backend.list_channels(server_id) {
-            Ok(channels) => channels,
-            Err(e) => {
-                return internal_err(e);
-            }
+        // Other devices of the user keep the server they are looking at
+        let online_user = match query.session_id {
+            Some(session_id) => OnlineUsers::get().get_session(user.0.id, session_id),
+            None => OnlineUsers::get().get_newest_session(user.0.id),
         };
-        let user_can_see_channel = match session.backend.has_permission(
-            &user,
-            server_id,
-            PermissionType::ListChannels,
-            None,
-        ) {
-            Ok(can_see) => can_see,
-            Err(e) => {
-                return internal_err(e);
-            }
-        };
-        let user_can_see_hidden_channels = match session.backend.has_permission(
-            &user,
-            server_id,
-            PermissionType::ListHiddenChannels,
-            None,
-        ) {
-            Ok(can_see) => can_see,
+        let channels = match session.backend.list_visible_channels(&user, server_id).await {
+            Ok(channels) => channels,
             Err(e) => {
                 return internal_err(e);
             }
         };
-        let channels = channels
-            .into_iter()
-            .filter(|channel| {
-                (channel.hidden && user_can_see_hidden_channels)
-                    || (!channel.hidden && user_can_see_channel)
-            });
-        // Convert the channels to channels with users
-        let channels = channels.into_iter().map(async |channel| {
-            Backend::convert_channel_to_with_users(channel).await
-        }).collect::<Vec<_>>();
-        let channels = join_all(channels).await;
         if let Some(online_user) = online_user {
             Server::unsubscribe(&online_user);
             server.subscribe(&online_user);
@@ -1,4 +1,4 @@
-use std::{hash::Hash, sync::{Arc, Mutex as StdMutex, OnceLock}};
+use std::{collections::HashMap, hash::Hash, sync::{Arc, Mutex as StdMutex, OnceLock}, time::Instant};
 
 use argon2::{
     Argon2,
@@ -151,8 +151,12 @@ impl Backend {
 
 static ONLINE_USERS: OnceLock<OnlineUsers> = OnceLock::new();
 
+/**
+ * Connected sessions of every user, keyed by user id and then by session id.
+ * A user can be connected from several devices at once.
+ */
 pub struct OnlineUsers {
-    pub users: DashMap<Uuid, OnlineUser>,
+    pub users: DashMap<Uuid, HashMap<Uuid, OnlineUser>>,
 }
 impl OnlineUsers {
     pub fn get() -> &'static OnlineUsers {
@@ -162,46 +166,83 @@ impl OnlineUsers {
     }
 
     pub fn add_user(&self, user: OnlineUser) {
-        self.users.insert(user.user.id, user);
+        self.users
+            .entry(user.user.id)
+            .or_default()
+            .insert(user.session_id, user);
     }
 
-    pub fn get_user(&self, user_id: Uuid) -> Option<OnlineUser> {
-        self.users.get(&user_id).map(|entry| entry.value().clone())
+    pub fn get_session(&self, user_id: Uuid, session_id: Uuid) -> Option<OnlineUser> {
+        self.users
+            .get(&user_id)
+            .and_then(|sessions| sessions.get(&session_id).cloned())
+    }
+
+    /// Session of the user that started last
+    pub fn get_newest_session(&self, user_id: Uuid) -> Option<OnlineUser> {
+        self.users.get(&user_id).and_then(|sessions| {
+            sessions
+                .values()
+                .max_by_key(|session| session.started_at)
+                .cloned()
+        })
+    }
+
+    pub fn get_sessions(&self, user_id: Uuid) -> Vec<OnlineUser> {
+        self.users
+            .get(&user_id)
+            .map(|sessions| sessions.values().cloned().collect())
+            .unwrap_or_default()
     }
 
     pub fn remove_user(&self, user: OnlineUser) {
-        Server::unsubscribe(&user);
-        self.users.remove(&user.user.id);
+        let removed = match self.users.get_mut(&user.user.id) {
+            Some(mut sessions) => sessions.remove(&user.session_id).is_some(),
+            None => false,
+        };
+        self.users
+            .remove_if(&user.user.id, |_, sessions| sessions.is_empty());
+        if removed {
+            Server::unsubscribe(&user);
+        }
     }
 }
 
+/**
+ * A single websocket session of a user, the other sessions of the same user are separate entries.
+ */
 #[derive(Clone)]
 pub struct OnlineUser {
     pub user: Users,
+    pub session_id: Uuid,
     pub websocket: Sender<WebSocketMessage>,
     pub audio_channel: Arc<StdMutex<Option<VoiceRoom>>>,
+    /// Resuming keeps the time the session first started
+    pub started_at: Instant,
 }
 
 impl Hash for OnlineUser {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.user.id.hash(state);
+        self.session_id.hash(state);
     }
 }
 
 impl PartialEq for OnlineUser {
     fn eq(&self, other: &Self) -> bool {
-        self.user.id == other.user.id
+        self.session_id == other.session_id
     }
 }
 
 impl Eq for OnlineUser {}
 
 impl OnlineUser {
-    pub fn new(user: Users, websocket: Sender<WebSocketMessage>) -> Self {
+    pub fn new(user: Users, session_id: Uuid, websocket: Sender<WebSocketMessage>) -> Self {
         Self {
             user,
+            session_id,
             websocket,
             audio_channel: Arc::new(StdMutex::new(None)),
+            started_at: Instant::now(),
         }
     }
     pub fn set_audio_channel(&self, channel: VoiceRoom) {
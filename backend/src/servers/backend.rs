@@ -51,12 +51,14 @@ impl Backend {
                 server_id,
                 type_: ChannelType::Text,
                 hidden: false,
+                user_limit: None,
             },
             NewChannel {
                 name: "Voice".to_string(),
                 server_id,
                 type_: ChannelType::Voice,
                 hidden: false,
+                user_limit: None,
             },
         ];
         for channel in default_channels {
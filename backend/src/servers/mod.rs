@@ -10,6 +10,7 @@ pub mod backend;
 pub mod web;
 
 pub struct UsersActiveServers {
+    /// Server each session is subscribed to, keyed by session id
     pub user_to_server_map: DashMap<Uuid, Server>,
     pub server_to_user_map: DashMap<Uuid, HashSet<OnlineUser>>,
 }
@@ -26,7 +27,7 @@ impl UsersActiveServers {
 
     pub fn add_user_to_server(&self, user: &OnlineUser, server: &Server) {
         let server_id = server.id;
-        self.user_to_server_map.insert(user.user.id, server.clone());
+        self.user_to_server_map.insert(user.session_id, server.clone());
         {
             // Ensure the server entry exists in the server_to_user_map
             self.server_to_user_map
@@ -40,7 +41,7 @@ impl UsersActiveServers {
     }
 
     pub fn remove_user_from_server(&self, user: &OnlineUser) {
-        let server_id = self.user_to_server_map.remove(&user.user.id);
+        let server_id = self.user_to_server_map.remove(&user.session_id);
         if let Some((_, server)) = server_id {
             self.server_to_user_map.alter(&server.id, |_, mut users| {
                 users.remove(user);
@@ -51,7 +52,7 @@ impl UsersActiveServers {
 
     pub fn get_server_for_user(&self, user: &OnlineUser) -> Option<Server> {
         self.user_to_server_map
-            .get(&user.user.id)
+            .get(&user.session_id)
             .map(|entry| entry.value().clone())
     }
 
@@ -10,6 +10,8 @@ pub enum Error {
     InvalidCredentials,
     #[error("Room is full")]
     RoomFull,
+    #[error("Room is closed")]
+    RoomClosed,
     #[error("Password hash error: {0}")]
     PasswordHash(String),
     #[error("File error: {0}")]
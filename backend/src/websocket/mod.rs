@@ -1 +1,3 @@
+pub mod rpc;
+pub mod session;
 pub mod web;
\ No newline at end of file
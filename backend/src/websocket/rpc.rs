@@ -0,0 +1,67 @@
+use shared::models::{PermissionType, Server};
+use shared::{RpcError, RpcRequest, RpcResponse};
+
+use crate::channels::VoiceRooms;
+use crate::Error;
+use crate::models::AuthSession;
+use crate::models::user::OnlineUser;
+use crate::utils::SubscribableOnce;
+
+/**
+ * Answers a request the client sent over its websocket instead of the REST API.
+ */
+pub async fn handle_request(
+    request: RpcRequest,
+    auth: &AuthSession,
+    online_user: &OnlineUser,
+) -> Result<RpcResponse, RpcError> {
+    let backend = &auth.backend;
+    let user = auth.user.as_ref().ok_or(RpcError::NotAuthorized)?;
+    match request {
+        RpcRequest::GetServers => {
+            let servers = backend.get_servers_for_user(user.0.id)?;
+            Ok(RpcResponse::Servers(servers))
+        }
+        RpcRequest::ListChannels { server_id } => {
+            let server = backend.get_server(server_id)?;
+            let channels = backend.list_visible_channels(user, server_id).await?;
+            Server::unsubscribe(online_user);
+            server.subscribe(online_user);
+            Ok(RpcResponse::Channels(channels))
+        }
+        RpcRequest::GetPermissions { server_id } => {
+            let permissions = backend.get_user_permissions(user, server_id)?;
+            Ok(RpcResponse::Permissions(permissions))
+        }
+        RpcRequest::GetCallQuality { server_id, channel_id } => {
+            if !backend.has_permission(user, server_id, PermissionType::ViewCallQuality, None)? {
+                return Err(RpcError::NotAuthorized);
+            }
+            let channel = backend
+                .get_channel(server_id, channel_id)?
+                .ok_or(RpcError::NotFound)?;
+            let room = VoiceRooms::get_or_init().get_room(&channel);
+            let quality = match room {
+                Some(room) => Some(room.quality().await),
+                None => None,
+            };
+            Ok(RpcResponse::CallQuality(quality))
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Database(diesel::result::Error::NotFound) | Error::UserNotFoundInRoom => {
+                RpcError::NotFound
+            }
+            Error::PermissionDenied | Error::InvalidCredentials => RpcError::NotAuthorized,
+            Error::InvalidFileName(name) => RpcError::InvalidRequest(name),
+            err => {
+                tracing::error!("Failed to answer websocket request: {}", err);
+                RpcError::Internal(err.to_string())
+            }
+        }
+    }
+}
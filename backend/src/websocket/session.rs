@@ -0,0 +1,327 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use shared::models::Users;
+use shared::{Feature, SequencedMessage, WebSocketMessage};
+use tokio::sync::mpsc::{Receiver, channel};
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard};
+use uuid::Uuid;
+
+use crate::models::user::{OnlineUser, OnlineUsers};
+
+/// Number of events kept per session for replaying after a reconnect
+const REPLAY_BUFFER_SIZE: usize = 256;
+/// How long a session waits for the client to resume after its websocket closed
+const RESUME_TIMEOUT: Duration = Duration::from_secs(120);
+
+/**
+ * Sessions whose websocket closed and that can still be resumed.
+ */
+pub struct Sessions {
+    pub sessions: DashMap<Uuid, Session>,
+}
+static SESSIONS: OnceLock<Sessions> = OnceLock::new();
+impl Sessions {
+    pub fn get() -> &'static Sessions {
+        SESSIONS.get_or_init(|| Sessions {
+            sessions: DashMap::new(),
+        })
+    }
+
+    /**
+     * Takes a detached session of the user so that a new websocket can continue it.
+     */
+    pub fn take(&self, session_id: Uuid, user_id: Uuid) -> Option<Session> {
+        self.sessions
+            .remove_if(&session_id, |_, session| session.online_user.user.id == user_id)
+            .map(|(_, session)| session)
+    }
+}
+
+struct ReplayBuffer {
+    next_seq: u64,
+    /// Highest sequence number that was dropped from the buffer
+    evicted_seq: u64,
+    events: VecDeque<SequencedMessage>,
+}
+
+impl ReplayBuffer {
+    fn new() -> Self {
+        ReplayBuffer {
+            next_seq: 1,
+            evicted_seq: 0,
+            events: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, message: WebSocketMessage) -> SequencedMessage {
+        let message = SequencedMessage {
+            seq: self.next_seq,
+            message,
+        };
+        self.next_seq += 1;
+        if message.message.is_replayable() {
+            if self.events.len() >= REPLAY_BUFFER_SIZE {
+                if let Some(evicted) = self.events.pop_front() {
+                    self.evicted_seq = evicted.seq;
+                }
+            }
+            self.events.push_back(message.clone());
+        }
+        message
+    }
+
+    fn since(&self, last_seq: u64) -> Option<Vec<SequencedMessage>> {
+        if last_seq < self.evicted_seq {
+            return None;
+        }
+        Some(
+            self.events
+                .iter()
+                .filter(|event| event.seq > last_seq)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/**
+ * Event stream of a websocket connection that outlives the socket itself.
+ *
+ * Every event sent to the client gets a sequence number. While the socket is closed the
+ * events keep being recorded, so that a reconnecting client can resume where it left off.
+ */
+#[derive(Clone)]
+pub struct Session {
+    pub id: Uuid,
+    pub online_user: OnlineUser,
+    events: Arc<Mutex<Receiver<WebSocketMessage>>>,
+    replay: Arc<StdMutex<ReplayBuffer>>,
+    resumed: Arc<Notify>,
+}
+
+impl Session {
+    pub fn new(user: Users) -> Self {
+        let (tx, rx) = channel::<WebSocketMessage>(100);
+        let id = Uuid::new_v4();
+        Session {
+            id,
+            online_user: OnlineUser::new(user, id, tx),
+            events: Arc::new(Mutex::new(rx)),
+            replay: Arc::new(StdMutex::new(ReplayBuffer::new())),
+            resumed: Arc::new(Notify::new()),
+        }
+    }
+
+    /**
+     * Locks the receiving end of the events, only one websocket can send them at a time.
+     */
+    pub async fn events(&self) -> OwnedMutexGuard<Receiver<WebSocketMessage>> {
+        self.events.clone().lock_owned().await
+    }
+
+    pub fn record(&self, message: WebSocketMessage) -> SequencedMessage {
+        self.replay.lock().unwrap().record(message)
+    }
+
+    /**
+     * Events after `last_seq`, None if some of them are no longer buffered.
+     */
+    pub fn replay_since(&self, last_seq: u64) -> Option<Vec<SequencedMessage>> {
+        self.replay.lock().unwrap().since(last_seq)
+    }
+
+    /**
+     * What a client resuming after `last_seq` is sent, the missed events it understands
+     * and then `Resumed`. None if some of them are no longer buffered.
+     */
+    pub fn resume_messages(
+        &self,
+        last_seq: u64,
+        features: &HashSet<Feature>,
+    ) -> Option<Vec<SequencedMessage>> {
+        let resumed = SequencedMessage {
+            seq: 0,
+            message: WebSocketMessage::Resumed { session_id: self.id },
+        };
+        // Missed events first, so that the client knows about a lost call before it rejoins
+        Some(
+            self.replay_since(last_seq)?
+                .into_iter()
+                .filter(|event| {
+                    event
+                        .message
+                        .required_feature()
+                        .is_none_or(|feature| features.contains(&feature))
+                })
+                .chain(std::iter::once(resumed))
+                .collect(),
+        )
+    }
+
+    /**
+     * Keeps recording the events of the session after its websocket closed,
+     * until the client resumes it or the resume timeout runs out.
+     */
+    pub fn detach(self) {
+        Sessions::get().sessions.insert(self.id, self.clone());
+        tokio::spawn(async move {
+            let mut events = self.events().await;
+            let timeout = tokio::time::sleep(RESUME_TIMEOUT);
+            tokio::pin!(timeout);
+            loop {
+                tokio::select! {
+                    event = events.recv() => {
+                        match event {
+                            Some(event) => {
+                                self.record(event);
+                            }
+                            None => break,
+                        }
+                    }
+                    _ = self.resumed.notified() => return,
+                    _ = &mut timeout => break,
+                }
+            }
+            drop(events);
+            // Only clean up if nobody resumed the session in the meantime
+            if Sessions::get().sessions.remove(&self.id).is_some() {
+                tracing::info!("Session {} expired", self.id);
+                OnlineUsers::get().remove_user(self.online_user);
+            }
+        });
+    }
+
+    /**
+     * Stops recording in the background so that a new websocket can take the events over.
+     */
+    pub fn resume(&self) {
+        self.resumed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> Users {
+        Users {
+            id: Uuid::new_v4(),
+            username: "test".to_string(),
+            email: "test@example.com".to_string(),
+            password: String::new(),
+            deleted: false,
+            created_at: chrono::NaiveDateTime::default(),
+            activated: true,
+        }
+    }
+
+    fn speaking(n: u128) -> WebSocketMessage {
+        WebSocketMessage::SpeakingStarted {
+            user_id: Uuid::from_u128(n),
+        }
+    }
+
+    fn seqs(events: Option<Vec<SequencedMessage>>) -> Option<Vec<u64>> {
+        events.map(|events| events.iter().map(|event| event.seq).collect())
+    }
+
+    #[test]
+    fn numbers_every_event_and_keeps_the_replayable_ones() {
+        let mut replay = ReplayBuffer::new();
+        assert_eq!(replay.record(speaking(1)).seq, 1);
+        assert_eq!(
+            replay
+                .record(WebSocketMessage::HeartbeatAck { nonce: 1 })
+                .seq,
+            2
+        );
+        assert_eq!(replay.record(speaking(2)).seq, 3);
+        assert_eq!(seqs(replay.since(0)), Some(vec![1, 3]));
+        assert_eq!(seqs(replay.since(1)), Some(vec![3]));
+        assert_eq!(seqs(replay.since(3)), Some(vec![]));
+    }
+
+    #[test]
+    fn resumes_only_after_the_evicted_events() {
+        let mut replay = ReplayBuffer::new();
+        for n in 0..REPLAY_BUFFER_SIZE as u128 + 2 {
+            replay.record(speaking(n));
+        }
+        assert_eq!(replay.events.len(), REPLAY_BUFFER_SIZE);
+        assert_eq!(replay.since(0).map(|events| events.len()), None);
+        assert_eq!(replay.since(1).map(|events| events.len()), None);
+        let missed = replay.since(2).unwrap();
+        assert_eq!(missed.len(), REPLAY_BUFFER_SIZE);
+        assert_eq!(missed.first().unwrap().seq, 3);
+    }
+
+    #[test]
+    fn events_that_are_not_replayed_take_no_room() {
+        let mut replay = ReplayBuffer::new();
+        for nonce in 0..REPLAY_BUFFER_SIZE as u64 * 2 {
+            replay.record(WebSocketMessage::HeartbeatAck { nonce });
+        }
+        assert_eq!(seqs(replay.since(0)), Some(vec![]));
+    }
+
+    #[test]
+    fn sends_the_missed_events_before_resumed() {
+        let session = Session::new(user());
+        session.record(speaking(1));
+        session.record(WebSocketMessage::VoiceTakenOver {
+            channel_id: Uuid::from_u128(2),
+        });
+        session.record(WebSocketMessage::HeartbeatAck { nonce: 3 });
+        let features = HashSet::from([Feature::Resume, Feature::VoiceTakeover]);
+        let messages = session.resume_messages(0, &features).unwrap();
+        assert_eq!(seqs(Some(messages.clone())), Some(vec![2, 0]));
+        assert!(matches!(
+            messages[0].message,
+            WebSocketMessage::VoiceTakenOver { .. }
+        ));
+        assert!(matches!(
+            messages[1].message,
+            WebSocketMessage::Resumed { session_id } if session_id == session.id
+        ));
+        // Nothing missed, only the confirmation
+        assert_eq!(seqs(session.resume_messages(3, &features)), Some(vec![0]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn detached_sessions_record_until_they_expire() {
+        let session = Session::new(user());
+        let (id, user_id) = (session.id, session.online_user.user.id);
+        let websocket = session.online_user.websocket.clone();
+        let replay = session.clone();
+        session.detach();
+        websocket.send(speaking(1)).await.unwrap();
+        tokio::time::sleep(RESUME_TIMEOUT - Duration::from_secs(1)).await;
+        assert!(Sessions::get().sessions.contains_key(&id));
+        assert_eq!(seqs(replay.replay_since(0)), Some(vec![1]));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(Sessions::get().take(id, user_id).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resumed_sessions_stop_recording() {
+        let session = Session::new(user());
+        let (id, user_id) = (session.id, session.online_user.user.id);
+        session.detach();
+        tokio::task::yield_now().await;
+        assert!(Sessions::get().take(id, Uuid::new_v4()).is_none());
+        let resumed = Sessions::get().take(id, user_id).unwrap();
+        resumed.resume();
+        tokio::time::sleep(RESUME_TIMEOUT * 2).await;
+        // The events are left for the new websocket
+        resumed
+            .online_user
+            .websocket
+            .send(speaking(1))
+            .await
+            .unwrap();
+        assert!(resumed.events().await.try_recv().is_ok());
+    }
+}
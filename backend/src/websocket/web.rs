@@ -1,17 +1,78 @@
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, OnceLock};
 use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 
-use axum::extract::ws::{Message::Text, WebSocket, WebSocketUpgrade};
+use axum::extract::ws::{Message::Binary, Message::Text, WebSocket, WebSocketUpgrade};
 use axum::response::IntoResponse;
 use axum_login::login_required;
-use shared::{Packet, Split, WebRTCConnection, WebSocketMessage};
+use shared::{
+    Encoding, Feature, Frame, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, Packet, PublishedTrack,
+    RTCPeerConnectionState, SequencedMessage, Split, WebRTCConnection, WebSocketMessage,
+};
 use ringbuf::HeapRb;
 use tokio::sync::Mutex;
-use tokio::sync::mpsc::{Sender, channel};
+use uuid::Uuid;
 
 use crate::Error;
-use crate::channels::VoiceRooms;
+use crate::channels::{VoiceRoom, VoiceRooms};
 use crate::models::{AuthSession, Backend};
+use crate::websocket::rpc::handle_request;
+use crate::websocket::session::{Session, Sessions};
+
+/// Peers that are not connected after this long are dropped from the voice room
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a failed peer keeps its slot while the client tries to restart ICE
+const ICE_RESTART_GRACE: Duration = Duration::from_secs(15);
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 15;
+/// Number of heartbeats a client can miss before its connection is considered dead
+const MISSED_HEARTBEATS: u32 = 3;
+
+/**
+ * How often clients have to send a heartbeat, configured with `HEARTBEAT_INTERVAL_SECS`.
+ */
+fn heartbeat_interval() -> Duration {
+    static INTERVAL: OnceLock<Duration> = OnceLock::new();
+    *INTERVAL.get_or_init(|| {
+        let secs = std::env::var("HEARTBEAT_INTERVAL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECS);
+        Duration::from_secs(secs)
+    })
+}
+
+/**
+ * How messages are written to the socket of a session. Clients from before the handshake
+ * read bare JSON messages, the others sequenced ones in the negotiated encoding.
+ */
+#[derive(Debug, Clone, Copy)]
+struct Framing {
+    encoding: Encoding,
+    sequenced: bool,
+}
+
+impl Framing {
+    const LEGACY: Framing = Framing {
+        encoding: Encoding::Json,
+        sequenced: false,
+    };
+
+    fn negotiated(features: &HashSet<Feature>) -> Self {
+        Framing {
+            encoding: Encoding::negotiated(features),
+            sequenced: true,
+        }
+    }
+
+    fn encode(&self, msg: &SequencedMessage) -> Result<Frame, shared::Error> {
+        if self.sequenced {
+            self.encoding.encode(msg)
+        } else {
+            self.encoding.encode(&msg.message)
+        }
+    }
+}
 
 pub fn router() -> axum::Router {
     axum::Router::new()
@@ -20,7 +81,7 @@ pub fn router() -> axum::Router {
 }
 
 mod post {
-    use shared::{WebSocketError, ROOM_SIZE};
+    use shared::WebSocketError;
 
     use shared::models::PermissionType;
 
@@ -44,12 +105,18 @@ mod post {
         ws.on_upgrade(async move |mut socket| {
             // Create a new WebRTC connection
             let mut web_rtc_connection = None;
-            let (tx, mut rx) = channel::<WebSocketMessage>(100);
-            // Add the user to the online users list
-            let online_users = OnlineUsers::get();
+            // Every connection starts a new session, the client may resume an older one instead
             let user = auth.user.as_ref().unwrap();
-            let online_user = OnlineUser::new(user.0.clone(), tx.clone());
-            online_users.add_user(online_user.clone());
+            let mut session = Session::new(user.0.clone());
+            // Add the user to the online users list
+            OnlineUsers::get().add_user(session.online_user.clone());
+            let mut events = session.events().await;
+            // Announced after the handshake, in the framing it negotiated
+            let mut session_started = false;
+            let mut last_seen = tokio::time::Instant::now();
+            // Clients that do not send Hello are from before the handshake
+            let mut features = Feature::legacy();
+            let mut framing = Framing::LEGACY;
             loop {
                 tokio::select! {
                     msg = socket.recv() => {
@@ -62,50 +129,275 @@ mod post {
                             tracing::error!("Error receiving WebSocket message: {}", err);
                             continue;
                         }
+                        last_seen = tokio::time::Instant::now();
                         let msg = msg.unwrap();
                         let msg = match msg {
-                            Text(msg) => msg,
+                            Text(msg) => WebSocketMessage::from_json(&msg).map_err(shared::Error::from),
+                            Binary(msg) => WebSocketMessage::from_msgpack(&msg),
                             _ => {
                                 tracing::error!("Received unsupported WebSocket message type");
                                 continue;
                             }
                         };
-                        let msg = serde_json::from_str::<WebSocketMessage>(&msg);
-                        if let Err(err) = msg {
-                            tracing::error!("Failed to parse WebSocket message: {}", err);
+                        let msg = match msg {
+                            Ok(Some(msg)) => msg,
+                            Ok(None) => {
+                                tracing::debug!("Skipping WebSocket message of a newer protocol version");
+                                continue;
+                            }
+                            Err(err) => {
+                                tracing::error!("Failed to parse WebSocket message: {}", err);
+                                continue;
+                            }
+                        };
+                        if let WebSocketMessage::Hello { protocol_version, features: client_features } = msg {
+                            // Answered in JSON, the negotiated encoding applies to what follows
+                            framing = Framing {
+                                encoding: Encoding::Json,
+                                sequenced: true,
+                            };
+                            if protocol_version < MIN_PROTOCOL_VERSION {
+                                tracing::warn!("User {} uses unsupported protocol version {}", user.0.id, protocol_version);
+                                send_control(
+                                    WebSocketMessage::Error {
+                                        err: WebSocketError::UnsupportedProtocol {
+                                            min_version: MIN_PROTOCOL_VERSION,
+                                        },
+                                    },
+                                    framing,
+                                    &mut socket,
+                                )
+                                .await;
+                                break;
+                            }
+                            features = Feature::negotiate(&client_features);
+                            send_control(
+                                WebSocketMessage::HelloAck {
+                                    protocol_version: PROTOCOL_VERSION.min(protocol_version),
+                                    features: features.clone(),
+                                },
+                                framing,
+                                &mut socket,
+                            )
+                            .await;
+                            framing = Framing::negotiated(&features);
+                            if !session_started {
+                                session_started = true;
+                                send_session_started(&session, framing, &mut socket).await;
+                            }
                             continue;
                         }
-                        let msg = msg.unwrap();
-                        match handle_recv(msg, &auth, &mut web_rtc_connection, tx.clone()).await {
+                        if !session_started {
+                            // The first frame was not a Hello, the client is from before the handshake
+                            session_started = true;
+                            send_session_started(&session, framing, &mut socket).await;
+                        }
+                        if let WebSocketMessage::Heartbeat { nonce } = msg {
+                            send_control(WebSocketMessage::HeartbeatAck { nonce }, framing, &mut socket).await;
+                            continue;
+                        }
+                        if let WebSocketMessage::Resume { session_id, last_seq } = msg {
+                            drop(events);
+                            session = resume_session(session, session_id, last_seq, &features, &mut socket).await;
+                            events = session.events().await;
+                            continue;
+                        }
+                        match handle_recv(msg, &auth, &mut web_rtc_connection, &session.online_user).await {
                             Ok(_) => {}
                             Err(err) => {
                                 tracing::error!("Failed to handle WebSocket message: {}", err);
                             }
                         }
                     },
-                    req = rx.recv() => {
+                    req = events.recv() => {
                         if req.is_none() {
                             tracing::warn!("WebSocket message channel closed");
                             break;
                         }
                         let req = req.unwrap();
-                        handle_send(req, &mut socket).await;
+                        // Older clients can not parse messages of features they do not have
+                        if !supports(&features, &req) {
+                            continue;
+                        }
+                        handle_send(session.record(req), framing, &mut socket).await;
+                    }
+                    // Clients without heartbeats are only dropped when their connection closes
+                    _ = tokio::time::sleep_until(last_seen + heartbeat_interval() * MISSED_HEARTBEATS),
+                        if features.contains(&Feature::Heartbeat) => {
+                        // A half open connection never closes by itself, treat it like a disconnect
+                        tracing::warn!("No heartbeat from user {}, closing the connection", user.0.id);
+                        break;
                     }
                 }
             }
+            let online_user = session.online_user.clone();
             if let Some(voice_room) = online_user.get_audio_channel() {
                 tracing::info!("Disconnecting from audio channel: {}", voice_room.channel.id);
-                if let Err(err) = voice_room.leave_person(user.0.id).await {
+                if let Err(err) = voice_room.leave_session(online_user.session_id).await {
                     tracing::error!("Failed to leave audio channel: {}", err);
                 }
             }
-            online_users.remove_user(online_user);
+            online_user.clear_audio_channel();
+            if let Some(web_rtc_connection) = web_rtc_connection.take() {
+                web_rtc_connection.close().await;
+            }
+            // Keep the events flowing into the session until the client resumes it
+            drop(events);
+            session.detach();
         })
     }
 
-    async fn handle_send(msg: WebSocketMessage, socket: &mut WebSocket) {
-        let serialized = serde_json::to_string(&msg).unwrap();
-        if let Err(err) = socket.send(Text(serialized.into())).await {
+    /**
+     * Continues a detached session of the user on this websocket and replays the events
+     * the client missed. Keeps the current session and tells the client to fetch everything
+     * again if the old one is gone or missed too many events.
+     */
+    async fn resume_session(
+        current: Session,
+        session_id: Uuid,
+        last_seq: u64,
+        features: &HashSet<Feature>,
+        socket: &mut WebSocket,
+    ) -> Session {
+        let framing = Framing::negotiated(features);
+        let user_id = current.online_user.user.id;
+        let Some(session) = Sessions::get().take(session_id, user_id) else {
+            tracing::info!("Session {} can not be resumed", session_id);
+            send_control(WebSocketMessage::FullRefresh, framing, socket).await;
+            return current;
+        };
+        session.resume();
+        let Some(messages) = session.resume_messages(last_seq, features) else {
+            tracing::info!("Session {} missed too many events to be resumed", session_id);
+            OnlineUsers::get().remove_user(session.online_user);
+            send_control(WebSocketMessage::FullRefresh, framing, socket).await;
+            return current;
+        };
+        tracing::info!("Resuming session {} with {} missed events", session_id, messages.len() - 1);
+        // The resumed session is still subscribed, the one of this connection is dropped
+        OnlineUsers::get().add_user(session.online_user.clone());
+        OnlineUsers::get().remove_user(current.online_user);
+        for message in messages {
+            handle_send(message, framing, socket).await;
+        }
+        session
+    }
+
+    /**
+     * Frees the slot of a user whose peer connection failed or was closed,
+     * unless they already moved on to another connection.
+     */
+    async fn leave_dead_connection(online_user: &OnlineUser, connection: &Arc<WebRTCConnection>) {
+        let Some(voice_room) = online_user.get_audio_channel() else {
+            return;
+        };
+        if voice_room.leave_connection(online_user.user.id, connection).await {
+            tracing::info!(
+                "Removed user {} with a dead connection from audio channel {}",
+                online_user.user.id,
+                voice_room.channel.id
+            );
+            online_user.clear_audio_channel();
+        }
+    }
+
+    /**
+     * Only one session of a user can be in a call. Removes the other sessions from their
+     * voice rooms and tells them, including detached ones so that they do not rejoin on resume.
+     */
+    async fn take_over_voice(online_user: &OnlineUser, channel_id: Uuid) {
+        for other in OnlineUsers::get().get_sessions(online_user.user.id) {
+            if other == *online_user {
+                continue;
+            }
+            take_over_session(online_user, &other, channel_id, false).await;
+        }
+    }
+
+    /**
+     * Removes `other` from its voice room and tells it, `replaced` when joining already
+     * took its slot because both sessions joined at the same time.
+     */
+    async fn take_over_session(online_user: &OnlineUser, other: &OnlineUser, channel_id: Uuid, replaced: bool) {
+        let in_call = match other.get_audio_channel() {
+            Some(voice_room) => {
+                tracing::info!(
+                    "Session {} takes over the call of session {} in audio channel {}",
+                    online_user.session_id,
+                    other.session_id,
+                    voice_room.channel.id
+                );
+                if !replaced {
+                    if let Err(err) = voice_room.leave_session(other.session_id).await {
+                        tracing::error!("Failed to leave audio channel: {}", err);
+                    }
+                }
+                other.clear_audio_channel();
+                true
+            }
+            None => replaced,
+        };
+        let detached = Sessions::get().sessions.contains_key(&other.session_id);
+        if !in_call && !detached {
+            return;
+        }
+        if let Err(err) = other
+            .websocket
+            .send(WebSocketMessage::VoiceTakenOver { channel_id })
+            .await
+        {
+            tracing::error!("Failed to tell session {} about the takeover: {}", other.session_id, err);
+        }
+    }
+
+    /**
+     * Voice room the session is in, tells the client if it is in none.
+     */
+    async fn current_voice_room(online_user: &OnlineUser) -> Result<VoiceRoom, Error> {
+        match online_user.get_audio_channel() {
+            Some(voice_room) => Ok(voice_room),
+            None => {
+                online_user
+                    .websocket
+                    .send(WebSocketMessage::Error {
+                        err: WebSocketError::NotInAudioChannel,
+                    })
+                    .await?;
+                Err(WebSocketError::NotInAudioChannel.into())
+            }
+        }
+    }
+
+    fn supports(features: &HashSet<Feature>, msg: &WebSocketMessage) -> bool {
+        msg.required_feature()
+            .is_none_or(|feature| features.contains(&feature))
+    }
+
+    /**
+     * Sends a message about the session itself, which is not part of the event stream.
+     */
+    async fn send_control(msg: WebSocketMessage, framing: Framing, socket: &mut WebSocket) {
+        handle_send(SequencedMessage { seq: 0, message: msg }, framing, socket).await;
+    }
+
+    async fn send_session_started(session: &Session, framing: Framing, socket: &mut WebSocket) {
+        let msg = WebSocketMessage::SessionStarted {
+            session_id: session.id,
+            heartbeat_interval_ms: heartbeat_interval().as_millis() as u64,
+        };
+        send_control(msg, framing, socket).await;
+    }
+
+    async fn handle_send(msg: SequencedMessage, framing: Framing, socket: &mut WebSocket) {
+        let frame = match framing.encode(&msg) {
+            Ok(Frame::Text(text)) => Text(text.into()),
+            Ok(Frame::Binary(bytes)) => Binary(bytes.into()),
+            Err(err) => {
+                tracing::error!("Failed to encode WebSocket message: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = socket.send(frame).await {
             tracing::error!("Failed to send WebSocket message: {}", err);
         }
     }
@@ -113,23 +405,13 @@ mod post {
     pub async fn handle_recv(
         msg: WebSocketMessage,
         auth: &AuthSession,
-        web_rtc_connection: &mut Option<WebRTCConnection>,
-        socket: Sender<WebSocketMessage>,
+        web_rtc_connection: &mut Option<Arc<WebRTCConnection>>,
+        online_user: &OnlineUser,
     ) -> Result<(), Error> {
         // Handle the WebSocket message here
         let backend = &auth.backend;
         let user = (&auth.user).as_ref().unwrap();
-        let online_user = OnlineUsers::get()
-            .users
-            .get(&user.0.id)
-            .unwrap_or_else(|| {
-                // Should never happen, but just in case
-                let online_user = OnlineUser::new(user.0.clone(), socket.clone());
-                let online_users = OnlineUsers::get();
-                online_users.add_user(online_user);
-                online_users.users.get(&user.0.id).unwrap()
-            });
-        let online_user = online_user.value();
+        let socket = online_user.websocket.clone();
         match msg {
             WebSocketMessage::JoinAudioChannel {
                 server_id,
@@ -165,29 +447,24 @@ mod post {
                         .await?;
                     return Err(WebSocketError::NotAuthorized.into());
                 }
-                // Initialize the WebRTC connection (dropping the previous one if it exists)
-                *web_rtc_connection = Some(WebRTCConnection::new(channel_id, None).await?);
-                let web_rtc_connection = web_rtc_connection.as_ref().unwrap();
-                // Create audio tracks for the user
-                let recv_tracks = web_rtc_connection.create_audio_track_rtp(ROOM_SIZE).await?;
-                // Disconnect old audio channel
-                if let Some(old_channel) = online_user.get_audio_channel() {
-                    tracing::info!("Leaving audio channel: {}", old_channel.channel.id);
-                    if let Err(err) = old_channel.leave_person(user.0.id).await {
-                        tracing::error!("Failed to leave audio channel: {}", err);
+                // Before leaving the current call, joining checks again in case it filled up meanwhile
+                if let Some(room) = VoiceRooms::get_or_init().get_room(&channel) {
+                    if !room.has_room_for(user.0.id).await {
+                        tracing::warn!("Channel {} is full", channel_id);
+                        socket
+                            .send(WebSocketMessage::Error {
+                                err: WebSocketError::RoomFull,
+                            })
+                            .await?;
+                        return Err(Error::RoomFull);
                     }
                 }
-                // Join the voice room
-                let room = VoiceRooms::get_or_init().get_room_or_init(&server, &channel);
-                let person_id = room.join_person(&user.0, recv_tracks).await?;
-                let tracks = room.get_track_i_of_all(person_id).await;
-                online_user.set_audio_channel(room);
-                // Set up the data forwarding
-                let (prod, cons) = HeapRb::<Packet>::new(100).split();
-                let dropped = Arc::new(AtomicBool::new(false));
-                web_rtc_connection
-                    .background_receive_data(Arc::new(Mutex::new(prod)), dropped.clone());
-                web_rtc_connection.background_stream_data(cons, dropped.clone(), tracks);
+                // Initialize the WebRTC connection (dropping the previous one if it exists)
+                let connection = Arc::new(WebRTCConnection::new(channel_id, None).await?);
+                if let Some(old_connection) = web_rtc_connection.replace(connection.clone()) {
+                    old_connection.close().await;
+                }
+                let web_rtc_connection = connection;
                 // Create the callbacks for the WebRTC connection
                 web_rtc_connection
                     .peer_connection
@@ -195,11 +472,40 @@ mod post {
                         tracing::debug!("ICE connection state: {:?}", state);
                         Box::pin(async {})
                     }));
+                let user_id = user.0.id;
+                let callback_user = online_user.clone();
+                let weak_connection = Arc::downgrade(&web_rtc_connection);
                 web_rtc_connection
                     .peer_connection
                     .on_peer_connection_state_change(Box::new(move |state| {
                         tracing::debug!("Peer connection state: {:?}", state);
-                        Box::pin(async move {})
+                        let weak_connection = weak_connection.clone();
+                        let online_user = callback_user.clone();
+                        Box::pin(async move {
+                            match state {
+                                RTCPeerConnectionState::Closed => {
+                                    if let Some(connection) = weak_connection.upgrade() {
+                                        leave_dead_connection(&online_user, &connection).await;
+                                    }
+                                }
+                                RTCPeerConnectionState::Failed => {
+                                    // Keep the slot while the client restarts ICE
+                                    tokio::spawn(async move {
+                                        tokio::time::sleep(ICE_RESTART_GRACE).await;
+                                        let Some(connection) = weak_connection.upgrade() else {
+                                            return;
+                                        };
+                                        if connection.peer_connection.connection_state()
+                                            != RTCPeerConnectionState::Connected
+                                        {
+                                            tracing::warn!("ICE restart did not recover the connection of user {}", user_id);
+                                            leave_dead_connection(&online_user, &connection).await;
+                                        }
+                                    });
+                                }
+                                _ => {}
+                            }
+                        })
                     }));
                 let socket_clone = socket.clone();
                 web_rtc_connection.setup_ice_handling(move |ws_candidate| {
@@ -213,10 +519,99 @@ mod post {
                         }
                     }
                 });
-
-                // Create and send the WebRTC offer
-                let offer = web_rtc_connection.create_offer().await?;
-                socket.send(offer).await?;
+                // Offers are created whenever people join or leave the room
+                let socket_clone = socket.clone();
+                web_rtc_connection.setup_renegotiation(move |offer| {
+                    let socket_clone = socket_clone.clone();
+                    async move {
+                        if let Err(err) = socket_clone.send(offer).await {
+                            tracing::error!("Failed to send WebRTC offer: {}", err);
+                        }
+                    }
+                });
+                // Receive the audio of the user even if the room is empty
+                web_rtc_connection.add_receive_transceiver().await?;
+                take_over_voice(online_user, channel_id).await;
+                // Disconnect old audio channel
+                if let Some(old_channel) = online_user.get_audio_channel() {
+                    tracing::info!("Leaving audio channel: {}", old_channel.channel.id);
+                    if let Err(err) = old_channel.leave_session(online_user.session_id).await {
+                        tracing::error!("Failed to leave audio channel: {}", err);
+                    }
+                    online_user.clear_audio_channel();
+                }
+                // Join the voice room
+                let (room, slot, tracks, published, replaced_session) = loop {
+                    let room = VoiceRooms::get_or_init().get_room_or_init(&server, &channel);
+                    match room
+                        .join_person(
+                            &user.0,
+                            online_user.session_id,
+                            web_rtc_connection.clone(),
+                            socket.clone(),
+                        )
+                        .await
+                    {
+                        Ok((slot, tracks, published, replaced_session)) => {
+                            break (room, slot, tracks, published, replaced_session)
+                        }
+                        // The room was emptied and removed while joining, get a new one
+                        Err(Error::RoomClosed) => continue,
+                        Err(Error::RoomFull) => {
+                            tracing::warn!("Channel {} is full", channel_id);
+                            socket
+                                .send(WebSocketMessage::Error {
+                                    err: WebSocketError::RoomFull,
+                                })
+                                .await?;
+                            return Err(Error::RoomFull);
+                        }
+                        Err(err) => return Err(err),
+                    }
+                };
+                let active_speakers = room.active_speakers.clone();
+                online_user.set_audio_channel(room);
+                // Another session of the user joined at the same time and lost its slot to this one
+                if let Some(replaced) = OnlineUsers::get()
+                    .get_sessions(user.0.id)
+                    .into_iter()
+                    .find(|other| Some(other.session_id) == replaced_session)
+                {
+                    take_over_session(online_user, &replaced, channel_id, true).await;
+                }
+                // Set up the data forwarding
+                let (prod, cons) = HeapRb::<Packet>::new(100).split();
+                let dropped = Arc::new(AtomicBool::new(false));
+                web_rtc_connection.request_wanted_keyframes(&published);
+                web_rtc_connection.background_receive_data(
+                    Arc::new(Mutex::new(prod)),
+                    dropped.clone(),
+                    active_speakers.clone(),
+                    published,
+                    slot,
+                );
+                web_rtc_connection.background_stream_data(
+                    cons,
+                    dropped.clone(),
+                    tracks,
+                    active_speakers,
+                    slot,
+                );
+                // Drop peers that never finish connecting, closing fires the state callback
+                let weak_connection = Arc::downgrade(&web_rtc_connection);
+                tokio::spawn(async move {
+                    tokio::time::sleep(NEGOTIATION_TIMEOUT).await;
+                    let Some(connection) = weak_connection.upgrade() else {
+                        return;
+                    };
+                    if matches!(
+                        connection.peer_connection.connection_state(),
+                        RTCPeerConnectionState::New | RTCPeerConnectionState::Connecting
+                    ) {
+                        tracing::warn!("WebRTC connection was not established in time, closing it");
+                        connection.close().await;
+                    }
+                });
             }
             WebSocketMessage::WebRTCOffer(_) => {
                 tracing::warn!("Received WebRTC offer as the server, this should not happen");
@@ -249,10 +644,66 @@ mod post {
                     return Err(Error::WebRTCConnectionNotInitialized);
                 }
             }
+            WebSocketMessage::RestartIce => {
+                tracing::info!("Restarting ICE for user {}", user.0.id);
+                let Some(web_rtc_connection) = web_rtc_connection else {
+                    tracing::error!("WebRTC connection is not initialized");
+                    return Err(Error::WebRTCConnectionNotInitialized);
+                };
+                if online_user.get_audio_channel().is_none() {
+                    // The slot is already gone, the client has to join again
+                    socket
+                        .send(WebSocketMessage::Error {
+                            err: WebSocketError::NotInAudioChannel,
+                        })
+                        .await?;
+                    return Err(WebSocketError::NotInAudioChannel.into());
+                }
+                let offer = web_rtc_connection.create_ice_restart_offer().await?;
+                socket.send(offer).await?;
+            }
+            WebSocketMessage::SetCamera { enabled } => {
+                let voice_room = current_voice_room(online_user).await?;
+                tracing::info!("User {} turned their camera {}", user.0.id, if enabled { "on" } else { "off" });
+                voice_room.set_camera(online_user.session_id, enabled).await?;
+            }
+            WebSocketMessage::SetVoiceState { muted, deafened } => {
+                let voice_room = current_voice_room(online_user).await?;
+                voice_room.set_voice_state(online_user.session_id, muted, deafened).await?;
+            }
+            WebSocketMessage::SetVideoSubscriptions { user_ids } => {
+                let voice_room = current_voice_room(online_user).await?;
+                voice_room
+                    .set_video_subscriptions(online_user.session_id, user_ids.into_iter().collect())
+                    .await?;
+            }
+            WebSocketMessage::StartScreenShare { audio } => {
+                let voice_room = current_voice_room(online_user).await?;
+                tracing::info!("User {} started sharing their screen, with audio: {}", user.0.id, audio);
+                voice_room.set_screen_share(online_user.session_id, true, audio).await?;
+            }
+            WebSocketMessage::StopScreenShare => {
+                let voice_room = current_voice_room(online_user).await?;
+                tracing::info!("User {} stopped sharing their screen", user.0.id);
+                voice_room.set_screen_share(online_user.session_id, false, false).await?;
+            }
+            WebSocketMessage::SetVideoSize { user_id, screen, height } => {
+                let voice_room = current_voice_room(online_user).await?;
+                let published = if screen { PublishedTrack::Screen } else { PublishedTrack::Camera };
+                voice_room.set_video_size(online_user.session_id, user_id, published, height).await?;
+            }
+            WebSocketMessage::WatchScreenShare { user_id } => {
+                let voice_room = current_voice_room(online_user).await?;
+                voice_room.watch_screen_share(online_user.session_id, user_id, true).await?;
+            }
+            WebSocketMessage::StopWatchingScreenShare { user_id } => {
+                let voice_room = current_voice_room(online_user).await?;
+                voice_room.watch_screen_share(online_user.session_id, user_id, false).await?;
+            }
             WebSocketMessage::DisconnectFromAudioChannel => {
                 if let Some(voice_room) = online_user.get_audio_channel() {
                     tracing::info!("Disconnecting from audio channel: {}", voice_room.channel.id);
-                    if let Err(err) = voice_room.leave_person(user.0.id).await {
+                    if let Err(err) = voice_room.leave_session(online_user.session_id).await {
                         tracing::error!("Failed to leave audio channel: {}", err);
                     }
                 } else {
@@ -274,6 +725,50 @@ mod post {
             WebSocketMessage::SomeoneLeftAudioChannel { data } => {
                 tracing::warn!("Received SomeoneLeftAudioChannel message, this should not happen on the server side: {:?}", data);
             }
+            WebSocketMessage::DominantSpeakerChanged { data } => {
+                tracing::warn!("Received DominantSpeakerChanged message, this should not happen on the server side: {:?}", data);
+            }
+            WebSocketMessage::SpeakingStarted { user_id } | WebSocketMessage::SpeakingStopped { user_id } => {
+                tracing::warn!("Received speaking update, this should not happen on the server side: {}", user_id);
+            }
+            WebSocketMessage::CameraChanged { data } => {
+                tracing::warn!("Received CameraChanged message, this should not happen on the server side: {:?}", data);
+            }
+            WebSocketMessage::ScreenShareChanged { data } => {
+                tracing::warn!("Received ScreenShareChanged message, this should not happen on the server side: {:?}", data);
+            }
+            WebSocketMessage::VoiceStateChanged { data } => {
+                tracing::warn!("Received VoiceStateChanged message, this should not happen on the server side: {:?}", data);
+            }
+            WebSocketMessage::Request { id, request } => {
+                tracing::debug!("Received request {}: {:?}", id, request);
+                let result = handle_request(request, auth, online_user).await;
+                socket.send(WebSocketMessage::Response { id, result }).await?;
+            }
+            WebSocketMessage::Response { id, .. } => {
+                tracing::warn!("Received Response {}, this should not happen on the server side", id);
+            }
+            WebSocketMessage::VoiceTakenOver { channel_id } => {
+                tracing::warn!("Received VoiceTakenOver message, this should not happen on the server side: {}", channel_id);
+            }
+            WebSocketMessage::Resume { session_id, .. } => {
+                tracing::warn!("Received Resume for session {} outside of the connection loop", session_id);
+            }
+            WebSocketMessage::Heartbeat { nonce } => {
+                tracing::warn!("Received Heartbeat {} outside of the connection loop", nonce);
+            }
+            WebSocketMessage::Hello { protocol_version, .. } => {
+                tracing::warn!("Received Hello for protocol version {} outside of the connection loop", protocol_version);
+            }
+            WebSocketMessage::HelloAck { protocol_version, .. } => {
+                tracing::warn!("Received HelloAck message, this should not happen on the server side: {}", protocol_version);
+            }
+            message @ (WebSocketMessage::SessionStarted { .. }
+            | WebSocketMessage::Resumed { .. }
+            | WebSocketMessage::FullRefresh
+            | WebSocketMessage::HeartbeatAck { .. }) => {
+                tracing::warn!("Received session message, this should not happen on the server side: {:?}", message);
+            }
         }
 
         Ok(())
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- 0: Voice, 1: Music
+ALTER TABLE audio_config ADD COLUMN audio_profile INTEGER NOT NULL DEFAULT 0;
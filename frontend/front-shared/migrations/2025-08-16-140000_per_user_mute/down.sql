@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE per_user_boost DROP COLUMN muted;
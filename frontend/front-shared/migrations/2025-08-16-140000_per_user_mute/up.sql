@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE per_user_boost ADD COLUMN muted BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE audio_config DROP COLUMN ptt_press_delay;
+ALTER TABLE audio_config DROP COLUMN ptt_release_delay;
+ALTER TABLE audio_config DROP COLUMN ptt_sound;
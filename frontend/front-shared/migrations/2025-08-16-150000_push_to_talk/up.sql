@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE audio_config ADD COLUMN ptt_press_delay INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE audio_config ADD COLUMN ptt_release_delay INTEGER NOT NULL DEFAULT 200;
+ALTER TABLE audio_config ADD COLUMN ptt_sound BOOLEAN NOT NULL DEFAULT 1;
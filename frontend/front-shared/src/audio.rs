@@ -3,9 +3,22 @@ use serde::{Deserialize, Serialize};
 pub mod last_used_devices;
 use last_used_devices::LastUsedAudioDevicesWString;
 
+use crate::FromEvent;
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct AudioDevices {
     pub mics: Vec<String>,
     pub speakers: Vec<String>,
     pub last_used_devices: Option<LastUsedAudioDevicesWString>,
-}
\ No newline at end of file
+}
+impl FromEvent for AudioDevices {}
+
+/// Level of the microphone for the meter in the settings
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct InputLevel {
+    /// Loudest level since the previous event in dBFS
+    pub level_db: f32,
+    /// Whether the microphone is being sent
+    pub transmitting: bool,
+}
+impl FromEvent for InputLevel {}
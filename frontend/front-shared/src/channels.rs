@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+use shared::models::ChannelWithUsers;
+use uuid::Uuid;
+
+use crate::FromEvent;
+
+/// Channels of the active server, sent again after the websocket reconnects
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelsSnapshot {
+    pub server_id: Uuid,
+    pub channels: Vec<ChannelWithUsers>,
+}
+impl FromEvent for ChannelsSnapshot {}
@@ -8,7 +8,7 @@ use std::error::Error;
 
 #[cfg(feature = "diesel")]
 use diesel::migration::MigrationVersion;
-use shared::models::AudioChannelMemberUpdate;
+use shared::models::{AudioChannelMemberUpdate, DominantSpeaker, VoiceState};
 pub use update::{DownloadProgress, UpdateState};
 
 mod login;
@@ -17,10 +17,17 @@ pub use login::{LoginStatus, Session};
 mod status;
 pub use status::*;
 
+mod channels;
+pub use channels::ChannelsSnapshot;
+
 pub mod audio;
 pub use audio::*;
 
+mod video;
+pub use video::VideoFrames;
+
 use serde::Deserialize;
+use uuid::Uuid;
 use wasm_bindgen::JsValue;
 
 #[derive(Deserialize)]
@@ -51,3 +58,6 @@ pub trait FromEvent {
 }
 
 impl FromEvent for AudioChannelMemberUpdate {}
+impl FromEvent for DominantSpeaker {}
+impl FromEvent for VoiceState {}
+impl FromEvent for Uuid {}
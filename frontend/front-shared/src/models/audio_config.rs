@@ -20,7 +20,19 @@ pub enum GlobalAttenuation {
 #[derive(Debug, Clone)]
 pub enum InputMode {
     VoiceActivityDetection(VoiceActivityDetectionCfg), // VAD configuration
-    PushToTalk(String),                                // PTT key code
+    PushToTalk(PushToTalkCfg),
+}
+
+#[derive(Debug, Clone)]
+pub struct PushToTalkCfg {
+    /// Code of the key as in `KeyboardEvent.code`, like "KeyV"
+    pub key_code: String,
+    /// Delay in ms between pressing the key and transmitting
+    pub press_delay_ms: i32,
+    /// Delay in ms the transmission goes on for after releasing the key
+    pub release_delay_ms: i32,
+    /// Whether a short sound plays when the transmission starts and stops
+    pub sound: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -37,6 +49,8 @@ pub struct AudioConfig {
     pub cfg: webrtc_audio_processing::Config,
     pub input_mode: Arc<StdMutex<InputMode>>,
     pub global_attenuation: Arc<StdMutex<Option<GlobalAttenuation>>>,
+    /// 0: Voice, 1: Music, see `AudioConfigDB::audio_profile`
+    pub audio_profile: i32,
 }
 
 #[cfg(feature = "diesel")]
@@ -78,7 +92,7 @@ impl AudioConfigDB {
     }
 }
 
-#[derive(Debug, Clone, PartialModify)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialModify)]
 #[cfg_attr(
     feature = "diesel",
     derive(Queryable, Selectable, Insertable, AsChangeset)
@@ -106,6 +120,11 @@ pub struct AudioConfigDB {
     /// Push-to-Talk (PTT) key code
     ///
     pub ptt_key_code: Option<String>,
+    /// Push-to-Talk delays in ms after pressing and releasing the key
+    pub ptt_press_delay: i32,
+    pub ptt_release_delay: i32,
+    /// Whether Push-to-Talk plays a sound when transmission starts and stops
+    pub ptt_sound: bool,
     /// Voice Activity Detection threshold in -dB
     ///
     /// None means automatic detection
@@ -120,6 +139,11 @@ pub struct AudioConfigDB {
     /// 0: Self Voice
     /// 1: Other Voice
     pub global_attenuation_trigger: Option<i32>,
+    /// What the microphone mostly carries, picks the Opus application and bitrate
+    ///
+    /// 0: Voice
+    /// 1: Music
+    pub audio_profile: i32,
 }
 
 impl Default for AudioConfigDB {
@@ -131,9 +155,13 @@ impl Default for AudioConfigDB {
             gain_controller: true,  // Assuming gain controller is enabled by default
             input_mode: 0,          // Default input mode
             ptt_key_code: None,     // Default PTT key code is None
+            ptt_press_delay: 0,
+            ptt_release_delay: 200, // Keeps the end of the last word
+            ptt_sound: true,
             vad_threshold: None,    // Default VAD threshold is None (automatic detection)
             global_attenuation: None, // Default global attenuation is None
             global_attenuation_trigger: None, // Default global attenuation trigger is None
+            audio_profile: 0,       // Voice
         }
     }
 }
@@ -156,9 +184,21 @@ impl From<&AudioConfig> for AudioConfigDB {
                 InputMode::PushToTalk(_) => 1,
             },
             ptt_key_code: match cfg.input_mode.lock().unwrap().deref() {
-                InputMode::PushToTalk(key_code) => Some(key_code.clone()),
+                InputMode::PushToTalk(ptt) => Some(ptt.key_code.clone()),
                 _ => None,
             },
+            ptt_press_delay: match cfg.input_mode.lock().unwrap().deref() {
+                InputMode::PushToTalk(ptt) => ptt.press_delay_ms,
+                _ => AudioConfigDB::default().ptt_press_delay,
+            },
+            ptt_release_delay: match cfg.input_mode.lock().unwrap().deref() {
+                InputMode::PushToTalk(ptt) => ptt.release_delay_ms,
+                _ => AudioConfigDB::default().ptt_release_delay,
+            },
+            ptt_sound: match cfg.input_mode.lock().unwrap().deref() {
+                InputMode::PushToTalk(ptt) => ptt.sound,
+                _ => AudioConfigDB::default().ptt_sound,
+            },
             vad_threshold: match cfg.input_mode.lock().unwrap().deref() {
                 InputMode::VoiceActivityDetection(VoiceActivityDetectionCfg::Manual {
                     threshold,
@@ -175,6 +215,7 @@ impl From<&AudioConfig> for AudioConfigDB {
                 Some(GlobalAttenuation::OtherVoice(_)) => Some(1),
                 None => None,
             },
+            audio_profile: cfg.audio_profile,
         }
     }
 }
@@ -241,9 +282,12 @@ impl From<&AudioConfigDB> for AudioConfig {
                         VoiceActivityDetectionCfg::Auto
                     },
                 ),
-                1 => {
-                    InputMode::PushToTalk(db_config.ptt_key_code.clone().unwrap_or("v".to_string()))
-                }
+                1 => InputMode::PushToTalk(PushToTalkCfg {
+                    key_code: db_config.ptt_key_code.clone().unwrap_or("KeyV".to_string()),
+                    press_delay_ms: db_config.ptt_press_delay,
+                    release_delay_ms: db_config.ptt_release_delay,
+                    sound: db_config.ptt_sound,
+                }),
                 _ => InputMode::VoiceActivityDetection(
                     if let Some(threshold) = db_config.vad_threshold {
                         VoiceActivityDetectionCfg::Manual { threshold }
@@ -266,6 +310,7 @@ impl From<&AudioConfigDB> for AudioConfig {
                     None => None, // No global attenuation
                 },
             )),
+            audio_profile: db_config.audio_profile,
         }
     }
 }
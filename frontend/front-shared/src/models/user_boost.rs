@@ -1,4 +1,4 @@
-use std::sync::{atomic::{AtomicI32, Ordering}, Arc};
+use std::sync::{atomic::{AtomicBool, AtomicI32, Ordering}, Arc};
 
 use diesel::prelude::*;
 use uuid::Uuid;
@@ -9,6 +9,8 @@ use crate::schema::per_user_boost;
 pub struct PerUserBoost {
     pub user_id: Option<Uuid>,
     pub boost_level: Arc<AtomicI32>,
+    /// Muted for this client only, the others still hear the user
+    pub muted: Arc<AtomicBool>,
 }
 
 impl PerUserBoost {
@@ -22,23 +24,31 @@ impl PerUserBoost {
             Ok(boost) => PerUserBoost {
                 user_id: Some(user_id),
                 boost_level: Arc::new(AtomicI32::new(boost.boost_level)),
+                muted: Arc::new(AtomicBool::new(boost.muted)),
             },
             Err(_) => PerUserBoost {
                 user_id: Some(user_id),
                 boost_level: Arc::new(AtomicI32::new(100)),
+                muted: Arc::new(AtomicBool::new(false)),
             },
         }
     }
     pub fn save(&self, conn: &mut SqliteConnection) -> Result<(), diesel::result::Error> {
         if let Some(user_id) = self.user_id {
+            let boost_level = self.boost_level.load(Ordering::Relaxed);
+            let muted = self.muted.load(Ordering::Relaxed);
             diesel::insert_into(per_user_boost::dsl::per_user_boost)
                 .values(PerUserBoostWString {
                     user_id: user_id.to_string(),
-                    boost_level: self.boost_level.load(Ordering::Relaxed),
+                    boost_level,
+                    muted,
                 })
                 .on_conflict(per_user_boost::dsl::user_id)
                 .do_update()
-                .set(per_user_boost::dsl::boost_level.eq(self.boost_level.load(Ordering::Relaxed)))
+                .set((
+                    per_user_boost::dsl::boost_level.eq(boost_level),
+                    per_user_boost::dsl::muted.eq(muted),
+                ))
                 .execute(conn)?;
         }
         Ok(())
@@ -51,4 +61,5 @@ impl PerUserBoost {
 pub struct PerUserBoostWString {
     pub user_id: String,
     pub boost_level: i32,
+    pub muted: bool,
 }
@@ -9,9 +9,13 @@ diesel::table! {
         gain_controller -> Bool,
         input_mode -> Integer,
         ptt_key_code -> Nullable<Text>,
+        ptt_press_delay -> Integer,
+        ptt_release_delay -> Integer,
+        ptt_sound -> Bool,
         vad_threshold -> Nullable<Integer>,
         global_attenuation -> Nullable<Integer>,
         global_attenuation_trigger -> Nullable<Integer>,
+        audio_profile -> Integer,
     }
 }
 
@@ -30,6 +34,7 @@ diesel::table! {
         id -> Nullable<Integer>,
         user_id -> Text,
         boost_level -> Integer,
+        muted -> Bool,
     }
 }
 
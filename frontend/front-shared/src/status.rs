@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use shared::models::ConnectionInfo;
+
 use crate::FromEvent;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -11,6 +13,8 @@ pub enum Status {
 }
 impl FromEvent for Status {}
 
+impl FromEvent for ConnectionInfo {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CallStatus {
     Connecting,
@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+use shared::models::{CameraState, ScreenShareState};
+
+use crate::FromEvent;
+
+/// Encoded frames of a camera or screen share, decoded by the UI
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct VideoFrames {
+    /// Codec of the frames, for example "video/VP8"
+    pub mime_type: String,
+    pub frames: Vec<Vec<u8>>,
+}
+
+impl FromEvent for CameraState {}
+
+impl FromEvent for ScreenShareState {}
@@ -5,42 +5,52 @@ use ringbuf::{
 };
 use shared::{
     models::{AudioChannelMemberUpdate, ChannelWithUsers},
-    Split, ROOM_SIZE,
+    AudioProfile, AudioSink, AudioSinks, OpusTuning, Split,
 };
 use std::{
-    iter,
+    collections::HashMap,
     ops::{Add, Div, Mul},
-    sync::atomic::Ordering,
+    sync::atomic::{AtomicBool, Ordering},
 };
 use std::{
     ops::Deref,
-    sync::{atomic::AtomicI32, Arc, Mutex as StdMutex},
+    sync::{Arc, Mutex as StdMutex},
 };
-use tauri::{AppHandle, Manager};
+use tauri::{AppHandle, Emitter, Manager};
 use webrtc_audio_processing::{InitializationConfig, Processor};
 
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    FromSample, SizedSample, SupportedStreamConfig,
+    FromSample, SizedSample,
 };
 
-use front_shared::models::audio_config::{AudioConfig, AudioConfigDB};
+use front_shared::models::audio_config::{AudioConfig, AudioConfigDB, GlobalAttenuation};
 use front_shared::models::user_boost::PerUserBoost;
 use front_shared::models::{
     audio_config::AudioConfigDBPartial,
     last_used_devices::{LastUsedAudioDevices, LastUsedAudioDevicesWString},
 };
 
+use front_shared::AudioDevices;
+
 use crate::{
     audio::ChannelWithBoosts,
     utils::{establish_connection, AppState},
     Error,
 };
 
-use super::AudioElement;
+use super::format::{
+    input_config, output_config, remix, Resampler, PROCESSING_CHANNELS, PROCESSING_FRAME,
+    PROCESSING_SAMPLE_RATE,
+};
+use super::ducking::{db_to_gain, GainRamp, SpeakingDetector};
+use super::limiter::Limiter;
+use super::ptt::{PushToTalk, CUE_SINK};
+use super::{AudioElement, InputGate, InputMeter, KeySource, MicPipeline, SystemDucker};
 
 impl AudioElement {
-    pub fn new(handle: AppHandle) -> Self {
+    /// Push-to-talk follows the keys of `key_source`, `GlobalKeys` outside of tests
+    pub fn new(handle: AppHandle, key_source: Arc<dyn KeySource>) -> Self {
         let state = handle.state::<AppState>();
         let devices =
             LastUsedAudioDevices::get_from_db_or_default(&mut establish_connection(&handle))
@@ -51,24 +61,30 @@ impl AudioElement {
             *last_used_audio_devices = Some(devices.clone().into());
         }
         let initialization_config = InitializationConfig {
-            num_capture_channels: 1,
-            num_render_channels: 1,
-            sample_rate_hz: 48_000,
+            num_capture_channels: PROCESSING_CHANNELS as _,
+            num_render_channels: PROCESSING_CHANNELS as _,
+            sample_rate_hz: PROCESSING_SAMPLE_RATE,
         };
         // SAFETY: we have just created the processor with a valid config
         let mut audio_processor =
             Processor::new(&initialization_config).expect("Failed to create audio processor");
         let mut conn = establish_connection(&handle);
         let audio_processor_config = AudioConfig::get(&mut conn);
+        let mic_tuning = OpusTuning::new(AudioProfile::from_id(audio_processor_config.audio_profile));
         audio_processor.set_config(audio_processor_config.cfg.clone());
         // Give an zero speaker stream to initialize
-        let mut speaker_stream: [f32; 480] = [0.0; 480]; // 10 ms of silence at 48 kHz
+        let mut speaker_stream = [0.0; PROCESSING_FRAME * PROCESSING_CHANNELS]; // 10 ms of silence
         for _ in 0..15 {
             audio_processor
                 .process_render_frame(&mut speaker_stream)
                 .expect("Failed to process initial speaker stream");
         }
         audio_processor.initialize();
+        let others_speaking = Arc::new(AtomicBool::new(false));
+        let system_ducker = SystemDucker::start(
+            audio_processor_config.global_attenuation.clone(),
+            others_speaking.clone(),
+        );
         AudioElement {
             audio_processor,
             audio_processor_config,
@@ -77,19 +93,35 @@ impl AudioElement {
             speaker_stream: None,
             mic_stream: None,
             mic_consumer: None,
-            speaker_producers: None,
+            speaker_sinks: Arc::new(StdMutex::new(HashMap::new())),
+            app_audio_stream: None,
+            app_audio_consumer: None,
+            mic_tuning,
+            mic_failed: Arc::new(AtomicBool::new(false)),
+            speaker_failed: Arc::new(AtomicBool::new(false)),
+            app_audio_failed: Arc::new(AtomicBool::new(false)),
+            known_devices: None,
+            key_source,
+            input_meter: Arc::new(InputMeter::default()),
+            others_speaking,
+            system_ducker,
+            muted: Arc::new(AtomicBool::new(false)),
+            deafened: Arc::new(AtomicBool::new(false)),
         }
     }
 
     pub fn set_channel(&mut self, channel_with_users: &ChannelWithUsers, handle: AppHandle) {
-        let mut user_boosts: [PerUserBoost; ROOM_SIZE] = Default::default();
+        let mut user_boosts = HashMap::new();
 
         for user in &channel_with_users.users {
-            user_boosts[user.slot] = PerUserBoost::get(&mut establish_connection(&handle), user.id);
+            user_boosts.insert(
+                user.slot,
+                PerUserBoost::get(&mut establish_connection(&handle), user.id),
+            );
         }
         self.channel_with_boosts = Some(ChannelWithBoosts {
             channel: channel_with_users.channel.clone(),
-            users: user_boosts,
+            users: Arc::new(StdMutex::new(user_boosts)),
         });
     }
 
@@ -107,10 +139,11 @@ impl AudioElement {
                 return Ok(());
             }
             let boost = PerUserBoost::get(&mut establish_connection(&handle), data.user.id);
-            channel_with_boosts.users[data.user.slot].user_id = Some(data.user.id);
-            channel_with_boosts.users[data.user.slot]
-                .boost_level
-                .store(boost.boost_level.load(Ordering::Relaxed), Ordering::Relaxed);
+            channel_with_boosts
+                .users
+                .lock()
+                .unwrap()
+                .insert(data.user.slot, boost);
         }
         Ok(())
     }
@@ -120,10 +153,11 @@ impl AudioElement {
             if channel_with_boosts.channel.id != data.channel.id {
                 return Ok(());
             }
-            channel_with_boosts.users[data.user.slot].user_id = None;
-            channel_with_boosts.users[data.user.slot]
-                .boost_level
-                .store(100, Ordering::Relaxed);
+            channel_with_boosts
+                .users
+                .lock()
+                .unwrap()
+                .remove(&data.user.slot);
         }
         Ok(())
     }
@@ -135,7 +169,8 @@ impl AudioElement {
         handle: AppHandle,
     ) -> Result<(), Error> {
         if let Some(channel_with_boosts) = &mut self.channel_with_boosts {
-            for user in channel_with_boosts.users.iter() {
+            let users = channel_with_boosts.users.lock().unwrap();
+            for user in users.values() {
                 if let Some(id) = user.user_id {
                     if id == user_id {
                         user.boost_level
@@ -149,6 +184,34 @@ impl AudioElement {
         Ok(())
     }
 
+    pub fn set_user_mute(
+        &mut self,
+        user_id: uuid::Uuid,
+        muted: bool,
+        handle: AppHandle,
+    ) -> Result<(), Error> {
+        if let Some(channel_with_boosts) = &mut self.channel_with_boosts {
+            let users = channel_with_boosts.users.lock().unwrap();
+            if let Some(user) = users.values().find(|user| user.user_id == Some(user_id)) {
+                user.muted.store(muted, Ordering::Relaxed);
+                user.save(&mut establish_connection(&handle))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_default_user_mute(
+        user_id: uuid::Uuid,
+        muted: bool,
+        handle: AppHandle,
+    ) -> Result<(), Error> {
+        let conn = &mut establish_connection(&handle);
+        let user_boost = PerUserBoost::get(conn, user_id);
+        user_boost.muted.store(muted, Ordering::Relaxed);
+        user_boost.save(conn)?;
+        Ok(())
+    }
+
     pub fn set_default_user_boost(
         user_id: uuid::Uuid,
         boost: i32,
@@ -163,37 +226,19 @@ impl AudioElement {
         Ok(())
     }
 
-    pub fn get_config() -> cpal::SupportedStreamConfig {
-        SupportedStreamConfig::new(
-            1,
-            cpal::SampleRate(48000),
-            cpal::SupportedBufferSize::Range { min: 480, max: 480 },
-            cpal::SampleFormat::F32,
-        )
-    }
-
-    pub fn start_speaker(&mut self) -> Result<Vec<Arc<StdMutex<HeapProd<f32>>>>, Error> {
+    pub fn start_speaker(&mut self) -> Result<AudioSinks, Error> {
         // If there is previously created speaker stream, stop it
         drop(self.speaker_stream.take());
-        // Create ringbuffers for each person in the room
-        let (tx_clone, (tx, rx)): (Vec<_>, (Vec<_>, Vec<_>)) = (0..ROOM_SIZE)
-            .map(|_| {
-                let (tx, rx) = HeapRb::<f32>::new(12000).split();
-                let tx = Arc::new(StdMutex::new(tx));
-                (tx.clone(), (tx, rx))
-            })
-            .unzip();
-        self.speaker_producers = Some(tx_clone);
         if let Some(speaker) = self.devices.speaker.as_ref() {
-            // Start the output stream with the created ringbuffers
-            let config = Self::get_config();
-            let stream = self.make_speaker_stream(speaker, &config, rx)?;
+            // Start the output stream mixing every sink in the room
+            let config = output_config(speaker)?;
+            let stream = self.make_speaker_stream(speaker, &config)?;
             self.speaker_stream = Some(stream);
             // Start the stream
             self.speaker_stream.as_ref().unwrap().play()?;
         }
 
-        Ok(tx)
+        Ok(self.speaker_sinks.clone())
     }
 
     pub fn start_mic(&mut self) -> Result<Arc<StdMutex<HeapCons<f32>>>, Error> {
@@ -204,9 +249,16 @@ impl AudioElement {
         let mic_consumer = Arc::new(StdMutex::new(mic_consumer));
         self.mic_consumer = Some(mic_consumer.clone());
         if let Some(mic) = self.devices.mic.as_ref() {
-            let config = Self::get_config();
+            let config = input_config(mic)?;
             // Start the input stream with the created ringbuffer
-            let stream = self.make_mic_stream(mic, &config, mic_producer)?;
+            let stream = self.make_mic_stream(
+                mic,
+                &config,
+                mic_producer,
+                Some(self.mic_pipeline()),
+                self.devices.mic_boost.unwrap_or(100),
+                self.mic_failed.clone(),
+            )?;
             self.mic_stream = Some(stream);
             // Start the stream
             self.mic_stream.as_ref().unwrap().play()?;
@@ -214,6 +266,76 @@ impl AudioElement {
         Ok(mic_consumer)
     }
 
+    /**
+     * Processing and gating of a new microphone stream, the push-to-talk cue of the previous
+     * one stops with it.
+     */
+    fn mic_pipeline(&self) -> MicPipeline {
+        let (cue_producer, cue_consumer) = HeapRb::<f32>::new(12000).split();
+        self.speaker_sinks.lock().unwrap().insert(
+            CUE_SINK.to_string(),
+            AudioSink {
+                // Matches nobody, the cue plays without a user boost
+                slot: usize::MAX,
+                ssrc: 0,
+                consumer: cue_consumer,
+            },
+        );
+        MicPipeline {
+            processor: self.audio_processor.clone(),
+            gate: InputGate::new(
+                self.audio_processor_config.input_mode.clone(),
+                self.muted.clone(),
+                PushToTalk::new(self.key_source.clone(), cue_producer),
+                self.input_meter.clone(),
+            ),
+        }
+    }
+
+    /**
+     * Creates the buffer of the screen share audio, it stays empty until `capture_app_audio`.
+     */
+    pub fn start_app_audio(&mut self) -> Arc<StdMutex<HeapCons<f32>>> {
+        let (_, app_audio_consumer) = HeapRb::<f32>::new(12000).split();
+        let app_audio_consumer = Arc::new(StdMutex::new(app_audio_consumer));
+        self.app_audio_consumer = Some(app_audio_consumer.clone());
+        app_audio_consumer
+    }
+
+    /**
+     * Records the input device carrying the audio of the shared applications,
+     * usually the monitor of the speakers.
+     */
+    pub fn capture_app_audio(&mut self, device_name: &str) -> Result<(), Error> {
+        drop(self.app_audio_stream.take());
+        let host = cpal::default_host();
+        let device = host
+            .input_devices()?
+            .find(|d| d.name().unwrap_or_default() == device_name)
+            .ok_or_else(|| Error::DeviceNotFound(device_name.to_string()))?;
+        let (app_audio_producer, app_audio_consumer) = HeapRb::<f32>::new(12000).split();
+        if let Some(app_audio_consumer_arc) = self.app_audio_consumer.as_ref() {
+            *app_audio_consumer_arc.lock().unwrap() = app_audio_consumer;
+        }
+        // Converted to the processing format like the microphone, but not processed
+        let config = input_config(&device)?;
+        let stream = self.make_mic_stream(
+            &device,
+            &config,
+            app_audio_producer,
+            None,
+            100,
+            self.app_audio_failed.clone(),
+        )?;
+        stream.play()?;
+        self.app_audio_stream = Some(stream);
+        Ok(())
+    }
+
+    pub fn stop_app_audio(&mut self) {
+        drop(self.app_audio_stream.take());
+    }
+
     pub fn change_speaker(&mut self, device_name: &str, state: &AppState) -> Result<(), Error> {
         // Set the current speaker in the app state
         {
@@ -230,18 +352,9 @@ impl AudioElement {
             .output_devices()?
             .find(|d| d.name().unwrap_or_default() == device_name)
             .ok_or_else(|| Error::DeviceNotFound(device_name.to_string()))?;
-        // Create ringbuffers for each person in the room
-        let (tx, rx): (Vec<_>, Vec<_>) = (0..ROOM_SIZE)
-            .map(|_| HeapRb::<f32>::new(12000).split())
-            .unzip();
-        if let Some(speaker_producers) = self.speaker_producers.as_ref() {
-            for (speaker_producer, tx) in speaker_producers.iter().zip(tx.into_iter()) {
-                *speaker_producer.lock().unwrap() = tx;
-            }
-        }
-        // Start the output stream with the created ringbuffers
-        let config = Self::get_config();
-        let stream = self.make_speaker_stream(&device, &config, rx)?;
+        // Start the output stream mixing every sink in the room
+        let config = output_config(&device)?;
+        let stream = self.make_speaker_stream(&device, &config)?;
         self.devices.speaker = Some(device);
         self.speaker_stream = Some(stream);
         // Start the stream
@@ -307,8 +420,15 @@ impl AudioElement {
             }
         }
         // Start the input stream with the created ringbuffer
-        let config = Self::get_config();
-        let stream = self.make_mic_stream(&device, &config, mic_producer)?;
+        let config = input_config(&device)?;
+        let stream = self.make_mic_stream(
+            &device,
+            &config,
+            mic_producer,
+            Some(self.mic_pipeline()),
+            self.devices.mic_boost.unwrap_or(100),
+            self.mic_failed.clone(),
+        )?;
         self.devices.mic = Some(device);
         self.mic_stream = Some(stream);
         // Start the stream
@@ -350,6 +470,9 @@ impl AudioElement {
             }
             AudioConfigDBPartial::InputMode(_)
             | AudioConfigDBPartial::PttKeyCode(_)
+            | AudioConfigDBPartial::PttPressDelay(_)
+            | AudioConfigDBPartial::PttReleaseDelay(_)
+            | AudioConfigDBPartial::PttSound(_)
             | AudioConfigDBPartial::VadThreshold(_) => {
                 let mut input_mode = self.audio_processor_config.input_mode.lock().unwrap();
                 *input_mode = new_cfg.input_mode.lock().unwrap().clone();
@@ -363,6 +486,10 @@ impl AudioElement {
                     .unwrap();
                 *global_attenuation = new_cfg.global_attenuation.lock().unwrap().clone();
             }
+            AudioConfigDBPartial::AudioProfile(profile) => {
+                self.audio_processor_config.audio_profile = profile;
+                self.mic_tuning.set_profile(AudioProfile::from_id(profile));
+            }
         }
         Ok(())
     }
@@ -409,42 +536,133 @@ impl AudioElement {
         Ok(mics)
     }
 
+    /**
+     * Sends the level of the microphone since the previous call to the settings,
+     * nothing while the microphone is not running.
+     */
+    pub fn emit_input_level(&self, handle: &AppHandle) {
+        if self.mic_stream.is_none() {
+            return;
+        }
+        if let Err(e) = handle.emit("input-level", self.input_meter.take()) {
+            tracing::error!("Failed to emit input level: {}", e);
+        }
+    }
+
+    /**
+     * Replaces the microphone and speaker of the call when they failed or went away with the
+     * default ones, and switches back to the saved ones once they return.
+     * Emits "audio-devices" when the devices or the lists of them changed.
+     */
+    pub fn check_devices(&mut self, state: &AppState, handle: &AppHandle) {
+        let (Ok(mics), Ok(speakers)) = (Self::list_mics(), Self::list_speakers()) else {
+            tracing::warn!("Failed to list audio devices");
+            return;
+        };
+        let mut changed = self.known_devices.as_ref() != Some(&(mics.clone(), speakers.clone()));
+        let saved =
+            LastUsedAudioDevicesWString::get_from_db(&mut establish_connection(handle)).unwrap_or_default();
+        let host = cpal::default_host();
+
+        // Only the streams of a call are switched, the devices are looked up again when joining
+        let mic_failed = self.mic_failed.swap(false, Ordering::Relaxed);
+        if self.mic_stream.is_some() {
+            let current = self.devices.mic.as_ref().and_then(|d| d.name().ok());
+            let default = host.default_input_device().and_then(|d| d.name().ok());
+            if let Some(mic) = Self::pick_device(current, mic_failed, saved.mic, default, &mics) {
+                match self.change_mic(&mic, state) {
+                    Ok(()) => tracing::info!("Switched microphone to {}", mic),
+                    Err(e) => tracing::error!("Failed to switch microphone to {}: {}", mic, e),
+                }
+                changed = true;
+            }
+        }
+        let speaker_failed = self.speaker_failed.swap(false, Ordering::Relaxed);
+        if self.speaker_stream.is_some() {
+            let current = self.devices.speaker.as_ref().and_then(|d| d.name().ok());
+            let default = host.default_output_device().and_then(|d| d.name().ok());
+            if let Some(speaker) =
+                Self::pick_device(current, speaker_failed, saved.speaker, default, &speakers)
+            {
+                match self.change_speaker(&speaker, state) {
+                    Ok(()) => tracing::info!("Switched speaker to {}", speaker),
+                    Err(e) => tracing::error!("Failed to switch speaker to {}: {}", speaker, e),
+                }
+                changed = true;
+            }
+        }
+        // Nothing to fall back to for the audio of a screen share
+        if self.app_audio_failed.swap(false, Ordering::Relaxed) {
+            tracing::warn!("Screen share audio device failed, sharing without audio");
+            self.stop_app_audio();
+        }
+
+        self.known_devices = Some((mics.clone(), speakers.clone()));
+        if changed {
+            let devices = AudioDevices {
+                mics,
+                speakers,
+                last_used_devices: state.last_used_audio_devices.lock().unwrap().clone().map(Into::into),
+            };
+            if let Err(e) = handle.emit("audio-devices", devices) {
+                tracing::error!("Failed to emit audio devices: {}", e);
+            }
+        }
+    }
+
+    /// Device to switch to, None to stay on the current one
+    fn pick_device(
+        current: Option<String>,
+        failed: bool,
+        saved: Option<String>,
+        default: Option<String>,
+        available: &[String],
+    ) -> Option<String> {
+        let is_available = |name: &String| available.contains(name);
+        if let Some(saved) = saved.filter(is_available) {
+            return (failed || current.as_ref() != Some(&saved)).then_some(saved);
+        }
+        if !failed && current.as_ref().is_some_and(is_available) {
+            return None;
+        }
+        default.filter(is_available)
+    }
+
     pub fn make_speaker_stream(
         &self,
         device: &cpal::Device,
         config: &cpal::SupportedStreamConfig,
-        consumers: Vec<HeapCons<f32>>,
     ) -> Result<cpal::Stream, Error> {
         match config.sample_format() {
             cpal::SampleFormat::I16 => {
-                self.make_speaker_stream_from::<i16>(device, &config.config(), consumers)
+                self.make_speaker_stream_from::<i16>(device, &config.config())
             }
             cpal::SampleFormat::F32 => {
-                self.make_speaker_stream_from::<f32>(device, &config.config(), consumers)
+                self.make_speaker_stream_from::<f32>(device, &config.config())
             }
             cpal::SampleFormat::I8 => {
-                self.make_speaker_stream_from::<i8>(device, &config.config(), consumers)
+                self.make_speaker_stream_from::<i8>(device, &config.config())
             }
             cpal::SampleFormat::I32 => {
-                self.make_speaker_stream_from::<i32>(device, &config.config(), consumers)
+                self.make_speaker_stream_from::<i32>(device, &config.config())
             }
             cpal::SampleFormat::I64 => {
-                self.make_speaker_stream_from::<i64>(device, &config.config(), consumers)
+                self.make_speaker_stream_from::<i64>(device, &config.config())
             }
             cpal::SampleFormat::U8 => {
-                self.make_speaker_stream_from::<u8>(device, &config.config(), consumers)
+                self.make_speaker_stream_from::<u8>(device, &config.config())
             }
             cpal::SampleFormat::U16 => {
-                self.make_speaker_stream_from::<u16>(device, &config.config(), consumers)
+                self.make_speaker_stream_from::<u16>(device, &config.config())
             }
             cpal::SampleFormat::U32 => {
-                self.make_speaker_stream_from::<u32>(device, &config.config(), consumers)
+                self.make_speaker_stream_from::<u32>(device, &config.config())
             }
             cpal::SampleFormat::U64 => {
-                self.make_speaker_stream_from::<u64>(device, &config.config(), consumers)
+                self.make_speaker_stream_from::<u64>(device, &config.config())
             }
             cpal::SampleFormat::F64 => {
-                self.make_speaker_stream_from::<f64>(device, &config.config(), consumers)
+                self.make_speaker_stream_from::<f64>(device, &config.config())
             }
             _ => todo!(),
         }
@@ -461,111 +679,156 @@ impl AudioElement {
         &self,
         device: &cpal::Device,
         config: &cpal::StreamConfig,
-        mut consumers: Vec<HeapCons<f32>>,
     ) -> Result<cpal::Stream, Error> {
         let boost = self.devices.speaker_boost.unwrap_or(100);
         let mut processor = self.audio_processor.clone();
+        let channels = config.channels as usize;
+        let mut resampler =
+            Resampler::new(PROCESSING_SAMPLE_RATE, config.sample_rate.0, PROCESSING_CHANNELS);
+        // Samples in the format of the device left over from the previous call
+        let mut pending: Vec<f32> = Vec::new();
+        let mut limiter = Limiter::new(PROCESSING_SAMPLE_RATE, PROCESSING_CHANNELS);
+        let mut ducking = GainRamp::new(PROCESSING_SAMPLE_RATE, PROCESSING_CHANNELS);
+        let mut speaking = SpeakingDetector::default();
+        let global_attenuation = self.audio_processor_config.global_attenuation.clone();
+        let input_meter = self.input_meter.clone();
+        let others_speaking = self.others_speaking.clone();
+        let deafened = self.deafened.clone();
+        let sinks = self.speaker_sinks.clone();
+        let failed = self.speaker_failed.clone();
         let user_boosts = self
             .channel_with_boosts
             .as_ref()
-            .map(|c| {
-                c.users
-                    .as_ref()
-                    .iter()
-                    .map(|b| b.boost_level.clone())
-                    .collect::<Vec<_>>()
-            })
+            .map(|c| c.users.clone())
             .unwrap_or_else(|| {
                 tracing::warn!(
                     "No channel with boosts set, using default boosts (NOT CHANGEABLE AFTERWARDS)"
                 );
-                iter::repeat(Arc::new(AtomicI32::new(100)))
-                    .take(ROOM_SIZE)
-                    .collect::<Vec<_>>()
+                Arc::new(StdMutex::new(HashMap::new()))
             });
         let stream = device.build_output_stream(
             &config,
             move |data: &mut [T], _| {
-                let mut data_f32: Vec<f32> = vec![0.0; data.len()];
-                // Receive raw samples from encoder thread
-                for (idx, sender) in consumers.iter_mut().enumerate() {
-                    let sender: &mut HeapCons<f32> = sender;
-                    let mut temp_data: Vec<f32> = vec![0.0; data.len()];
-                    // Pop samples from the ring buffer
-                    let cnt = sender.pop_slice(&mut temp_data);
-                    if cnt == 0 {
-                        // If no samples were received, fill with silence
-                        continue;
+                // Mix 10 ms at a time in the processing format, the audio processor takes
+                // exactly that, until there is enough for the device
+                while pending.len() < data.len() {
+                    let mut data_f32: Vec<f32> = vec![0.0; PROCESSING_FRAME * PROCESSING_CHANNELS];
+                    // Sounds of the app itself, kept out of the ducking
+                    let mut local: Vec<f32> = vec![0.0; data_f32.len()];
+                    let mut sinks = sinks.lock().unwrap();
+                    let user_boosts = user_boosts.lock().unwrap();
+                    let deafened = deafened.load(Ordering::Relaxed);
+                    // Receive raw samples from decoder threads
+                    for (key, sink) in sinks.iter_mut() {
+                        let mut temp_data: Vec<f32> = vec![0.0; data_f32.len()];
+                        // Pop samples from the ring buffer
+                        let cnt = sink.consumer.pop_slice(&mut temp_data);
+                        if cnt == 0 {
+                            // If no samples were received, fill with silence
+                            continue;
+                        }
+                        // Popped anyway so that undeafening does not play what was said meanwhile
+                        if deafened {
+                            continue;
+                        }
+                        if key == CUE_SINK {
+                            local = temp_data;
+                            continue;
+                        }
+                        let user_boost = user_boosts.get(&sink.slot);
+                        // Popped anyway so that unmuting does not play what was said meanwhile
+                        if user_boost.is_some_and(|b| b.muted.load(Ordering::Relaxed)) {
+                            continue;
+                        }
+                        // Apply user boosts
+                        let user_boost =
+                            user_boost.map_or(100, |b| b.boost_level.load(Ordering::Relaxed));
+                        for (d, s) in data_f32.iter_mut().zip(temp_data.iter()) {
+                            *d = *d + *s * user_boost as f32 / 100.0;
+                        }
                     }
-                    // Apply user boosts
-                    let user_boost = user_boosts[idx].clone();
-                    // Convert f32 to T and write to output buffer
-                    for (d, s) in data_f32.iter_mut().zip(temp_data.iter()) {
-                        *d = *d + *s * user_boost.load(Ordering::Relaxed) as f32 / 100.0;
+                    drop(user_boosts);
+                    drop(sinks);
+                    others_speaking.store(speaking.update(&data_f32), Ordering::Relaxed);
+                    // Duck the others while sending the microphone if the attenuation says so
+                    let duck = match &*global_attenuation.lock().unwrap() {
+                        Some(GlobalAttenuation::SelfVoice(db)) if input_meter.transmitting() => {
+                            db_to_gain(*db)
+                        }
+                        _ => 1.0,
+                    };
+                    ducking.process(&mut data_f32, duck);
+                    for (d, s) in data_f32.iter_mut().zip(local.iter()) {
+                        *d += *s;
                     }
-                }
-                // Apply the speaker boost
-                for d in data_f32.iter_mut() {
-                    *d = *d * boost as f32 / 100.0;
-                }
-                // Process the samples with the audio processor
-                // SAFETY: we need to ensure that each frame is 480 samples long
-                let mut data_f32_chunks = data_f32.chunks_exact_mut(480);
-                for chunk in &mut data_f32_chunks {
-                    if let Err(processed_samples) =
-                        processor.process_render_frame(chunk)
-                    {
+                    // Apply the speaker boost
+                    for d in data_f32.iter_mut() {
+                        *d = *d * boost as f32 / 100.0;
+                    }
+                    // Boosts can push the mix past full scale, the echo canceller gets it limited too
+                    limiter.process(&mut data_f32);
+                    // Process the samples with the audio processor
+                    if let Err(processed_samples) = processor.process_render_frame(&mut data_f32) {
                         tracing::error!("Error processing audio frame: {}", processed_samples);
                     }
-                }
-                if data_f32_chunks.into_remainder().len() > 0 {
-                    tracing::warn!("Speaker stream received a frame with less than 480 samples, this is not supported by the audio processor");
+                    pending.extend(remix(&resampler.process(&data_f32), PROCESSING_CHANNELS, channels));
                 }
                 // Convert f32 to T and write to output buffer
-                for (d, s) in data.iter_mut().zip(data_f32.iter()) {
-                    *d = T::from_sample(*s);
+                for (d, s) in data.iter_mut().zip(pending.drain(..data.len())) {
+                    *d = T::from_sample(s);
                 }
             },
-            |e| {
+            move |e| {
                 tracing::error!("Error in speaker stream: {}", e);
+                failed.store(true, Ordering::Relaxed);
             },
             None,
         )?;
         Ok(stream)
     }
 
+    /**
+     * Records `device` into `tx` in the processing format, through `pipeline` if there is one.
+     */
     pub fn make_mic_stream(
         &self,
         device: &cpal::Device,
         config: &cpal::SupportedStreamConfig,
         tx: HeapProd<f32>,
+        pipeline: Option<MicPipeline>,
+        boost: i32,
+        failed: Arc<AtomicBool>,
     ) -> Result<cpal::Stream, Error> {
         match config.sample_format() {
             cpal::SampleFormat::I16 => {
-                self.make_mic_stream_from::<i16>(device, &config.config(), tx)
+                self.make_mic_stream_from::<i16>(device, &config.config(), tx, pipeline, boost, failed)
             }
             cpal::SampleFormat::F32 => {
-                self.make_mic_stream_from::<f32>(device, &config.config(), tx)
+                self.make_mic_stream_from::<f32>(device, &config.config(), tx, pipeline, boost, failed)
+            }
+            cpal::SampleFormat::I8 => {
+                self.make_mic_stream_from::<i8>(device, &config.config(), tx, pipeline, boost, failed)
             }
-            cpal::SampleFormat::I8 => self.make_mic_stream_from::<i8>(device, &config.config(), tx),
             cpal::SampleFormat::I32 => {
-                self.make_mic_stream_from::<i32>(device, &config.config(), tx)
+                self.make_mic_stream_from::<i32>(device, &config.config(), tx, pipeline, boost, failed)
             }
             cpal::SampleFormat::I64 => {
-                self.make_mic_stream_from::<i64>(device, &config.config(), tx)
+                self.make_mic_stream_from::<i64>(device, &config.config(), tx, pipeline, boost, failed)
+            }
+            cpal::SampleFormat::U8 => {
+                self.make_mic_stream_from::<u8>(device, &config.config(), tx, pipeline, boost, failed)
             }
-            cpal::SampleFormat::U8 => self.make_mic_stream_from::<u8>(device, &config.config(), tx),
             cpal::SampleFormat::U16 => {
-                self.make_mic_stream_from::<u16>(device, &config.config(), tx)
+                self.make_mic_stream_from::<u16>(device, &config.config(), tx, pipeline, boost, failed)
             }
             cpal::SampleFormat::U32 => {
-                self.make_mic_stream_from::<u32>(device, &config.config(), tx)
+                self.make_mic_stream_from::<u32>(device, &config.config(), tx, pipeline, boost, failed)
             }
             cpal::SampleFormat::U64 => {
-                self.make_mic_stream_from::<u64>(device, &config.config(), tx)
+                self.make_mic_stream_from::<u64>(device, &config.config(), tx, pipeline, boost, failed)
             }
             cpal::SampleFormat::F64 => {
-                self.make_mic_stream_from::<f64>(device, &config.config(), tx)
+                self.make_mic_stream_from::<f64>(device, &config.config(), tx, pipeline, boost, failed)
             }
             _ => todo!(),
         }
@@ -576,71 +839,67 @@ impl AudioElement {
         device: &cpal::Device,
         config: &cpal::StreamConfig,
         mut tx: HeapProd<f32>,
+        mut pipeline: Option<MicPipeline>,
+        boost: i32,
+        failed: Arc<AtomicBool>,
     ) -> Result<cpal::Stream, Error>
     where
         T: SizedSample,
         f32: FromSample<T>,
     {
-        let boost = self.devices.mic_boost.unwrap_or(100);
-        let mut processor = self.audio_processor.clone();
+        let channels = config.channels as usize;
+        let mut resampler =
+            Resampler::new(config.sample_rate.0, PROCESSING_SAMPLE_RATE, PROCESSING_CHANNELS);
+        // Samples in the processing format waiting for a full frame of the audio processor
+        let mut pending: Vec<f32> = Vec::new();
         let stream = device.build_input_stream(
             &config,
             move |data: &[T], _| {
-                // Convert T to f32 and send to the encoder thread
-                let mut samples = data
+                // Convert T to f32 in the processing format
+                let samples = data
                     .iter()
                     .map(|s| s.to_sample::<f32>() * (boost as f32) / 100.0)
                     .collect::<Vec<f32>>();
-                // Process the samples with the audio processor
-                // SAFETY: we need to ensure that each frame is 480 samples long
-                let mut samples_chunks = samples.chunks_exact_mut(480);
-                for chunk in &mut samples_chunks {
-                    if let Err(processed_samples) =
-                        processor.process_capture_frame(chunk)
-                    {
-                        tracing::error!("Error processing audio frame: {}", processed_samples);
+                pending.extend(resampler.process(&remix(&samples, channels, PROCESSING_CHANNELS)));
+                // The audio processor takes exactly 10 ms at once, the rest waits for the next call
+                let frame_len = PROCESSING_FRAME * PROCESSING_CHANNELS;
+                let ready = pending.len() / frame_len * frame_len;
+                if let Some(pipeline) = pipeline.as_mut() {
+                    for chunk in pending[..ready].chunks_exact_mut(frame_len) {
+                        if let Err(processed_samples) = pipeline.processor.process_capture_frame(chunk)
+                        {
+                            tracing::error!("Error processing audio frame: {}", processed_samples);
+                        }
+                        // Gated after the processor, which has to hear every frame to keep adapting
+                        pipeline.gate.process(chunk);
                     }
                 }
-                if samples_chunks.into_remainder().len() > 0 {
-                    tracing::warn!("Mic stream received a frame with less than 480 samples, this is not supported by the audio processor");
-                }
-                // Note: This will block if the channel is full.
-                tx.push_slice(&samples);
+                // Send to the encoder thread, dropped if it is full
+                tx.push_slice(&pending[..ready]);
+                pending.drain(..ready);
             },
-            |e| {
+            move |e| {
                 tracing::error!("Error in mic stream: {}", e);
+                failed.store(true, Ordering::Relaxed);
             },
             None,
         )?;
         Ok(stream)
     }
 
-    pub fn mute(&self) -> Result<(), Error> {
-        if let Some(mic_stream) = self.mic_stream.as_ref() {
-            mic_stream.pause()?;
-        }
-        Ok(())
-    }
-
-    pub fn unmute(&self) -> Result<(), Error> {
-        if let Some(mic_stream) = self.mic_stream.as_ref() {
-            mic_stream.play()?;
-        }
-        Ok(())
-    }
-
-    pub fn deafen(&self) -> Result<(), Error> {
-        if let Some(speaker_stream) = self.speaker_stream.as_ref() {
-            speaker_stream.pause()?;
-        }
-        Ok(())
+    /**
+     * Sends silence instead of the microphone, which the encoder leaves out like any other.
+     * The stream keeps running so that the audio processor stays adapted to the room.
+     */
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
     }
 
-    pub fn undeafen(&self) -> Result<(), Error> {
-        if let Some(speaker_stream) = self.speaker_stream.as_ref() {
-            speaker_stream.play()?;
-        }
-        Ok(())
+    /**
+     * Plays silence instead of the others, the echo canceller still gets what is played.
+     */
+    pub fn set_deafened(&self, deafened: bool) {
+        self.deafened.store(deafened, Ordering::Relaxed);
     }
 
     pub fn quit(&mut self) -> Result<(), Error> {
@@ -650,9 +909,13 @@ impl AudioElement {
         if let Some(speaker_stream) = self.speaker_stream.take() {
             speaker_stream.pause()?;
         }
+        if let Some(app_audio_stream) = self.app_audio_stream.take() {
+            app_audio_stream.pause()?;
+        }
         {
             self.mic_consumer.take();
-            self.speaker_producers.take();
+            self.app_audio_consumer.take();
+            self.speaker_sinks.lock().unwrap().clear();
         }
         Ok(())
     }
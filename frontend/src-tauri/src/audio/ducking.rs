@@ -0,0 +1,262 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+
+use front_shared::models::audio_config::GlobalAttenuation;
+
+/// Time the gain takes to go all the way between unity and silence, shorter ducks take less
+const RAMP_MS: f32 = 150.0;
+/// RMS of the mix of the others over which they count as speaking, about -50 dBFS
+const SPEAKING_RMS: f32 = 0.003;
+/// Frames of 10 ms the others still count as speaking for after going quiet
+const SPEAKING_HANGOVER_FRAMES: u32 = 30;
+
+/// Gain of an attenuation of `db`, given either as a positive or a negative number
+pub fn db_to_gain(db: i32) -> f32 {
+    10f32.powf(-(db.abs() as f32) / 20.0)
+}
+
+/**
+ * Gain that moves towards its target a little every frame, so that ducking does not click.
+ */
+pub struct GainRamp {
+    channels: usize,
+    gain: f32,
+    /// Change of the gain per frame
+    step: f32,
+}
+
+impl GainRamp {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        GainRamp {
+            channels,
+            gain: 1.0,
+            step: 1000.0 / (RAMP_MS * sample_rate as f32),
+        }
+    }
+
+    /// Applies the gain to the interleaved samples in place while moving it towards `target`
+    pub fn process(&mut self, samples: &mut [f32], target: f32) {
+        for frame in samples.chunks_exact_mut(self.channels) {
+            self.gain = if self.gain < target {
+                (self.gain + self.step).min(target)
+            } else {
+                (self.gain - self.step).max(target)
+            };
+            for s in frame.iter_mut() {
+                *s *= self.gain;
+            }
+        }
+    }
+}
+
+/**
+ * Whether the others are speaking, from 10 ms frames of their mix. Silence is not sent,
+ * so anything over the noise floor is someone speaking.
+ */
+#[derive(Default)]
+pub struct SpeakingDetector {
+    hangover: u32,
+}
+
+impl SpeakingDetector {
+    pub fn update(&mut self, mix: &[f32]) -> bool {
+        let rms = (mix.iter().map(|s| s * s).sum::<f32>() / mix.len().max(1) as f32).sqrt();
+        if rms > SPEAKING_RMS {
+            self.hangover = SPEAKING_HANGOVER_FRAMES;
+        } else {
+            self.hangover = self.hangover.saturating_sub(1);
+        }
+        self.hangover > 0
+    }
+}
+
+/**
+ * Ducks the audio of the other applications while the others in the call speak and the
+ * attenuation is triggered by their voice. Only PulseAudio and PipeWire let an application
+ * change the volume of the others, elsewhere it does nothing.
+ * The volumes are restored when it is dropped.
+ */
+pub struct SystemDucker {
+    stop: Arc<AtomicBool>,
+}
+
+impl SystemDucker {
+    pub fn start(
+        global_attenuation: Arc<StdMutex<Option<GlobalAttenuation>>>,
+        others_speaking: Arc<AtomicBool>,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        #[cfg(target_os = "linux")]
+        {
+            let stop = stop.clone();
+            std::thread::spawn(move || pulse::run(global_attenuation, others_speaking, stop));
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (global_attenuation, others_speaking);
+            tracing::debug!("Ducking other applications is not supported on this platform");
+        }
+        SystemDucker { stop }
+    }
+}
+
+impl Drop for SystemDucker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod pulse {
+    use std::collections::HashMap;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex as StdMutex};
+    use std::time::Duration;
+
+    use front_shared::models::audio_config::GlobalAttenuation;
+
+    /// The volumes change by a step every tick, faster going down than coming back up
+    const TICK: Duration = Duration::from_millis(50);
+    const ATTACK_STEP_DB: f32 = 3.0;
+    const RELEASE_STEP_DB: f32 = 1.0;
+
+    pub fn run(
+        global_attenuation: Arc<StdMutex<Option<GlobalAttenuation>>>,
+        others_speaking: Arc<AtomicBool>,
+        stop: Arc<AtomicBool>,
+    ) {
+        // Attenuation applied so far to each stream of the other applications, keyed by index
+        let mut applied: HashMap<u32, f32> = HashMap::new();
+        let mut current = 0.0f32;
+        while !stop.load(Ordering::Relaxed) {
+            std::thread::sleep(TICK);
+            let target = match &*global_attenuation.lock().unwrap() {
+                Some(GlobalAttenuation::OtherVoice(db)) if others_speaking.load(Ordering::Relaxed) => {
+                    -(db.abs() as f32)
+                }
+                _ => 0.0,
+            };
+            if target == 0.0 && current == 0.0 {
+                continue;
+            }
+            current = if current > target {
+                (current - ATTACK_STEP_DB).max(target)
+            } else {
+                (current + RELEASE_STEP_DB).min(target)
+            };
+            // Listed every tick while ducked, streams starting meanwhile are ducked as well
+            let Some(inputs) = sink_inputs() else {
+                continue;
+            };
+            applied.retain(|index, _| inputs.contains(index));
+            for index in inputs {
+                let previous = applied.insert(index, current).unwrap_or(0.0);
+                change_volume(index, current - previous);
+            }
+        }
+        for (index, db) in applied {
+            change_volume(index, -db);
+        }
+    }
+
+    /// Streams of the applications playing audio, except this one
+    fn sink_inputs() -> Option<Vec<u32>> {
+        let output = Command::new("pactl")
+            .args(["-f", "json", "list", "sink-inputs"])
+            .output()
+            .map_err(|e| tracing::debug!("Failed to list the streams of other applications: {}", e))
+            .ok()?;
+        let inputs: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+        let own_pid = std::process::id().to_string();
+        Some(
+            inputs
+                .as_array()?
+                .iter()
+                .filter(|input| {
+                    input["properties"]["application.process.id"].as_str() != Some(own_pid.as_str())
+                })
+                .filter_map(|input| input["index"].as_u64().map(|index| index as u32))
+                .collect(),
+        )
+    }
+
+    fn change_volume(index: u32, db: f32) {
+        if db == 0.0 {
+            return;
+        }
+        // A leading sign makes the change relative, `--` keeps the minus from reading as an option
+        let result = Command::new("pactl")
+            .args(["--", "set-sink-input-volume", &index.to_string(), &format!("{:+.1}dB", db)])
+            .status();
+        if let Err(e) = result {
+            tracing::warn!("Failed to change the volume of stream {}: {}", index, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    #[test]
+    fn ramps_the_gain_over_the_ramp_time() {
+        let mut ramp = GainRamp::new(SAMPLE_RATE, 2);
+        let ramp_frames = (RAMP_MS * SAMPLE_RATE as f32 / 1000.0) as usize;
+        let mut samples = vec![1.0; ramp_frames * 2];
+        ramp.process(&mut samples, 0.0);
+        // Moves by the same step every frame, without a jump
+        let step = 1.0 / ramp_frames as f32;
+        for frame in samples.chunks_exact(4) {
+            assert_eq!(frame[0], frame[1]);
+            assert!((frame[0] - frame[2] - step).abs() < 1e-5);
+        }
+        assert!((samples[ramp_frames - 1] - 0.5).abs() < 1e-3);
+        assert!(samples[samples.len() - 1].abs() < 1e-3);
+        let mut samples = vec![1.0; 2];
+        ramp.process(&mut samples, 0.0);
+        assert_eq!(samples, vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn stops_at_the_target() {
+        let mut ramp = GainRamp::new(SAMPLE_RATE, 2);
+        let target = db_to_gain(-6);
+        let mut samples = vec![1.0; SAMPLE_RATE as usize];
+        ramp.process(&mut samples, target);
+        assert!(samples.iter().all(|s| *s >= target));
+        assert_eq!(samples[samples.len() - 1], target);
+        let mut samples = vec![1.0; SAMPLE_RATE as usize];
+        ramp.process(&mut samples, 1.0);
+        assert_eq!(samples[samples.len() - 1], 1.0);
+    }
+
+    #[test]
+    fn converts_attenuations_either_way() {
+        assert_eq!(db_to_gain(0), 1.0);
+        assert!((db_to_gain(20) - 0.1).abs() < 1e-6);
+        assert_eq!(db_to_gain(-20), db_to_gain(20));
+    }
+
+    #[test]
+    fn keeps_speaking_through_the_hangover() {
+        let mut detector = SpeakingDetector::default();
+        let voice = vec![0.1; 960];
+        let noise = vec![SPEAKING_RMS / 2.0; 960];
+        assert!(!detector.update(&noise));
+        assert!(detector.update(&voice));
+        for _ in 1..SPEAKING_HANGOVER_FRAMES {
+            assert!(detector.update(&noise));
+        }
+        assert!(!detector.update(&noise));
+        // Voice during the hangover starts it over
+        assert!(detector.update(&voice));
+        for _ in 1..SPEAKING_HANGOVER_FRAMES {
+            detector.update(&noise);
+        }
+        assert!(detector.update(&voice));
+        assert!(!SpeakingDetector::default().update(&[]));
+    }
+}
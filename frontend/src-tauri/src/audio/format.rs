@@ -0,0 +1,198 @@
+use cpal::{
+    traits::DeviceTrait, SampleFormat, SampleRate, SupportedStreamConfig,
+    SupportedStreamConfigRange,
+};
+
+use crate::Error;
+
+/// Format of the audio between the devices and the rest of the pipeline, the audio processor,
+/// the ring buffers and the Opus encoder and decoder all work on it
+pub const PROCESSING_SAMPLE_RATE: u32 = 48_000;
+pub const PROCESSING_CHANNELS: usize = 2;
+/// Frames the audio processor takes at once, 10 ms
+pub const PROCESSING_FRAME: usize = 480;
+
+/**
+ * Picks the config to open the microphone `device` with, the processing format if the device
+ * supports it and its own default otherwise.
+ */
+pub fn input_config(device: &cpal::Device) -> Result<SupportedStreamConfig, Error> {
+    if let Some(config) = device.supported_input_configs()?.find_map(processing_config) {
+        return Ok(config);
+    }
+    Ok(device.default_input_config()?)
+}
+
+/**
+ * Same as `input_config` for the speaker `device`.
+ */
+pub fn output_config(device: &cpal::Device) -> Result<SupportedStreamConfig, Error> {
+    if let Some(config) = device.supported_output_configs()?.find_map(processing_config) {
+        return Ok(config);
+    }
+    Ok(device.default_output_config()?)
+}
+
+/// The range as the processing format, None unless it needs neither resampling nor conversion
+fn processing_config(range: SupportedStreamConfigRange) -> Option<SupportedStreamConfig> {
+    let rate = SampleRate(PROCESSING_SAMPLE_RATE);
+    (range.sample_format() == SampleFormat::F32
+        && range.channels() as usize == PROCESSING_CHANNELS
+        && range.min_sample_rate() <= rate
+        && range.max_sample_rate() >= rate)
+        .then(|| range.with_sample_rate(rate))
+}
+
+/**
+ * Converts interleaved samples with `from` channels to `to` channels.
+ * Mono is copied to every channel, everything is averaged down to mono,
+ * otherwise the first channels are kept, they are the front left and right.
+ */
+pub fn remix(input: &[f32], from: usize, to: usize) -> Vec<f32> {
+    if from == to {
+        return input.to_vec();
+    }
+    let mut output = Vec::with_capacity(input.len() / from * to);
+    for frame in input.chunks_exact(from) {
+        if to == 1 {
+            output.push(frame.iter().sum::<f32>() / from as f32);
+        } else if from == 1 {
+            output.extend(std::iter::repeat_n(frame[0], to));
+        } else {
+            output.extend((0..to).map(|channel| frame.get(channel).copied().unwrap_or(0.0)));
+        }
+    }
+    output
+}
+
+/**
+ * Linear resampler for a stream of interleaved samples that arrives in chunks of any size.
+ */
+pub struct Resampler {
+    channels: usize,
+    /// Input frames per output frame
+    step: f64,
+    /// Position of the next output frame, 0 is the last frame of the previous chunk
+    position: f64,
+    last: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32, channels: usize) -> Self {
+        Resampler {
+            channels,
+            step: from as f64 / to as f64,
+            position: 0.0,
+            last: vec![0.0; channels],
+        }
+    }
+
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        if self.step == 1.0 {
+            return input.to_vec();
+        }
+        let channels = self.channels;
+        let frames = input.len() / channels;
+        let mut output = Vec::with_capacity((frames as f64 / self.step) as usize * channels + channels);
+        let mut position = self.position;
+        while (position as usize) < frames {
+            let index = position as usize;
+            let t = (position - index as f64) as f32;
+            let (a, b) = (self.frame(input, index), self.frame(input, index + 1));
+            output.extend(a.iter().zip(b).map(|(a, b)| a + (b - a) * t));
+            position += self.step;
+        }
+        if frames > 0 {
+            self.last = self.frame(input, frames).to_vec();
+        }
+        self.position = position - frames as f64;
+        output
+    }
+
+    /// Frame `index` of `input` following the last frame of the previous chunk
+    fn frame<'a>(&'a self, input: &'a [f32], index: usize) -> &'a [f32] {
+        if index == 0 {
+            &self.last
+        } else {
+            &input[(index - 1) * self.channels..index * self.channels]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Chunk sizes in frames that do not line up with either rate
+    const CHUNKS: [usize; 6] = [1, 7, 441, 13, 480, 100];
+
+    /// Stereo ramp going up on the left and down on the right, linear interpolation keeps it exact
+    fn ramp(frames: usize) -> Vec<f32> {
+        (1..=frames)
+            .flat_map(|frame| {
+                let value = frame as f32 / 1000.0;
+                [value, -value]
+            })
+            .collect()
+    }
+
+    fn resample_in_chunks(from: u32, to: u32, input: &[f32]) -> Vec<f32> {
+        let mut resampler = Resampler::new(from, to, 2);
+        let mut output = Vec::new();
+        let mut rest = input;
+        for frames in CHUNKS.into_iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (chunk, next) = rest.split_at((frames * 2).min(rest.len()));
+            output.extend(resampler.process(chunk));
+            rest = next;
+        }
+        output
+    }
+
+    fn check_resampling(from: u32, to: u32) {
+        let frames = from as usize;
+        let output = resample_in_chunks(from, to, &ramp(frames));
+        // A second of input, give or take the rounding of the position
+        assert!(
+            (output.len() / 2).abs_diff(to as usize) <= 1,
+            "{} frames",
+            output.len() / 2
+        );
+        // Output frame k lies k steps into the input, which starts after a silent frame
+        let step = from as f32 / to as f32;
+        for (k, frame) in output.chunks_exact(2).enumerate() {
+            let expected = k as f32 * step / 1000.0;
+            assert!((frame[0] - expected).abs() < 1e-4, "frame {k}: {frame:?}");
+            assert!((frame[1] + expected).abs() < 1e-4, "frame {k}: {frame:?}");
+        }
+    }
+
+    #[test]
+    fn resamples_44_1_to_48_khz_across_chunks() {
+        check_resampling(44_100, 48_000);
+    }
+
+    #[test]
+    fn resamples_48_to_44_1_khz_across_chunks() {
+        check_resampling(48_000, 44_100);
+    }
+
+    #[test]
+    fn passes_the_same_rate_through() {
+        let input = ramp(10);
+        assert_eq!(Resampler::new(48_000, 48_000, 2).process(&input), input);
+    }
+
+    #[test]
+    fn remixes_channels() {
+        assert_eq!(remix(&[0.1, 0.2], 1, 2), vec![0.1, 0.1, 0.2, 0.2]);
+        assert_eq!(remix(&[0.2, 0.4, -0.2, 0.2], 2, 1), vec![0.3f32, 0.0]);
+        // Surround keeps the front left and right
+        assert_eq!(remix(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6], 6, 2), vec![0.1, 0.2]);
+        assert_eq!(remix(&[0.1, 0.2], 1, 1), vec![0.1, 0.2]);
+        // Stereo to surround leaves the other channels silent
+        assert_eq!(remix(&[0.1, 0.2], 2, 4), vec![0.1, 0.2, 0.0, 0.0]);
+    }
+}
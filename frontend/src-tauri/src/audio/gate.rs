@@ -0,0 +1,226 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Instant;
+
+use front_shared::models::audio_config::{InputMode, VoiceActivityDetectionCfg};
+use front_shared::InputLevel;
+use webrtc_vad::{SampleRate, Vad, VadMode};
+
+use super::format::PROCESSING_CHANNELS;
+use super::ptt::PushToTalk;
+
+/// Frames of voice in a row it takes to open the gate, so that clicks and knocks stay out
+const ATTACK_FRAMES: u32 = 2;
+/// Frames the gate stays open for after the voice stopped, so that the ends of words get through
+const HANGOVER_FRAMES: u32 = 30;
+/// Floor of the level meter, digital silence would be minus infinity
+const SILENCE_DB: f32 = -100.0;
+
+/**
+ * Level of the microphone between two reads, written by the capture callback and read
+ * by the websocket loop for the meter in the settings.
+ */
+#[derive(Debug, Default)]
+pub struct InputMeter {
+    /// Bits of the highest RMS since the last read, as non-negative floats they order like integers
+    peak: AtomicU32,
+    transmitting: AtomicBool,
+}
+
+impl InputMeter {
+    fn update(&self, rms: f32, transmitting: bool) {
+        self.peak.fetch_max(rms.to_bits(), Ordering::Relaxed);
+        self.transmitting.store(transmitting, Ordering::Relaxed);
+    }
+
+    /// Whether the microphone was being sent at the last frame
+    pub fn transmitting(&self) -> bool {
+        self.transmitting.load(Ordering::Relaxed)
+    }
+
+    /// The level since the previous call
+    pub fn take(&self) -> InputLevel {
+        InputLevel {
+            level_db: to_db(f32::from_bits(self.peak.swap(0, Ordering::Relaxed))),
+            transmitting: self.transmitting.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/**
+ * Voice activity detection, the WebRTC detector in auto mode and a level threshold in
+ * manual mode, with the attack and hangover on top.
+ */
+struct VoiceActivity {
+    vad: Vad,
+    voiced_frames: u32,
+    hangover: u32,
+}
+
+impl VoiceActivity {
+    fn new() -> Self {
+        VoiceActivity {
+            vad: Vad::new_with_rate_and_mode(SampleRate::Rate48kHz, VadMode::Aggressive),
+            voiced_frames: 0,
+            hangover: 0,
+        }
+    }
+
+    fn detect(&mut self, cfg: &VoiceActivityDetectionCfg, frame: &[f32], rms: f32) -> bool {
+        let voiced = match cfg {
+            VoiceActivityDetectionCfg::Auto => {
+                let mono = frame
+                    .chunks_exact(PROCESSING_CHANNELS)
+                    .map(|s| s.iter().sum::<f32>() / PROCESSING_CHANNELS as f32)
+                    .map(|s| (s * i16::MAX as f32) as i16)
+                    .collect::<Vec<i16>>();
+                // Sends rather than cuts the voice off if the detector fails
+                self.vad.is_voice_segment(&mono).unwrap_or(true)
+            }
+            // Stored as the dB under full scale
+            VoiceActivityDetectionCfg::Manual { threshold } => {
+                to_db(rms) >= -(threshold.abs() as f32)
+            }
+        };
+        self.voiced_frames = if voiced { self.voiced_frames + 1 } else { 0 };
+        // Voice while open keeps it open, it takes the attack only to open
+        if voiced && (self.hangover > 0 || self.voiced_frames >= ATTACK_FRAMES) {
+            self.hangover = HANGOVER_FRAMES;
+        } else {
+            self.hangover = self.hangover.saturating_sub(1);
+        }
+        self.hangover > 0
+    }
+}
+
+/**
+ * Decides from the input mode which frames of the microphone are sent, the others are
+ * replaced with silence, which the encoder then leaves out.
+ */
+pub struct InputGate {
+    input_mode: Arc<StdMutex<InputMode>>,
+    muted: Arc<AtomicBool>,
+    push_to_talk: PushToTalk,
+    voice_activity: VoiceActivity,
+    meter: Arc<InputMeter>,
+}
+
+impl InputGate {
+    pub fn new(
+        input_mode: Arc<StdMutex<InputMode>>,
+        muted: Arc<AtomicBool>,
+        push_to_talk: PushToTalk,
+        meter: Arc<InputMeter>,
+    ) -> Self {
+        InputGate {
+            input_mode,
+            muted,
+            push_to_talk,
+            voice_activity: VoiceActivity::new(),
+            meter,
+        }
+    }
+
+    /// Silences the processed 10 ms `frame` unless it is to be sent
+    pub fn process(&mut self, frame: &mut [f32]) {
+        let rms = (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt();
+        let open = !self.muted.load(Ordering::Relaxed)
+            && match &*self.input_mode.lock().unwrap() {
+                InputMode::PushToTalk(cfg) => self.push_to_talk.transmitting(cfg, Instant::now()),
+                InputMode::VoiceActivityDetection(cfg) => {
+                    self.voice_activity.detect(cfg, frame, rms)
+                }
+            };
+        self.meter.update(rms, open);
+        if !open {
+            frame.fill(0.0);
+        }
+    }
+}
+
+fn to_db(rms: f32) -> f32 {
+    (20.0 * rms.log10()).max(SILENCE_DB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CFG: VoiceActivityDetectionCfg = VoiceActivityDetectionCfg::Manual { threshold: 40 };
+    /// -20 and -60 dBFS, above and below the threshold
+    const VOICE: f32 = 0.1;
+    const QUIET: f32 = 0.001;
+
+    #[test]
+    fn opens_after_the_attack() {
+        let mut voice_activity = VoiceActivity::new();
+        assert!(!voice_activity.detect(&CFG, &[], QUIET));
+        for _ in 1..ATTACK_FRAMES {
+            assert!(!voice_activity.detect(&CFG, &[], VOICE));
+        }
+        assert!(voice_activity.detect(&CFG, &[], VOICE));
+    }
+
+    #[test]
+    fn keeps_clicks_out() {
+        let mut voice_activity = VoiceActivity::new();
+        for _ in 0..10 {
+            assert!(!voice_activity.detect(&CFG, &[], VOICE));
+            assert!(!voice_activity.detect(&CFG, &[], QUIET));
+        }
+    }
+
+    #[test]
+    fn stays_open_for_the_hangover() {
+        let mut voice_activity = VoiceActivity::new();
+        for _ in 0..ATTACK_FRAMES {
+            voice_activity.detect(&CFG, &[], VOICE);
+        }
+        for _ in 1..HANGOVER_FRAMES {
+            assert!(voice_activity.detect(&CFG, &[], QUIET));
+        }
+        assert!(!voice_activity.detect(&CFG, &[], QUIET));
+    }
+
+    #[test]
+    fn voice_while_open_restarts_the_hangover() {
+        let mut voice_activity = VoiceActivity::new();
+        for _ in 0..ATTACK_FRAMES {
+            voice_activity.detect(&CFG, &[], VOICE);
+        }
+        for _ in 0..10 {
+            voice_activity.detect(&CFG, &[], QUIET);
+        }
+        // A single frame is enough while open
+        assert!(voice_activity.detect(&CFG, &[], VOICE));
+        for _ in 1..HANGOVER_FRAMES {
+            assert!(voice_activity.detect(&CFG, &[], QUIET));
+        }
+        assert!(!voice_activity.detect(&CFG, &[], QUIET));
+    }
+
+    #[test]
+    fn reads_the_threshold_with_either_sign() {
+        let cfg = VoiceActivityDetectionCfg::Manual { threshold: -40 };
+        let mut voice_activity = VoiceActivity::new();
+        for _ in 0..ATTACK_FRAMES {
+            voice_activity.detect(&cfg, &[], VOICE);
+        }
+        assert!(voice_activity.detect(&cfg, &[], VOICE));
+        let mut voice_activity = VoiceActivity::new();
+        for _ in 0..ATTACK_FRAMES {
+            assert!(!voice_activity.detect(&cfg, &[], QUIET));
+        }
+    }
+
+    #[test]
+    fn meters_the_peak_since_the_last_read() {
+        let meter = InputMeter::default();
+        meter.update(0.1, true);
+        meter.update(0.01, false);
+        let level = meter.take();
+        assert!((level.level_db + 20.0).abs() < 1e-3);
+        assert!(!level.transmitting);
+        assert_eq!(meter.take().level_db, SILENCE_DB);
+    }
+}
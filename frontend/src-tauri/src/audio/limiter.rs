@@ -0,0 +1,100 @@
+/// Level the limiter keeps the mix under, about -1 dBFS
+const THRESHOLD: f32 = 0.89;
+/// Time for the gain to come back up after a peak
+const RELEASE_MS: f32 = 150.0;
+
+/**
+ * Peak limiter for the mixed speaker output, boosted people and several loud people at once
+ * go above full scale and would clip hard in the conversion to the device format.
+ * The gain drops at once for a peak and recovers slowly, which keeps it from pumping.
+ */
+pub struct Limiter {
+    channels: usize,
+    gain: f32,
+    /// Share of the way back to unity gain covered per frame
+    release: f32,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        Limiter {
+            channels,
+            gain: 1.0,
+            release: 1.0 - (-1000.0 / (RELEASE_MS * sample_rate as f32)).exp(),
+        }
+    }
+
+    /// Limits the interleaved samples in place
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(self.channels) {
+            let peak = frame.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            let target = if peak > THRESHOLD { THRESHOLD / peak } else { 1.0 };
+            if target < self.gain {
+                self.gain = target;
+            } else {
+                self.gain += (target - self.gain) * self.release;
+            }
+            for s in frame.iter_mut() {
+                *s *= self.gain;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    #[test]
+    fn keeps_a_boosted_mix_under_the_threshold() {
+        let mut limiter = Limiter::new(SAMPLE_RATE, 2);
+        for chunk in 0..100 {
+            // Two loud people at three times full scale
+            let mut samples = (0..480)
+                .flat_map(|i| {
+                    let t = (chunk * 480 + i) as f32 / SAMPLE_RATE as f32;
+                    let s = 3.0 * (std::f32::consts::TAU * 440.0 * t).sin();
+                    [s, -s]
+                })
+                .collect::<Vec<_>>();
+            limiter.process(&mut samples);
+            let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            assert!(peak <= THRESHOLD + 1e-6, "peak {peak} in chunk {chunk}");
+        }
+    }
+
+    #[test]
+    fn leaves_quiet_audio_alone() {
+        let mut limiter = Limiter::new(SAMPLE_RATE, 2);
+        let mut samples = vec![0.5, -0.5, THRESHOLD, -THRESHOLD];
+        limiter.process(&mut samples);
+        assert_eq!(samples, vec![0.5, -0.5, THRESHOLD, -THRESHOLD]);
+    }
+
+    #[test]
+    fn recovers_over_the_release() {
+        let mut limiter = Limiter::new(SAMPLE_RATE, 2);
+        let mut peak = vec![2.0, 2.0];
+        limiter.process(&mut peak);
+        assert!((peak[0] - THRESHOLD).abs() < 1e-6);
+        let ducked = THRESHOLD / 2.0;
+        // Gain after `frames` frames of quiet audio following the peak
+        let gain_after = |limiter: &mut Limiter, frames: usize| {
+            let mut samples = vec![0.1; frames * 2];
+            limiter.process(&mut samples);
+            samples[samples.len() - 1] / 0.1
+        };
+        assert!(gain_after(&mut limiter, 1) < ducked + 0.01);
+        // One time constant covers all but 1/e of the way back to unity
+        let release_frames = (RELEASE_MS * SAMPLE_RATE as f32 / 1000.0) as usize;
+        let expected = 1.0 - (1.0 - ducked) * (-1.0f32).exp();
+        let gain = gain_after(&mut limiter, release_frames - 1);
+        assert!(
+            (gain - expected).abs() < 1e-3,
+            "gain {gain}, expected {expected}"
+        );
+        assert!(gain_after(&mut limiter, 4 * release_frames) > 0.99);
+    }
+}
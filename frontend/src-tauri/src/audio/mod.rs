@@ -1,10 +1,11 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex as StdMutex};
 
 use front_shared::models::audio_config::AudioConfigDBPartial;
-use ringbuf::HeapProd;
 use ringbuf::HeapCons;
 use shared::models::Channel;
-use shared::ROOM_SIZE;
+use shared::{AudioSinks, OpusTuning};
 use webrtc_audio_processing::Processor;
 
 use front_shared::models::audio_config::AudioConfig;
@@ -12,6 +13,15 @@ use front_shared::models::last_used_devices::LastUsedAudioDevices;
 use front_shared::models::user_boost::PerUserBoost;
 
 mod audio;
+mod ducking;
+mod format;
+mod gate;
+mod limiter;
+mod ptt;
+
+pub use ducking::SystemDucker;
+pub use gate::{InputGate, InputMeter};
+pub use ptt::{GlobalKeys, KeySource, SimulatedKeys};
 
 pub struct AudioElement {
     pub audio_processor: Processor,
@@ -21,7 +31,34 @@ pub struct AudioElement {
     pub speaker_stream: Option<cpal::Stream>,
     pub mic_stream: Option<cpal::Stream>,
     pub mic_consumer: Option<Arc<StdMutex<HeapCons<f32>>>>,
-    pub speaker_producers: Option<Vec<Arc<StdMutex<HeapProd<f32>>>>>,
+    pub speaker_sinks: AudioSinks,
+    /// Capture of the applications shared with the screen, without the audio processing
+    pub app_audio_stream: Option<cpal::Stream>,
+    pub app_audio_consumer: Option<Arc<StdMutex<HeapCons<f32>>>>,
+    /// Opus settings of the microphone track, the profile comes from the audio settings
+    pub mic_tuning: Arc<OpusTuning>,
+    /// Set by the error callbacks of the streams, see `check_devices`
+    pub mic_failed: Arc<AtomicBool>,
+    pub speaker_failed: Arc<AtomicBool>,
+    pub app_audio_failed: Arc<AtomicBool>,
+    /// Microphones and speakers at the previous check
+    pub known_devices: Option<(Vec<String>, Vec<String>)>,
+    /// Keys push-to-talk follows, given to `new`
+    pub key_source: Arc<dyn KeySource>,
+    /// Level of the microphone for the settings, see `emit_input_level`
+    pub input_meter: Arc<InputMeter>,
+    /// Set by the speaker stream while the others are speaking
+    pub others_speaking: Arc<AtomicBool>,
+    pub system_ducker: SystemDucker,
+    /// Muting sends silence and deafening plays it, the streams and the audio processor keep running
+    pub muted: Arc<AtomicBool>,
+    pub deafened: Arc<AtomicBool>,
+}
+
+/// What the microphone goes through and the audio of a screen share does not
+pub struct MicPipeline {
+    pub processor: Processor,
+    pub gate: InputGate,
 }
 
 pub enum AudioCommand {
@@ -38,6 +75,11 @@ pub enum AudioCommand {
         user_id: uuid::Uuid,
         boost_level: i32,
     },
+    /// Mutes the user for this client only
+    SetUserMute {
+        user_id: uuid::Uuid,
+        muted: bool,
+    },
     ChangeSetting {
         cfg: AudioConfigDBPartial
     }
@@ -45,5 +87,6 @@ pub enum AudioCommand {
 
 pub struct ChannelWithBoosts {
     pub channel: Channel,
-    pub users: [PerUserBoost; ROOM_SIZE],
+    /// Boosts of the people in the channel, keyed by their slot
+    pub users: Arc<StdMutex<HashMap<usize, PerUserBoost>>>,
 }
\ No newline at end of file
@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use front_shared::models::audio_config::PushToTalkCfg;
+use ringbuf::{traits::Producer, HeapProd};
+
+use super::format::{PROCESSING_CHANNELS, PROCESSING_SAMPLE_RATE};
+
+/// Key of the speaker sink the push-to-talk cue plays through
+pub const CUE_SINK: &str = "push-to-talk-cue";
+/// Length and volume of the cue, a higher tone when the transmission starts than when it stops
+const CUE_MS: u32 = 60;
+const CUE_VOLUME: f32 = 0.2;
+const CUE_START_HZ: f32 = 880.0;
+const CUE_STOP_HZ: f32 = 587.0;
+
+#[derive(Debug, Clone, Copy)]
+struct KeyState {
+    pressed: bool,
+    pressed_at: Instant,
+    released_at: Option<Instant>,
+}
+
+/**
+ * State of the keys of the keyboard, keyed by the names rdev gives them, like "KeyV".
+ */
+#[derive(Debug, Default)]
+pub struct KeyStates {
+    keys: StdMutex<HashMap<String, KeyState>>,
+}
+
+impl KeyStates {
+    pub fn key_event(&self, name: String, pressed: bool, at: Instant) {
+        let mut keys = self.keys.lock().unwrap();
+        let key = keys.entry(name).or_insert(KeyState {
+            pressed: false,
+            pressed_at: at,
+            released_at: None,
+        });
+        // Repeats of a held key do not count as new presses
+        if pressed && !key.pressed {
+            *key = KeyState {
+                pressed: true,
+                pressed_at: at,
+                released_at: None,
+            };
+        } else if !pressed && key.pressed {
+            key.pressed = false;
+            key.released_at = Some(at);
+        }
+    }
+
+    /**
+     * Whether the key of `cfg` keeps push-to-talk transmitting at `now`, it opens once the key
+     * is held for the press delay and closes the release delay after letting go of it.
+     */
+    fn transmits(&self, cfg: &PushToTalkCfg, now: Instant) -> bool {
+        let Some(key) = self.keys.lock().unwrap().get(&key_name(&cfg.key_code)).copied() else {
+            return false;
+        };
+        let opened_at = key.pressed_at + Duration::from_millis(cfg.press_delay_ms.max(0) as u64);
+        if key.pressed {
+            return now >= opened_at;
+        }
+        // Let go of before the press delay was over, it never transmitted
+        key.released_at.is_some_and(|released_at| {
+            released_at >= opened_at
+                && now < released_at + Duration::from_millis(cfg.release_delay_ms.max(0) as u64)
+        })
+    }
+}
+
+/**
+ * Where push-to-talk learns about the keys.
+ */
+pub trait KeySource: Send + Sync {
+    fn key_states(&self) -> Arc<KeyStates>;
+}
+
+/**
+ * Keys pressed anywhere on the system, whichever window has the focus.
+ * The listener starts the first time push-to-talk asks for the keys and runs until the app closes.
+ */
+pub struct GlobalKeys;
+
+impl KeySource for GlobalKeys {
+    fn key_states(&self) -> Arc<KeyStates> {
+        static KEYS: OnceLock<Arc<KeyStates>> = OnceLock::new();
+        KEYS.get_or_init(|| {
+            let keys = Arc::new(KeyStates::default());
+            let listener_keys = keys.clone();
+            std::thread::spawn(move || {
+                // Only listens, the key still reaches the focused application
+                let result = rdev::listen(move |event| match event.event_type {
+                    rdev::EventType::KeyPress(key) => {
+                        listener_keys.key_event(format!("{:?}", key), true, Instant::now())
+                    }
+                    rdev::EventType::KeyRelease(key) => {
+                        listener_keys.key_event(format!("{:?}", key), false, Instant::now())
+                    }
+                    _ => {}
+                });
+                if let Err(e) = result {
+                    tracing::error!("Failed to listen for push-to-talk keys: {:?}", e);
+                }
+            });
+            keys
+        })
+        .clone()
+    }
+}
+
+/**
+ * Keys pressed and released from code, to drive push-to-talk without a keyboard.
+ */
+#[derive(Default)]
+pub struct SimulatedKeys {
+    keys: Arc<KeyStates>,
+}
+
+impl SimulatedKeys {
+    pub fn press(&self, key_code: &str) {
+        self.keys.key_event(key_name(key_code), true, Instant::now());
+    }
+
+    pub fn release(&self, key_code: &str) {
+        self.keys.key_event(key_name(key_code), false, Instant::now());
+    }
+}
+
+impl KeySource for SimulatedKeys {
+    fn key_states(&self) -> Arc<KeyStates> {
+        self.keys.clone()
+    }
+}
+
+/**
+ * Push-to-talk state of the microphone, plays the cue into `cue` when it starts and stops
+ * transmitting.
+ */
+pub struct PushToTalk {
+    source: Arc<dyn KeySource>,
+    /// Taken from the source on first use, nothing listens to the keyboard without push-to-talk
+    keys: Option<Arc<KeyStates>>,
+    cue: HeapProd<f32>,
+    transmitting: bool,
+}
+
+impl PushToTalk {
+    pub fn new(source: Arc<dyn KeySource>, cue: HeapProd<f32>) -> Self {
+        PushToTalk {
+            source,
+            keys: None,
+            cue,
+            transmitting: false,
+        }
+    }
+
+    pub fn transmitting(&mut self, cfg: &PushToTalkCfg, now: Instant) -> bool {
+        let source = &self.source;
+        let keys = self.keys.get_or_insert_with(|| source.key_states());
+        let transmitting = keys.transmits(cfg, now);
+        if transmitting != self.transmitting {
+            self.transmitting = transmitting;
+            if cfg.sound {
+                self.cue.push_slice(&cue(transmitting));
+            }
+        }
+        transmitting
+    }
+}
+
+/// Short tone in the processing format, faded in and out so that it does not click
+fn cue(start: bool) -> Vec<f32> {
+    let frequency = if start { CUE_START_HZ } else { CUE_STOP_HZ };
+    let frames = (CUE_MS * PROCESSING_SAMPLE_RATE / 1000) as usize;
+    (0..frames)
+        .flat_map(|i| {
+            let t = i as f32 / PROCESSING_SAMPLE_RATE as f32;
+            let fade = (std::f32::consts::PI * i as f32 / frames as f32).sin();
+            [(TAU * frequency * t).sin() * fade * CUE_VOLUME; PROCESSING_CHANNELS]
+        })
+        .collect()
+}
+
+/**
+ * Name rdev gives the key of `code`, which is either a `KeyboardEvent.code` like "KeyV" or
+ * "Digit1" as the settings record it, or a single character as older settings have it.
+ */
+fn key_name(code: &str) -> String {
+    let mut chars = code.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return if c.is_ascii_digit() {
+            format!("Num{c}")
+        } else if c.is_ascii_alphabetic() {
+            format!("Key{}", c.to_ascii_uppercase())
+        } else {
+            code.to_string()
+        };
+    }
+    if let Some(digit) = code.strip_prefix("Digit") {
+        return format!("Num{digit}");
+    }
+    if let Some(key) = code.strip_prefix("Numpad") {
+        return match key {
+            "Enter" => "KpReturn".to_string(),
+            "Subtract" => "KpMinus".to_string(),
+            "Add" => "KpPlus".to_string(),
+            "Multiply" => "KpMultiply".to_string(),
+            "Divide" => "KpDivide".to_string(),
+            "Decimal" => "KpDelete".to_string(),
+            digit => format!("Kp{digit}"),
+        };
+    }
+    match code {
+        "AltLeft" => "Alt",
+        "AltRight" => "AltGr",
+        "OSLeft" => "MetaLeft",
+        "OSRight" => "MetaRight",
+        "ArrowUp" => "UpArrow",
+        "ArrowDown" => "DownArrow",
+        "ArrowLeft" => "LeftArrow",
+        "ArrowRight" => "RightArrow",
+        "Enter" => "Return",
+        "Backquote" => "BackQuote",
+        "BracketLeft" => "LeftBracket",
+        "BracketRight" => "RightBracket",
+        "Semicolon" => "SemiColon",
+        "Backslash" => "BackSlash",
+        "Period" => "Dot",
+        code => code,
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use ringbuf::{traits::Observer, traits::Split, HeapRb};
+
+    use super::*;
+
+    fn cfg(press_delay_ms: i32, release_delay_ms: i32) -> PushToTalkCfg {
+        PushToTalkCfg {
+            key_code: "KeyV".to_string(),
+            press_delay_ms,
+            release_delay_ms,
+            sound: true,
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn transmits_after_the_press_delay_until_the_release_delay() {
+        let keys = KeyStates::default();
+        let cfg = cfg(50, 200);
+        let start = Instant::now();
+        assert!(!keys.transmits(&cfg, start));
+        keys.key_event("KeyV".to_string(), true, start);
+        assert!(!keys.transmits(&cfg, start + ms(49)));
+        assert!(keys.transmits(&cfg, start + ms(50)));
+        keys.key_event("KeyV".to_string(), false, start + ms(500));
+        assert!(keys.transmits(&cfg, start + ms(699)));
+        assert!(!keys.transmits(&cfg, start + ms(700)));
+    }
+
+    #[test]
+    fn key_repeats_do_not_restart_the_press_delay() {
+        let keys = KeyStates::default();
+        let cfg = cfg(50, 0);
+        let start = Instant::now();
+        keys.key_event("KeyV".to_string(), true, start);
+        keys.key_event("KeyV".to_string(), true, start + ms(30));
+        keys.key_event("KeyV".to_string(), true, start + ms(60));
+        assert!(keys.transmits(&cfg, start + ms(60)));
+    }
+
+    #[test]
+    fn release_before_the_press_delay_never_transmits() {
+        let keys = KeyStates::default();
+        let cfg = cfg(100, 200);
+        let start = Instant::now();
+        keys.key_event("KeyV".to_string(), true, start);
+        keys.key_event("KeyV".to_string(), false, start + ms(80));
+        assert!(!keys.transmits(&cfg, start + ms(100)));
+        assert!(!keys.transmits(&cfg, start + ms(150)));
+    }
+
+    #[test]
+    fn other_keys_do_not_transmit() {
+        let keys = KeyStates::default();
+        let start = Instant::now();
+        keys.key_event("KeyB".to_string(), true, start);
+        assert!(!keys.transmits(&cfg(0, 0), start));
+    }
+
+    #[test]
+    fn maps_codes_to_rdev_names() {
+        assert_eq!(key_name("KeyV"), "KeyV");
+        assert_eq!(key_name("Digit1"), "Num1");
+        assert_eq!(key_name("Numpad5"), "Kp5");
+        assert_eq!(key_name("NumpadEnter"), "KpReturn");
+        assert_eq!(key_name("NumpadSubtract"), "KpMinus");
+        assert_eq!(key_name("AltRight"), "AltGr");
+        assert_eq!(key_name("Space"), "Space");
+        assert_eq!(key_name("v"), "KeyV");
+        assert_eq!(key_name("1"), "Num1");
+        assert_eq!(key_name("-"), "-");
+    }
+
+    #[test]
+    fn cue_plays_only_on_transitions() {
+        let keys = Arc::new(SimulatedKeys::default());
+        let (producer, consumer) = HeapRb::<f32>::new(48_000).split();
+        let mut push_to_talk = PushToTalk::new(keys.clone(), producer);
+        let cfg = cfg(0, 0);
+        let cue_len = cue(true).len();
+        assert_eq!(
+            cue_len,
+            (CUE_MS * PROCESSING_SAMPLE_RATE / 1000) as usize * PROCESSING_CHANNELS
+        );
+
+        assert!(!push_to_talk.transmitting(&cfg, Instant::now()));
+        assert_eq!(consumer.occupied_len(), 0);
+
+        keys.press("KeyV");
+        assert!(push_to_talk.transmitting(&cfg, Instant::now()));
+        assert!(push_to_talk.transmitting(&cfg, Instant::now()));
+        assert_eq!(consumer.occupied_len(), cue_len);
+
+        keys.release("KeyV");
+        assert!(!push_to_talk.transmitting(&cfg, Instant::now()));
+        assert!(!push_to_talk.transmitting(&cfg, Instant::now()));
+        assert_eq!(consumer.occupied_len(), 2 * cue_len);
+    }
+
+    #[test]
+    fn cue_is_silent_without_the_sound() {
+        let keys = Arc::new(SimulatedKeys::default());
+        let (producer, consumer) = HeapRb::<f32>::new(48_000).split();
+        let mut push_to_talk = PushToTalk::new(keys.clone(), producer);
+        let cfg = PushToTalkCfg {
+            sound: false,
+            ..cfg(0, 0)
+        };
+        keys.press("KeyV");
+        assert!(push_to_talk.transmitting(&cfg, Instant::now()));
+        keys.release("KeyV");
+        assert!(!push_to_talk.transmitting(&cfg, Instant::now()));
+        assert_eq!(consumer.occupied_len(), 0);
+    }
+}
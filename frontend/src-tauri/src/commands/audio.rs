@@ -1,7 +1,8 @@
+use front_shared::models::audio_config::{AudioConfigDB, AudioConfigDBPartial};
 use front_shared::AudioDevices;
 use tauri::Manager;
 
-use crate::{audio::{AudioCommand, AudioElement}, websocket::WebSocketRequest, AppState};
+use crate::{audio::{AudioCommand, AudioElement}, utils::establish_connection, websocket::WebSocketRequest, AppState};
 
 #[tauri::command]
 pub async fn mute_microphone(app_handle: tauri::AppHandle) {
@@ -137,4 +138,57 @@ pub async fn set_user_boost(
             .await
             .unwrap_or_else(|e| eprintln!("Failed to send set user boost command: {}", e.to_string()));
     }
-}
\ No newline at end of file
+}
+
+#[tauri::command(rename_all = "snake_case")]
+pub async fn set_user_mute(app_handle: tauri::AppHandle, user_id: uuid::Uuid, muted: bool) {
+    let app_state = app_handle.state::<AppState>();
+    let ws = &app_state.websocket;
+    {
+        let ws = ws.read().await;
+        ws.send(WebSocketRequest::AudioCommand(AudioCommand::SetUserMute { user_id, muted }))
+            .await
+            .unwrap_or_else(|e| tracing::error!("Failed to send set user mute command: {}", e));
+    }
+}
+/**
+ * Profile of the microphone, 0 for voice and 1 for music.
+ */
+#[tauri::command]
+pub async fn get_audio_profile(app_handle: tauri::AppHandle) -> i32 {
+    AudioConfigDB::get(&mut establish_connection(&app_handle)).audio_profile
+}
+
+#[tauri::command]
+pub async fn set_audio_profile(app_handle: tauri::AppHandle, profile: i32) {
+    let app_state = app_handle.state::<AppState>();
+    let ws = &app_state.websocket;
+    {
+        let ws = ws.read().await;
+        ws.send(WebSocketRequest::AudioCommand(AudioCommand::ChangeSetting {
+            cfg: AudioConfigDBPartial::AudioProfile(profile),
+        }))
+            .await
+            .unwrap_or_else(|e| tracing::error!("Failed to send set audio profile command: {}", e));
+    }
+}
+
+#[tauri::command]
+pub async fn get_audio_config(app_handle: tauri::AppHandle) -> AudioConfigDB {
+    AudioConfigDB::get(&mut establish_connection(&app_handle))
+}
+
+/**
+ * Changes one audio setting, saved even outside of a call.
+ */
+#[tauri::command]
+pub async fn set_audio_setting(app_handle: tauri::AppHandle, cfg: AudioConfigDBPartial) {
+    let app_state = app_handle.state::<AppState>();
+    let ws = &app_state.websocket;
+    {
+        let ws = ws.read().await;
+        ws.send(WebSocketRequest::AudioCommand(AudioCommand::ChangeSetting { cfg }))
+            .await
+            .unwrap_or_else(|e| tracing::error!("Failed to send set audio setting command: {}", e));
+    }
+}
@@ -1,37 +1,99 @@
 use std::sync::atomic::Ordering;
 
 use front_shared::{models::user_boost::PerUserBoost, URL};
-use shared::models::ChannelWithUsers;
+use shared::models::{ChannelWithUsers, RoomQuality};
+use shared::{Feature, RpcError, RpcRequest, RpcResponse};
 use tauri::Manager;
 use uuid::Uuid;
 
-use crate::{utils::{establish_connection, handle_auth_error}, websocket::WebSocketRequest};
+use crate::{utils::{establish_connection, handle_auth_error}, websocket::{rpc, WebSocketRequest}, Error};
 
 #[tauri::command(rename_all = "snake_case")]
 pub async fn get_channels(
     server_id: Uuid,
     handle: tauri::AppHandle,
 ) -> Result<Vec<ChannelWithUsers>, String> {
+    {
+        let state = handle.state::<crate::AppState>();
+        *state.active_server.lock().unwrap() = Some(server_id);
+    }
+    let response = match rpc::request(&handle, RpcRequest::ListChannels { server_id }).await {
+        Ok(response) => response,
+        Err(RpcError::NotSupported) => {
+            return fetch_channels(server_id, &handle).await.map_err(|e| e.to_string());
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    let RpcResponse::Channels(mut channels) = response else {
+        return Err(Error::UnexpectedResponse.to_string());
+    };
+    apply_boosts(&mut channels, &handle);
+    Ok(channels)
+}
+
+/**
+ * Connection quality of everyone in a voice channel, for people allowed to see it.
+ * None when nobody is in the channel.
+ */
+#[tauri::command(rename_all = "snake_case")]
+pub async fn get_call_quality(
+    server_id: Uuid,
+    channel_id: Uuid,
+    handle: tauri::AppHandle,
+) -> Result<Option<RoomQuality>, String> {
+    if !handle.state::<crate::AppState>().server_supports(Feature::CallQuality) {
+        return Err(RpcError::NotSupported.to_string());
+    }
+    match rpc::request(&handle, RpcRequest::GetCallQuality { server_id, channel_id }).await {
+        Ok(RpcResponse::CallQuality(quality)) => Ok(quality),
+        Ok(_) => Err(Error::UnexpectedResponse.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/**
+ * Lists the channels of the server with the people in them,
+ * which also subscribes the websocket session to the events of the server.
+ */
+pub async fn fetch_channels(
+    server_id: Uuid,
+    handle: &tauri::AppHandle,
+) -> Result<Vec<ChannelWithUsers>, Error> {
     let state = handle.state::<crate::AppState>();
     let client = state.client.clone();
     let url = format!("https://{}/channels/{}/list", URL, server_id);
-    let response = client.get(&url).send().await;
+    // Only this device's session is subscribed, other devices can look at other servers
+    let session_id = state
+        .session
+        .lock()
+        .unwrap()
+        .current
+        .map(|token| token.session_id);
+    let mut request = client.get(&url);
+    if let Some(session_id) = session_id {
+        request = request.query(&[("session_id", session_id)]);
+    }
+    let response = request.send().await;
 
-    let resp = handle_auth_error(response, handle.clone())
-        .await
-        .map_err(|e| e.to_string())?;
+    let resp = handle_auth_error(response, handle.clone()).await?;
 
-    let mut channels: Vec<ChannelWithUsers> = resp.json().await.map_err(|e| e.to_string())?;
+    let mut channels: Vec<ChannelWithUsers> = resp.json().await?;
+    apply_boosts(&mut channels, handle);
+    Ok(channels)
+}
 
-    // Get the boost level for each user in the channels
-    let mut conn = establish_connection(&handle);
+/**
+ * Fills in the locally stored boost level and mute of each user in the channels.
+ */
+fn apply_boosts(channels: &mut [ChannelWithUsers], handle: &tauri::AppHandle) {
+    let mut conn = establish_connection(handle);
     channels.iter_mut().for_each(|channel| {
         channel.users.iter_mut().for_each(|user| {
-            user.boost = Some(PerUserBoost::get(&mut conn, user.id).boost_level.load(Ordering::Relaxed));
+            let user_boost = PerUserBoost::get(&mut conn, user.id);
+            user.boost = Some(user_boost.boost_level.load(Ordering::Relaxed));
+            user.locally_muted = user_boost.muted.load(Ordering::Relaxed);
         });
     });
-
-    Ok(channels)
 }
 
 
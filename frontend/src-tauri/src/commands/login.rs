@@ -13,6 +13,7 @@ use front_shared::models::session::SessionStore;
 use crate::{
     commands::connect_ws,
     utils::{establish_connection, AppState},
+    websocket::SessionState,
 };
 
 #[tauri::command]
@@ -56,6 +57,10 @@ pub async fn login(
         let mut websocket = state.websocket.write().await;
         *websocket = websocket_tx;
     }
+    // Nothing of the previous user should be resumed
+    *state.session.lock().unwrap() = SessionState::default();
+    *state.voice_channel.lock().unwrap() = None;
+    *state.active_server.lock().unwrap() = None;
     connect_ws(handle, websocket_rx);
     Ok(LoginStatus::LoggedIn(cookie))
 }
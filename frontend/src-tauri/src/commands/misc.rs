@@ -1,4 +1,5 @@
 use front_shared::Status;
+use shared::models::ConnectionInfo;
 use tauri::Manager;
 
 use crate::utils::AppState;
@@ -9,3 +10,12 @@ pub async fn get_status(handle: tauri::AppHandle) -> Result<Status, String> {
     let conn_status = state.conn_status.lock().unwrap();
     Ok(conn_status.clone())
 }
+
+/**
+ * Latency of the websocket and quality of the call, updated by the "connection-info" event.
+ */
+#[tauri::command]
+pub async fn get_connection_info(handle: tauri::AppHandle) -> Result<ConnectionInfo, String> {
+    let state = handle.state::<AppState>();
+    Ok(state.connection_info())
+}
@@ -4,10 +4,12 @@ mod ws;
 mod channels;
 mod misc;
 mod audio;
+mod video;
 
 pub use login::*;
 pub use server::*;
 pub use ws::*;
 pub use channels::*;
 pub use audio::*;
-pub use misc::*;
\ No newline at end of file
+pub use misc::*;
+pub use video::*;
\ No newline at end of file
@@ -1,10 +1,14 @@
 use reqwest::multipart;
 use front_shared::{URL};
-use shared::models::Server;
+use shared::models::{PermissionsOfUser, Server};
+use shared::{RpcError, RpcRequest, RpcResponse};
 use tauri::Manager;
 use tauri_plugin_dialog::{DialogExt, FilePath};
+use uuid::Uuid;
 
 use crate::utils::{handle_auth_error, AppState};
+use crate::websocket::rpc;
+use crate::Error;
 
 #[tauri::command(rename_all = "snake_case")]
 pub async fn create_server(
@@ -52,20 +56,42 @@ pub async fn join_server(_app: tauri::AppHandle, connection_string: String) -> R
 
 #[tauri::command]
 pub async fn get_servers(app: tauri::AppHandle) -> Result<Vec<Server>, String> {
-    // Implementation for fetching the list of joined servers
-    let state = app.state::<AppState>();
-    let resp = state
-        .client
-        .get(format!("https://{}/servers/get-servers", URL))
-        .send()
-        .await;
+    // Fetched over the websocket, which is already authenticated
+    match rpc::request(&app, RpcRequest::GetServers).await {
+        Ok(RpcResponse::Servers(servers)) => Ok(servers),
+        Ok(_) => Err(Error::UnexpectedResponse.to_string()),
+        Err(RpcError::NotSupported) => {
+            fetch(&app, format!("https://{}/servers/get-servers", URL)).await
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
 
-    let resp = handle_auth_error(resp, app)
+#[tauri::command(rename_all = "snake_case")]
+pub async fn get_permissions(
+    app: tauri::AppHandle,
+    server_id: Uuid,
+) -> Result<PermissionsOfUser, String> {
+    match rpc::request(&app, RpcRequest::GetPermissions { server_id }).await {
+        Ok(RpcResponse::Permissions(permissions)) => Ok(permissions),
+        Ok(_) => Err(Error::UnexpectedResponse.to_string()),
+        Err(RpcError::NotSupported) => {
+            fetch(&app, format!("https://{}/servers/get-permissions/{}", URL, server_id)).await
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/**
+ * Fetches over HTTPS, for servers that do not support websocket requests.
+ */
+async fn fetch<T: serde::de::DeserializeOwned>(app: &tauri::AppHandle, url: String) -> Result<T, String> {
+    let state = app.state::<AppState>();
+    let resp = state.client.get(url).send().await;
+    let resp = handle_auth_error(resp, app.clone())
         .await
         .map_err(|e| e.to_string())?;
-
-    let servers: Vec<Server> = resp.json().await.map_err(|e| e.to_string())?;
-    Ok(servers)
+    resp.json().await.map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -0,0 +1,121 @@
+use front_shared::VideoFrames;
+use shared::{Consumer, PublishedTrack};
+use tauri::Manager;
+use uuid::Uuid;
+
+use crate::{websocket::WebSocketRequest, AppState};
+
+#[tauri::command(rename_all = "snake_case")]
+pub async fn set_camera(enabled: bool, handle: tauri::AppHandle) -> Result<(), String> {
+    let state = handle.state::<AppState>();
+    let ws = state.websocket.read().await;
+    ws.send(WebSocketRequest::SetCamera { enabled })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/**
+ * Chooses the users whose camera is received, the others are not sent by the server at all.
+ */
+#[tauri::command(rename_all = "snake_case")]
+pub async fn set_video_subscriptions(
+    user_ids: Vec<Uuid>,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let state = handle.state::<AppState>();
+    let ws = state.websocket.read().await;
+    ws.send(WebSocketRequest::SetVideoSubscriptions { user_ids })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/**
+ * Tells the server how high the camera, or the screen share if `screen` is set, of the user
+ * is shown, so that it sends the smallest layer that still looks sharp.
+ */
+#[tauri::command(rename_all = "snake_case")]
+pub async fn set_video_size(
+    user_id: Uuid,
+    screen: bool,
+    height: u32,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let state = handle.state::<AppState>();
+    let ws = state.websocket.read().await;
+    ws.send(WebSocketRequest::SetVideoSize {
+        user_id,
+        screen,
+        height,
+    })
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/**
+ * Shares the screen in the current call. `audio_device` is the input device carrying the
+ * audio of the shared applications, for example the monitor of the speakers.
+ */
+#[tauri::command(rename_all = "snake_case")]
+pub async fn start_screen_share(
+    audio_device: Option<String>,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let state = handle.state::<AppState>();
+    let ws = state.websocket.read().await;
+    ws.send(WebSocketRequest::StartScreenShare { audio_device })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command(rename_all = "snake_case")]
+pub async fn stop_screen_share(handle: tauri::AppHandle) -> Result<(), String> {
+    let state = handle.state::<AppState>();
+    let ws = state.websocket.read().await;
+    ws.send(WebSocketRequest::StopScreenShare)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/**
+ * Starts or stops receiving the screen share of the user, nobody receives it by default.
+ */
+#[tauri::command(rename_all = "snake_case")]
+pub async fn watch_screen_share(
+    user_id: Uuid,
+    watch: bool,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let state = handle.state::<AppState>();
+    let ws = state.websocket.read().await;
+    ws.send(WebSocketRequest::WatchScreenShare { user_id, watch })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/**
+ * Takes the frames received from the camera, or the screen share if `screen` is set,
+ * of the person in `slot` since the last call.
+ */
+#[tauri::command(rename_all = "snake_case")]
+pub fn take_video_frames(
+    slot: usize,
+    screen: bool,
+    state: tauri::State<'_, AppState>,
+) -> Option<VideoFrames> {
+    let published = if screen {
+        PublishedTrack::Screen
+    } else {
+        PublishedTrack::Camera
+    };
+    let mut video_sinks = state.video_sinks.lock().unwrap();
+    let sink = video_sinks.get_mut(&published.forward_id(slot))?;
+    let frames = sink
+        .consumer
+        .pop_iter()
+        .map(|sample| sample.data.to_vec())
+        .collect();
+    Some(VideoFrames {
+        mime_type: sink.mime_type.clone(),
+        frames,
+    })
+}
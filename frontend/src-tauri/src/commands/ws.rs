@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use front_shared::LoginStatus;
 use tauri::{http, AppHandle};
 use tokio::{
@@ -10,6 +12,10 @@ use crate::{
     Error,
 };
 
+/// Delay before the first reconnection attempt, doubled after every failed attempt
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
 pub fn connect_ws(handle: AppHandle, mut rx: Receiver<WebSocketRequest>) {
     let rt = Builder::new_current_thread().enable_all().build().unwrap();
 
@@ -17,6 +23,7 @@ pub fn connect_ws(handle: AppHandle, mut rx: Receiver<WebSocketRequest>) {
         let local = LocalSet::new();
 
         local.spawn_local(async move {
+            let mut delay = RECONNECT_MIN_DELAY;
             loop {
                 // Check for cookies
                 let handle = handle.clone();
@@ -41,6 +48,18 @@ pub fn connect_ws(handle: AppHandle, mut rx: Receiver<WebSocketRequest>) {
                     _ => {}
                 }
                 tracing::error!("WebSocket handler error: {:?}", result);
+                if rx.is_closed() {
+                    // Logged out or logged in again with a new connection
+                    tracing::info!("WebSocket requests channel closed, not reconnecting.");
+                    return;
+                }
+                // A connection that was established resets the backoff
+                if result.is_ok() {
+                    delay = RECONNECT_MIN_DELAY;
+                }
+                tracing::info!("Reconnecting to WebSocket in {:?}", delay);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
             }
         });
         rt.block_on(local);
@@ -1,5 +1,6 @@
 pub mod audio;
 pub mod commands;
+pub mod screen;
 pub mod utils;
 pub mod websocket;
 
@@ -92,6 +93,11 @@ pub async fn run() {
             set_mic_boost,
             set_speaker_boost,
             set_user_boost,
+            set_user_mute,
+            get_audio_profile,
+            set_audio_profile,
+            get_audio_config,
+            set_audio_setting,
             mute_microphone,
             unmute_microphone,
             deafen_speaker,
@@ -104,11 +110,21 @@ pub async fn run() {
             create_server,
             join_server,
             get_servers,
+            get_permissions,
             pick_file,
             get_channels,
             join_channel,
             disconnect_call,
             get_status,
+            get_connection_info,
+            get_call_quality,
+            set_camera,
+            set_video_subscriptions,
+            take_video_frames,
+            set_video_size,
+            start_screen_share,
+            stop_screen_share,
+            watch_screen_share,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use shared::Layer;
+use vpx_encode::{Config, Encoder, VideoCodecId};
+
+use super::RawFrame;
+use crate::Error;
+
+/// Encodes one simulcast layer of the screen
+pub struct Vp8Encoder {
+    encoder: Encoder,
+    layer: Layer,
+    /// Size of the captured frames
+    frame_width: u32,
+    frame_height: u32,
+    /// Size of the encoded frames
+    width: u32,
+    height: u32,
+    i420: Vec<u8>,
+}
+
+impl Vp8Encoder {
+    pub fn new(frame_width: u32, frame_height: u32, layer: Layer) -> Result<Self, Error> {
+        // libvpx wants even sizes, the last row and column are cut off otherwise
+        let width = (frame_width / layer.scale()).max(2) & !1;
+        let height = (frame_height / layer.scale()).max(2) & !1;
+        let encoder = Encoder::new(Config {
+            width,
+            height,
+            timebase: [1, 1000],
+            bitrate: (layer.bitrate() / 1000) as u32,
+            codec: VideoCodecId::VP8,
+        })
+        .map_err(|e| Error::ScreenCapture(format!("{:?}", e)))?;
+        Ok(Vp8Encoder {
+            encoder,
+            layer,
+            frame_width,
+            frame_height,
+            width,
+            height,
+            i420: Vec::new(),
+        })
+    }
+
+    pub fn layer(&self) -> Layer {
+        self.layer
+    }
+
+    pub fn fits(&self, frame: &RawFrame) -> bool {
+        frame.width == self.frame_width && frame.height == self.frame_height
+    }
+
+    /**
+     * Encodes the frame shown `pts` after the start of the share into VP8 frames.
+     */
+    pub fn encode(&mut self, frame: &RawFrame, pts: Duration) -> Result<Vec<Vec<u8>>, Error> {
+        let scale = self.layer.scale() as usize;
+        bgra_to_i420(frame, scale, self.width, self.height, &mut self.i420);
+        let packets = self
+            .encoder
+            .encode(pts.as_millis() as i64, &self.i420)
+            .map_err(|e| Error::ScreenCapture(format!("{:?}", e)))?;
+        Ok(packets.map(|packet| packet.data.to_vec()).collect())
+    }
+}
+
+/**
+ * Converts to planar YUV 4:2:0 with the BT.601 coefficients, taking the chroma of
+ * the top left pixel of every 2x2 block. Every `scale`th pixel of the frame is kept.
+ */
+fn bgra_to_i420(frame: &RawFrame, scale: usize, width: u32, height: u32, out: &mut Vec<u8>) {
+    let (width, height) = (width as usize, height as usize);
+    let stride = frame.width as usize * 4;
+    out.clear();
+    out.resize(width * height * 3 / 2, 0);
+    let (y_plane, chroma) = out.split_at_mut(width * height);
+    let (u_plane, v_plane) = chroma.split_at_mut(width * height / 4);
+    for row in 0..height {
+        for col in 0..width {
+            let i = row * scale * stride + col * scale * 4;
+            let b = frame.data[i] as i32;
+            let g = frame.data[i + 1] as i32;
+            let r = frame.data[i + 2] as i32;
+            y_plane[row * width + col] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+            if row % 2 == 0 && col % 2 == 0 {
+                let j = (row / 2) * (width / 2) + col / 2;
+                u_plane[j] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+                v_plane[j] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen::{FrameSource, SyntheticSource};
+
+    #[test]
+    fn encodes_synthetic_frames_in_every_layer() {
+        let mut source = SyntheticSource::new(640, 360);
+        let frames: Vec<RawFrame> = (0..5).map(|_| source.capture().unwrap()).collect();
+        for layer in Layer::ALL {
+            let mut vp8 = Vp8Encoder::new(640, 360, layer).unwrap();
+            assert!(vp8.fits(&frames[0]));
+            assert_eq!(
+                (vp8.width, vp8.height),
+                (640 / layer.scale(), 360 / layer.scale())
+            );
+            let mut encoded = Vec::new();
+            for (i, frame) in frames.iter().enumerate() {
+                encoded.extend(
+                    vp8.encode(frame, Duration::from_millis(i as u64 * 66))
+                        .unwrap(),
+                );
+            }
+            assert!(!encoded.is_empty(), "no frames for {:?}", layer);
+            // The first frame is a keyframe, the P bit of the VP8 frame tag is clear
+            assert_eq!(encoded[0][0] & 1, 0, "no keyframe for {:?}", layer);
+        }
+    }
+
+    #[test]
+    fn converts_to_i420() {
+        // White, red, green and blue in BGRA order
+        let frame = RawFrame {
+            width: 2,
+            height: 2,
+            data: vec![
+                255, 255, 255, 255, 0, 0, 255, 255, 0, 255, 0, 255, 255, 0, 0, 255,
+            ],
+        };
+        let mut out = Vec::new();
+        bgra_to_i420(&frame, 1, 2, 2, &mut out);
+        assert_eq!(out.len(), 6);
+        assert_eq!(&out[..4], &[235, 82, 144, 41]);
+        // Chroma of the white top left pixel
+        assert_eq!(&out[4..], &[128, 128]);
+    }
+}
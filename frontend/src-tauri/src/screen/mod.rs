@@ -0,0 +1,198 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use shared::{Layer, LayerSample};
+use tokio::sync::mpsc::Sender;
+
+use crate::Error;
+
+#[cfg(target_os = "linux")]
+mod encoder;
+mod synthetic;
+#[cfg(target_os = "linux")]
+mod x11;
+
+pub use synthetic::SyntheticSource;
+
+/// Frames per second of a screen share
+#[cfg(target_os = "linux")]
+const FRAME_RATE: u32 = 15;
+
+/// Uncompressed frame of the shared screen, 4 bytes per pixel in BGRA order
+pub struct RawFrame {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/**
+ * Where the frames of a screen share come from.
+ */
+pub trait FrameSource: Send {
+    fn capture(&mut self) -> Result<RawFrame, Error>;
+}
+
+/**
+ * Opens the desktop, or generated frames when `SCREEN_CAPTURE_SOURCE` is "synthetic"
+ * so that sharing can be tried on machines without a display.
+ */
+pub fn open_source() -> Result<Box<dyn FrameSource>, Error> {
+    if std::env::var("SCREEN_CAPTURE_SOURCE").is_ok_and(|source| source == "synthetic") {
+        return Ok(Box::new(SyntheticSource::new(1280, 720)));
+    }
+    #[cfg(target_os = "linux")]
+    return Ok(Box::new(x11::X11Source::new()?));
+    #[cfg(not(target_os = "linux"))]
+    Err(Error::NotImplemented)
+}
+
+/**
+ * Captures, encodes and sends the frames of a screen share on its own thread
+ * until it is stopped or dropped. Each frame is encoded once for every layer.
+ */
+pub struct ScreenCapture {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ScreenCapture {
+    #[cfg(target_os = "linux")]
+    pub fn start(
+        source: Box<dyn FrameSource>,
+        layers: &'static [Layer],
+        frames: Sender<LayerSample>,
+    ) -> Result<Self, Error> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::Builder::new()
+            .name("screen-capture".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || run(source, layers, frames, stop)
+            })
+            .map_err(|e| Error::ScreenCapture(e.to_string()))?;
+        Ok(ScreenCapture {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn start(
+        _source: Box<dyn FrameSource>,
+        _layers: &'static [Layer],
+        _frames: Sender<LayerSample>,
+    ) -> Result<Self, Error> {
+        Err(Error::NotImplemented)
+    }
+
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                tracing::error!("Screen capture thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for ScreenCapture {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn run(
+    mut source: Box<dyn FrameSource>,
+    layers: &'static [Layer],
+    frames: Sender<LayerSample>,
+    stop: Arc<AtomicBool>,
+) {
+    use shared::Sample;
+    use std::time::{Duration, Instant};
+
+    let interval = Duration::from_secs(1) / FRAME_RATE;
+    let started = Instant::now();
+    let mut encoders: Vec<encoder::Vp8Encoder> = Vec::new();
+    while !stop.load(Ordering::Acquire) {
+        let frame_start = Instant::now();
+        let frame = match source.capture() {
+            Ok(frame) => frame,
+            Err(e) => {
+                tracing::error!("Failed to capture the screen: {}", e);
+                break;
+            }
+        };
+        // The encoders are created again when the resolution of the screen changes
+        if encoders.first().is_none_or(|vp8| !vp8.fits(&frame)) {
+            let created: Result<Vec<_>, Error> = layers
+                .iter()
+                .map(|layer| encoder::Vp8Encoder::new(frame.width, frame.height, *layer))
+                .collect();
+            encoders = match created {
+                Ok(encoders) => encoders,
+                Err(e) => {
+                    tracing::error!("Failed to create screen encoder: {}", e);
+                    break;
+                }
+            };
+        }
+        let pts = started.elapsed();
+        for vp8 in encoders.iter_mut() {
+            let packets = match vp8.encode(&frame, pts) {
+                Ok(packets) => packets,
+                Err(e) => {
+                    tracing::error!("Failed to encode screen frame: {}", e);
+                    continue;
+                }
+            };
+            for data in packets {
+                let sample = LayerSample {
+                    layer: vp8.layer(),
+                    sample: Sample {
+                        data: data.into(),
+                        duration: interval,
+                        ..Default::default()
+                    },
+                };
+                // The call ended
+                if frames.blocking_send(sample).is_err() {
+                    return;
+                }
+            }
+        }
+        if let Some(rest) = interval.checked_sub(frame_start.elapsed()) {
+            std::thread::sleep(rest);
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn sends_samples_of_every_layer() {
+        let (frames, mut samples) = tokio::sync::mpsc::channel(64);
+        let mut capture = ScreenCapture::start(
+            Box::new(SyntheticSource::new(640, 360)),
+            &Layer::ALL,
+            frames,
+        )
+        .unwrap();
+        let mut layers = HashSet::new();
+        while layers.len() < Layer::ALL.len() {
+            let sample = samples.blocking_recv().expect("capture stopped");
+            assert!(!sample.sample.data.is_empty());
+            assert_eq!(sample.sample.duration, Duration::from_secs(1) / FRAME_RATE);
+            layers.insert(sample.layer);
+        }
+        // Unblocks the capture thread if it waits for room in the channel
+        drop(samples);
+        capture.stop();
+    }
+}
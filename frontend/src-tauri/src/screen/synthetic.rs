@@ -0,0 +1,53 @@
+use super::{FrameSource, RawFrame};
+use crate::Error;
+
+/// Colors of the bars in BGRA order
+const BARS: [[u8; 4]; 8] = [
+    [255, 255, 255, 255],
+    [0, 255, 255, 255],
+    [255, 255, 0, 255],
+    [0, 255, 0, 255],
+    [255, 0, 255, 255],
+    [0, 0, 255, 255],
+    [255, 0, 0, 255],
+    [0, 0, 0, 255],
+];
+
+/**
+ * Color bars moving a bit with every frame, stand in for the desktop
+ * when trying the screen share pipeline without a display.
+ */
+pub struct SyntheticSource {
+    width: u32,
+    height: u32,
+    frame: u32,
+}
+
+impl SyntheticSource {
+    pub fn new(width: u32, height: u32) -> Self {
+        SyntheticSource {
+            width,
+            height,
+            frame: 0,
+        }
+    }
+}
+
+impl FrameSource for SyntheticSource {
+    fn capture(&mut self) -> Result<RawFrame, Error> {
+        let shift = self.frame.wrapping_mul(4);
+        let row: Vec<u8> = (0..self.width)
+            .flat_map(|col| {
+                let col = (col + shift) % self.width;
+                BARS[(col as usize * BARS.len()) / self.width as usize]
+            })
+            .collect();
+        let data = row.repeat(self.height as usize);
+        self.frame = self.frame.wrapping_add(1);
+        Ok(RawFrame {
+            width: self.width,
+            height: self.height,
+            data,
+        })
+    }
+}
@@ -0,0 +1,63 @@
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{ConnectionExt, ImageFormat, Window};
+use x11rb::rust_connection::RustConnection;
+
+use super::{FrameSource, RawFrame};
+use crate::Error;
+
+/**
+ * Grabs the whole root window of the X server, works on Wayland only through XWayland
+ * and then shows just the X applications.
+ */
+pub struct X11Source {
+    connection: RustConnection,
+    root: Window,
+    width: u16,
+    height: u16,
+}
+
+impl X11Source {
+    pub fn new() -> Result<Self, Error> {
+        let (connection, screen) =
+            x11rb::connect(None).map_err(|e| Error::ScreenCapture(e.to_string()))?;
+        let screen = &connection.setup().roots[screen];
+        let (root, width, height) = (screen.root, screen.width_in_pixels, screen.height_in_pixels);
+        Ok(X11Source {
+            connection,
+            root,
+            width,
+            height,
+        })
+    }
+}
+
+impl FrameSource for X11Source {
+    fn capture(&mut self) -> Result<RawFrame, Error> {
+        let image = self
+            .connection
+            .get_image(
+                ImageFormat::Z_PIXMAP,
+                self.root,
+                0,
+                0,
+                self.width,
+                self.height,
+                !0,
+            )
+            .map_err(|e| Error::ScreenCapture(e.to_string()))?
+            .reply()
+            .map_err(|e| Error::ScreenCapture(e.to_string()))?;
+        // Both are sent with 32 bits per pixel in BGRX order
+        if image.depth != 24 && image.depth != 32 {
+            return Err(Error::ScreenCapture(format!(
+                "Unsupported screen depth {}",
+                image.depth
+            )));
+        }
+        Ok(RawFrame {
+            width: self.width as u32,
+            height: self.height as u32,
+            data: image.data,
+        })
+    }
+}
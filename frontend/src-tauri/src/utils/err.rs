@@ -42,6 +42,14 @@ pub enum Error {
     TokioChannelError(#[from] tokio::sync::mpsc::error::SendError<WebSocketMessage>),
     #[error("Diesel error: {0}")]
     DieselError(#[from] diesel::result::Error),
+    #[error("HTTP error: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("Request error: {0}")]
+    Rpc(#[from] shared::RpcError),
+    #[error("Unexpected response to a websocket request")]
+    UnexpectedResponse,
+    #[error("Screen capture error: {0}")]
+    ScreenCapture(String),
 }
 
 pub async fn handle_auth_error(
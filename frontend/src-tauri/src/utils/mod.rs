@@ -1,18 +1,24 @@
 mod err;
 mod update;
 
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Duration;
 
 use diesel::prelude::*;
 pub use err::*;
 use front_shared::Status;
 use reqwest::{cookie::Jar, Client};
+use shared::models::{ChannelWithUsers, ConnectionInfo, ConnectionQuality};
+use shared::{Feature, LayerSample, VideoSinks};
 use tauri::{AppHandle, Emitter, Manager};
 use tokio::sync::{mpsc::Sender, RwLock};
 use std::sync::Mutex as StdMutex;
+use uuid::Uuid;
 pub use update::*;
 
-use crate::websocket::WebSocketRequest;
+use crate::screen::ScreenCapture;
+use crate::websocket::{SessionState, WebSocketRequest};
 use front_shared::models::last_used_devices::LastUsedAudioDevicesWString;
 
 pub struct AppState {
@@ -22,6 +28,33 @@ pub struct AppState {
     pub cookie_store: Arc<Jar>,
     pub conn_status: Arc<StdMutex<Status>>,
     pub last_used_audio_devices: StdMutex<Option<LastUsedAudioDevicesWString>>,
+    /// Server whose channels are shown, resubscribed to after reconnecting
+    pub active_server: StdMutex<Option<Uuid>>,
+    /// Voice channel the user is in, joined again after reconnecting
+    pub voice_channel: StdMutex<Option<ChannelWithUsers>>,
+    /// Websocket session to resume after reconnecting
+    pub session: StdMutex<SessionState>,
+    /// Round trip time of the last websocket heartbeat
+    pub websocket_latency: StdMutex<Option<Duration>>,
+    /// Last quality sample of the connection of the current call
+    pub call_quality: StdMutex<Option<ConnectionQuality>>,
+    /// Protocol features negotiated with the server
+    pub server_features: StdMutex<HashSet<Feature>>,
+    /// Whether the camera is shared in calls, sent again after joining
+    pub camera: StdMutex<bool>,
+    /// Whether the microphone is muted and the speakers are deafened, sent again after joining
+    pub muted: StdMutex<bool>,
+    pub deafened: StdMutex<bool>,
+    /// Encoded frames of the camera for the current call
+    pub camera_frames: StdMutex<Option<Sender<LayerSample>>>,
+    /// Users whose camera is received in calls, sent again after joining
+    pub video_subscriptions: StdMutex<Vec<Uuid>>,
+    /// Frames of the received cameras and screen shares, keyed by the id of the remote track
+    pub video_sinks: VideoSinks,
+    /// Encoded frames of the screen share for the current call
+    pub screen_frames: StdMutex<Option<Sender<LayerSample>>>,
+    /// Running screen share, stopped with the call
+    pub screen_capture: StdMutex<Option<ScreenCapture>>,
 }
 
 impl AppState {
@@ -38,9 +71,70 @@ impl AppState {
             cookie_store,
             conn_status: Arc::new(StdMutex::new(Status::Offline)),
             last_used_audio_devices: StdMutex::new(None),
+            active_server: StdMutex::new(None),
+            voice_channel: StdMutex::new(None),
+            session: StdMutex::new(SessionState::default()),
+            websocket_latency: StdMutex::new(None),
+            call_quality: StdMutex::new(None),
+            server_features: StdMutex::new(Feature::legacy()),
+            camera: StdMutex::new(false),
+            muted: StdMutex::new(false),
+            deafened: StdMutex::new(false),
+            camera_frames: StdMutex::new(None),
+            video_subscriptions: StdMutex::new(Vec::new()),
+            video_sinks: VideoSinks::default(),
+            screen_frames: StdMutex::new(None),
+            screen_capture: StdMutex::new(None),
         }
     }
 
+    /**
+     * Drops the senders of the camera and the screen share of a call that ended,
+     * which stops the screen capture as well.
+     */
+    pub fn clear_call_media(&self) {
+        *self.camera_frames.lock().unwrap() = None;
+        *self.screen_frames.lock().unwrap() = None;
+        let screen_capture = self.screen_capture.lock().unwrap().take();
+        drop(screen_capture);
+        *self.call_quality.lock().unwrap() = None;
+    }
+
+    /**
+     * Stores the measured websocket latency and sends it to the UI in milliseconds.
+     */
+    pub fn set_websocket_latency(&self, latency: Option<Duration>, handle: &AppHandle) {
+        *self.websocket_latency.lock().unwrap() = latency;
+        let _ = handle.emit(
+            "websocket-latency",
+            latency.map(|latency| latency.as_millis() as u64),
+        );
+        let _ = handle.emit("connection-info", self.connection_info());
+    }
+
+    /**
+     * Stores the latest quality sample of the call and sends the connection info to the UI.
+     */
+    pub fn set_call_quality(&self, quality: Option<ConnectionQuality>, handle: &AppHandle) {
+        *self.call_quality.lock().unwrap() = quality;
+        let _ = handle.emit("connection-info", self.connection_info());
+    }
+
+    pub fn connection_info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            websocket_latency_ms: self
+                .websocket_latency
+                .lock()
+                .unwrap()
+                .map(|latency| latency.as_millis() as u64),
+            call: self.call_quality.lock().unwrap().clone(),
+        }
+    }
+
+    pub fn server_supports(&self, feature: Feature) -> bool {
+        self.server_features.lock().unwrap().contains(&feature)
+    }
+
     pub fn change_status(&self, status: Status, handle: &AppHandle) {
         {
             let mut conn_status = self.conn_status.lock().unwrap();
@@ -1,22 +1,131 @@
-use std::sync::atomic::Ordering;
+pub mod rpc;
+
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
 
 use front_shared::models::audio_config::AudioConfigDB;
-use front_shared::{CallStatus, Status, URL};
+use front_shared::{CallStatus, ChannelsSnapshot, Status, URL};
 use futures_util::{SinkExt, StreamExt};
 use native_tls::TlsConnector;
 use reqwest::cookie::CookieStore;
 use reqwest::header;
-use ringbuf::HeapProd;
 use shared::models::{ChannelWithUsers, TurnCreds};
-use shared::{HeapCons, RTCPeerConnectionState, WebRTCConnection, WebSocketMessage, ROOM_SIZE};
+use shared::{
+    AudioProfile, AudioSinks, Encoding, Feature, Frame, HeapCons, Layer, OpusTuning,
+    RTCPeerConnectionState, RpcError,
+    RpcRequest, SequencedMessage, WebRTCConnection, WebSocketError, WebSocketMessage,
+    PROTOCOL_VERSION,
+};
 use tauri::{AppHandle, Emitter, Manager};
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
 use tokio::{select, sync::mpsc::Receiver};
 use tokio_tungstenite::{
     connect_async_tls_with_config, tungstenite::client::IntoClientRequest,
-    tungstenite::Message::Text, Connector,
+    tungstenite::Message, tungstenite::Message::Binary, tungstenite::Message::Text, Connector,
 };
+use uuid::Uuid;
+
+/// Capacity of the ring buffer holding the decoded audio of each remote person
+const SPEAKER_BUFFER_SIZE: usize = 12000;
+/// Number of encoded frames of each remote camera waiting for the UI
+const VIDEO_BUFFER_SIZE: usize = 30;
+/// Number of encoded camera frames waiting to be sent
+const CAMERA_BUFFER_SIZE: usize = 30;
+/// Number of encoded screen frames waiting to be sent
+const SCREEN_BUFFER_SIZE: usize = 30;
+/// How long a disconnected peer gets to recover on its own before ICE is restarted
+const ICE_DISCONNECTED_GRACE: Duration = Duration::from_secs(2);
+/// How long an ICE restart gets before the channel is joined again from scratch
+const ICE_RESTART_TIMEOUT: Duration = Duration::from_secs(10);
+/// Heartbeat interval until the server tells us its own
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// Number of unanswered heartbeats after which the connection is considered dead
+const MISSED_HEARTBEATS: u32 = 3;
+/// How often the quality of the call is sampled for the connection info panel
+const CALL_QUALITY_INTERVAL: Duration = Duration::from_secs(2);
+/// How often the audio devices are checked for being unplugged or plugged back in
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// How often the level of the microphone is sent to the meter in the settings
+const INPUT_LEVEL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Position of the client in the event stream of a server session
+#[derive(Debug, Clone, Copy)]
+pub struct ResumeToken {
+    pub session_id: Uuid,
+    pub last_seq: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct SessionState {
+    pub current: Option<ResumeToken>,
+    /// Session the client asked to resume after reconnecting
+    pub resuming: Option<ResumeToken>,
+    /// The server acknowledged the handshake of this connection
+    pub acknowledged: bool,
+    /// A new session started before the handshake was acknowledged, it is synced once it is
+    pub sync_pending: bool,
+}
+
+/**
+ * Keeps the websocket alive and measures its round trip time.
+ * A connection whose heartbeats stay unanswered is dropped, so that it gets reconnected.
+ */
+struct Heartbeat {
+    interval: tokio::time::Interval,
+    period: Duration,
+    next_nonce: u64,
+    /// Nonce and send time of the heartbeat waiting for an answer
+    pending: Option<(u64, Instant)>,
+    last_ack: Instant,
+}
+
+impl Heartbeat {
+    fn new(period: Duration) -> Self {
+        Heartbeat {
+            interval: tokio::time::interval(period),
+            period,
+            next_nonce: 0,
+            pending: None,
+            last_ack: Instant::now(),
+        }
+    }
+
+    fn set_period(&mut self, period: Duration) {
+        if period.is_zero() || period == self.period {
+            return;
+        }
+        self.interval = tokio::time::interval(period);
+        self.period = period;
+    }
+
+    fn is_dead(&self) -> bool {
+        self.last_ack.elapsed() > self.period * MISSED_HEARTBEATS
+    }
+
+    fn next(&mut self) -> WebSocketMessage {
+        self.next_nonce += 1;
+        self.pending = Some((self.next_nonce, Instant::now()));
+        WebSocketMessage::Heartbeat {
+            nonce: self.next_nonce,
+        }
+    }
+
+    /**
+     * Returns the round trip time if the ack answers the last heartbeat.
+     */
+    fn ack(&mut self, nonce: u64) -> Option<Duration> {
+        self.last_ack = Instant::now();
+        match self.pending {
+            Some((pending, sent)) if pending == nonce => {
+                self.pending = None;
+                Some(sent.elapsed())
+            }
+            _ => None,
+        }
+    }
+}
 
 pub enum WebSocketRequest {
     JoinAudioChannel {
@@ -25,9 +134,38 @@ pub enum WebSocketRequest {
     DisconnectFromAudioChannel,
     Disconnect,
     AudioCommand(AudioCommand),
+    SetCamera {
+        enabled: bool,
+    },
+    SetVideoSubscriptions {
+        user_ids: Vec<Uuid>,
+    },
+    /// Shares the screen, with the audio recorded from `audio_device` if set
+    StartScreenShare {
+        audio_device: Option<String>,
+    },
+    StopScreenShare,
+    WatchScreenShare {
+        user_id: Uuid,
+        watch: bool,
+    },
+    /// Height the video of the user is shown at, picks the layer the server sends
+    SetVideoSize {
+        user_id: Uuid,
+        screen: bool,
+        height: u32,
+    },
+    /// Request over the websocket, see `rpc::request`
+    Rpc {
+        request: RpcRequest,
+        reply: oneshot::Sender<RpcResult>,
+    },
 }
 
-use crate::audio::{AudioCommand, AudioElement};
+use crate::audio::{AudioCommand, AudioElement, GlobalKeys};
+use crate::commands::fetch_channels;
+use crate::screen::{self, ScreenCapture};
+use crate::websocket::rpc::{PendingRequests, RpcResult};
 use crate::utils::establish_connection;
 use crate::{utils::AppState, Error};
 use front_shared::models::user_boost::PerUserBoost;
@@ -41,7 +179,8 @@ pub async fn websocket_handler(
     let (ws_tx, mut ws_rx) = tokio::sync::mpsc::channel(100);
     let url = format!("wss://{}/websocket", URL);
     let mut web_rtc_connection: Option<WebRTCConnection> = None;
-    let mut audio: Option<AudioElement> = Some(AudioElement::new(handle.clone()));
+    let mut audio: Option<AudioElement> =
+        Some(AudioElement::new(handle.clone(), Arc::new(GlobalKeys)));
 
     let mut request = url.into_client_request()?;
     let cookie_store = state.cookie_store.clone();
@@ -60,25 +199,122 @@ pub async fn websocket_handler(
     let (mut ws_stream, _) =
         connect_async_tls_with_config(request, None, false, Some(connector)).await?;
     state.change_status(Status::Online, &handle);
+    // Until the server acknowledges the handshake it may be from before it
+    *state.server_features.lock().unwrap() = Feature::legacy();
+    ws_tx
+        .send(WebSocketMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            features: Feature::supported(),
+        })
+        .await?;
+    let resuming = {
+        let mut session = state.session.lock().unwrap();
+        session.resuming = session.current.take();
+        session.acknowledged = false;
+        session.sync_pending = false;
+        session.resuming
+    };
+    // The server answers with Resumed or FullRefresh, a new session is synced once it started
+    if let Some(token) = resuming {
+        ws_tx
+            .send(WebSocketMessage::Resume {
+                session_id: token.session_id,
+                last_seq: token.last_seq,
+            })
+            .await?;
+    }
+    let mut heartbeat = Heartbeat::new(DEFAULT_HEARTBEAT_INTERVAL);
+    let mut pending_requests = PendingRequests::default();
+    let mut call_quality = tokio::time::interval(CALL_QUALITY_INTERVAL);
+    let mut device_check = tokio::time::interval(DEVICE_CHECK_INTERVAL);
+    let mut input_level = tokio::time::interval(INPUT_LEVEL_INTERVAL);
     loop {
         select! {
+            _ = device_check.tick() => {
+                if let Some(audio_element) = audio.as_mut() {
+                    audio_element.check_devices(&state, &handle);
+                }
+            },
+            _ = input_level.tick() => {
+                if let Some(audio_element) = audio.as_ref() {
+                    audio_element.emit_input_level(&handle);
+                }
+            },
+            _ = call_quality.tick() => {
+                let Some(connection) = web_rtc_connection.as_ref() else {
+                    continue;
+                };
+                let quality = connection.quality().await;
+                state.set_call_quality(Some(quality), &handle);
+            },
+            _ = heartbeat.interval.tick() => {
+                if !state.server_supports(Feature::Heartbeat) {
+                    continue;
+                }
+                if heartbeat.is_dead() {
+                    tracing::warn!("Server stopped answering heartbeats, reconnecting");
+                    break;
+                }
+                let Some(msg) = encode_frame(&heartbeat.next(), &state) else {
+                    continue;
+                };
+                if let Err(e) = ws_stream.send(msg).await {
+                    tracing::error!("Failed to send heartbeat over WebSocket: {}", e);
+                    break;
+                }
+            },
             msg = ws_stream.next() => {
                 match msg {
                     Some(Ok(message)) => {
-                        let message = match message {
-                            Text(text) => {
-                                match serde_json::from_str::<WebSocketMessage>(&text) {
-                                    Ok(msg) => msg,
-                                    Err(e) => {
-                                        tracing::error!("Failed to parse WebSocket message: {}", e);
+                        let decoded = match message {
+                            Text(text) => SequencedMessage::from_json(&text).map_err(shared::Error::from),
+                            Binary(bytes) => SequencedMessage::from_msgpack(&bytes),
+                            _ => {
+                                tracing::warn!("Received unsupported message type over WebSocket");
+                                continue;
+                            }
+                        };
+                        let message = match decoded {
+                            Ok((seq, msg)) => {
+                                if seq > 0 {
+                                    let mut session = state.session.lock().unwrap();
+                                    // Replayed events belong to the session being resumed
+                                    let session = &mut *session;
+                                    if let Some(token) = session.resuming.as_mut().or(session.current.as_mut()) {
+                                        token.last_seq = seq;
+                                    }
+                                }
+                                match msg {
+                                    Some(msg) => msg,
+                                    None => {
+                                        tracing::debug!("Skipping WebSocket message of a newer protocol version");
                                         continue;
                                     }
                                 }
                             },
-                            _ => {
-                                tracing::warn!("Received non-text message over WebSocket");
+                            Err(e) => {
+                                tracing::error!("Failed to parse WebSocket message: {}", e);
+                                continue;
+                            }
+                        };
+                        match &message {
+                            WebSocketMessage::SessionStarted { heartbeat_interval_ms, .. } => {
+                                heartbeat.set_period(Duration::from_millis(*heartbeat_interval_ms));
+                            }
+                            WebSocketMessage::HeartbeatAck { nonce } => {
+                                if let Some(latency) = heartbeat.ack(*nonce) {
+                                    state.set_websocket_latency(Some(latency), &handle);
+                                }
+                                continue;
+                            }
+                            _ => {}
+                        }
+                        let message = match message {
+                            WebSocketMessage::Response { id, result } => {
+                                pending_requests.finish(id, result);
                                 continue;
                             }
+                            message => message,
                         };
                         if let Err(e) = handle_websocket_message(message, &mut web_rtc_connection, &mut audio, ws_tx.clone(), handle.clone()).await {
                             tracing::error!("Failed to handle WebSocket message: {}", e);
@@ -97,9 +333,9 @@ pub async fn websocket_handler(
             },
             msg = ws_rx.recv() => {
                 let msg = match msg {
-                    Some(message) => {
-                        let msg = serde_json::to_string(&message).unwrap();
-                        Text(msg.into())
+                    Some(message) => match encode_frame(&message, &state) {
+                        Some(msg) => msg,
+                        None => continue,
                     },
                     None => {
                         tracing::error!("WebSocket internal message channel closed");
@@ -113,6 +349,9 @@ pub async fn websocket_handler(
             },
             msg = cmd_rx.recv() => {
                 let msg = match msg {
+                    Some(WebSocketRequest::Rpc { request, reply }) => {
+                        ws_tx.send(pending_requests.start(request, reply)).await.map_err(Error::from)
+                    },
                     Some(message) => {
                         handle_internal_request(message, &mut web_rtc_connection, &mut audio, ws_tx.clone(), handle.clone()).await
                     },
@@ -128,10 +367,183 @@ pub async fn websocket_handler(
             }
         }
     }
+    // The server drops the call with the websocket, it is joined again after reconnecting
+    if let Some(web_rtc_connection) = web_rtc_connection.take() {
+        web_rtc_connection.close().await;
+    }
+    if let Some(mut audio_element) = audio.take() {
+        audio_element.clear_channel();
+        audio_element.quit()?;
+    }
+    state.clear_call_media();
+    state.set_websocket_latency(None, &handle);
     state.change_status(Status::Offline, &handle);
     Ok(())
 }
 
+/// Emits `event` to the frontend, logging why it failed
+fn emit_event<S: serde::Serialize + Clone>(handle: &AppHandle, event: &str, payload: S) {
+    if let Err(e) = handle.emit(event, payload) {
+        tracing::error!("Failed to emit {}: {}", event, e);
+    }
+}
+
+/**
+ * Writes the message in the encoding negotiated with the server.
+ */
+fn encode_frame(message: &WebSocketMessage, state: &AppState) -> Option<Message> {
+    let encoding = Encoding::negotiated(&state.server_features.lock().unwrap());
+    match encoding.encode(message) {
+        Ok(Frame::Text(text)) => Some(Text(text.into())),
+        Ok(Frame::Binary(bytes)) => Some(Binary(bytes.into())),
+        Err(e) => {
+            tracing::error!("Failed to encode WebSocket message: {}", e);
+            None
+        }
+    }
+}
+
+/**
+ * Tears down the call after the server ended it, so that it is not joined again after reconnecting.
+ */
+async fn end_call(
+    web_rtc_connection: &mut Option<WebRTCConnection>,
+    audio: &mut Option<AudioElement>,
+    handle: &AppHandle,
+) -> Result<(), Error> {
+    let state = handle.state::<AppState>();
+    *state.voice_channel.lock().unwrap() = None;
+    state.clear_call_media();
+    if let Some(web_rtc_connection) = web_rtc_connection.take() {
+        web_rtc_connection.close().await;
+    }
+    if let Some(mut audio_element) = audio.take() {
+        audio_element.clear_channel();
+        audio_element.quit()?;
+    }
+    state.change_status(Status::Online, handle);
+    Ok(())
+}
+
+/**
+ * Brings a new websocket connection back to where the previous one was:
+ * subscribes to the active server again, sends the UI a fresh snapshot of
+ * its channels and joins the voice channel the user was in.
+ *
+ * A resumed session is still subscribed and replays the missed events itself,
+ * so only the voice channel is joined again unless `full` is set.
+ */
+async fn resync(
+    full: bool,
+    web_rtc_connection: &mut Option<WebRTCConnection>,
+    audio: &mut Option<AudioElement>,
+    socket: Sender<WebSocketMessage>,
+    handle: AppHandle,
+) {
+    let state = handle.state::<AppState>();
+    let active_server = *state.active_server.lock().unwrap();
+    let voice_channel = state.voice_channel.lock().unwrap().clone();
+    let mut channels = None;
+    if let Some(server_id) = active_server.filter(|_| full) {
+        // Listing the channels also subscribes to the events of the server
+        match fetch_channels(server_id, &handle).await {
+            Ok(fetched) => {
+                let snapshot = ChannelsSnapshot {
+                    server_id,
+                    channels: fetched.clone(),
+                };
+                emit_event(&handle, "channels-snapshot", snapshot);
+                channels = Some(fetched);
+            }
+            Err(e) => tracing::error!("Failed to fetch channels after reconnecting: {}", e),
+        }
+    }
+    if let Some(voice_channel) = voice_channel {
+        // Prefer the fresh list of people in the channel
+        let channel_with_users = channels
+            .and_then(|channels| {
+                channels
+                    .into_iter()
+                    .find(|channel| channel.channel.id == voice_channel.channel.id)
+            })
+            .unwrap_or(voice_channel);
+        tracing::info!(
+            "Joining audio channel {} again after reconnecting",
+            channel_with_users.channel.id
+        );
+        let request = WebSocketRequest::JoinAudioChannel { channel_with_users };
+        if let Err(e) =
+            handle_internal_request(request, web_rtc_connection, audio, socket, handle.clone())
+                .await
+        {
+            tracing::error!("Failed to join audio channel again: {}", e);
+        }
+    }
+}
+
+/**
+ * Recovers a call whose peer connection dropped, for example after a network change.
+ * The server is asked for an ICE restart offer, which keeps the slot in the voice room,
+ * and the channel is joined again from scratch only if that does not help.
+ */
+struct IceRestart {
+    socket: Sender<WebSocketMessage>,
+    peer_state: Arc<StdMutex<RTCPeerConnectionState>>,
+    restarting: Arc<AtomicBool>,
+    channel_with_users: ChannelWithUsers,
+    handle: AppHandle,
+}
+
+impl IceRestart {
+    async fn run(self) {
+        if self.restarting.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        tracing::info!("Restarting ICE");
+        if let Err(e) = self.socket.send(WebSocketMessage::RestartIce).await {
+            tracing::error!("Failed to request ICE restart: {}", e);
+        }
+        tokio::time::sleep(ICE_RESTART_TIMEOUT).await;
+        self.restarting.store(false, Ordering::Release);
+        let state = *self.peer_state.lock().unwrap();
+        if matches!(
+            state,
+            RTCPeerConnectionState::Connected | RTCPeerConnectionState::Closed
+        ) {
+            return;
+        }
+        tracing::warn!(
+            "ICE restart failed, joining audio channel {} again",
+            self.channel_with_users.channel.id
+        );
+        let state = self.handle.state::<AppState>();
+        let websocket = state.websocket.read().await;
+        if let Err(e) = websocket
+            .send(WebSocketRequest::JoinAudioChannel {
+                channel_with_users: self.channel_with_users,
+            })
+            .await
+        {
+            tracing::error!("Failed to join audio channel again: {}", e);
+        }
+    }
+}
+
+/**
+ * Tells the server whether the client is muted and deafened, if it knows about it.
+ */
+async fn send_voice_state(state: &AppState, socket: &Sender<WebSocketMessage>) -> Result<(), Error> {
+    if !state.server_supports(Feature::VoiceState) {
+        return Ok(());
+    }
+    let muted = *state.muted.lock().unwrap();
+    let deafened = *state.deafened.lock().unwrap();
+    socket
+        .send(WebSocketMessage::SetVoiceState { muted, deafened })
+        .await?;
+    Ok(())
+}
+
 pub async fn handle_internal_request(
     request: WebSocketRequest,
     web_rtc_connection: &mut Option<WebRTCConnection>,
@@ -142,6 +554,7 @@ pub async fn handle_internal_request(
     let state = handle.state::<AppState>();
     match request {
         WebSocketRequest::JoinAudioChannel { channel_with_users } => {
+            *state.voice_channel.lock().unwrap() = Some(channel_with_users.clone());
             let channel_id = channel_with_users.channel.id;
             let server_id = channel_with_users.channel.server_id;
             let channel_name = channel_with_users.channel.name.clone();
@@ -160,26 +573,63 @@ pub async fn handle_internal_request(
             };
             *web_rtc_connection = Some(WebRTCConnection::new(channel_id, turn_creds).await?);
             let web_rtc_connection = web_rtc_connection.as_ref().unwrap();
-            *audio = Some(AudioElement::new(handle.clone()));
+            *audio = Some(AudioElement::new(handle.clone(), Arc::new(GlobalKeys)));
             let audio_element = audio.as_mut().unwrap();
             audio_element.set_channel(&channel_with_users, handle.clone());
+            let (muted, deafened) = (*state.muted.lock().unwrap(), *state.deafened.lock().unwrap());
+            audio_element.set_muted(muted);
+            audio_element.set_deafened(deafened);
 
             // Start the audio element streams
             let mic_consumer: Arc<StdMutex<HeapCons<f32>>> = audio_element.start_mic()?;
-            let speaker_producers: Vec<Arc<StdMutex<HeapProd<f32>>>> =
-                audio_element.start_speaker()?;
+            let speaker_sinks: AudioSinks = audio_element.start_speaker()?;
 
-            // Create the WebRTC streams
-            let audio_track = web_rtc_connection
-                .create_audio_track_sample(ROOM_SIZE)
-                .await?;
-            let audio_track = audio_track[0].clone();
+            // Create the WebRTC streams, remote tracks are added by the server as people join
+            let (audio_track, audio_sender) = web_rtc_connection.create_audio_track().await?;
             web_rtc_connection
-                .background_stream_audio(mic_consumer, audio_track)
+                .background_stream_audio(
+                    mic_consumer,
+                    audio_track,
+                    audio_sender,
+                    audio_element.mic_tuning.clone(),
+                )
                 .await?;
             web_rtc_connection
-                .background_receive_audio(speaker_producers)
+                .background_receive_media(
+                    speaker_sinks,
+                    state.video_sinks.clone(),
+                    SPEAKER_BUFFER_SIZE,
+                    VIDEO_BUFFER_SIZE,
+                )
                 .await?;
+            // The camera track is always negotiated, frames are only sent while it is on
+            let video = state.server_supports(Feature::Video);
+            let simulcast = state.server_supports(Feature::Simulcast);
+            if video {
+                let (video_tracks, _) =
+                    web_rtc_connection.create_video_track_sample(simulcast).await?;
+                let (frames_tx, frames_rx) = tokio::sync::mpsc::channel(CAMERA_BUFFER_SIZE);
+                web_rtc_connection.background_stream_video(frames_rx, video_tracks);
+                *state.camera_frames.lock().unwrap() = Some(frames_tx);
+            }
+            // Same for the screen share, negotiated after the camera like on the server
+            if video && state.server_supports(Feature::ScreenShare) {
+                let (screen_tracks, _) =
+                    web_rtc_connection.create_screen_track_sample(simulcast).await?;
+                let (app_audio_track, app_audio_sender) =
+                    web_rtc_connection.create_screen_audio_track().await?;
+                let (frames_tx, frames_rx) = tokio::sync::mpsc::channel(SCREEN_BUFFER_SIZE);
+                web_rtc_connection.background_stream_video(frames_rx, screen_tracks);
+                web_rtc_connection
+                    .background_stream_audio(
+                        audio_element.start_app_audio(),
+                        app_audio_track,
+                        app_audio_sender,
+                        OpusTuning::new(AudioProfile::Music),
+                    )
+                    .await?;
+                *state.screen_frames.lock().unwrap() = Some(frames_tx);
+            }
 
             // Create WebRTC handlers
             web_rtc_connection
@@ -190,10 +640,21 @@ pub async fn handle_internal_request(
                 }));
             let handle_clone = handle.clone();
             let channel_name_clone = channel_name.clone();
+            let socket_clone = socket.clone();
+            let peer_state = Arc::new(StdMutex::new(RTCPeerConnectionState::New));
+            let ice_restarting = Arc::new(AtomicBool::new(false));
             web_rtc_connection
                 .peer_connection
                 .on_peer_connection_state_change(Box::new(move |state| {
                     tracing::debug!("Peer connection state: {:?}", state);
+                    *peer_state.lock().unwrap() = state;
+                    let restart = IceRestart {
+                        socket: socket_clone.clone(),
+                        peer_state: peer_state.clone(),
+                        restarting: ice_restarting.clone(),
+                        channel_with_users: channel_with_users.clone(),
+                        handle: handle_clone.clone(),
+                    };
                     let appstate = handle_clone.state::<AppState>();
                     match state {
                         RTCPeerConnectionState::Connecting => {
@@ -216,12 +677,21 @@ pub async fn handle_internal_request(
                                 ),
                                 &handle_clone,
                             );
+                            tokio::spawn(async move {
+                                tokio::time::sleep(ICE_DISCONNECTED_GRACE).await;
+                                if *restart.peer_state.lock().unwrap()
+                                    == RTCPeerConnectionState::Disconnected
+                                {
+                                    restart.run().await;
+                                }
+                            });
                         }
                         RTCPeerConnectionState::Failed => {
                             appstate.change_status(
                                 Status::OnCall(channel_name_clone.clone(), CallStatus::Failed),
                                 &handle_clone,
                             );
+                            tokio::spawn(restart.run());
                         }
                         RTCPeerConnectionState::Closed => {
                             appstate.change_status(
@@ -263,8 +733,102 @@ pub async fn handle_internal_request(
             if let Err(e) = socket.send(join_message).await {
                 tracing::error!("Failed to send join audio channel message: {}", e);
             }
+            // The server forgets these when the call ends
+            if muted || deafened {
+                send_voice_state(&state, &socket).await?;
+            }
+            if video {
+                let enabled = *state.camera.lock().unwrap();
+                let user_ids = state.video_subscriptions.lock().unwrap().clone();
+                if enabled {
+                    socket.send(WebSocketMessage::SetCamera { enabled }).await?;
+                }
+                if !user_ids.is_empty() {
+                    socket
+                        .send(WebSocketMessage::SetVideoSubscriptions { user_ids })
+                        .await?;
+                }
+            }
+        }
+        WebSocketRequest::SetCamera { enabled } => {
+            *state.camera.lock().unwrap() = enabled;
+            if web_rtc_connection.is_some() && state.server_supports(Feature::Video) {
+                socket.send(WebSocketMessage::SetCamera { enabled }).await?;
+            }
+        }
+        WebSocketRequest::SetVideoSubscriptions { user_ids } => {
+            *state.video_subscriptions.lock().unwrap() = user_ids.clone();
+            if web_rtc_connection.is_some() && state.server_supports(Feature::Video) {
+                socket
+                    .send(WebSocketMessage::SetVideoSubscriptions { user_ids })
+                    .await?;
+            }
+        }
+        WebSocketRequest::StartScreenShare { audio_device } => {
+            let Some(frames) = state.screen_frames.lock().unwrap().clone() else {
+                tracing::warn!("Cannot share the screen outside of a call with screen sharing");
+                return Ok(());
+            };
+            // Replaces a share that is still running
+            let previous = state.screen_capture.lock().unwrap().take();
+            drop(previous);
+            let layers = Layer::published(state.server_supports(Feature::Simulcast));
+            let capture = ScreenCapture::start(screen::open_source()?, layers, frames)?;
+            *state.screen_capture.lock().unwrap() = Some(capture);
+            let with_audio = match (audio_device, audio.as_mut()) {
+                (Some(device_name), Some(audio_element)) => {
+                    match audio_element.capture_app_audio(&device_name) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            tracing::error!("Failed to capture application audio: {}", e);
+                            false
+                        }
+                    }
+                }
+                _ => false,
+            };
+            socket
+                .send(WebSocketMessage::StartScreenShare { audio: with_audio })
+                .await?;
+        }
+        WebSocketRequest::StopScreenShare => {
+            let capture = state.screen_capture.lock().unwrap().take();
+            drop(capture);
+            if let Some(audio_element) = audio.as_mut() {
+                audio_element.stop_app_audio();
+            }
+            if web_rtc_connection.is_some() && state.server_supports(Feature::ScreenShare) {
+                socket.send(WebSocketMessage::StopScreenShare).await?;
+            }
+        }
+        WebSocketRequest::SetVideoSize {
+            user_id,
+            screen,
+            height,
+        } => {
+            if web_rtc_connection.is_some() && state.server_supports(Feature::Simulcast) {
+                socket
+                    .send(WebSocketMessage::SetVideoSize {
+                        user_id,
+                        screen,
+                        height,
+                    })
+                    .await?;
+            }
+        }
+        WebSocketRequest::WatchScreenShare { user_id, watch } => {
+            if web_rtc_connection.is_some() && state.server_supports(Feature::ScreenShare) {
+                let message = if watch {
+                    WebSocketMessage::WatchScreenShare { user_id }
+                } else {
+                    WebSocketMessage::StopWatchingScreenShare { user_id }
+                };
+                socket.send(message).await?;
+            }
         }
         WebSocketRequest::DisconnectFromAudioChannel => {
+            *state.voice_channel.lock().unwrap() = None;
+            state.clear_call_media();
             if let Some(web_rtc_connection) = web_rtc_connection.take() {
                 web_rtc_connection.close().await;
             }
@@ -279,29 +843,17 @@ pub async fn handle_internal_request(
             state.change_status(Status::Online, &handle);
         }
         WebSocketRequest::Disconnect => todo!(),
+        WebSocketRequest::Rpc { reply, .. } => {
+            tracing::warn!("Websocket request outside of the connection loop");
+            let _ = reply.send(Err(RpcError::Disconnected));
+        }
         WebSocketRequest::AudioCommand(command) => {
             if let Some(audio_element) = audio {
                 match &command {
-                    AudioCommand::Mute => {
-                        if let Err(e) = audio_element.mute() {
-                            tracing::error!("Failed to mute audio: {}", e);
-                        }
-                    }
-                    AudioCommand::Unmute => {
-                        if let Err(e) = audio_element.unmute() {
-                            tracing::error!("Failed to unmute audio: {}", e);
-                        }
-                    }
-                    AudioCommand::Deafen => {
-                        if let Err(e) = audio_element.deafen() {
-                            tracing::error!("Failed to deafen audio: {}", e);
-                        }
-                    }
-                    AudioCommand::Undeafen => {
-                        if let Err(e) = audio_element.undeafen() {
-                            tracing::error!("Failed to undeafen audio: {}", e);
-                        }
-                    }
+                    AudioCommand::Mute => audio_element.set_muted(true),
+                    AudioCommand::Unmute => audio_element.set_muted(false),
+                    AudioCommand::Deafen => audio_element.set_deafened(true),
+                    AudioCommand::Undeafen => audio_element.set_deafened(false),
                     AudioCommand::Quit => {
                         if let Err(e) = audio_element.quit() {
                             tracing::error!("Failed to quit audio: {}", e);
@@ -334,6 +886,11 @@ pub async fn handle_internal_request(
                             tracing::error!("Failed to set user boost: {}", e);
                         }
                     }
+                    AudioCommand::SetUserMute { user_id, muted } => {
+                        if let Err(e) = audio_element.set_user_mute(*user_id, *muted, handle.clone()) {
+                            tracing::error!("Failed to set user mute: {}", e);
+                        }
+                    }
                     AudioCommand::ChangeSetting { cfg } => {
                         let mut conn = establish_connection(&handle);
                         if let Err(e) = audio_element.change_audio_config(cfg.clone(), &mut conn) {
@@ -365,17 +922,30 @@ pub async fn handle_internal_request(
                         tracing::error!("Failed to set user boost: {}", e);
                     }
                 }
+                AudioCommand::SetUserMute { user_id, muted } => {
+                    if let Err(e) = AudioElement::set_default_user_mute(*user_id, *muted, handle) {
+                        tracing::error!("Failed to set user mute: {}", e);
+                    }
+                }
                 AudioCommand::ChangeSetting { cfg } => {
                     let mut conn = establish_connection(&handle);
                     AudioConfigDB::get(&mut conn)
                         .apply(cfg.clone())
                         .save(&mut conn)?;
                 }
-                AudioCommand::Mute
-                | AudioCommand::Deafen
-                | AudioCommand::Quit
-                | AudioCommand::Undeafen
-                | AudioCommand::Unmute => {}
+                AudioCommand::Mute | AudioCommand::Unmute => {
+                    *state.muted.lock().unwrap() = matches!(command, AudioCommand::Mute);
+                    if web_rtc_connection.is_some() {
+                        send_voice_state(&state, &socket).await?;
+                    }
+                }
+                AudioCommand::Deafen | AudioCommand::Undeafen => {
+                    *state.deafened.lock().unwrap() = matches!(command, AudioCommand::Deafen);
+                    if web_rtc_connection.is_some() {
+                        send_voice_state(&state, &socket).await?;
+                    }
+                }
+                AudioCommand::Quit => {}
             }
         }
     }
@@ -430,11 +1000,96 @@ pub async fn handle_websocket_message(
         WebSocketMessage::WebRTCAnswer(_) => {
             tracing::warn!("Received WebRTCAnswer message, but this is client");
         }
+        WebSocketMessage::RestartIce => {
+            tracing::warn!("Received RestartIce message, but this is client");
+        }
+        WebSocketMessage::Resume { .. } => {
+            tracing::warn!("Received Resume message, but this is client");
+        }
+        WebSocketMessage::Heartbeat { .. } => {
+            tracing::warn!("Received Heartbeat message, but this is client");
+        }
+        WebSocketMessage::Hello { .. } => {
+            tracing::warn!("Received Hello message, but this is client");
+        }
+        WebSocketMessage::HelloAck {
+            protocol_version,
+            features,
+        } => {
+            tracing::info!(
+                "Server speaks protocol version {} with features {:?}",
+                protocol_version,
+                features
+            );
+            let state = handle.state::<AppState>();
+            *state.server_features.lock().unwrap() = features;
+            let sync_pending = {
+                let mut session = state.session.lock().unwrap();
+                session.acknowledged = true;
+                std::mem::take(&mut session.sync_pending)
+            };
+            // Synced with the features of the server, not the ones of before the handshake
+            if sync_pending {
+                resync(true, web_rtc_connection, audio, tx, handle.clone()).await;
+            }
+        }
+        WebSocketMessage::HeartbeatAck { nonce } => {
+            tracing::warn!("Received HeartbeatAck {} outside of the connection loop", nonce);
+        }
+        WebSocketMessage::SessionStarted { session_id, .. } => {
+            tracing::info!("Started websocket session {}", session_id);
+            let sync = {
+                let state = handle.state::<AppState>();
+                let mut session = state.session.lock().unwrap();
+                session.current = Some(ResumeToken {
+                    session_id,
+                    last_seq: 0,
+                });
+                // Waits for the features of the server if the handshake is still going on
+                let new_session = session.resuming.is_none();
+                session.sync_pending = new_session && !session.acknowledged;
+                new_session && session.acknowledged
+            };
+            if sync {
+                resync(true, web_rtc_connection, audio, tx, handle).await;
+            }
+        }
+        WebSocketMessage::Resumed { session_id } => {
+            tracing::info!("Resumed websocket session {}", session_id);
+            {
+                let state = handle.state::<AppState>();
+                let mut session = state.session.lock().unwrap();
+                session.current = session.resuming.take();
+            }
+            resync(false, web_rtc_connection, audio, tx, handle).await;
+        }
+        WebSocketMessage::FullRefresh => {
+            tracing::info!("Websocket session could not be resumed, fetching everything again");
+            handle.state::<AppState>().session.lock().unwrap().resuming = None;
+            resync(true, web_rtc_connection, audio, tx, handle).await;
+        }
+        WebSocketMessage::Request { id, .. } => {
+            tracing::warn!("Received Request {}, but this is client", id);
+        }
+        WebSocketMessage::Response { id, .. } => {
+            tracing::warn!("Received Response {} outside of the connection loop", id);
+        }
+        WebSocketMessage::VoiceTakenOver { channel_id } => {
+            tracing::info!("Call was taken over by another device in audio channel {}", channel_id);
+            end_call(web_rtc_connection, audio, &handle).await?;
+        }
         WebSocketMessage::Disconnect => {
             tracing::info!("Received Disconnect message, but this is client");
         }
         WebSocketMessage::Error { err } => {
             tracing::error!("WebSocket error: {}", err);
+            if matches!(err, WebSocketError::RoomFull) {
+                // The server did not let us in, tear down the call we prepared
+                end_call(web_rtc_connection, audio, &handle).await?;
+            }
+            if let WebSocketError::UnsupportedProtocol { min_version } = err {
+                emit_event(&handle, "update-required", min_version);
+            }
             return Err(Error::WebSocketError(err));
         }
         WebSocketMessage::SomeoneJoinedAudioChannel { mut data } => {
@@ -450,17 +1105,12 @@ pub async fn handle_websocket_message(
                     tracing::error!("Failed to handle join channel: {}", e);
                 }
             }
-            // Get boost level for the user
+            // Get boost level and mute for the user
             let mut conn = establish_connection(&handle);
-            data.user.boost = Some(
-                PerUserBoost::get(&mut conn, data.user.id)
-                    .boost_level
-                    .load(Ordering::Relaxed),
-            );
-            // Fails only when the event name is invalid
-            if handle.emit("someone-joined-audio-channel", data).is_err() {
-                tracing::error!("Event name 'someone-joined-audio-channel' is invalid");
-            }
+            let user_boost = PerUserBoost::get(&mut conn, data.user.id);
+            data.user.boost = Some(user_boost.boost_level.load(Ordering::Relaxed));
+            data.user.locally_muted = user_boost.muted.load(Ordering::Relaxed);
+            emit_event(&handle, "someone-joined-audio-channel", data);
         }
         WebSocketMessage::SomeoneLeftAudioChannel { data } => {
             tracing::info!(
@@ -475,10 +1125,68 @@ pub async fn handle_websocket_message(
                     tracing::error!("Failed to handle leave channel: {}", e);
                 }
             }
-            // Fails only when the event name is invalid
-            if handle.emit("someone-left-audio-channel", data).is_err() {
-                tracing::error!("Event name 'someone-left-audio-channel' is invalid");
-            }
+            emit_event(&handle, "someone-left-audio-channel", data);
+        }
+        WebSocketMessage::DominantSpeakerChanged { data } => {
+            tracing::debug!(
+                "Dominant speaker of channel {} is now {:?}",
+                data.channel_id,
+                data.user_id
+            );
+            emit_event(&handle, "dominant-speaker-changed", data);
+        }
+        WebSocketMessage::SpeakingStarted { user_id } => {
+            emit_event(&handle, "speaking-started", user_id);
+        }
+        WebSocketMessage::SpeakingStopped { user_id } => {
+            emit_event(&handle, "speaking-stopped", user_id);
+        }
+        WebSocketMessage::CameraChanged { data } => {
+            tracing::info!(
+                "User {} turned their camera {} in audio channel {}",
+                data.user_id,
+                if data.enabled { "on" } else { "off" },
+                data.channel_id
+            );
+            emit_event(&handle, "camera-changed", data);
+        }
+        WebSocketMessage::SetCamera { .. } => {
+            tracing::warn!("Received SetCamera message, but this is client");
+        }
+        WebSocketMessage::VoiceStateChanged { data } => {
+            tracing::info!(
+                "User {} is {}muted and {}deafened in audio channel {}",
+                data.user_id,
+                if data.muted { "" } else { "not " },
+                if data.deafened { "" } else { "not " },
+                data.channel_id
+            );
+            emit_event(&handle, "voice-state-changed", data);
+        }
+        WebSocketMessage::SetVoiceState { .. } => {
+            tracing::warn!("Received SetVoiceState message, but this is client");
+        }
+        WebSocketMessage::SetVideoSubscriptions { .. } => {
+            tracing::warn!("Received SetVideoSubscriptions message, but this is client");
+        }
+        WebSocketMessage::ScreenShareChanged { data } => {
+            tracing::info!(
+                "User {} {} sharing their screen in audio channel {}",
+                data.user_id,
+                if data.sharing { "started" } else { "stopped" },
+                data.channel_id
+            );
+            emit_event(&handle, "screen-share-changed", data);
+        }
+        WebSocketMessage::StartScreenShare { .. } | WebSocketMessage::StopScreenShare => {
+            tracing::warn!("Received screen share message, but this is client");
+        }
+        WebSocketMessage::WatchScreenShare { .. }
+        | WebSocketMessage::StopWatchingScreenShare { .. } => {
+            tracing::warn!("Received screen share subscription message, but this is client");
+        }
+        WebSocketMessage::SetVideoSize { .. } => {
+            tracing::warn!("Received SetVideoSize message, but this is client");
         }
     }
     Ok(())
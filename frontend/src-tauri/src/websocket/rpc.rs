@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use shared::{Feature, RpcError, RpcRequest, RpcResponse, WebSocketMessage};
+use tauri::{AppHandle, Manager};
+use tokio::sync::oneshot;
+
+use crate::utils::AppState;
+use crate::websocket::WebSocketRequest;
+
+/// How long a request waits for its response
+const RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type RpcResult = Result<RpcResponse, RpcError>;
+
+/**
+ * Requests sent over the current websocket that still wait for their response.
+ * Dropping it tells every waiting caller that the websocket disconnected.
+ */
+#[derive(Default)]
+pub struct PendingRequests {
+    next_id: u64,
+    pending: HashMap<u64, oneshot::Sender<RpcResult>>,
+}
+
+impl PendingRequests {
+    /**
+     * Registers the request and returns the message to send for it.
+     */
+    pub fn start(&mut self, request: RpcRequest, reply: oneshot::Sender<RpcResult>) -> WebSocketMessage {
+        self.next_id += 1;
+        // Callers that timed out are not waiting anymore
+        self.pending.retain(|_, reply| !reply.is_closed());
+        self.pending.insert(self.next_id, reply);
+        WebSocketMessage::Request {
+            id: self.next_id,
+            request,
+        }
+    }
+
+    pub fn finish(&mut self, id: u64, result: RpcResult) {
+        match self.pending.remove(&id) {
+            Some(reply) => {
+                let _ = reply.send(result);
+            }
+            None => tracing::warn!("Received response {} for an unknown request", id),
+        }
+    }
+}
+
+/**
+ * Sends a request over the websocket and waits for its response.
+ * Fails with `RpcError::NotSupported` if the server is too old, callers fall back to HTTPS then.
+ */
+pub async fn request(handle: &AppHandle, request: RpcRequest) -> RpcResult {
+    let (reply, response) = oneshot::channel();
+    {
+        let state = handle.state::<AppState>();
+        if !state.server_supports(Feature::Rpc) {
+            return Err(RpcError::NotSupported);
+        }
+        let websocket = state.websocket.read().await;
+        websocket
+            .send(WebSocketRequest::Rpc { request, reply })
+            .await
+            .map_err(|_| RpcError::Disconnected)?;
+    }
+    match tokio::time::timeout(RPC_TIMEOUT, response).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err(RpcError::Disconnected),
+        Err(_) => Err(RpcError::Timeout),
+    }
+}
@@ -3,6 +3,7 @@ mod login;
 mod create_server;
 mod status;
 mod settings;
+mod connection_info;
 
 use leptos::{context, logging::error, prelude::*, task::spawn_local};
 use serde_wasm_bindgen::from_value;
@@ -0,0 +1,115 @@
+use leptos::{logging::log, prelude::*, task::spawn_local};
+use shared::models::{ConnectionInfo, TrackDirection, TrackQuality};
+use wasm_bindgen::JsValue;
+
+use crate::utils::{create_listener, invoke};
+
+stylance::import_style!(
+    #[allow(dead_code)]
+    style,
+    "connection_info.css"
+);
+
+fn format_ms(value: Option<f64>) -> String {
+    value.map_or("-".into(), |value| format!("{:.0} ms", value))
+}
+
+fn format_loss(value: Option<f64>) -> String {
+    value.map_or("-".into(), |value| format!("{:.1} %", value * 100.0))
+}
+
+fn format_bitrate(bitrate: u64) -> String {
+    if bitrate >= 1_000_000 {
+        format!("{:.1} Mbit/s", bitrate as f64 / 1_000_000.0)
+    } else {
+        format!("{} kbit/s", bitrate / 1000)
+    }
+}
+
+fn track_row(track: TrackQuality) -> impl IntoView {
+    let buffer = track.jitter_buffer.map(|buffer| {
+        (
+            format!("{} ms", buffer.target_delay_ms),
+            format!("{} / {}", buffer.underruns, buffer.overruns),
+            format!(
+                "{} ms buffered, {} late, {} recovered by FEC, {} concealed, {} restarts",
+                buffer.buffered_ms, buffer.late, buffer.recovered, buffer.concealed, buffer.resets,
+            ),
+        )
+    });
+    let (delay, runs, details) = buffer.unwrap_or(("-".into(), "-".into(), String::new()));
+    let direction = match track.direction {
+        TrackDirection::Inbound => "in",
+        TrackDirection::Outbound => "out",
+    };
+    view! {
+        <tr>
+            <td>{track.track_id}</td>
+            <td>{format!("{} {}", track.kind, direction)}</td>
+            <td>{format_loss(track.packet_loss)}</td>
+            <td>{format_ms(track.jitter_ms)}</td>
+            <td>{format_ms(track.rtt_ms)}</td>
+            <td>{format_bitrate(track.bitrate)}</td>
+            <td title=details.clone()>{delay}</td>
+            <td title=details>{runs}</td>
+        </tr>
+    }
+}
+
+/**
+ * Latency of the websocket and loss, jitter, round trip time, bitrate and jitter buffer of each track of the call.
+ */
+#[component]
+pub fn ConnectionInfoPanel() -> impl IntoView {
+    let (info, set_info) = signal(ConnectionInfo::default());
+
+    spawn_local(async move {
+        match invoke("get_connection_info", JsValue::NULL).await {
+            Ok(value) => {
+                if let Ok(value) = serde_wasm_bindgen::from_value(value) {
+                    set_info.set(value);
+                }
+            }
+            Err(e) => log!("Failed to fetch connection info: {:?}", e),
+        }
+    });
+    create_listener("connection-info", move |new_info: ConnectionInfo| {
+        set_info.set(new_info);
+    });
+
+    view! {
+        <div class=style::connection_info>
+            <span class=style::title>"Connection"</span>
+            <span>
+                {move || {
+                    format!(
+                        "Server latency: {}",
+                        info.get().websocket_latency_ms.map_or("-".into(), |ms| format!("{} ms", ms)),
+                    )
+                }}
+            </span>
+            {move || {
+                info.get()
+                    .call
+                    .map(|call| {
+                        view! {
+                            <span>{format!("Call round trip: {}", format_ms(call.rtt_ms))}</span>
+                            <table class=style::tracks>
+                                <tr>
+                                    <th>"Track"</th>
+                                    <th>"Kind"</th>
+                                    <th>"Loss"</th>
+                                    <th>"Jitter"</th>
+                                    <th>"RTT"</th>
+                                    <th>"Bitrate"</th>
+                                    <th>"Buffer"</th>
+                                    <th>"Under/overruns"</th>
+                                </tr>
+                                {call.tracks.into_iter().map(track_row).collect_view()}
+                            </table>
+                        }
+                    })
+            }}
+        </div>
+    }
+}
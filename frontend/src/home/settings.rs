@@ -1,9 +1,10 @@
-use front_shared::AudioDevices;
+use front_shared::models::audio_config::{AudioConfigDB, AudioConfigDBPartial};
+use front_shared::{AudioDevices, InputLevel};
 use leptos::{logging::log, prelude::*, task::spawn_local};
 use serde_wasm_bindgen::to_value;
 use wasm_bindgen::JsValue;
 
-use crate::utils::{dropdown::Dropdown, invoke};
+use crate::utils::{create_listener, dropdown::Dropdown, invoke};
 
 stylance::import_style!(
     #[allow(dead_code)]
@@ -21,6 +22,34 @@ struct SetBoostArgs {
     boost: i32,
 }
 
+#[derive(Default, Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct SetAudioProfileArgs {
+    profile: i32,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct SetAudioSettingArgs {
+    cfg: AudioConfigDBPartial,
+}
+
+/// Audio profiles in the order of their ids
+const AUDIO_PROFILES: [&str; 2] = ["Voice", "Music"];
+/// Input modes in the order of their ids
+const INPUT_MODES: [&str; 2] = ["Voice Activity", "Push to Talk"];
+/// Threshold the slider starts at when switching off automatic detection, in dBFS
+const DEFAULT_VAD_THRESHOLD: i32 = -50;
+/// Ducking triggers, off and then in the order of their ids
+const DUCKING_TRIGGERS: [&str; 3] = ["Off", "While I Talk", "While Others Talk"];
+const DEFAULT_DUCKING_DB: i32 = 12;
+
+fn set_audio_setting(cfg: AudioConfigDBPartial) {
+    spawn_local(async move {
+        if let Err(e) = invoke("set_audio_setting", to_value(&SetAudioSettingArgs { cfg }).unwrap()).await {
+            log!("Failed to set audio setting: {:?}", e);
+        }
+    });
+}
+
 #[component]
 pub fn Settings() -> impl IntoView {
     let (devices, set_devices) = signal(None::<AudioDevices>);
@@ -114,6 +143,30 @@ pub fn AudioSettings(devices: AudioDevices) -> impl IntoView {
         .map(|d| d.speaker.clone())
         .flatten()
         .unwrap_or("No Speaker Selected".to_string()));
+    let mics = RwSignal::new(devices.mics.clone());
+    let speakers = RwSignal::new(devices.speakers.clone());
+    // Devices come and go, and the client falls back to the default ones while they are gone
+    create_listener("audio-devices", move |devices: AudioDevices| {
+        let last_used_devices = devices.last_used_devices.unwrap_or_default();
+        if let Some(mic) = last_used_devices.mic {
+            cur_mic.set(mic);
+        }
+        if let Some(speaker) = last_used_devices.speaker {
+            cur_speaker.set(speaker);
+        }
+        mics.set(devices.mics);
+        speakers.set(devices.speakers);
+    });
+    let audio_profile = RwSignal::new(AUDIO_PROFILES[0].to_string());
+    spawn_local(async move {
+        match invoke("get_audio_profile", JsValue::NULL).await {
+            Ok(value) => {
+                let profile: usize = serde_wasm_bindgen::from_value(value).unwrap_or_default();
+                audio_profile.set(AUDIO_PROFILES.get(profile).unwrap_or(&AUDIO_PROFILES[0]).to_string());
+            }
+            Err(e) => log!("Failed to fetch audio profile: {:?}", e),
+        }
+    });
     view! {
         <h3>{"Audio Settings"}</h3>
         <div class=style::audio_settings>
@@ -121,7 +174,7 @@ pub fn AudioSettings(devices: AudioDevices) -> impl IntoView {
                 <p>Input Device:</p>
                 <Dropdown
                     item=move || { cur_mic.get() }
-                    drop_list=move || devices.mics.clone()
+                    drop_list=move || mics.get()
                     callback=move |mic| {
                         spawn_local(async move {
                             if let Err(e) = invoke(
@@ -153,7 +206,7 @@ pub fn AudioSettings(devices: AudioDevices) -> impl IntoView {
                 <p>Output Device:</p>
                 <Dropdown
                     item=move || { cur_speaker.get() }
-                    drop_list=move || devices.speakers.clone()
+                    drop_list=move || speakers.get()
                     callback=move |speaker| {
                         spawn_local(async move {
                             if let Err(e) = invoke(
@@ -181,7 +234,30 @@ pub fn AudioSettings(devices: AudioDevices) -> impl IntoView {
                     on:change=move |_| set_speaker_boost()
                 />
             </div>
+            <div class=style::audio_setting_type>
+                <p>Audio Profile:</p>
+                <Dropdown
+                    item=move || { audio_profile.get() }
+                    drop_list=move || AUDIO_PROFILES.map(String::from).to_vec()
+                    callback=move |profile: String| {
+                        let id = AUDIO_PROFILES.iter().position(|name| *name == profile).unwrap_or(0);
+                        spawn_local(async move {
+                            if let Err(e) = invoke(
+                                    "set_audio_profile",
+                                    to_value(&SetAudioProfileArgs { profile: id as i32 }).unwrap(),
+                                )
+                                .await
+                            {
+                                log!("Failed to set audio profile: {:?}", e);
+                            } else {
+                                audio_profile.set(profile);
+                            }
+                        });
+                    }
+                />
+            </div>
         </div>
+        <InputSettings />
 
         <datalist id="volume_markers">
             <option value="0"></option>
@@ -192,3 +268,179 @@ pub fn AudioSettings(devices: AudioDevices) -> impl IntoView {
         </datalist>
     }
 }
+
+/**
+ * How the microphone decides when to send, with voice activity or while a key is held.
+ */
+#[component]
+pub fn InputSettings() -> impl IntoView {
+    let input_mode = RwSignal::new(INPUT_MODES[0].to_string());
+    let ptt_key = RwSignal::new(String::new());
+    let press_delay = RwSignal::new(String::new());
+    let release_delay = RwSignal::new(String::new());
+    let ptt_sound = RwSignal::new(false);
+    // Waiting for the next key press to become the push-to-talk key
+    let capturing = RwSignal::new(false);
+    let vad_auto = RwSignal::new(true);
+    let vad_threshold = RwSignal::new(DEFAULT_VAD_THRESHOLD.to_string());
+    // Only comes during a call, the microphone is not running otherwise
+    let input_level = RwSignal::new(InputLevel::default());
+    create_listener("input-level", move |level: InputLevel| input_level.set(level));
+    let ducking_trigger = RwSignal::new(DUCKING_TRIGGERS[0].to_string());
+    let ducking_db = RwSignal::new(DEFAULT_DUCKING_DB.to_string());
+    let set_vad_threshold = move || {
+        let threshold = (!vad_auto.get())
+            .then(|| -vad_threshold.get().parse().unwrap_or(DEFAULT_VAD_THRESHOLD));
+        set_audio_setting(AudioConfigDBPartial::VadThreshold(threshold));
+    };
+    spawn_local(async move {
+        match invoke("get_audio_config", JsValue::NULL).await {
+            Ok(value) => {
+                let config: AudioConfigDB =
+                    serde_wasm_bindgen::from_value(value).unwrap_or_default();
+                input_mode.set(
+                    INPUT_MODES
+                        .get(config.input_mode as usize)
+                        .unwrap_or(&INPUT_MODES[0])
+                        .to_string(),
+                );
+                ptt_key.set(config.ptt_key_code.unwrap_or("KeyV".to_string()));
+                press_delay.set(config.ptt_press_delay.to_string());
+                release_delay.set(config.ptt_release_delay.to_string());
+                ptt_sound.set(config.ptt_sound);
+                // Stored as the dB under full scale
+                vad_auto.set(config.vad_threshold.is_none());
+                if let Some(threshold) = config.vad_threshold {
+                    vad_threshold.set((-threshold.abs()).to_string());
+                }
+                let trigger = config.global_attenuation_trigger.map_or(0, |t| t as usize + 1);
+                ducking_trigger.set(DUCKING_TRIGGERS.get(trigger).unwrap_or(&DUCKING_TRIGGERS[0]).to_string());
+                if let Some(db) = config.global_attenuation {
+                    ducking_db.set(db.abs().to_string());
+                }
+            }
+            Err(e) => log!("Failed to fetch audio config: {:?}", e),
+        }
+    });
+    view! {
+        <div class=style::audio_settings>
+            <div class=style::audio_setting_type>
+                <p>Input Mode:</p>
+                <Dropdown
+                    item=move || { input_mode.get() }
+                    drop_list=move || INPUT_MODES.map(String::from).to_vec()
+                    callback=move |mode: String| {
+                        let id = INPUT_MODES.iter().position(|name| *name == mode).unwrap_or(0);
+                        set_audio_setting(AudioConfigDBPartial::InputMode(id as i32));
+                        input_mode.set(mode);
+                    }
+                />
+            </div>
+            <div class=style::audio_setting_type>
+                <p>Lower Other Audio:</p>
+                <Dropdown
+                    item=move || { ducking_trigger.get() }
+                    drop_list=move || DUCKING_TRIGGERS.map(String::from).to_vec()
+                    callback=move |trigger: String| {
+                        let id = DUCKING_TRIGGERS.iter().position(|name| *name == trigger).unwrap_or(0);
+                        let trigger_id = id.checked_sub(1).map(|id| id as i32);
+                        set_audio_setting(AudioConfigDBPartial::GlobalAttenuationTrigger(trigger_id));
+                        // Never set before the first time, the slider shows the default
+                        let db = ducking_db.get().parse().unwrap_or(DEFAULT_DUCKING_DB);
+                        set_audio_setting(AudioConfigDBPartial::GlobalAttenuation(Some(db)));
+                        ducking_trigger.set(trigger);
+                    }
+                />
+                <p>{move || format!("By {} dB", ducking_db.get())}</p>
+                <input
+                    type="range"
+                    min="0"
+                    max="40"
+                    class=style::slider
+                    bind:value=ducking_db
+                    on:change=move |_| {
+                        let db = ducking_db.get().parse().unwrap_or(DEFAULT_DUCKING_DB);
+                        set_audio_setting(AudioConfigDBPartial::GlobalAttenuation(Some(db)));
+                    }
+                />
+            </div>
+            <Show when=move || input_mode.get() == INPUT_MODES[0]>
+                <div class=style::audio_setting_type>
+                    <p>Input Level:</p>
+                    <meter min="-100" max="0" value=move || input_level.get().level_db></meter>
+                    <span>{move || if input_level.get().transmitting { "Sending" } else { "" }}</span>
+                    <label>
+                        <input
+                            type="checkbox"
+                            bind:checked=vad_auto
+                            on:change=move |_| set_vad_threshold()
+                        />
+                        "Detect voice automatically"
+                    </label>
+                    <p>{move || format!("Threshold: {} dB", vad_threshold.get())}</p>
+                    <input
+                        type="range"
+                        min="-100"
+                        max="0"
+                        class=style::slider
+                        disabled=move || vad_auto.get()
+                        bind:value=vad_threshold
+                        on:change=move |_| set_vad_threshold()
+                    />
+                </div>
+            </Show>
+            <Show when=move || input_mode.get() == INPUT_MODES[1]>
+                <div class=style::audio_setting_type>
+                    <p>Push to Talk Key:</p>
+                    <button
+                        on:click=move |_| capturing.set(true)
+                        on:keydown=move |ev| {
+                            if !capturing.get() {
+                                return;
+                            }
+                            ev.prevent_default();
+                            let code = ev.code();
+                            set_audio_setting(AudioConfigDBPartial::PttKeyCode(Some(code.clone())));
+                            ptt_key.set(code);
+                            capturing.set(false);
+                        }
+                    >
+                        {move || if capturing.get() { "Press a key...".to_string() } else { ptt_key.get() }}
+                    </button>
+                    <p>Press Delay (ms):</p>
+                    <input
+                        type="number"
+                        min="0"
+                        max="1000"
+                        bind:value=press_delay
+                        on:change=move |_| {
+                            let delay = press_delay.get().parse().unwrap_or(0);
+                            set_audio_setting(AudioConfigDBPartial::PttPressDelay(delay));
+                        }
+                    />
+                    <p>Release Delay (ms):</p>
+                    <input
+                        type="number"
+                        min="0"
+                        max="2000"
+                        bind:value=release_delay
+                        on:change=move |_| {
+                            let delay = release_delay.get().parse().unwrap_or(200);
+                            set_audio_setting(AudioConfigDBPartial::PttReleaseDelay(delay));
+                        }
+                    />
+                    <label>
+                        <input
+                            type="checkbox"
+                            bind:checked=ptt_sound
+                            on:change=move |_| {
+                                set_audio_setting(AudioConfigDBPartial::PttSound(ptt_sound.get()));
+                            }
+                        />
+                        "Play a sound"
+                    </label>
+                </div>
+            </Show>
+        </div>
+    }
+}
@@ -5,7 +5,7 @@ use stylance::classes;
 use wasm_bindgen::JsValue;
 
 use crate::{
-    app::LoggedInSignal, home::settings::Settings, utils::{create_listener, invoke, popup::{Popup, PopupBackgroundStyle}}
+    app::LoggedInSignal, home::{connection_info::ConnectionInfoPanel, settings::Settings}, utils::{create_listener, invoke, popup::{Popup, PopupBackgroundStyle}}
 };
 
 stylance::import_style!(
@@ -55,6 +55,7 @@ pub fn StatusBox() -> impl IntoView {
     });
 
     let settings_popup = RwSignal::new(false);
+    let connection_popup = RwSignal::new(false);
 
     view! {
         <div class=style::status_box>
@@ -77,7 +78,19 @@ pub fn StatusBox() -> impl IntoView {
                 </Popup>
             </div>
             <div class=style::call_status>
-                <span class=style::status>{move || format!("{:?}", status.get())}</span>
+                <span
+                    class=style::status
+                    title="Connection info"
+                    on:click=move |_| connection_popup.set(true)
+                >
+                    {move || format!("{:?}", status.get())}
+                </span>
+                <Popup
+                    visible=connection_popup
+                    background_style=vec![PopupBackgroundStyle::Blur, PopupBackgroundStyle::Brightness]
+                >
+                    <ConnectionInfoPanel/>
+                </Popup>
                 <Show when=move || matches!(status.get(), Status::OnCall(_, _)) fallback=move || {}>
                     <div class=style::call_container>
                         <div class=style::icon_div>
@@ -1,6 +1,8 @@
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 use std::vec;
 
+use front_shared::ChannelsSnapshot;
 use leptos::logging::log;
 use leptos::logging::warn;
 use leptos::prelude::*;
@@ -8,8 +10,10 @@ use leptos::task::spawn_local;
 use serde_wasm_bindgen::to_value;
 use shared::models::AudioChannelMemberUpdate;
 use shared::models::ChannelWithUsers;
+use shared::models::DominantSpeaker;
 use shared::models::JoinChannel;
 use shared::models::Server;
+use shared::models::VoiceState;
 use stylance::classes;
 use uuid::Uuid;
 
@@ -31,6 +35,12 @@ struct SetBoostArgs {
     boost_level: i32,
 }
 
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SetMuteArgs {
+    user_id: Uuid,
+    muted: bool,
+}
+
 async fn get_channels(server_id: Uuid) -> Result<Vec<ChannelWithUsers>, String> {
     let arg = GetChannels { server_id };
     let arg = serde_wasm_bindgen::to_value(&arg).unwrap();
@@ -51,6 +61,9 @@ stylance::import_style!(
 #[component]
 pub fn Channels(active_server: RwSignal<Option<Server>>) -> impl IntoView {
     let channels_signal = RwSignal::new(None);
+    // Dominant speaker of each voice channel, keyed by the channel id
+    let dominant_speakers = RwSignal::new(HashMap::<Uuid, Uuid>::new());
+    let speaking_users = RwSignal::new(HashSet::<Uuid>::new());
     // Fetch channels for the active server
     Effect::new(move || {
         channels_signal.set(None);
@@ -140,6 +153,56 @@ pub fn Channels(active_server: RwSignal<Option<Server>>) -> impl IntoView {
             }
         },
     );
+    create_listener("dominant-speaker-changed", move |data: DominantSpeaker| {
+        dominant_speakers.update(|speakers| match data.user_id {
+            Some(user_id) => {
+                speakers.insert(data.channel_id, user_id);
+            }
+            None => {
+                speakers.remove(&data.channel_id);
+            }
+        });
+    });
+    create_listener("voice-state-changed", move |data: VoiceState| {
+        channels_signal.update(|channels| {
+            if let Some(Ok((_, voice_channels))) = channels {
+                let user = voice_channels
+                    .iter_mut()
+                    .filter(|c| c.channel.id == data.channel_id)
+                    .flat_map(|c| c.users.iter_mut())
+                    .find(|u| u.id == data.user_id);
+                if let Some(user) = user {
+                    user.muted = data.muted;
+                    user.deafened = data.deafened;
+                }
+            }
+        });
+    });
+    // The websocket reconnected, replace everything that may have changed while offline
+    create_listener("channels-snapshot", move |snapshot: ChannelsSnapshot| {
+        if active_server
+            .get_untracked()
+            .map_or(false, |s| s.id == snapshot.server_id)
+        {
+            let channels: (Vec<_>, Vec<_>) =
+                snapshot.channels.into_iter().partition(|channel| {
+                    channel.channel.type_ == shared::models::ChannelType::Text
+                });
+            channels_signal.set(Some(Ok(channels)));
+            dominant_speakers.set(HashMap::new());
+            speaking_users.set(HashSet::new());
+        }
+    });
+    create_listener("speaking-started", move |user_id: Uuid| {
+        speaking_users.update(|users| {
+            users.insert(user_id);
+        });
+    });
+    create_listener("speaking-stopped", move |user_id: Uuid| {
+        speaking_users.update(|users| {
+            users.remove(&user_id);
+        });
+    });
     view! {
         <div class=style::channel_list_container>
             <Show
@@ -167,6 +230,8 @@ pub fn Channels(active_server: RwSignal<Option<Server>>) -> impl IntoView {
                                 server_name=active_server
                                 text_channels=text_channels
                                 voice_channels=voice_channels
+                                dominant_speakers=dominant_speakers
+                                speaking_users=speaking_users
                             />
                         }
                     }}
@@ -190,6 +255,8 @@ pub fn ChannelList(
     server_name: RwSignal<Option<Server>>,
     text_channels: Vec<ChannelWithUsers>,
     voice_channels: Vec<ChannelWithUsers>,
+    dominant_speakers: RwSignal<HashMap<Uuid, Uuid>>,
+    speaking_users: RwSignal<HashSet<Uuid>>,
 ) -> impl IntoView {
     let (show_text_channels, set_show_text_channels) = signal(true);
     let (show_voice_channels, set_show_voice_channels) = signal(true);
@@ -316,6 +383,8 @@ pub fn ChannelList(
                         view! {
                             <ChannelItem
                                 channel=channel.clone()
+                                dominant_speakers=dominant_speakers
+                                speaking_users=speaking_users
                                 join_fn=move || {
                                     let channel = channel.clone();
                                     spawn_local(async move {
@@ -343,7 +412,13 @@ pub fn ChannelList(
 }
 
 #[component]
-pub fn ChannelItem(channel: ChannelWithUsers, join_fn: impl Fn() + 'static) -> impl IntoView {
+pub fn ChannelItem(
+    channel: ChannelWithUsers,
+    dominant_speakers: RwSignal<HashMap<Uuid, Uuid>>,
+    speaking_users: RwSignal<HashSet<Uuid>>,
+    join_fn: impl Fn() + 'static,
+) -> impl IntoView {
+    let channel_id = channel.channel.id;
     view! {
         <li class=style::channel_list_item
             on:click=move |_| {
@@ -351,7 +426,14 @@ pub fn ChannelItem(channel: ChannelWithUsers, join_fn: impl Fn() + 'static) -> i
             }
         >
             <h3>{channel.channel.name}</h3>
-            <span>{channel.users.len()} " users"</span>
+            <span>
+                {match channel.channel.user_limit {
+                    Some(limit) if limit > 0 => {
+                        format!("{} / {} users", channel.users.len(), limit)
+                    }
+                    _ => format!("{} users", channel.users.len()),
+                }}
+            </span>
         </li>
         <ul>
             <For
@@ -359,12 +441,29 @@ pub fn ChannelItem(channel: ChannelWithUsers, join_fn: impl Fn() + 'static) -> i
                 key=|user| user.id
                 children=move |user| {
                     let boost = RwSignal::new(user.boost.unwrap_or(100).to_string());
+                    let muted = RwSignal::new(user.locally_muted);
                     view! {
                         <HoverMenu
                             item=move || {
                                 view! {
-                                    <span class=style::channel_user>
+                                    <span class=move || {
+                                        let is_dominant = dominant_speakers
+                                            .with(|speakers| speakers.get(&channel_id) == Some(&user.id));
+                                        let is_speaking = speaking_users.with(|users| users.contains(&user.id));
+                                        classes!(
+                                            style::channel_user,
+                                            { if is_dominant { Some(style::channel_user_dominant) } else { None } },
+                                            { if is_speaking { Some(style::channel_user_speaking) } else { None } }
+                                        )
+                                    }>
                                         {user.username.clone()}
+                                        {if user.deafened {
+                                            " (deafened)"
+                                        } else if user.muted {
+                                            " (muted)"
+                                        } else {
+                                            ""
+                                        }}
                                     </span>
                                 }
                             }
@@ -375,7 +474,7 @@ pub fn ChannelItem(channel: ChannelWithUsers, join_fn: impl Fn() + 'static) -> i
                                         <input
                                             type="range"
                                             min="0"
-                                            max="100"
+                                            max="200"
                                             bind:value=boost
                                             on:change=move |_| {
                                                 let boost_value = boost.get().parse::<i32>().unwrap_or(100);
@@ -397,6 +496,30 @@ pub fn ChannelItem(channel: ChannelWithUsers, join_fn: impl Fn() + 'static) -> i
                                                 });
                                             }
                                         />
+                                        <label>
+                                            <input
+                                                type="checkbox"
+                                                bind:checked=muted
+                                                on:change=move |_| {
+                                                    let muted = muted.get();
+                                                    spawn_local(async move {
+                                                        if let Err(e) = invoke(
+                                                            "set_user_mute",
+                                                            to_value(&SetMuteArgs {
+                                                                user_id: user.id,
+                                                                muted,
+                                                            })
+                                                            .unwrap(),
+                                                        )
+                                                        .await
+                                                        {
+                                                            log!("Failed to set user mute: {:?}", e);
+                                                        }
+                                                    });
+                                                }
+                                            />
+                                            "Mute for me"
+                                        </label>
                                     </div>
                                 }
                             }
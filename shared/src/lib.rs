@@ -2,25 +2,47 @@ pub mod models;
 #[cfg(feature = "diesel")]
 pub mod schema;
 
-pub static ROOM_SIZE: usize = 10;
-
 #[cfg(not(target_arch = "wasm32"))]
 mod notwasm {
+    mod active_speakers;
+    mod codec;
+    mod jitter_buffer;
     mod my_web_rtc;
+    mod opus_tuning;
+    mod protocol;
+    mod quality;
+    mod rpc;
+    mod simulcast;
 
-    pub use my_web_rtc::WebRTCConnection;
+    pub use active_speakers::{ActiveSpeakers, audio_level};
+    pub use codec::{Encoding, Frame};
+    pub use jitter_buffer::{JitterBuffer, Playout};
+    pub use my_web_rtc::{
+        AudioSink, AudioSinks, ForwardedTrack, LayerTracks, PublishedForwards, PublishedTrack,
+        TrackForward, VideoSink, VideoSinks, WebRTCConnection,
+    };
+    pub use opus_tuning::{AudioProfile, OpusSettings, OpusTuning};
+    pub use simulcast::{Layer, LayerSample, LayerSelector};
+    pub use protocol::{Feature, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+    pub use rpc::{RpcError, RpcRequest, RpcResponse};
     pub use ringbuf::HeapCons;
     pub use ringbuf::HeapRb;
     pub use ringbuf::traits::Consumer;
     pub use ringbuf::traits::Observer;
     pub use ringbuf::traits::Producer;
     pub use ringbuf::traits::Split;
+    use std::collections::HashSet;
+
     use serde::{Deserialize, Serialize};
+    use strum::VariantNames;
+    use strum_macros::VariantNames;
     use thiserror::Error;
     use uuid::Uuid;
     pub use webrtc::Error as WebRTCError;
+    pub use webrtc::media::Sample;
     pub use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
     pub use webrtc::rtp::packet::Packet;
+    pub use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
     pub use webrtc::stats;
     pub use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
     pub use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
@@ -29,7 +51,9 @@ mod notwasm {
         peer_connection::sdp::session_description::RTCSessionDescription,
     };
 
-    use crate::models::AudioChannelMemberUpdate;
+    use crate::models::{
+        AudioChannelMemberUpdate, CameraState, DominantSpeaker, ScreenShareState, VoiceState,
+    };
     
     #[derive(Debug, thiserror::Error)]
     pub enum Error {
@@ -43,6 +67,10 @@ mod notwasm {
         IceCandidate(#[from] webrtc::ice::Error),
         #[error("Serde error: {0}")]
         Serde(#[from] serde_json::Error),
+        #[error("MessagePack encode error: {0}")]
+        MessagePackEncode(#[from] rmp_serde::encode::Error),
+        #[error("MessagePack decode error: {0}")]
+        MessagePackDecode(#[from] rmp_serde::decode::Error),
         #[error("WebSocket not connected")]
         WebSocketNotConnected,
         #[error("Mutex error")]
@@ -55,25 +83,207 @@ mod notwasm {
         }
     }
     
-    #[derive(Debug, Clone, Serialize, Deserialize)]
+    /// Variants can be added in newer protocol versions, peers skip the ones they do not know
+    /// (see `from_json`) and only send the ones the other side negotiated a feature for.
+    #[derive(Debug, Clone, Serialize, Deserialize, VariantNames)]
     pub enum WebSocketMessage {
+        /// First message of the client, the server answers with `HelloAck`
+        /// or with `WebSocketError::UnsupportedProtocol`
+        Hello { protocol_version: u32, features: HashSet<Feature> },
+        /// Protocol version and features both sides use for the rest of the connection
+        HelloAck { protocol_version: u32, features: HashSet<Feature> },
         JoinAudioChannel { server_id: Uuid, channel_id: Uuid },
         SomeoneJoinedAudioChannel { data: AudioChannelMemberUpdate },
         SomeoneLeftAudioChannel { data: AudioChannelMemberUpdate },
+        DominantSpeakerChanged { data: DominantSpeaker },
+        SpeakingStarted { user_id: Uuid },
+        SpeakingStopped { user_id: Uuid },
+        /// Someone in a voice channel turned their camera on or off
+        CameraChanged { data: CameraState },
+        /// Turns the camera of the client on or off, its video track is sent all the time
+        SetCamera { enabled: bool },
+        /// Users whose camera the client wants to receive, replaces the previous list
+        SetVideoSubscriptions { user_ids: Vec<Uuid> },
+        /// Someone in a voice channel muted, deafened, unmuted or undeafened themselves
+        VoiceStateChanged { data: VoiceState },
+        /// Tells whether the client is muted and deafened, both are off when joining
+        SetVoiceState { muted: bool, deafened: bool },
+        /// Someone in a voice channel started or stopped sharing their screen
+        ScreenShareChanged { data: ScreenShareState },
+        /// Starts sending the screen share track, and the application audio track if `audio` is set
+        StartScreenShare { audio: bool },
+        StopScreenShare,
+        /// Starts receiving the screen share of the user
+        WatchScreenShare { user_id: Uuid },
+        StopWatchingScreenShare { user_id: Uuid },
+        /// Height the client shows the camera, or the screen share if `screen` is set, of the user at.
+        /// The server forwards the smallest layer that is at least as high.
+        SetVideoSize { user_id: Uuid, screen: bool, height: u32 },
         WebRTCOffer(RTCSessionDescription),
         WebRTCAnswer(RTCSessionDescription),
         IceCandidate(RTCIceCandidateInit),
+        /// Asks the server for an offer that restarts ICE on the current connection
+        RestartIce,
         DisconnectFromAudioChannel,
+        /// Another session of the same user took the call over, `channel_id` is where it joined
+        VoiceTakenOver { channel_id: Uuid },
         Disconnect,
         Error { err: WebSocketError },
+        /// First message of a new session, the id is needed to resume it later
+        SessionStarted { session_id: Uuid, heartbeat_interval_ms: u64 },
+        /// Continues a session after reconnecting, replaying the events after `last_seq`
+        Resume { session_id: Uuid, last_seq: u64 },
+        Resumed { session_id: Uuid },
+        /// The session can not be resumed, the client has to fetch everything again
+        FullRefresh,
+        /// Sent by the client every heartbeat interval, the server answers with `HeartbeatAck`
+        Heartbeat { nonce: u64 },
+        HeartbeatAck { nonce: u64 },
+        /// Request with an id chosen by the client, answered by a `Response` with the same id
+        Request { id: u64, request: RpcRequest },
+        Response { id: u64, result: Result<RpcResponse, RpcError> },
+    }
+
+    impl WebSocketMessage {
+        /**
+         * Whether the message is an event that can be replayed to a resumed session.
+         * Signaling of the old WebRTC connection is useless after reconnecting.
+         */
+        pub fn is_replayable(&self) -> bool {
+            matches!(
+                self,
+                WebSocketMessage::SomeoneJoinedAudioChannel { .. }
+                    | WebSocketMessage::SomeoneLeftAudioChannel { .. }
+                    | WebSocketMessage::DominantSpeakerChanged { .. }
+                    | WebSocketMessage::SpeakingStarted { .. }
+                    | WebSocketMessage::SpeakingStopped { .. }
+                    | WebSocketMessage::CameraChanged { .. }
+                    | WebSocketMessage::ScreenShareChanged { .. }
+                    | WebSocketMessage::VoiceStateChanged { .. }
+                    | WebSocketMessage::VoiceTakenOver { .. }
+            )
+        }
+
+        /**
+         * Feature the receiver must have negotiated to understand the message.
+         */
+        pub fn required_feature(&self) -> Option<Feature> {
+            match self {
+                WebSocketMessage::DominantSpeakerChanged { .. }
+                | WebSocketMessage::SpeakingStarted { .. }
+                | WebSocketMessage::SpeakingStopped { .. } => Some(Feature::SpeakerEvents),
+                WebSocketMessage::VoiceTakenOver { .. } => Some(Feature::VoiceTakeover),
+                WebSocketMessage::CameraChanged { .. }
+                | WebSocketMessage::SetCamera { .. }
+                | WebSocketMessage::SetVideoSubscriptions { .. } => Some(Feature::Video),
+                WebSocketMessage::ScreenShareChanged { .. }
+                | WebSocketMessage::StartScreenShare { .. }
+                | WebSocketMessage::StopScreenShare
+                | WebSocketMessage::WatchScreenShare { .. }
+                | WebSocketMessage::StopWatchingScreenShare { .. } => Some(Feature::ScreenShare),
+                WebSocketMessage::SetVideoSize { .. } => Some(Feature::Simulcast),
+                WebSocketMessage::VoiceStateChanged { .. } | WebSocketMessage::SetVoiceState { .. } => {
+                    Some(Feature::VoiceState)
+                }
+                WebSocketMessage::Resumed { .. } | WebSocketMessage::FullRefresh => {
+                    Some(Feature::Resume)
+                }
+                WebSocketMessage::HeartbeatAck { .. } => Some(Feature::Heartbeat),
+                WebSocketMessage::Response { .. } => Some(Feature::Rpc),
+                _ => None,
+            }
+        }
+
+        /**
+         * Parses a message from a peer that may speak a newer protocol version.
+         * Returns None for variants this build does not know, so that they can be skipped.
+         */
+        pub fn from_value(value: serde_json::Value) -> Result<Option<Self>, serde_json::Error> {
+            let tag = match &value {
+                serde_json::Value::String(tag) => Some(tag.as_str()),
+                serde_json::Value::Object(map) if map.len() == 1 => {
+                    map.keys().next().map(String::as_str)
+                }
+                _ => None,
+            };
+            if tag.is_some_and(|tag| !Self::VARIANTS.contains(&tag)) {
+                return Ok(None);
+            }
+            serde_json::from_value(value).map(Some)
+        }
+
+        pub fn from_json(text: &str) -> Result<Option<Self>, serde_json::Error> {
+            Self::from_value(serde_json::from_str(text)?)
+        }
+    }
+
+    /// Server to client message with its position in the event stream of the session,
+    /// 0 for messages about the session itself that are not part of the stream
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct SequencedMessage {
+        pub seq: u64,
+        pub message: WebSocketMessage,
+    }
+
+    impl SequencedMessage {
+        /**
+         * Like `WebSocketMessage::from_json`, keeping the sequence number of unknown messages.
+         * Servers from before the handshake send bare messages, which get the sequence number 0.
+         */
+        pub fn from_json(text: &str) -> Result<(u64, Option<WebSocketMessage>), serde_json::Error> {
+            #[derive(Deserialize)]
+            #[serde(untagged)]
+            enum RawSequencedMessage {
+                Sequenced { seq: u64, message: serde_json::Value },
+                Bare(serde_json::Value),
+            }
+            match serde_json::from_str::<RawSequencedMessage>(text)? {
+                RawSequencedMessage::Sequenced { seq, message } => {
+                    Ok((seq, WebSocketMessage::from_value(message)?))
+                }
+                RawSequencedMessage::Bare(message) => Ok((0, WebSocketMessage::from_value(message)?)),
+            }
+        }
     }
     
+    /// Deserializes any error of a newer protocol version as `Unknown`, whatever its content
     #[derive(Debug, Clone, Serialize, Deserialize, Error)]
+    #[serde(remote = "Self")]
     pub enum WebSocketError {
         #[error("Not Authorized")]
         NotAuthorized,
         #[error("Not Found")]
         NotFound,
+        #[error("Room is full")]
+        RoomFull,
+        #[error("Not in an audio channel")]
+        NotInAudioChannel,
+        #[error("Protocol version is not supported, at least {min_version} is required")]
+        UnsupportedProtocol { min_version: u32 },
+        /// An error of a newer protocol version
+        #[error("Unknown error")]
+        Unknown,
+    }
+
+    impl Serialize for WebSocketError {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            WebSocketError::serialize(self, serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for WebSocketError {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            #[derive(Deserialize)]
+            #[serde(untagged)]
+            enum OrUnknown {
+                Known(#[serde(deserialize_with = "WebSocketError::deserialize")] WebSocketError),
+                Unknown(serde::de::IgnoredAny),
+            }
+            Ok(match OrUnknown::deserialize(deserializer)? {
+                OrUnknown::Known(err) => err,
+                OrUnknown::Unknown(_) => WebSocketError::Unknown,
+            })
+        }
     }
     
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +26,10 @@ pub struct Channel {
     pub server_id: Uuid,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    /// Maximum number of people in the voice room
+    ///
+    /// None, 0 or less means no limit
+    pub user_limit: Option<i32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -34,6 +38,21 @@ pub struct VoiceUser {
     pub username: String,
     pub slot: usize,
     pub boost: Option<i32>,
+    /// Whether this client muted the user for itself, filled in by the client like `boost`
+    #[serde(default)]
+    pub locally_muted: bool,
+    /// Whether the user shares their camera, missing from servers without video
+    #[serde(default)]
+    pub camera: bool,
+    /// Whether the user shares their screen, missing from servers without screen sharing
+    #[serde(default)]
+    pub screen_share: bool,
+    /// Whether the user muted their microphone or deafened themselves,
+    /// missing from servers without voice states
+    #[serde(default)]
+    pub muted: bool,
+    #[serde(default)]
+    pub deafened: bool,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChannelWithUsers {
@@ -47,6 +66,37 @@ pub struct AudioChannelMemberUpdate {
     pub user: VoiceUser,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DominantSpeaker {
+    pub channel_id: Uuid,
+    /// None when nobody in the channel is talking
+    pub user_id: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraState {
+    pub channel_id: Uuid,
+    pub user_id: Uuid,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoiceState {
+    pub channel_id: Uuid,
+    pub user_id: Uuid,
+    pub muted: bool,
+    pub deafened: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScreenShareState {
+    pub channel_id: Uuid,
+    pub user_id: Uuid,
+    pub sharing: bool,
+    /// Whether the audio of the shared applications is sent with the screen
+    pub audio: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(feature = "diesel", derive(Queryable, Selectable, Insertable))]
 #[cfg_attr(feature = "diesel", diesel(table_name = crate::schema::channels))]
@@ -56,6 +106,7 @@ pub struct NewChannel {
     pub type_: ChannelType,
     pub hidden: bool,
     pub server_id: Uuid,
+    pub user_limit: Option<i32>,
 }   
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -4,9 +4,11 @@ mod permissions;
 mod servers;
 mod channels;
 mod turn;
+mod quality;
 
 pub use user::*;
 pub use permissions::*;
 pub use servers::*;
 pub use channels::*;
-pub use turn::*;
\ No newline at end of file
+pub use turn::*;
+pub use quality::*;
\ No newline at end of file
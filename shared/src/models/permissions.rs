@@ -23,6 +23,7 @@ pub enum PermissionType {
     SendMessagesInHiddenChannels,
     DeleteMessages,
     DeleteMessagesSelf,
+    ViewCallQuality,
 }
 
 pub struct PermissionContext {
@@ -62,7 +63,7 @@ pub static DEFAULT_USER_PERMISSIONS: &[PermissionType] = &[
     PermissionType::DeleteMessagesSelf,
 ];
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PermissionsOfUser {
     pub user_id: Uuid,
     pub role: String,
@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TrackDirection {
+    Inbound,
+    Outbound,
+}
+
+/// Quality of one RTP stream of a connection, each simulcast layer is a stream of its own
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackQuality {
+    pub track_id: String,
+    /// "audio" or "video"
+    pub kind: String,
+    pub direction: TrackDirection,
+    pub ssrc: u32,
+    /// Fraction of the packets lost since the previous sample, from 0 to 1.
+    /// Reported by the remote peer for outbound streams, None until it sent a report
+    pub packet_loss: Option<f64>,
+    /// Interarrival jitter in milliseconds, only known for inbound streams
+    pub jitter_ms: Option<f64>,
+    /// Round trip time reported for the stream in milliseconds
+    pub rtt_ms: Option<f64>,
+    /// Bitrate since the previous sample in bit/s, headers included
+    pub bitrate: u64,
+    /// Playout of received audio streams
+    #[serde(default)]
+    pub jitter_buffer: Option<JitterBufferStats>,
+}
+
+/// Counters of the jitter buffer of a received audio stream since it started
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JitterBufferStats {
+    /// Delay the buffer currently aims for and holds, in milliseconds
+    pub target_delay_ms: u32,
+    pub buffered_ms: u32,
+    /// Times the buffer ran empty while playing
+    pub underruns: u64,
+    /// Times the buffer grew too large and dropped its oldest packets
+    pub overruns: u64,
+    /// Packets that arrived after their turn to play
+    pub late: u64,
+    /// Lost packets recovered from the FEC of the next one
+    pub recovered: u64,
+    /// Lost packets concealed by the decoder
+    pub concealed: u64,
+    /// Times the sender restarted its sequence numbers
+    pub resets: u64,
+}
+
+/// Quality of a WebRTC connection, sampled every few seconds
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectionQuality {
+    /// Round trip time of the selected ICE candidate pair in milliseconds
+    pub rtt_ms: Option<f64>,
+    /// Bandwidth estimate for sending in bit/s
+    pub available_outgoing_bitrate: Option<u64>,
+    pub tracks: Vec<TrackQuality>,
+}
+
+impl ConnectionQuality {
+    /// Worst packet loss of the streams, None when nothing reported loss yet
+    pub fn packet_loss(&self) -> Option<f64> {
+        self.tracks
+            .iter()
+            .filter_map(|track| track.packet_loss)
+            .reduce(f64::max)
+    }
+
+    /// Worst jitter of the received streams
+    pub fn jitter_ms(&self) -> Option<f64> {
+        self.tracks
+            .iter()
+            .filter_map(|track| track.jitter_ms)
+            .reduce(f64::max)
+    }
+
+    pub fn bitrate(&self, direction: TrackDirection) -> u64 {
+        self.tracks
+            .iter()
+            .filter(|track| track.direction == direction)
+            .map(|track| track.bitrate)
+            .sum()
+    }
+}
+
+/// Connection of the client for the connection info panel
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    /// Round trip time of the last websocket heartbeat in milliseconds
+    pub websocket_latency_ms: Option<u64>,
+    /// None while not in a call
+    pub call: Option<ConnectionQuality>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersonQuality {
+    pub user_id: Uuid,
+    pub username: String,
+    pub quality: ConnectionQuality,
+}
+
+/// Quality of the connections of everyone in a voice room as seen by the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomQuality {
+    pub channel_id: Uuid,
+    pub people: Vec<PersonQuality>,
+    pub average_rtt_ms: Option<f64>,
+    pub average_packet_loss: Option<f64>,
+    pub average_jitter_ms: Option<f64>,
+    /// Bitrate the server receives from and sends to everyone, in bit/s
+    pub inbound_bitrate: u64,
+    pub outbound_bitrate: u64,
+}
+
+impl RoomQuality {
+    pub fn new(channel_id: Uuid, people: Vec<PersonQuality>) -> Self {
+        let average = |values: Vec<f64>| {
+            (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+        };
+        let average_rtt_ms = average(people.iter().filter_map(|person| person.quality.rtt_ms).collect());
+        let average_packet_loss = average(
+            people
+                .iter()
+                .filter_map(|person| person.quality.packet_loss())
+                .collect(),
+        );
+        let average_jitter_ms = average(
+            people
+                .iter()
+                .filter_map(|person| person.quality.jitter_ms())
+                .collect(),
+        );
+        let inbound_bitrate = people
+            .iter()
+            .map(|person| person.quality.bitrate(TrackDirection::Inbound))
+            .sum();
+        let outbound_bitrate = people
+            .iter()
+            .map(|person| person.quality.bitrate(TrackDirection::Outbound))
+            .sum();
+        RoomQuality {
+            channel_id,
+            people,
+            average_rtt_ms,
+            average_packet_loss,
+            average_jitter_ms,
+            inbound_bitrate,
+            outbound_bitrate,
+        }
+    }
+}
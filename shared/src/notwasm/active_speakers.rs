@@ -0,0 +1,451 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+use webrtc::rtp::packet::Packet;
+
+/// RFC 6464 audio level header extension
+pub const AUDIO_LEVEL_URI: &str = "urn:ietf:params:rtp-hdrext:ssrc-audio-level";
+/// Audio levels (in -dBov) at or below this are flagged as voice by the sender
+pub const VOICE_ACTIVITY_LEVEL: u8 = 50;
+
+/// Weight of the newest packet in the smoothed loudness
+const SMOOTHING: f32 = 0.2;
+/// A speaker that has not sent a packet for this long is considered silent
+const STALE_AFTER: Duration = Duration::from_millis(500);
+/// Loudness a new dominant speaker needs over the current one to take over
+const DOMINANT_MARGIN: f32 = 6.0;
+/// Minimum loudness to become the dominant speaker
+const DOMINANT_MIN_LOUDNESS: f32 = (127 - VOICE_ACTIVITY_LEVEL) as f32;
+/// Minimum time between two dominant speaker changes
+const DOMINANT_HOLD: Duration = Duration::from_millis(300);
+/// How long someone has to keep talking before they count as speaking
+const SPEAKING_ATTACK: Duration = Duration::from_millis(100);
+/// How long someone has to stay quiet before they stop counting as speaking
+const SPEAKING_HANGOVER: Duration = Duration::from_millis(400);
+
+struct SpeakerLevel {
+    /// Smoothed loudness, 0 is silence and 127 is the loudest
+    loudness: f32,
+    last_update: Instant,
+    /// Start of the current run of voiced packets
+    voice_started: Option<Instant>,
+    last_voice: Instant,
+}
+
+struct ActiveSpeakersState {
+    levels: HashMap<usize, SpeakerLevel>,
+    /// Slots ordered from the loudest to the quietest
+    ranking: Vec<usize>,
+    dominant: Option<usize>,
+    dominant_since: Instant,
+}
+
+/**
+ * Ranks the people in a voice room by the audio level they report,
+ * so that the SFU only forwards the loudest streams to each receiver.
+ */
+pub struct ActiveSpeakers {
+    max_forwarded: usize,
+    state: StdMutex<ActiveSpeakersState>,
+    dominant_tx: watch::Sender<Option<usize>>,
+}
+
+impl ActiveSpeakers {
+    pub fn new(max_forwarded: usize) -> (Arc<Self>, watch::Receiver<Option<usize>>) {
+        let (dominant_tx, dominant_rx) = watch::channel(None);
+        let speakers = ActiveSpeakers {
+            max_forwarded,
+            state: StdMutex::new(ActiveSpeakersState {
+                levels: HashMap::new(),
+                ranking: Vec::new(),
+                dominant: None,
+                dominant_since: Instant::now(),
+            }),
+            dominant_tx,
+        };
+        (Arc::new(speakers), dominant_rx)
+    }
+
+    /**
+     * Records the audio level (in -dBov, as carried by RFC 6464) and the voice flag of a packet from `slot`.
+     */
+    pub fn update(&self, slot: usize, level: u8, voice: bool) {
+        self.update_at(slot, level, voice, Instant::now());
+    }
+
+    /// Like `update`, for a packet that arrived at `now`
+    pub fn update_at(&self, slot: usize, level: u8, voice: bool, now: Instant) {
+        let loudness = (127 - level.min(127)) as f32;
+        let mut state = self.state.lock().unwrap();
+        let speaker = state.levels.entry(slot).or_insert(SpeakerLevel {
+            loudness,
+            last_update: now,
+            voice_started: None,
+            last_voice: now,
+        });
+        speaker.loudness = speaker.loudness * (1.0 - SMOOTHING) + loudness * SMOOTHING;
+        speaker.last_update = now;
+        if voice {
+            if speaker.voice_started.is_none()
+                || now.duration_since(speaker.last_voice) > SPEAKING_HANGOVER
+            {
+                speaker.voice_started = Some(now);
+            }
+            speaker.last_voice = now;
+        }
+        Self::rank(&mut state, now);
+        self.update_dominant(&mut state, now);
+    }
+
+    pub fn remove(&self, slot: usize) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.levels.remove(&slot);
+        Self::rank(&mut state, now);
+        if state.dominant == Some(slot) {
+            state.dominant = None;
+            state.dominant_since = now;
+            self.dominant_tx.send_replace(None);
+        }
+    }
+
+    /**
+     * Whether the audio of `from` is among the loudest streams that `to` should receive.
+     * Streams that never reported an audio level are always forwarded.
+     */
+    pub fn should_forward(&self, from: usize, to: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        if !state.levels.contains_key(&from) {
+            return true;
+        }
+        state
+            .ranking
+            .iter()
+            .filter(|slot| **slot != to)
+            .take(self.max_forwarded)
+            .any(|slot| *slot == from)
+    }
+
+    /**
+     * Slots of the people that are currently speaking, debounced so that
+     * short noises and the pauses between words do not toggle the state.
+     */
+    pub fn speaking(&self) -> HashSet<usize> {
+        self.speaking_at(Instant::now())
+    }
+
+    fn speaking_at(&self, now: Instant) -> HashSet<usize> {
+        let state = self.state.lock().unwrap();
+        state
+            .levels
+            .iter()
+            .filter(|(_, speaker)| {
+                speaker.voice_started.is_some_and(|started| {
+                    speaker.last_voice.duration_since(started) >= SPEAKING_ATTACK
+                }) && now.duration_since(speaker.last_voice) <= SPEAKING_HANGOVER
+            })
+            .map(|(slot, _)| *slot)
+            .collect()
+    }
+
+    fn loudness(speaker: &SpeakerLevel, now: Instant) -> f32 {
+        if now.duration_since(speaker.last_update) > STALE_AFTER {
+            0.0
+        } else {
+            speaker.loudness
+        }
+    }
+
+    fn rank(state: &mut ActiveSpeakersState, now: Instant) {
+        let mut ranking = state.levels.keys().copied().collect::<Vec<_>>();
+        ranking.sort_by(|a, b| {
+            let a = Self::loudness(&state.levels[a], now);
+            let b = Self::loudness(&state.levels[b], now);
+            b.total_cmp(&a)
+        });
+        state.ranking = ranking;
+    }
+
+    fn update_dominant(&self, state: &mut ActiveSpeakersState, now: Instant) {
+        if now.duration_since(state.dominant_since) < DOMINANT_HOLD {
+            return;
+        }
+        let Some(loudest) = state.ranking.first().copied() else {
+            return;
+        };
+        let loudest_loudness = Self::loudness(&state.levels[&loudest], now);
+        let dominant_loudness = state
+            .dominant
+            .and_then(|slot| state.levels.get(&slot))
+            .map_or(0.0, |speaker| Self::loudness(speaker, now));
+        let new_dominant = if loudest_loudness < DOMINANT_MIN_LOUDNESS {
+            // Nobody is talking loud enough
+            if dominant_loudness < DOMINANT_MIN_LOUDNESS {
+                None
+            } else {
+                state.dominant
+            }
+        } else if Some(loudest) != state.dominant
+            && loudest_loudness > dominant_loudness + DOMINANT_MARGIN
+        {
+            Some(loudest)
+        } else {
+            state.dominant
+        };
+        if new_dominant != state.dominant {
+            state.dominant = new_dominant;
+            state.dominant_since = now;
+            self.dominant_tx.send_replace(new_dominant);
+        }
+    }
+}
+
+/**
+ * Computes the RFC 6464 audio level (in -dBov, 0 to 127) of a frame of samples.
+ */
+pub fn audio_level(samples: &[f32]) -> u8 {
+    if samples.is_empty() {
+        return 127;
+    }
+    let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+    if rms <= 0.0 {
+        return 127;
+    }
+    let dbov = 20.0 * rms.log10();
+    (-dbov).clamp(0.0, 127.0) as u8
+}
+
+/**
+ * Renumbers the audio of a speaker forwarded to one receiver, so that the packets left out
+ * while the speaker is not one of the loudest for it do not look lost. The timestamps are
+ * left alone, they still tell the time that passed like after a DTX pause.
+ */
+#[derive(Debug, Default)]
+pub struct ForwardedAudio {
+    /// Whether the previous packet was forwarded
+    forwarding: bool,
+    seq_offset: u16,
+    /// Sequence number of the last packet forwarded, after the offset
+    last_seq: Option<u16>,
+}
+
+impl ForwardedAudio {
+    /**
+     * Returns the packet to send to the receiver, None if it is not `forwarded`.
+     * The first packet after a gap continues where the last forwarded one stopped.
+     */
+    pub fn forward(&mut self, packet: &Packet, forwarded: bool) -> Option<Packet> {
+        if !forwarded {
+            self.forwarding = false;
+            return None;
+        }
+        if !self.forwarding {
+            if let Some(last_seq) = self.last_seq {
+                self.seq_offset = last_seq.wrapping_add(1).wrapping_sub(packet.header.sequence_number);
+            }
+            self.forwarding = true;
+        }
+        let mut packet = packet.clone();
+        packet.header.sequence_number = packet.header.sequence_number.wrapping_add(self.seq_offset);
+        self.last_seq = Some(packet.header.sequence_number);
+        Some(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn forwards_the_loudest_others() {
+        let (speakers, _) = ActiveSpeakers::new(3);
+        let now = Instant::now();
+        for (slot, level) in [10, 20, 30, 40, 50].into_iter().enumerate() {
+            speakers.update_at(slot, level, true, now);
+        }
+        assert!(speakers.should_forward(0, 5));
+        assert!(speakers.should_forward(2, 5));
+        assert!(!speakers.should_forward(3, 5));
+        // The receiver does not take a place among the loudest
+        assert!(speakers.should_forward(3, 0));
+        assert!(!speakers.should_forward(4, 0));
+        // Nothing known about the stream yet
+        assert!(speakers.should_forward(9, 0));
+    }
+
+    #[test]
+    fn ranks_stale_speakers_as_silent() {
+        let (speakers, _) = ActiveSpeakers::new(1);
+        let start = Instant::now();
+        speakers.update_at(0, 10, true, start);
+        speakers.update_at(1, 60, false, start + ms(100));
+        assert!(speakers.should_forward(0, 2));
+        speakers.update_at(1, 60, false, start + STALE_AFTER + ms(1));
+        assert!(!speakers.should_forward(0, 2));
+        assert!(speakers.should_forward(1, 2));
+    }
+
+    #[test]
+    fn takes_over_as_dominant_only_by_the_margin() {
+        let (speakers, dominant_rx) = ActiveSpeakers::new(3);
+        let start = Instant::now() + DOMINANT_HOLD;
+        // Loudness 97 and 101
+        speakers.update_at(0, 30, true, start);
+        assert_eq!(*dominant_rx.borrow(), Some(0));
+        speakers.update_at(1, 26, true, start + DOMINANT_HOLD);
+        assert_eq!(*dominant_rx.borrow(), Some(0));
+        // Loudness 117
+        speakers.update_at(2, 10, true, start + DOMINANT_HOLD);
+        assert_eq!(*dominant_rx.borrow(), Some(2));
+    }
+
+    #[test]
+    fn holds_the_dominant_speaker() {
+        let (speakers, dominant_rx) = ActiveSpeakers::new(3);
+        let start = Instant::now() + DOMINANT_HOLD;
+        speakers.update_at(0, 30, true, start);
+        speakers.update_at(1, 10, true, start + DOMINANT_HOLD - ms(1));
+        assert_eq!(*dominant_rx.borrow(), Some(0));
+        speakers.update_at(1, 10, true, start + DOMINANT_HOLD);
+        assert_eq!(*dominant_rx.borrow(), Some(1));
+    }
+
+    #[test]
+    fn needs_a_minimum_loudness_to_dominate() {
+        let (speakers, dominant_rx) = ActiveSpeakers::new(3);
+        let start = Instant::now() + DOMINANT_HOLD;
+        speakers.update_at(0, VOICE_ACTIVITY_LEVEL + 1, true, start);
+        assert_eq!(*dominant_rx.borrow(), None);
+        speakers.update_at(1, VOICE_ACTIVITY_LEVEL, true, start + ms(20));
+        assert_eq!(*dominant_rx.borrow(), Some(1));
+        speakers.remove(1);
+        assert_eq!(*dominant_rx.borrow(), None);
+    }
+
+    /// Voiced packets from `slot` every 20 ms from `from` until before `until`
+    fn talk(speakers: &ActiveSpeakers, slot: usize, from: Instant, until: Instant) {
+        let mut at = from;
+        while at < until {
+            speakers.update_at(slot, 30, true, at);
+            at += ms(20);
+        }
+    }
+
+    #[test]
+    fn speaks_after_the_attack_until_the_hangover() {
+        let (speakers, _) = ActiveSpeakers::new(3);
+        let start = Instant::now();
+        talk(&speakers, 0, start, start + ms(100));
+        assert!(speakers.speaking_at(start + ms(80)).is_empty());
+        speakers.update_at(0, 30, true, start + SPEAKING_ATTACK);
+        let last_voice = start + SPEAKING_ATTACK;
+        assert!(speakers.speaking_at(last_voice).contains(&0));
+        // Quiet packets do not end it before the hangover
+        speakers.update_at(0, 90, false, last_voice + ms(20));
+        assert!(speakers
+            .speaking_at(last_voice + SPEAKING_HANGOVER)
+            .contains(&0));
+        assert!(speakers
+            .speaking_at(last_voice + SPEAKING_HANGOVER + ms(1))
+            .is_empty());
+    }
+
+    #[test]
+    fn short_noises_are_not_speaking() {
+        let (speakers, _) = ActiveSpeakers::new(3);
+        let start = Instant::now();
+        talk(&speakers, 0, start, start + ms(60));
+        speakers.update_at(0, 90, false, start + ms(60));
+        assert!(speakers.speaking_at(start + ms(60)).is_empty());
+        assert!(speakers.speaking_at(start + ms(200)).is_empty());
+    }
+
+    #[test]
+    fn pauses_shorter_than_the_hangover_continue_the_run() {
+        let (speakers, _) = ActiveSpeakers::new(3);
+        let start = Instant::now();
+        talk(&speakers, 0, start, start + ms(60));
+        let resumed = start + ms(40) + SPEAKING_HANGOVER;
+        speakers.update_at(0, 30, true, resumed);
+        assert!(speakers.speaking_at(resumed).contains(&0));
+        // After a longer pause the attack starts over
+        let restarted = resumed + SPEAKING_HANGOVER + ms(1);
+        speakers.update_at(0, 30, true, restarted);
+        assert!(speakers.speaking_at(restarted).is_empty());
+        speakers.update_at(0, 30, true, restarted + SPEAKING_ATTACK);
+        assert!(speakers
+            .speaking_at(restarted + SPEAKING_ATTACK)
+            .contains(&0));
+    }
+
+    #[test]
+    fn measures_the_audio_level() {
+        assert_eq!(audio_level(&[]), 127);
+        assert_eq!(audio_level(&[0.0; 480]), 127);
+        assert_eq!(audio_level(&[1.0, -1.0]), 0);
+        assert_eq!(audio_level(&[0.5, -0.5]), 6);
+        assert_eq!(audio_level(&[0.05, -0.05]), 26);
+        assert_eq!(audio_level(&[1e-9, -1e-9]), 127);
+    }
+
+    fn packet(seq: u16, timestamp: u32) -> Packet {
+        let mut packet = Packet::default();
+        packet.header.sequence_number = seq;
+        packet.header.timestamp = timestamp;
+        packet
+    }
+
+    /// Sequence numbers and timestamps the receiver gets of the packets from 10 on
+    fn forward(forward: &mut ForwardedAudio, forwarded: &[bool]) -> Vec<Option<(u16, u32)>> {
+        forwarded
+            .iter()
+            .zip(10u16..)
+            .map(|(&forwarded, seq)| {
+                forward
+                    .forward(&packet(seq, seq as u32 * 960), forwarded)
+                    .map(|packet| (packet.header.sequence_number, packet.header.timestamp))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn forwards_packets_unchanged_until_left_out() {
+        let mut forwarded = ForwardedAudio::default();
+        assert_eq!(
+            forward(&mut forwarded, &[true, true]),
+            vec![Some((10, 9600)), Some((11, 10560))]
+        );
+    }
+
+    #[test]
+    fn continues_the_numbering_after_packets_left_out() {
+        let mut forwarded = ForwardedAudio::default();
+        let received = forward(&mut forwarded, &[true, true, false, false, true, true]);
+        // Only the timestamps tell the time that passed
+        assert_eq!(received[2..4], [None, None]);
+        assert_eq!(received[4..], [Some((12, 13440)), Some((13, 14400))]);
+    }
+
+    #[test]
+    fn keeps_the_gaps_of_lost_packets() {
+        let mut forwarded = ForwardedAudio::default();
+        forwarded.forward(&packet(10, 0), true);
+        let packet = forwarded.forward(&packet(12, 0), true).unwrap();
+        assert_eq!(packet.header.sequence_number, 12);
+    }
+
+    #[test]
+    fn continues_the_numbering_across_the_wraparound() {
+        let mut forwarded = ForwardedAudio::default();
+        forwarded.forward(&packet(65535, 0), true);
+        forwarded.forward(&packet(0, 0), false);
+        let packet = forwarded.forward(&packet(1, 0), true).unwrap();
+        assert_eq!(packet.header.sequence_number, 0);
+    }
+}
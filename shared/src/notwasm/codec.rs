@@ -0,0 +1,344 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use serde::de::{IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use strum::VariantNames;
+
+use crate::{Error, Feature, SequencedMessage, WebSocketMessage};
+
+/**
+ * How messages are written to the websocket. JSON text frames are the default,
+ * MessagePack binary frames are used once both sides negotiated `Feature::MessagePack`.
+ * Either side reads both, decided by the type of the frame.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Encoding {
+    pub fn negotiated(features: &HashSet<Feature>) -> Self {
+        if features.contains(&Feature::MessagePack) {
+            Encoding::MessagePack
+        } else {
+            Encoding::Json
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, message: &T) -> Result<Frame, Error> {
+        match self {
+            Encoding::Json => Ok(Frame::Text(serde_json::to_string(message)?)),
+            // Named fields, so that unknown variants can be told apart like in JSON
+            Encoding::MessagePack => Ok(Frame::Binary(rmp_serde::to_vec_named(message)?)),
+        }
+    }
+}
+
+/**
+ * Name of the variant of an externally tagged enum, read without its content. Unit variants
+ * are written as their name, the others as a map from their name to their content.
+ */
+struct VariantTag(Option<String>);
+
+impl VariantTag {
+    fn is_unknown<T: VariantNames>(&self) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|tag| !T::VARIANTS.contains(&tag.as_str()))
+    }
+}
+
+impl<'de> Deserialize<'de> for VariantTag {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TagVisitor;
+
+        impl<'de> Visitor<'de> for TagVisitor {
+            type Value = VariantTag;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an enum variant")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, tag: &str) -> Result<VariantTag, E> {
+                Ok(VariantTag(Some(tag.to_string())))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<VariantTag, A::Error> {
+                let tag = map.next_key::<String>()?;
+                map.next_value::<IgnoredAny>()?;
+                // Like in `from_value`, only a map with a single entry is a variant
+                if map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {
+                    while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+                    return Ok(VariantTag(None));
+                }
+                Ok(VariantTag(tag))
+            }
+        }
+
+        deserializer.deserialize_any(TagVisitor)
+    }
+}
+
+impl WebSocketMessage {
+    /**
+     * Like `from_json`, for MessagePack binary frames. The tag is read first and the message
+     * only decoded when it is known, straight from MessagePack, which writes ids as bytes.
+     */
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Option<Self>, Error> {
+        if rmp_serde::from_slice::<VariantTag>(bytes)?.is_unknown::<Self>() {
+            return Ok(None);
+        }
+        Ok(Some(rmp_serde::from_slice(bytes)?))
+    }
+}
+
+impl SequencedMessage {
+    /**
+     * Like `from_json`, for MessagePack binary frames.
+     */
+    pub fn from_msgpack(bytes: &[u8]) -> Result<(u64, Option<WebSocketMessage>), Error> {
+        #[derive(Deserialize)]
+        struct RawSequencedMessage {
+            seq: u64,
+            message: VariantTag,
+        }
+        let raw = rmp_serde::from_slice::<RawSequencedMessage>(bytes)?;
+        if raw.message.is_unknown::<WebSocketMessage>() {
+            return Ok((raw.seq, None));
+        }
+        let sequenced = rmp_serde::from_slice::<SequencedMessage>(bytes)?;
+        Ok((sequenced.seq, Some(sequenced.message)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::models::{
+        AudioChannelMemberUpdate, CameraState, Channel, ChannelType, ChannelWithUsers,
+        DominantSpeaker, ScreenShareState, VoiceState, VoiceUser,
+    };
+    use crate::{RpcError, RpcRequest, RpcResponse};
+
+    fn channel() -> Channel {
+        Channel {
+            id: Uuid::new_v4(),
+            name: "General".to_string(),
+            type_: ChannelType::Voice,
+            hidden: false,
+            server_id: Uuid::new_v4(),
+            created_at: chrono::NaiveDateTime::default(),
+            updated_at: chrono::NaiveDateTime::default(),
+            user_limit: Some(5),
+        }
+    }
+
+    fn user() -> VoiceUser {
+        VoiceUser {
+            id: Uuid::new_v4(),
+            username: "alice".to_string(),
+            slot: 1,
+            boost: None,
+            locally_muted: false,
+            camera: true,
+            screen_share: false,
+            muted: true,
+            deafened: false,
+        }
+    }
+
+    /// Every message that carries an id, which MessagePack writes as bytes rather than a string
+    fn messages_with_ids() -> Vec<WebSocketMessage> {
+        let id = Uuid::new_v4();
+        let member = AudioChannelMemberUpdate {
+            channel: channel(),
+            user: user(),
+        };
+        vec![
+            WebSocketMessage::JoinAudioChannel {
+                server_id: id,
+                channel_id: id,
+            },
+            WebSocketMessage::SomeoneJoinedAudioChannel {
+                data: member.clone(),
+            },
+            WebSocketMessage::SomeoneLeftAudioChannel { data: member },
+            WebSocketMessage::DominantSpeakerChanged {
+                data: DominantSpeaker {
+                    channel_id: id,
+                    user_id: Some(Uuid::new_v4()),
+                },
+            },
+            WebSocketMessage::DominantSpeakerChanged {
+                data: DominantSpeaker {
+                    channel_id: id,
+                    user_id: None,
+                },
+            },
+            WebSocketMessage::SpeakingStarted { user_id: id },
+            WebSocketMessage::SpeakingStopped { user_id: id },
+            WebSocketMessage::CameraChanged {
+                data: CameraState {
+                    channel_id: id,
+                    user_id: id,
+                    enabled: true,
+                },
+            },
+            WebSocketMessage::SetVideoSubscriptions {
+                user_ids: vec![id, Uuid::new_v4()],
+            },
+            WebSocketMessage::VoiceStateChanged {
+                data: VoiceState {
+                    channel_id: id,
+                    user_id: id,
+                    muted: true,
+                    deafened: true,
+                },
+            },
+            WebSocketMessage::ScreenShareChanged {
+                data: ScreenShareState {
+                    channel_id: id,
+                    user_id: id,
+                    sharing: true,
+                    audio: false,
+                },
+            },
+            WebSocketMessage::WatchScreenShare { user_id: id },
+            WebSocketMessage::StopWatchingScreenShare { user_id: id },
+            WebSocketMessage::SetVideoSize {
+                user_id: id,
+                screen: true,
+                height: 720,
+            },
+            WebSocketMessage::VoiceTakenOver { channel_id: id },
+            WebSocketMessage::SessionStarted {
+                session_id: id,
+                heartbeat_interval_ms: 15000,
+            },
+            WebSocketMessage::Resume {
+                session_id: id,
+                last_seq: 7,
+            },
+            WebSocketMessage::Resumed { session_id: id },
+            WebSocketMessage::Request {
+                id: 1,
+                request: RpcRequest::ListChannels { server_id: id },
+            },
+            WebSocketMessage::Request {
+                id: 2,
+                request: RpcRequest::GetCallQuality {
+                    server_id: id,
+                    channel_id: id,
+                },
+            },
+            WebSocketMessage::Response {
+                id: 1,
+                result: Ok(RpcResponse::Channels(vec![ChannelWithUsers {
+                    channel: channel(),
+                    users: vec![user()],
+                }])),
+            },
+            WebSocketMessage::Response {
+                id: 2,
+                result: Err(RpcError::NotFound),
+            },
+        ]
+    }
+
+    /// Messages do not implement `PartialEq`, their JSON is compared instead
+    fn assert_same(decoded: &WebSocketMessage, original: &WebSocketMessage) {
+        assert_eq!(
+            serde_json::to_value(decoded).unwrap(),
+            serde_json::to_value(original).unwrap()
+        );
+    }
+
+    fn msgpack<T: Serialize>(message: &T) -> Vec<u8> {
+        match Encoding::MessagePack.encode(message).unwrap() {
+            Frame::Binary(bytes) => bytes,
+            Frame::Text(_) => panic!("MessagePack is written as binary frames"),
+        }
+    }
+
+    #[test]
+    fn msgpack_round_trips_messages_with_ids() {
+        for message in messages_with_ids() {
+            let decoded = WebSocketMessage::from_msgpack(&msgpack(&message)).unwrap();
+            assert_same(&decoded.expect("known variant"), &message);
+
+            let sequenced = SequencedMessage {
+                seq: 42,
+                message: message.clone(),
+            };
+            let (seq, decoded) = SequencedMessage::from_msgpack(&msgpack(&sequenced)).unwrap();
+            assert_eq!(seq, 42);
+            assert_same(&decoded.expect("known variant"), &message);
+        }
+    }
+
+    #[test]
+    fn msgpack_round_trips_unit_variants() {
+        for message in [
+            WebSocketMessage::FullRefresh,
+            WebSocketMessage::StopScreenShare,
+        ] {
+            let decoded = WebSocketMessage::from_msgpack(&msgpack(&message)).unwrap();
+            assert_same(&decoded.expect("known variant"), &message);
+        }
+    }
+
+    /// Variants a newer build could send
+    #[derive(Serialize)]
+    enum NewerMessage {
+        RaisedHand { user_id: Uuid, channel_id: Uuid },
+        Shrug,
+    }
+
+    #[test]
+    fn msgpack_skips_unknown_variants() {
+        let newer = [
+            NewerMessage::RaisedHand {
+                user_id: Uuid::new_v4(),
+                channel_id: Uuid::new_v4(),
+            },
+            NewerMessage::Shrug,
+        ];
+        for message in newer {
+            assert!(
+                WebSocketMessage::from_msgpack(&msgpack(&message))
+                    .unwrap()
+                    .is_none()
+            );
+
+            #[derive(Serialize)]
+            struct NewerSequenced {
+                seq: u64,
+                message: NewerMessage,
+            }
+            let sequenced = msgpack(&NewerSequenced { seq: 9, message });
+            let (seq, decoded) = SequencedMessage::from_msgpack(&sequenced).unwrap();
+            assert_eq!(seq, 9);
+            assert!(decoded.is_none());
+        }
+    }
+
+    #[test]
+    fn msgpack_rejects_malformed_known_variants() {
+        #[derive(Serialize)]
+        enum Malformed {
+            SpeakingStarted { user_id: u32 },
+        }
+        let bytes = msgpack(&Malformed::SpeakingStarted { user_id: 3 });
+        assert!(WebSocketMessage::from_msgpack(&bytes).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use webrtc::rtp::packet::Packet;
+
+use crate::models::JitterBufferStats;
+
+/// Fewest and most frames held back before playing, the target moves in between with the jitter
+const MIN_TARGET_FRAMES: usize = 2;
+const MAX_TARGET_FRAMES: usize = 10;
+/// Frames over the target the buffer can hold before the oldest ones are dropped
+const OVERRUN_MARGIN: usize = 5;
+/// A sequence number this far ahead starts the stream over instead of being concealed
+const MAX_GAP: i64 = 100;
+/// A sequence number this far behind the highest one starts the stream over too
+const MAX_MISORDER: i64 = 100;
+
+/// What to play next
+#[derive(Debug, Clone)]
+pub enum Playout {
+    /// Decode the packet
+    Decode(Packet),
+    /// The packet is lost, decode its FEC from the packet after it
+    Fec(Packet),
+    /// The packet is lost without FEC, let the decoder conceal it
+    Conceal,
+}
+
+/**
+ * Orders the packets of a received audio stream by sequence number and holds them back
+ * for a delay that follows the jitter of their arrival, so that reordered packets still
+ * play in order and lost ones can be concealed.
+ */
+#[derive(Debug)]
+pub struct JitterBuffer {
+    /// Packets keyed by extended sequence number
+    packets: BTreeMap<u64, Packet>,
+    /// Highest extended sequence number received
+    highest: Option<u64>,
+    /// Extended sequence number to play next, None until playing started
+    next: Option<u64>,
+    /// Waiting for the buffer to reach the target before playing
+    buffering: bool,
+    /// Extended sequence number and timestamp of the last packet played
+    played: Option<(u64, u32)>,
+    /// Ran empty while playing, the next packet tells an underrun from a pause of the sender
+    drained: bool,
+    clock_rate: u32,
+    frame_ms: f64,
+    target: usize,
+    started: Instant,
+    /// Relative transit time of the previous packet and the smoothed jitter, in milliseconds
+    transit: Option<f64>,
+    jitter: f64,
+    stats: JitterBufferStats,
+}
+
+impl JitterBuffer {
+    pub fn new(clock_rate: u32, frame_ms: f64) -> Self {
+        JitterBuffer {
+            packets: BTreeMap::new(),
+            highest: None,
+            next: None,
+            buffering: true,
+            played: None,
+            drained: false,
+            clock_rate,
+            frame_ms,
+            target: MIN_TARGET_FRAMES,
+            started: Instant::now(),
+            transit: None,
+            jitter: 0.0,
+            stats: JitterBufferStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, packet: Packet) {
+        self.push_at(packet, Instant::now());
+    }
+
+    /// Like `push`, for a packet that arrived at `arrival`
+    pub fn push_at(&mut self, packet: Packet, arrival: Instant) {
+        let seq = packet.header.sequence_number;
+        let seq = match self.highest {
+            None => seq as u64,
+            Some(highest) => {
+                let delta = seq.wrapping_sub(highest as u16) as i16 as i64;
+                if delta > MAX_GAP || delta < -MAX_MISORDER {
+                    self.reset();
+                    // Past everything numbered so far
+                    (highest | 0xffff) + 1 + seq as u64
+                } else if delta < 0 && (-delta) as u64 > highest {
+                    // Reordered before the first packet of the stream
+                    return;
+                } else {
+                    highest.wrapping_add_signed(delta)
+                }
+            }
+        };
+        self.highest = Some(self.highest.map_or(seq, |highest| highest.max(seq)));
+        if self.next.is_some_and(|next| seq < next) {
+            self.stats.late += 1;
+            return;
+        }
+        if self.drained {
+            self.drained = false;
+            if !self.follows_a_pause(seq, packet.header.timestamp) {
+                self.stats.underruns += 1;
+            }
+        }
+        self.update_jitter(packet.header.timestamp, arrival);
+        self.packets.insert(seq, packet);
+
+        if self.packets.len() > self.target + OVERRUN_MARGIN {
+            self.stats.overruns += 1;
+            while self.packets.len() > self.target {
+                self.packets.pop_first();
+            }
+            self.next = self.packets.first_key_value().map(|(seq, _)| *seq);
+        }
+    }
+
+    /**
+     * Returns the next frame to play, or None while the buffer is filling up.
+     */
+    pub fn pop(&mut self) -> Option<Playout> {
+        if self.buffering {
+            if self.packets.len() < self.target {
+                return None;
+            }
+            self.buffering = false;
+        }
+        let Some((&first, _)) = self.packets.first_key_value() else {
+            self.buffering = true;
+            self.drained = true;
+            return None;
+        };
+        let next = self.next.unwrap_or(first);
+        self.next = Some(next + 1);
+        if first == next {
+            let packet = self.packets.remove(&first)?;
+            self.played = Some((first, packet.header.timestamp));
+            return Some(Playout::Decode(packet));
+        }
+        if first == next + 1 {
+            self.stats.recovered += 1;
+            return self.packets.get(&first).cloned().map(Playout::Fec);
+        }
+        self.stats.concealed += 1;
+        Some(Playout::Conceal)
+    }
+
+    pub fn stats(&self) -> JitterBufferStats {
+        JitterBufferStats {
+            target_delay_ms: (self.target as f64 * self.frame_ms) as u32,
+            buffered_ms: (self.packets.len() as f64 * self.frame_ms) as u32,
+            ..self.stats.clone()
+        }
+    }
+
+    fn reset(&mut self) {
+        self.packets.clear();
+        self.next = None;
+        self.buffering = true;
+        self.played = None;
+        self.drained = false;
+        self.transit = None;
+        self.stats.resets += 1;
+    }
+
+    /**
+     * Whether the timestamp of the packet numbered `seq` is past the frames since the last
+     * one played. Frames left out for DTX only move the timestamp on, so the buffer running
+     * empty before it was a pause of the sender and not an underrun.
+     */
+    fn follows_a_pause(&self, seq: u64, timestamp: u32) -> bool {
+        let Some((played_seq, played_timestamp)) = self.played else {
+            return false;
+        };
+        let frame_samples = self.clock_rate as f64 * self.frame_ms / 1000.0;
+        let frames = seq.saturating_sub(played_seq) as f64;
+        let expected = played_timestamp.wrapping_add((frames * frame_samples) as u32);
+        timestamp.wrapping_sub(expected) as i32 as f64 > frame_samples / 2.0
+    }
+
+    /// Interarrival jitter as in RFC 3550, the target delay covers twice of it
+    fn update_jitter(&mut self, timestamp: u32, arrival: Instant) {
+        let arrival = arrival
+            .saturating_duration_since(self.started)
+            .as_secs_f64()
+            * 1000.0;
+        let transit = arrival - timestamp as f64 * 1000.0 / self.clock_rate as f64;
+        if let Some(previous) = self.transit {
+            let difference = (transit - previous).abs();
+            // A wrapped or restarted timestamp is not jitter
+            if difference < MAX_TARGET_FRAMES as f64 * self.frame_ms * 10.0 {
+                self.jitter += (difference - self.jitter) / 16.0;
+            }
+        }
+        self.transit = Some(transit);
+        let frames = ((2.0 * self.jitter) / self.frame_ms).ceil() as usize + 1;
+        self.target = frames.clamp(MIN_TARGET_FRAMES, MAX_TARGET_FRAMES);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use webrtc::rtp::header::Header;
+
+    use super::*;
+    use crate::notwasm::my_web_rtc::{AudioPacketizer, DTX_KEEPALIVE_FRAMES};
+
+    const CLOCK_RATE: u32 = 48000;
+    const FRAME_MS: u64 = 20;
+    const FRAME_SAMPLES: u32 = 960;
+
+    fn packet(seq: u16, timestamp: u32) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number: seq,
+                timestamp,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Played {
+        Decode(u16),
+        Fec(u16),
+        Conceal,
+    }
+
+    fn pop(buffer: &mut JitterBuffer) -> Option<Played> {
+        buffer.pop().map(|playout| match playout {
+            Playout::Decode(packet) => Played::Decode(packet.header.sequence_number),
+            Playout::Fec(packet) => Played::Fec(packet.header.sequence_number),
+            Playout::Conceal => Played::Conceal,
+        })
+    }
+
+    /// Pushes the packet of the `frame`th frame as it arrives on time, without jitter
+    fn push(buffer: &mut JitterBuffer, start: Instant, seq: u16, frame: u64) {
+        let arrival = start + Duration::from_millis(frame * FRAME_MS);
+        buffer.push_at(packet(seq, frame as u32 * FRAME_SAMPLES), arrival);
+    }
+
+    fn buffer_of(seqs: &[u16]) -> JitterBuffer {
+        let mut buffer = JitterBuffer::new(CLOCK_RATE, FRAME_MS as f64);
+        let start = Instant::now();
+        for &seq in seqs {
+            push(&mut buffer, start, seq, seq as u64);
+        }
+        buffer
+    }
+
+    #[test]
+    fn plays_reordered_packets_in_order() {
+        let mut buffer = buffer_of(&[0, 2, 1, 3]);
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(0)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(1)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(2)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(3)));
+        let stats = buffer.stats();
+        assert_eq!((stats.recovered, stats.concealed, stats.late), (0, 0, 0));
+    }
+
+    #[test]
+    fn waits_for_the_target_before_playing() {
+        let mut buffer = buffer_of(&[0]);
+        assert_eq!(pop(&mut buffer), None);
+        assert_eq!(buffer.stats().underruns, 0);
+        push(&mut buffer, Instant::now(), 1, 1);
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(0)));
+    }
+
+    #[test]
+    fn recovers_a_single_loss_from_fec() {
+        let mut buffer = buffer_of(&[0, 1, 3, 4]);
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(0)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(1)));
+        assert_eq!(pop(&mut buffer), Some(Played::Fec(3)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(3)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(4)));
+        let stats = buffer.stats();
+        assert_eq!((stats.recovered, stats.concealed), (1, 0));
+    }
+
+    #[test]
+    fn conceals_longer_gaps() {
+        let mut buffer = buffer_of(&[0, 1, 5, 6]);
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(0)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(1)));
+        assert_eq!(pop(&mut buffer), Some(Played::Conceal));
+        assert_eq!(pop(&mut buffer), Some(Played::Conceal));
+        assert_eq!(pop(&mut buffer), Some(Played::Fec(5)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(5)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(6)));
+        let stats = buffer.stats();
+        assert_eq!((stats.recovered, stats.concealed), (1, 2));
+    }
+
+    #[test]
+    fn counts_late_packets() {
+        let mut buffer = buffer_of(&[0, 1, 2]);
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(0)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(1)));
+        push(&mut buffer, Instant::now(), 1, 1);
+        assert_eq!(buffer.stats().late, 1);
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(2)));
+    }
+
+    #[test]
+    fn counts_underruns_and_buffers_again() {
+        let start = Instant::now();
+        let mut buffer = buffer_of(&[0, 1]);
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(0)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(1)));
+        assert_eq!(pop(&mut buffer), None);
+        // Told from a pause once the stream goes on where it stopped
+        assert_eq!(buffer.stats().underruns, 0);
+        push(&mut buffer, start, 2, 2);
+        assert_eq!(buffer.stats().underruns, 1);
+        assert_eq!(pop(&mut buffer), None);
+        push(&mut buffer, start, 3, 3);
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(2)));
+        assert_eq!(buffer.stats().underruns, 1);
+    }
+
+    #[test]
+    fn drops_the_oldest_packets_on_overrun() {
+        let seqs = (0..=(MIN_TARGET_FRAMES + OVERRUN_MARGIN) as u16).collect::<Vec<_>>();
+        let mut buffer = buffer_of(&seqs);
+        let stats = buffer.stats();
+        assert_eq!(stats.overruns, 1);
+        assert_eq!(stats.buffered_ms, stats.target_delay_ms);
+        let last = *seqs.last().unwrap();
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(last - 1)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(last)));
+    }
+
+    #[test]
+    fn follows_the_sequence_number_across_the_wraparound() {
+        let mut buffer = JitterBuffer::new(CLOCK_RATE, FRAME_MS as f64);
+        let start = Instant::now();
+        for (frame, seq) in [65534, 0, 65535, 1].into_iter().enumerate() {
+            push(&mut buffer, start, seq, frame as u64);
+        }
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(65534)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(65535)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(0)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(1)));
+        let stats = buffer.stats();
+        assert_eq!((stats.resets, stats.concealed, stats.late), (0, 0, 0));
+    }
+
+    #[test]
+    fn starts_over_after_a_jump_of_the_sequence_number() {
+        let start = Instant::now();
+        let mut buffer = buffer_of(&[0, 1]);
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(0)));
+        let restart = 1 + MAX_GAP as u16 + 1;
+        push(&mut buffer, start, restart, 2);
+        assert_eq!(buffer.stats().resets, 1);
+        // The packet before the jump is dropped and the buffer fills up again
+        assert_eq!(pop(&mut buffer), None);
+        push(&mut buffer, start, restart + 1, 3);
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(restart)));
+        assert_eq!(pop(&mut buffer), Some(Played::Decode(restart + 1)));
+        // A gap up to the limit is concealed instead
+        push(&mut buffer, start, restart + 1 + MAX_GAP as u16, 4);
+        assert_eq!(buffer.stats().resets, 1);
+    }
+
+    /**
+     * Sends a talk spurt, a pause with DTX and another talk spurt like the microphone track
+     * does, one frame every 20 ms, and plays it out at the same pace. The left out frames
+     * move the timestamp on if `skip_timestamps`. Returns the highest target and the stats.
+     */
+    fn play_dtx_pause(skip_timestamps: bool) -> (u32, JitterBufferStats) {
+        let mut buffer = JitterBuffer::new(CLOCK_RATE, FRAME_MS as f64);
+        let mut packetizer = AudioPacketizer::new(FRAME_SAMPLES);
+        let start = Instant::now();
+        let mut highest_target = 0;
+        for frame in 0..300u64 {
+            let silent = (50..250).contains(&frame);
+            if silent && (frame - 49) % DTX_KEEPALIVE_FRAMES as u64 != 0 {
+                if skip_timestamps {
+                    packetizer.skip();
+                }
+            } else {
+                let arrival = start + Duration::from_millis(frame * FRAME_MS);
+                buffer.push_at(packetizer.packetize(Vec::new()), arrival);
+            }
+            buffer.pop();
+            highest_target = highest_target.max(buffer.stats().target_delay_ms);
+        }
+        (highest_target, buffer.stats())
+    }
+
+    #[test]
+    fn dtx_pause_is_neither_loss_nor_underrun() {
+        let (highest_target, stats) = play_dtx_pause(true);
+        assert_eq!(highest_target, MIN_TARGET_FRAMES as u32 * FRAME_MS as u32);
+        assert_eq!(
+            (
+                stats.concealed,
+                stats.recovered,
+                stats.underruns,
+                stats.resets
+            ),
+            (0, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn dtx_pause_without_timestamp_skip_looks_like_jitter() {
+        let (highest_target, stats) = play_dtx_pause(false);
+        assert!(highest_target > MIN_TARGET_FRAMES as u32 * FRAME_MS as u32);
+        assert!(stats.underruns > 0);
+    }
+}
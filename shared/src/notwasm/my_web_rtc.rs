@@ -1,21 +1,44 @@
-use ringbuf::{HeapCons, HeapProd, traits::{Consumer, Observer, Producer}};
+use ringbuf::{HeapCons, HeapProd, HeapRb, traits::{Consumer, Observer, Producer, Split}};
 use uuid::Uuid;
-use std::sync::{Arc, Mutex as StdMutex};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+use std::time::Duration;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
+use webrtc::api::interceptor_registry::{configure_twcc_sender_only, register_default_interceptors};
 use webrtc::api::setting_engine::SettingEngine;
+use webrtc::interceptor::registry::Registry;
 use webrtc::ice::udp_network::EphemeralUDP;
 use webrtc::ice::udp_network::UDPNetwork;
+use webrtc::rtp::extension::HeaderExtension;
+use webrtc::rtp::extension::audio_level_extension::AudioLevelExtension;
+use webrtc::media::io::sample_builder::SampleBuilder;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::FullIntraRequest;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtp::codecs::h264::H264Packet;
+use webrtc::rtp::codecs::vp8::Vp8Packet;
+use webrtc::rtp::codecs::vp9::Vp9Packet;
+use webrtc::rtp::header::Header;
 use webrtc::rtp::packet::Packet;
+use webrtc::rtp::packetizer::Depacketizer;
 use webrtc::track::track_local::TrackLocalWriter;
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 
 use crate::{models::TurnCreds, Error};
+use super::active_speakers::{
+    audio_level, ActiveSpeakers, ForwardedAudio, AUDIO_LEVEL_URI, VOICE_ACTIVITY_LEVEL,
+};
+use super::jitter_buffer::{JitterBuffer, Playout};
+use super::opus_tuning::OpusTuning;
+use super::quality::QualityMonitor;
+use super::simulcast::{estimate_bandwidth, is_keyframe, Layer, LayerSample, LayerSelector};
+use crate::models::ConnectionQuality;
 use crate::WebSocketMessage;
 
 use opus::{Application, Channels};
-use tokio::sync::Mutex;
-use webrtc::api::media_engine::MIME_TYPE_OPUS;
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::sync::mpsc::Receiver;
+use webrtc::api::media_engine::{MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_VP8, MIME_TYPE_VP9};
 use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::media::Sample;
@@ -23,8 +46,15 @@ use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::offer_answer_options::{RTCAnswerOptions, RTCOfferOptions};
 use webrtc::peer_connection::policy::bundle_policy::RTCBundlePolicy;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
-use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
+use webrtc::rtp_transceiver::rtp_codec::{
+    RTCRtpCodecCapability, RTCRtpHeaderExtensionCapability, RTPCodecType,
+};
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::rtp_transceiver::{RTCPFeedback, RTCRtpTransceiverInit};
 use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_remote::TrackRemote;
+use webrtc::util::Unmarshal;
 use webrtc::{
     api::media_engine::MediaEngine, peer_connection::RTCPeerConnection,
     rtp_transceiver::rtp_codec::RTCRtpCodecParameters,
@@ -34,11 +64,232 @@ pub struct WebRTCConnection {
     pub peer_connection: RTCPeerConnection,
     pub audio_config: AudioConfig,
     pub room_id: Uuid,
+    /// Arrival statistics of the received streams, see `quality`
+    quality_monitor: Arc<QualityMonitor>,
+}
+
+/// Decoded audio of a single remote track, waiting to be mixed by the speaker
+pub struct AudioSink {
+    /// Slot of the person in the voice room the audio comes from
+    pub slot: usize,
+    /// SSRC of the track feeding this sink, used to avoid removing a newer sink
+    /// that reuses the same slot
+    pub ssrc: u32,
+    pub consumer: HeapCons<f32>,
+}
+
+/// Audio sinks keyed by the id of the remote track, a person can send their microphone
+/// and the audio of their screen share
+pub type AudioSinks = Arc<StdMutex<HashMap<String, AudioSink>>>;
+
+/// Encoded frames of a single remote video track, waiting to be decoded by the UI
+pub struct VideoSink {
+    /// Slot of the person in the voice room the video comes from
+    pub slot: usize,
+    /// SSRC of the track feeding this sink, used to avoid removing a newer sink
+    /// that reuses the same slot
+    pub ssrc: u32,
+    pub mime_type: String,
+    pub consumer: HeapCons<Sample>,
+}
+
+/// Video sinks keyed by the id of the remote track, see `PublishedTrack::forward_id`
+pub type VideoSinks = Arc<StdMutex<HashMap<String, VideoSink>>>;
+
+/// How many packets a video frame can wait for its missing packets before it is dropped
+const VIDEO_MAX_LATE: u16 = 256;
+/// How often the task asking a publisher for keyframes checks whether it is still connected
+const KEYFRAME_REQUEST_POLL: Duration = Duration::from_secs(1);
+/// Audio levels (in -dBov) at or above this are left out while DTX is on
+const DTX_SILENCE_LEVEL: u8 = 70;
+/// One in this many silent frames is still sent so that the stream does not look dead, 400 ms like Opus
+pub const DTX_KEEPALIVE_FRAMES: u32 = 20;
+/// How often decoded audio is moved from the jitter buffer to the speaker ring buffer
+const PLAYOUT_INTERVAL: Duration = Duration::from_millis(5);
+/// Frames kept in the speaker ring buffer ahead of the speaker, the rest waits in the jitter buffer
+const PLAYOUT_FRAMES: usize = 2;
+
+/// Sample tracks of the layers of a published video track
+pub type LayerTracks = HashMap<Layer, Arc<TrackLocalStaticSample>>;
+
+/**
+ * Optional tracks a person in a voice room can publish besides their microphone.
+ * They are only forwarded to the people that subscribed to them.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PublishedTrack {
+    Camera,
+    Screen,
+    /// Audio of the applications on the shared screen
+    ScreenAudio,
+}
+
+impl PublishedTrack {
+    pub const ALL: [PublishedTrack; 3] = [
+        PublishedTrack::Camera,
+        PublishedTrack::Screen,
+        PublishedTrack::ScreenAudio,
+    ];
+
+    pub fn is_video(&self) -> bool {
+        matches!(self, PublishedTrack::Camera | PublishedTrack::Screen)
+    }
+
+    pub fn kind(&self) -> RTPCodecType {
+        if self.is_video() {
+            RTPCodecType::Video
+        } else {
+            RTPCodecType::Audio
+        }
+    }
+
+    /// Id of the track sent by the client
+    fn client_id(&self) -> &'static str {
+        match self {
+            PublishedTrack::Camera => "client-video",
+            PublishedTrack::Screen => "client-screen",
+            PublishedTrack::ScreenAudio => "client-screen-audio",
+        }
+    }
+
+    /// Id of the track that forwards this track of the person in `slot`
+    pub fn forward_id(&self, slot: usize) -> String {
+        match self {
+            PublishedTrack::Camera => format!("server-video-{}", slot),
+            PublishedTrack::Screen => format!("server-screen-{}", slot),
+            PublishedTrack::ScreenAudio => format!("server-screen-audio-{}", slot),
+        }
+    }
+
+    fn from_client_id(id: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|track| track.client_id() == id)
+    }
+}
+
+/**
+ * Track of a person in the voice room, forwarded to the people that subscribed to it.
+ * Video can be received in several simulcast layers, each subscriber gets one of them.
+ */
+#[derive(Default)]
+pub struct TrackForward {
+    /// SSRC of each layer being received, needed to ask for keyframes
+    pub layers: StdMutex<HashMap<Layer, u32>>,
+    /// Tracks that carry it, keyed by the slot of the subscriber
+    pub tracks: Mutex<HashMap<usize, ForwardedTrack>>,
+    /// Layers subscribers need a keyframe of, see `WebRTCConnection::request_wanted_keyframes`
+    keyframes_wanted: StdMutex<HashSet<Layer>>,
+    keyframe_notify: Notify,
+}
+
+/// Track carrying a published track to one subscriber
+pub struct ForwardedTrack {
+    pub track: Arc<TrackLocalStaticRTP>,
+    /// Layer the subscriber receives, shared with the task reading its feedback
+    pub selector: Arc<StdMutex<LayerSelector>>,
+}
+
+impl ForwardedTrack {
+    pub fn new(track: Arc<TrackLocalStaticRTP>) -> Self {
+        ForwardedTrack {
+            track,
+            selector: Arc::new(StdMutex::new(LayerSelector::default())),
+        }
+    }
+}
+
+impl TrackForward {
+    /// Layers being received, smallest first
+    fn available_layers(&self) -> Vec<Layer> {
+        let mut layers: Vec<Layer> = self.layers.lock().unwrap().keys().copied().collect();
+        layers.sort();
+        layers
+    }
+
+    /**
+     * Asks the publisher for a keyframe of the layer.
+     */
+    pub fn want_keyframe(&self, layer: Layer) {
+        self.keyframes_wanted.lock().unwrap().insert(layer);
+        self.keyframe_notify.notify_one();
+    }
+
+    /**
+     * Picks the layer of a subscriber again after its request, its estimate
+     * or the received layers changed.
+     */
+    pub fn retarget(&self, selector: &StdMutex<LayerSelector>) {
+        let available = self.available_layers();
+        let switch_to = selector.lock().unwrap().retarget(&available);
+        if let Some(layer) = switch_to {
+            self.want_keyframe(layer);
+        }
+    }
+
+    async fn retarget_all(&self) {
+        for forwarded in self.tracks.lock().await.values() {
+            self.retarget(&forwarded.selector);
+        }
+    }
+
+    async fn layer_started(&self, layer: Layer, ssrc: u32) {
+        self.layers.lock().unwrap().insert(layer, ssrc);
+        self.retarget_all().await;
+    }
+
+    async fn layer_stopped(&self, layer: Layer, ssrc: u32) {
+        {
+            let mut layers = self.layers.lock().unwrap();
+            // A newer track of the same layer may already have replaced it
+            if layers.get(&layer) != Some(&ssrc) {
+                return;
+            }
+            layers.remove(&layer);
+        }
+        for forwarded in self.tracks.lock().await.values() {
+            forwarded.selector.lock().unwrap().layer_stopped(layer);
+            self.retarget(&forwarded.selector);
+        }
+    }
+
+    /**
+     * Sends a packet of `layer` to the subscribers that receive that layer.
+     */
+    async fn forward(&self, layer: Layer, packet: &Packet, keyframe: bool) {
+        let forward_tracks = self.tracks.lock().await;
+        for forwarded in forward_tracks.values() {
+            let packet = forwarded.selector.lock().unwrap().forward(layer, packet, keyframe);
+            let Some(packet) = packet else {
+                continue;
+            };
+            if let Err(e) = forwarded.track.write_rtp(&packet).await {
+                tracing::error!("Error writing forwarded RTP packet: {}", e);
+            }
+        }
+    }
+}
+
+/// Forwarding of each published track of a person
+#[derive(Default)]
+pub struct PublishedForwards {
+    pub camera: Arc<TrackForward>,
+    pub screen: Arc<TrackForward>,
+    pub screen_audio: Arc<TrackForward>,
+}
+
+impl PublishedForwards {
+    pub fn get(&self, track: PublishedTrack) -> &Arc<TrackForward> {
+        match track {
+            PublishedTrack::Camera => &self.camera,
+            PublishedTrack::Screen => &self.screen,
+            PublishedTrack::ScreenAudio => &self.screen_audio,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct AudioConfig {
     pub sample_rate: u32,
+    /// Channels of the samples in the ring buffers, the encoder downmixes them for mono profiles
     pub channels: Channels,
     pub frame_size: usize,
     pub opus_max_payload_size: usize,
@@ -48,7 +299,7 @@ impl Default for AudioConfig {
     fn default() -> Self {
         AudioConfig {
             sample_rate: 48000,
-            channels: Channels::Mono,
+            channels: Channels::Stereo,
             frame_size: 960, // 20ms at 48kHz
             opus_max_payload_size: 1275,
         }
@@ -56,8 +307,12 @@ impl Default for AudioConfig {
 }
 
 impl AudioConfig {
-    pub fn get_opus_encoder(&self) -> Result<opus::Encoder, opus::Error> {
-        opus::Encoder::new(self.sample_rate, self.channels, Application::Audio)
+    pub fn get_opus_encoder(
+        &self,
+        application: Application,
+        channels: Channels,
+    ) -> Result<opus::Encoder, opus::Error> {
+        opus::Encoder::new(self.sample_rate, channels, application)
     }
 
     pub fn get_opus_decoder(&self) -> Result<opus::Decoder, opus::Error> {
@@ -65,6 +320,57 @@ impl AudioConfig {
     }
 }
 
+/**
+ * Numbers the packets of an outgoing audio track. Frames left out for DTX only move the
+ * timestamp on, the sequence numbers stay contiguous so that receivers do not take the
+ * pauses for loss.
+ */
+#[derive(Debug)]
+pub struct AudioPacketizer {
+    samples_per_frame: u32,
+    sequence_number: u16,
+    timestamp: u32,
+    /// Frames were left out before the next packet, it starts a talk spurt
+    talk_spurt: bool,
+}
+
+impl AudioPacketizer {
+    pub fn new(samples_per_frame: u32) -> Self {
+        // Random starting points as RFC 3550 asks for
+        let random = Uuid::new_v4().as_u128();
+        AudioPacketizer {
+            samples_per_frame,
+            sequence_number: random as u16,
+            timestamp: (random >> 16) as u32,
+            talk_spurt: true,
+        }
+    }
+
+    /// Leaves out the next frame
+    pub fn skip(&mut self) {
+        self.timestamp = self.timestamp.wrapping_add(self.samples_per_frame);
+        self.talk_spurt = true;
+    }
+
+    /// Packet of the next frame, the first one of a talk spurt has the marker bit set
+    pub fn packetize(&mut self, payload: Vec<u8>) -> Packet {
+        let packet = Packet {
+            header: Header {
+                version: 2,
+                marker: self.talk_spurt,
+                sequence_number: self.sequence_number,
+                timestamp: self.timestamp,
+                ..Default::default()
+            },
+            payload: payload.into(),
+        };
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(self.samples_per_frame);
+        self.talk_spurt = false;
+        packet
+    }
+}
+
 impl WebRTCConnection {
     pub async fn new(room_id: Uuid, turn_creds: Option<TurnCreds>) -> Result<Self, Error> {
         let peer_connection = Self::create_peer_connection(turn_creds).await?;
@@ -72,12 +378,27 @@ impl WebRTCConnection {
             peer_connection: peer_connection,
             audio_config: AudioConfig::default(),
             room_id,
+            quality_monitor: Arc::new(QualityMonitor::default()),
         })
     }
 
     pub async fn create_peer_connection(turn_creds: Option<TurnCreds>) -> Result<RTCPeerConnection, Error> {
         let mut m = MediaEngine::default();
         m.register_codec(Self::get_audio_codec(), RTPCodecType::Audio)?;
+        for codec in Self::get_video_codecs() {
+            m.register_codec(codec, RTPCodecType::Video)?;
+        }
+        m.register_header_extension(
+            RTCRtpHeaderExtensionCapability {
+                uri: AUDIO_LEVEL_URI.to_owned(),
+            },
+            RTPCodecType::Audio,
+            None,
+        )?;
+        // Reports, NACKs, the MID and RID extensions simulcast needs, and transport wide
+        // feedback in both directions for the bandwidth estimate of the subscribers
+        let registry = register_default_interceptors(Registry::new(), &mut m)?;
+        let registry = configure_twcc_sender_only(registry, &mut m)?;
 
         let mut udp = EphemeralUDP::default();
         udp.set_ports(12000, 13000)?;
@@ -87,6 +408,7 @@ impl WebRTCConnection {
 
         let api = webrtc::api::APIBuilder::new()
             .with_media_engine(m)
+            .with_interceptor_registry(registry)
             .with_setting_engine(settings_engine)
             .build();
 
@@ -133,8 +455,9 @@ impl WebRTCConnection {
             capability: RTCRtpCodecCapability {
                 mime_type: MIME_TYPE_OPUS.to_owned(),
                 clock_rate: 48000,
-                channels: 1,
-                sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
+                channels: 2,
+                // Stereo is only sent with the music profile, mono packets decode as stereo too
+                sdp_fmtp_line: "minptime=10;useinbandfec=1;usedtx=1;stereo=1;sprop-stereo=1".to_owned(),
                 rtcp_feedback: vec![],
             },
             payload_type: 111,
@@ -143,46 +466,383 @@ impl WebRTCConnection {
         audio_codec
     }
 
-    pub async fn create_audio_track_sample(
+    /**
+     * Video codecs in order of preference, VP8 is what our own clients send.
+     */
+    pub fn get_video_codecs() -> Vec<RTCRtpCodecParameters> {
+        let rtcp_feedback = vec![
+            RTCPFeedback {
+                typ: "goog-remb".to_owned(),
+                parameter: "".to_owned(),
+            },
+            RTCPFeedback {
+                typ: "ccm".to_owned(),
+                parameter: "fir".to_owned(),
+            },
+            RTCPFeedback {
+                typ: "nack".to_owned(),
+                parameter: "".to_owned(),
+            },
+            RTCPFeedback {
+                typ: "nack".to_owned(),
+                parameter: "pli".to_owned(),
+            },
+        ];
+        let codec = |mime_type: &str, sdp_fmtp_line: &str, payload_type: u8| RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: mime_type.to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                sdp_fmtp_line: sdp_fmtp_line.to_owned(),
+                rtcp_feedback: rtcp_feedback.clone(),
+            },
+            payload_type,
+            ..Default::default()
+        };
+        vec![
+            codec(MIME_TYPE_VP8, "", 96),
+            codec(MIME_TYPE_VP9, "profile-id=0", 98),
+            codec(
+                MIME_TYPE_H264,
+                "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f",
+                102,
+            ),
+        ]
+    }
+
+    /**
+     * Codec the remote peer sends the published track with, None before the first answer.
+     */
+    pub async fn negotiated_codec(&self, published: PublishedTrack) -> Option<RTCRtpCodecCapability> {
+        if !published.is_video() {
+            return Some(Self::get_audio_codec().capability);
+        }
+        for transceiver in self.peer_connection.get_transceivers().await {
+            if transceiver.kind() != RTPCodecType::Video {
+                continue;
+            }
+            let codecs = transceiver.receiver().await.get_parameters().await.codecs;
+            if let Some(codec) = codecs.into_iter().next() {
+                return Some(codec.capability);
+            }
+        }
+        None
+    }
+
+    pub async fn create_audio_track(
         &self,
-        n: usize,
-    ) -> Result<Vec<Arc<TrackLocalStaticSample>>, Error> {
-        let mut ret = Vec::new();
-        for id in 0..n {
-            let track = Arc::new(TrackLocalStaticSample::new(
-                Self::get_audio_codec().capability,
-                format!("client-audio-{}", id),
-                format!("client-audio-stream-{}", id),
-            ));
-            self.peer_connection.add_track(track.clone()).await?;
-            ret.push(track);
+    ) -> Result<(Arc<TrackLocalStaticRTP>, Arc<RTCRtpSender>), Error> {
+        let track = Arc::new(TrackLocalStaticRTP::new(
+            Self::get_audio_codec().capability,
+            "client-audio".to_owned(),
+            "client-audio-stream".to_owned(),
+        ));
+        let sender = self.peer_connection.add_track(track.clone()).await?;
+        Ok((track, sender))
+    }
+
+    /**
+     * Adds the camera track of this client, it only carries frames while the camera is on
+     * so that turning the camera on and off does not need a renegotiation.
+     * With `simulcast` it is sent in every `Layer`, otherwise only in the high one.
+     */
+    pub async fn create_video_track_sample(
+        &self,
+        simulcast: bool,
+    ) -> Result<(LayerTracks, Arc<RTCRtpSender>), Error> {
+        self.create_layered_track_sample(PublishedTrack::Camera, simulcast).await
+    }
+
+    /**
+     * Adds the screen share track of this client, like the camera it is always negotiated.
+     * Has to be added after the camera so that both match the transceivers of the server.
+     */
+    pub async fn create_screen_track_sample(
+        &self,
+        simulcast: bool,
+    ) -> Result<(LayerTracks, Arc<RTCRtpSender>), Error> {
+        self.create_layered_track_sample(PublishedTrack::Screen, simulcast).await
+    }
+
+    /**
+     * Adds the track for the audio of the shared applications, encoded like the microphone.
+     */
+    pub async fn create_screen_audio_track(
+        &self,
+    ) -> Result<(Arc<TrackLocalStaticRTP>, Arc<RTCRtpSender>), Error> {
+        let id = PublishedTrack::ScreenAudio.client_id();
+        let track = Arc::new(TrackLocalStaticRTP::new(
+            Self::get_audio_codec().capability,
+            id.to_owned(),
+            format!("{}-stream", id),
+        ));
+        let sender = self.peer_connection.add_track(track.clone()).await?;
+        Ok((track, sender))
+    }
+
+    async fn create_layered_track_sample(
+        &self,
+        published: PublishedTrack,
+        simulcast: bool,
+    ) -> Result<(LayerTracks, Arc<RTCRtpSender>), Error> {
+        if !simulcast {
+            let track = Self::published_track_sample(published, None);
+            let sender = self.peer_connection.add_track(track.clone()).await?;
+            return Ok((HashMap::from([(Layer::High, track)]), sender));
+        }
+        // The sender is created with the full video, the smaller layers are added as encodings
+        let track = Self::published_track_sample(published, Some(Layer::High));
+        let sender = self.peer_connection.add_track(track.clone()).await?;
+        let mut tracks = HashMap::from([(Layer::High, track)]);
+        for layer in [Layer::Medium, Layer::Low] {
+            let track = Self::published_track_sample(published, Some(layer));
+            sender.add_encoding(track.clone()).await?;
+            tracks.insert(layer, track);
         }
-        Ok(ret)
+        Ok((tracks, sender))
     }
 
-    pub async fn create_audio_track_rtp(
+    fn published_track_sample(
+        published: PublishedTrack,
+        layer: Option<Layer>,
+    ) -> Arc<TrackLocalStaticSample> {
+        let codec = Self::get_video_codecs()[0].capability.clone();
+        let id = published.client_id().to_owned();
+        let stream_id = format!("{}-stream", published.client_id());
+        Arc::new(match layer {
+            Some(layer) => {
+                TrackLocalStaticSample::new_with_rid(codec, id, layer.rid().to_owned(), stream_id)
+            }
+            None => TrackLocalStaticSample::new(codec, id, stream_id),
+        })
+    }
+
+    /**
+     * Adds a track that forwards the audio of the person in `slot` to this peer.
+     * The returned sender is needed to remove the track when that person leaves.
+     */
+    pub async fn add_forward_track(
         &self,
-        n: usize,
-    ) -> Result<Vec<Arc<TrackLocalStaticRTP>>, Error> {
-        let mut ret = Vec::new();
-        for id in 0..n {
-            let track = Arc::new(TrackLocalStaticRTP::new(
-                Self::get_audio_codec().capability,
-                format!("server-audio-{}", id),
-                format!("server-audio-stream-{}", id),
-            ));
-            self.peer_connection.add_track(track.clone()).await?;
-            ret.push(track);
+        slot: usize,
+    ) -> Result<(Arc<TrackLocalStaticRTP>, Arc<RTCRtpSender>), Error> {
+        let track = Arc::new(TrackLocalStaticRTP::new(
+            Self::get_audio_codec().capability,
+            format!("server-audio-{}", slot),
+            format!("server-audio-stream-{}", slot),
+        ));
+        let sender = self.peer_connection.add_track(track.clone()).await?;
+        Ok((track, sender))
+    }
+
+    /**
+     * Adds a track that forwards a published track of the person in `slot` to this peer,
+     * `codec` is the one negotiated with that person.
+     */
+    pub async fn add_published_forward_track(
+        &self,
+        published: PublishedTrack,
+        slot: usize,
+        codec: RTCRtpCodecCapability,
+    ) -> Result<(Arc<TrackLocalStaticRTP>, Arc<RTCRtpSender>), Error> {
+        let id = published.forward_id(slot);
+        let track = Arc::new(TrackLocalStaticRTP::new(
+            codec,
+            id.clone(),
+            format!("{}-stream", id),
+        ));
+        let sender = self.peer_connection.add_track(track.clone()).await?;
+        Ok((track, sender))
+    }
+
+    pub async fn remove_forward_track(&self, sender: &Arc<RTCRtpSender>) -> Result<(), Error> {
+        self.peer_connection.remove_track(sender).await?;
+        Ok(())
+    }
+
+    /**
+     * Adds receive only transceivers so that the remote peer can send its audio,
+     * camera and screen share even when there is nobody else in the room yet.
+     * The order matches the order in which clients add their tracks.
+     */
+    pub async fn add_receive_transceiver(&self) -> Result<(), Error> {
+        let published = PublishedTrack::ALL.map(|track| track.kind());
+        for kind in std::iter::once(RTPCodecType::Audio).chain(published) {
+            self.peer_connection
+                .add_transceiver_from_kind(
+                    kind,
+                    Some(RTCRtpTransceiverInit {
+                        direction: RTCRtpTransceiverDirection::Recvonly,
+                        send_encodings: vec![],
+                    }),
+                )
+                .await?;
         }
-        Ok(ret)
+        Ok(())
+    }
+
+    /**
+     * Asks the remote peer to send a keyframe on the video track with `media_ssrc`.
+     */
+    pub async fn request_keyframe(&self, media_ssrc: u32) -> Result<(), Error> {
+        self.peer_connection
+            .write_rtcp(&[Box::new(PictureLossIndication {
+                sender_ssrc: 0,
+                media_ssrc,
+            })])
+            .await?;
+        Ok(())
+    }
+
+    /**
+     * Reads the RTCP of a forwarded video track. Keyframes are asked for when the subscriber
+     * starts receiving it or lost a picture, and its bandwidth estimate picks its layer.
+     * The task ends once the track is removed.
+     */
+    pub fn relay_keyframe_requests(
+        sender: Arc<RTCRtpSender>,
+        video: Arc<TrackForward>,
+        selector: Arc<StdMutex<LayerSelector>>,
+    ) {
+        tokio::spawn(async move {
+            // The first feedback means the subscriber negotiated the track and needs a keyframe
+            let mut started = false;
+            while let Ok((packets, _)) = sender.read_rtcp().await {
+                let lost_picture = packets.iter().any(|packet| {
+                    packet.as_any().is::<PictureLossIndication>()
+                        || packet.as_any().is::<FullIntraRequest>()
+                });
+                let estimate_changed = {
+                    let mut selector = selector.lock().unwrap();
+                    match estimate_bandwidth(selector.estimate(), &packets) {
+                        Some(estimate) if Some(estimate) != selector.estimate() => {
+                            selector.set_estimate(estimate);
+                            true
+                        }
+                        _ => false,
+                    }
+                };
+                if estimate_changed {
+                    video.retarget(&selector);
+                }
+                if started && !lost_picture {
+                    continue;
+                }
+                let layer = selector.lock().unwrap().keyframe_layer();
+                if let Some(layer) = layer {
+                    started = true;
+                    video.want_keyframe(layer);
+                }
+            }
+        });
+    }
+
+    /**
+     * Asks this peer for the keyframes the subscribers of its published video need,
+     * until the connection is dropped.
+     */
+    pub fn request_wanted_keyframes(self: &Arc<Self>, forwards: &PublishedForwards) {
+        for published in PublishedTrack::ALL.into_iter().filter(PublishedTrack::is_video) {
+            let video = forwards.get(published).clone();
+            let connection = Arc::downgrade(self);
+            tokio::spawn(async move {
+                loop {
+                    // Wakes up now and then to notice that the connection is gone
+                    let _ = tokio::time::timeout(KEYFRAME_REQUEST_POLL, video.keyframe_notify.notified()).await;
+                    let Some(connection) = connection.upgrade() else {
+                        break;
+                    };
+                    let wanted: Vec<Layer> = video.keyframes_wanted.lock().unwrap().drain().collect();
+                    for layer in wanted {
+                        let ssrc = video.layers.lock().unwrap().get(&layer).copied();
+                        let Some(ssrc) = ssrc else {
+                            continue;
+                        };
+                        if let Err(e) = connection.request_keyframe(ssrc).await {
+                            tracing::warn!("Failed to request a keyframe: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    /**
+     * Samples the quality of the connection since the previous sample.
+     */
+    pub async fn quality(&self) -> ConnectionQuality {
+        let report = self.peer_connection.get_stats().await;
+        self.quality_monitor.sample(&report)
+    }
+
+    /**
+     * Samples the quality of the connection every `interval` until the connection is dropped.
+     */
+    pub fn monitor_quality(self: &Arc<Self>, interval: Duration) -> watch::Receiver<ConnectionQuality> {
+        let (quality_tx, quality_rx) = watch::channel(ConnectionQuality::default());
+        let connection = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let Some(connection) = connection.upgrade() else {
+                    break;
+                };
+                let quality = connection.quality().await;
+                drop(connection);
+                if quality_tx.send(quality).is_err() {
+                    break;
+                }
+            }
+        });
+        quality_rx
+    }
+
+    /**
+     * Creates a new offer every time tracks are added or removed.
+     * Callback should send the offer to the remote peer via your signaling channel.
+     */
+    pub fn setup_renegotiation<F, Fut>(self: &Arc<Self>, callback: F)
+    where
+        F: Fn(WebSocketMessage) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let callback = Arc::new(callback);
+        // Weak reference to avoid the peer connection keeping itself alive
+        let connection: Weak<Self> = Arc::downgrade(self);
+
+        self.peer_connection.on_negotiation_needed(Box::new(move || {
+            let callback = callback.clone();
+            let connection = connection.clone();
+            Box::pin(async move {
+                let Some(connection) = connection.upgrade() else {
+                    return;
+                };
+                match connection.create_offer().await {
+                    Ok(offer) => (callback)(offer).await,
+                    Err(err) => tracing::error!("Failed to create renegotiation offer: {}", err),
+                }
+            })
+        }));
     }
 
     pub async fn create_offer(&self) -> Result<WebSocketMessage, Error> {
+        self.create_offer_with(false).await
+    }
+
+    /**
+     * Creates an offer with new ICE credentials so that the connection can recover
+     * after a network change without being recreated.
+     */
+    pub async fn create_ice_restart_offer(&self) -> Result<WebSocketMessage, Error> {
+        self.create_offer_with(true).await
+    }
+
+    async fn create_offer_with(&self, ice_restart: bool) -> Result<WebSocketMessage, Error> {
         let offer = self
             .peer_connection
             .create_offer(Some(RTCOfferOptions {
                 voice_activity_detection: true,
-                ice_restart: false,
+                ice_restart,
             }))
             .await?;
         // Set the local description
@@ -192,18 +852,39 @@ impl WebRTCConnection {
         Ok(WebSocketMessage::WebRTCOffer(offer))
     }
 
+    /**
+     * Encodes the audio in `data` and sends it on the track. The encoder follows `tuning`,
+     * which the receiver reports read from `sender` adapt to the network.
+     */
     pub async fn background_stream_audio(
         &self,
         data: Arc<StdMutex<HeapCons<f32>>>,
-        audio_tracks: Arc<TrackLocalStaticSample>,
+        audio_tracks: Arc<TrackLocalStaticRTP>,
+        sender: Arc<RTCRtpSender>,
+        tuning: Arc<OpusTuning>,
     ) -> Result<(), Error> {
         // Opus frames typically encode 20ms of audio
-        let channels = self.audio_config.channels;
-        let frame_size = self.audio_config.frame_size;
-        let opus_max_payload_size = self.audio_config.opus_max_payload_size;
-        let mut opus_encoder = self.audio_config.get_opus_encoder()?;
+        let audio_config = self.audio_config;
+        let channels = audio_config.channels;
+        let frame_size = audio_config.frame_size;
+        let opus_max_payload_size = audio_config.opus_max_payload_size;
+        let mut settings = tuning.settings();
+        let mut opus_encoder = audio_config
+            .get_opus_encoder(settings.profile.application(), settings.profile.channels())?;
+        settings.apply(&mut opus_encoder)?;
 
+        tokio::spawn({
+            let tuning = tuning.clone();
+            async move {
+                // Ends once the track is removed
+                while let Ok((packets, _)) = sender.read_rtcp().await {
+                    tuning.report_rtcp(&packets);
+                }
+            }
+        });
         tokio::spawn(async move {
+            let mut silent_frames = 0;
+            let mut packetizer = AudioPacketizer::new(frame_size as u32);
             loop {
                 if data.lock().unwrap().occupied_len() < frame_size * (channels as usize) {
                     // Wait for enough data to fill a frame
@@ -224,26 +905,61 @@ impl WebRTCConnection {
                     );
                     continue;
                 }
+                let next_settings = tuning.settings();
+                if next_settings != settings {
+                    if next_settings.profile != settings.profile {
+                        let profile = next_settings.profile;
+                        match audio_config.get_opus_encoder(profile.application(), profile.channels()) {
+                            Ok(encoder) => opus_encoder = encoder,
+                            Err(e) => tracing::error!("Failed to create Opus encoder: {}", e),
+                        }
+                    }
+                    if let Err(e) = next_settings.apply(&mut opus_encoder) {
+                        tracing::error!("Failed to apply Opus settings: {}", e);
+                    }
+                    tracing::debug!("Opus settings changed to {:?}", next_settings);
+                    settings = next_settings;
+                }
+                // Lets the SFU rank speakers without decoding the audio
+                let level = audio_level(&buffer);
+                if settings.dtx && level >= DTX_SILENCE_LEVEL {
+                    silent_frames += 1;
+                    if silent_frames < DTX_KEEPALIVE_FRAMES {
+                        packetizer.skip();
+                        continue;
+                    }
+                }
+                silent_frames = 0;
+                let mono;
+                let pcm = if channels == Channels::Stereo
+                    && settings.profile.channels() == Channels::Mono
+                {
+                    mono = buffer.chunks_exact(2).map(|s| (s[0] + s[1]) / 2.0).collect::<Vec<f32>>();
+                    &mono
+                } else {
+                    &buffer
+                };
                 let mut encoded = vec![0u8; opus_max_payload_size];
                 let encoded_bytes =
                     opus_encoder
-                        .encode_float(&buffer, &mut encoded)
+                        .encode_float(pcm, &mut encoded)
                         .unwrap_or_else(|e| {
                             tracing::error!("Opus encoding error: {}", e);
                             0
                         });
                 if encoded_bytes > 0 {
-                    let sample = Sample {
-                        data: encoded[..encoded_bytes].to_vec().into(),
-                        duration: std::time::Duration::from_millis(20), // 20ms
-                        ..Default::default()
-                    };
-                    if let Err(e) = audio_tracks.write_sample(&sample).await {
-                        tracing::error!("Error writing audio sample: {}", e);
+                    let level = HeaderExtension::AudioLevel(AudioLevelExtension {
+                        level,
+                        voice: level <= VOICE_ACTIVITY_LEVEL,
+                    });
+                    let packet = packetizer.packetize(encoded[..encoded_bytes].to_vec());
+                    if let Err(e) = audio_tracks.write_rtp_with_extensions(&packet, &[level]).await {
+                        tracing::error!("Error writing audio packet: {}", e);
                     } else {
-                        tracing::trace!("Sent audio frame: {:?}", sample);
+                        tracing::trace!("Sent audio packet: {:?}", packet.header);
                     }
                 } else {
+                    packetizer.skip();
                     eprintln!("No encoded bytes");
                 }
             }
@@ -251,13 +967,43 @@ impl WebRTCConnection {
         Ok(())
     }
 
+    /**
+     * Sends the encoded frames to the track of their layer until the sender of `frames`
+     * is dropped. Frames of layers that were not negotiated are skipped.
+     */
+    pub fn background_stream_video(
+        &self,
+        mut frames: Receiver<LayerSample>,
+        video_tracks: LayerTracks,
+    ) {
+        tokio::spawn(async move {
+            while let Some(LayerSample { layer, sample }) = frames.recv().await {
+                let Some(video_track) = video_tracks.get(&layer) else {
+                    continue;
+                };
+                if let Err(e) = video_track.write_sample(&sample).await {
+                    tracing::error!("Error writing video sample: {}", e);
+                }
+            }
+            tracing::info!("Video frames closed, exiting background task");
+        });
+    }
+
+    /**
+     * Forwards the packets of the person in `slot` to the tracks of the other people,
+     * skipping the receivers for which `slot` is not one of the loudest speakers.
+     * Each receiver gets contiguous sequence numbers, see `ForwardedAudio`.
+     */
     pub fn background_stream_data(
         &self,
         mut data: HeapCons<Packet>,
         dropped: Arc<AtomicBool>,
-        audio_tracks: Vec<Arc<Mutex<Option<Arc<TrackLocalStaticRTP>>>>>,
+        audio_tracks: Arc<Mutex<HashMap<usize, Arc<TrackLocalStaticRTP>>>>,
+        active_speakers: Arc<ActiveSpeakers>,
+        slot: usize,
     ) {
         tokio::spawn(async move {
+            let mut forwarded: HashMap<usize, ForwardedAudio> = HashMap::new();
             loop {
                 if dropped.load(Ordering::Relaxed) {
                     tracing::info!("Data stream dropped, exiting background task");
@@ -265,14 +1011,18 @@ impl WebRTCConnection {
                 }
                 // Pop data from the ring buffer
                 if let Some(packet) = data.try_pop() {
-                    for audio_track in &audio_tracks {
-                        let audio_track = audio_track.lock().await;
-                        if let Some(audio_track) = audio_track.as_ref() {
-                            if let Err(e) = audio_track.write_rtp(&packet).await {
-                                tracing::error!("Error writing RTP packet: {}", e);
-                            } else {
-                                tracing::trace!("Sent RTP packet: {:?}", packet);
-                            }
+                    let audio_tracks = audio_tracks.lock().await;
+                    forwarded.retain(|receiver, _| audio_tracks.contains_key(receiver));
+                    for (receiver, audio_track) in audio_tracks.iter() {
+                        let should_forward = active_speakers.should_forward(slot, *receiver);
+                        let forward = forwarded.entry(*receiver).or_default();
+                        let Some(packet) = forward.forward(&packet, should_forward) else {
+                            continue;
+                        };
+                        if let Err(e) = audio_track.write_rtp(&packet).await {
+                            tracing::error!("Error writing RTP packet: {}", e);
+                        } else {
+                            tracing::trace!("Sent RTP packet: {:?}", packet);
                         }
                     }
                 } else {
@@ -306,51 +1056,119 @@ impl WebRTCConnection {
         Ok(WebSocketMessage::WebRTCAnswer(answer))
     }
 
-    pub async fn background_receive_audio(
+    /**
+     * Decodes the audio tracks the server forwards into `sinks` and collects
+     * the frames of its video tracks in `video_sinks`.
+     */
+    pub async fn background_receive_media(
         &self,
-        receiver_queues: Vec<Arc<StdMutex<HeapProd<f32>>>>,
+        sinks: AudioSinks,
+        video_sinks: VideoSinks,
+        buffer_size: usize,
+        video_buffer_size: usize,
     ) -> Result<(), Error> {
         let audio_config = self.audio_config.clone();
-        tracing::info!("Setting up background receive audio");
-        // let data = receiver_queues.into_iter().map(|q| Arc::new(Mutex::new(q))).collect::<Vec<_>>();
-        let data = receiver_queues;
+        let quality_monitor = self.quality_monitor.clone();
+        tracing::info!("Setting up background receive media");
 
         self.peer_connection.on_track(Box::new({
-            // let receiver_queues = receiver_queues.clone();
             move |track, _receiver, _| {
                 tracing::info!("Received remote track: {}", track.kind());
                 println!("Track ID: {}", track.id());
 
                 if track.kind() == webrtc::rtp_transceiver::rtp_codec::RTPCodecType::Audio {
-                    // track_id = server-audio-{id}
+                    // track_id = server-audio-{slot} or server-screen-audio-{slot}
                     let track_id = track.id();
-                    let id = track_id.split('-').last().unwrap_or("0");
-                    let id = id.parse::<usize>().unwrap_or(0);
-                    let data = data[id].clone();
+                    let slot = track_id.split('-').last().unwrap_or("0");
+                    let slot = slot.parse::<usize>().unwrap_or(0);
+                    let ssrc = track.ssrc();
+                    let (mut producer, consumer) = HeapRb::<f32>::new(buffer_size).split();
+                    sinks.lock().unwrap().insert(track_id.clone(), AudioSink { slot, ssrc, consumer });
+                    let sinks = sinks.clone();
+                    let quality_monitor = quality_monitor.clone();
                     return Box::pin(async move {
-                        // let track_map = Arc::clone(&track_map);
                         let mut opus_decoder = audio_config.get_opus_decoder().unwrap();
-                        while let Ok((rtp, _)) = track.read_rtp().await {
-                            let mut decoded = vec![
-                                0f32;
-                                audio_config.frame_size
-                                    * (audio_config.channels as usize)
-                            ];
-                            let decoded_bytes = opus_decoder
-                                .decode_float(&rtp.payload, &mut decoded, false)
-                                .unwrap_or_else(|e| {
-                                    tracing::error!("Opus decoding error: {}", e);
-                                    0
-                                });
-                            if decoded_bytes == 0 {
-                                tracing::warn!("No decoded bytes, skipping");
-                                continue;
+                        let clock_rate = track.codec().capability.clock_rate;
+                        let channels = audio_config.channels as usize;
+                        let frame_ms = audio_config.frame_size as f64 * 1000.0 / audio_config.sample_rate as f64;
+                        let jitter_buffer = Arc::new(StdMutex::new(JitterBuffer::new(clock_rate, frame_ms)));
+                        let reader = tokio::spawn({
+                            let jitter_buffer = jitter_buffer.clone();
+                            let quality_monitor = quality_monitor.clone();
+                            async move {
+                                while let Ok((rtp, _)) = track.read_rtp().await {
+                                    quality_monitor.packet_received(clock_rate, &rtp.header);
+                                    jitter_buffer.lock().unwrap().push(rtp);
+                                }
                             }
-                            let mut data = data.lock().unwrap();
+                        });
 
-                            // let mut data_guard = data.lock().await;
-                            data.push_slice(&decoded[..decoded_bytes]);
+                        // Play out at the pace the speaker takes the samples, not as the packets arrive
+                        let mut decoded = vec![0f32; audio_config.frame_size * channels];
+                        let mut playout = tokio::time::interval(PLAYOUT_INTERVAL);
+                        while !reader.is_finished() {
+                            playout.tick().await;
+                            let mut jitter_buffer = jitter_buffer.lock().unwrap();
+                            while producer.occupied_len() < PLAYOUT_FRAMES * decoded.len() {
+                                let Some(frame) = jitter_buffer.pop() else {
+                                    break;
+                                };
+                                let result = match frame {
+                                    Playout::Decode(rtp) => opus_decoder.decode_float(&rtp.payload, &mut decoded, false),
+                                    Playout::Fec(rtp) => opus_decoder.decode_float(&rtp.payload, &mut decoded, true),
+                                    Playout::Conceal => opus_decoder.decode_float(&[], &mut decoded, false),
+                                };
+                                match result {
+                                    Ok(samples) => {
+                                        producer.push_slice(&decoded[..samples * channels]);
+                                    }
+                                    Err(e) => tracing::error!("Opus decoding error: {}", e),
+                                }
+                            }
+                            quality_monitor.jitter_buffer_updated(ssrc, jitter_buffer.stats());
+                        }
+                        quality_monitor.stream_closed(ssrc);
+                        // The track is removed, drop its sink unless the slot is already reused
+                        let mut sinks = sinks.lock().unwrap();
+                        if sinks.get(&track_id).is_some_and(|sink| sink.ssrc == ssrc) {
+                            sinks.remove(&track_id);
                         }
+                        tracing::info!("Remote track {} closed", track_id);
+                    });
+                }
+                if track.kind() == webrtc::rtp_transceiver::rtp_codec::RTPCodecType::Video {
+                    // track_id = server-video-{slot} or server-screen-{slot}
+                    let track_id = track.id();
+                    let slot = track_id.split('-').last().unwrap_or("0");
+                    let slot = slot.parse::<usize>().unwrap_or(0);
+                    let ssrc = track.ssrc();
+                    let mime_type = track.codec().capability.mime_type;
+                    let (producer, consumer) = HeapRb::<Sample>::new(video_buffer_size).split();
+                    video_sinks.lock().unwrap().insert(
+                        track_id.clone(),
+                        VideoSink {
+                            slot,
+                            ssrc,
+                            mime_type: mime_type.clone(),
+                            consumer,
+                        },
+                    );
+                    let video_sinks = video_sinks.clone();
+                    let quality_monitor = quality_monitor.clone();
+                    return Box::pin(async move {
+                        if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+                            Self::read_video_frames(&track, Vp9Packet::default(), producer, &quality_monitor).await;
+                        } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+                            Self::read_video_frames(&track, H264Packet::default(), producer, &quality_monitor).await;
+                        } else {
+                            Self::read_video_frames(&track, Vp8Packet::default(), producer, &quality_monitor).await;
+                        }
+                        quality_monitor.stream_closed(ssrc);
+                        let mut video_sinks = video_sinks.lock().unwrap();
+                        if video_sinks.get(&track_id).is_some_and(|sink| sink.ssrc == ssrc) {
+                            video_sinks.remove(&track_id);
+                        }
+                        tracing::info!("Remote video track {} closed", track_id);
                     });
                 }
 
@@ -360,23 +1178,96 @@ impl WebRTCConnection {
         Ok(())
     }
 
+    /**
+     * Puts the packets of the track back together into frames until the track is removed.
+     * Frames are dropped while the sink is full.
+     */
+    async fn read_video_frames<T: Depacketizer>(
+        track: &TrackRemote,
+        depacketizer: T,
+        mut producer: HeapProd<Sample>,
+        quality_monitor: &QualityMonitor,
+    ) {
+        let clock_rate = track.codec().capability.clock_rate;
+        let mut builder = SampleBuilder::new(VIDEO_MAX_LATE, depacketizer, clock_rate);
+        while let Ok((rtp, _)) = track.read_rtp().await {
+            quality_monitor.packet_received(clock_rate, &rtp.header);
+            builder.push(rtp);
+            while let Some(sample) = builder.pop() {
+                if producer.try_push(sample).is_err() {
+                    tracing::trace!("Video sink is full, dropping a frame");
+                }
+            }
+        }
+    }
+
+    /**
+     * Queues the packets of the person in `slot` for forwarding and feeds
+     * the audio level they report into the speaker ranking of the room.
+     * Their published tracks are forwarded right away to the tracks in `published`,
+     * each simulcast layer arrives as its own track.
+     */
     pub fn background_receive_data(
         &self,
         receiver_queue: Arc<Mutex<HeapProd<Packet>>>,
         dropped: Arc<AtomicBool>,
+        active_speakers: Arc<ActiveSpeakers>,
+        published: Arc<PublishedForwards>,
+        slot: usize,
     ) {
+        let quality_monitor = self.quality_monitor.clone();
         tracing::info!("Setting up background receive data");
 
         self.peer_connection.on_track(Box::new({
-            move |track, _receiver, _| {
+            move |track, receiver, _| {
                 println!("Track ID: {}", track.id());
                 tracing::info!("Received remote track: {}", track.kind());
+                if let Some(published_track) = PublishedTrack::from_client_id(&track.id()) {
+                    let forward = published.get(published_track).clone();
+                    let layer = Layer::from_rid(track.rid());
+                    let quality_monitor = quality_monitor.clone();
+                    return Box::pin(async move {
+                        let ssrc = track.ssrc();
+                        let codec = track.codec().capability;
+                        let mime_type = codec.mime_type;
+                        forward.layer_started(layer, ssrc).await;
+                        while let Ok((rtp, _)) = track.read_rtp().await {
+                            quality_monitor.packet_received(codec.clock_rate, &rtp.header);
+                            // Audio has no keyframes, it can switch at any packet
+                            let keyframe = !published_track.is_video() || is_keyframe(&mime_type, &rtp.payload);
+                            forward.forward(layer, &rtp, keyframe).await;
+                        }
+                        forward.layer_stopped(layer, ssrc).await;
+                        quality_monitor.stream_closed(ssrc);
+                        tracing::info!("{:?} track of slot {} closed, layer {:?}", published_track, slot, layer);
+                    });
+                }
                 if track.kind() == webrtc::rtp_transceiver::rtp_codec::RTPCodecType::Audio {
                     let receiver_queue = receiver_queue.clone();
                     let dropped = dropped.clone();
+                    let active_speakers = active_speakers.clone();
+                    let quality_monitor = quality_monitor.clone();
                     return Box::pin(async move {
+                        let audio_level_id = receiver
+                            .get_parameters()
+                            .await
+                            .header_extensions
+                            .iter()
+                            .find(|extension| extension.uri == AUDIO_LEVEL_URI)
+                            .map(|extension| extension.id as u8);
                         let mut receiver_queue = receiver_queue.lock().await;
+                        let ssrc = track.ssrc();
+                        let clock_rate = track.codec().capability.clock_rate;
                         while let Ok((rtp, _)) = track.read_rtp().await {
+                            quality_monitor.packet_received(clock_rate, &rtp.header);
+                            if let Some(mut payload) =
+                                audio_level_id.and_then(|id| rtp.header.get_extension(id))
+                            {
+                                match AudioLevelExtension::unmarshal(&mut payload) {
+                                    Ok(extension) => active_speakers.update(slot, extension.level, extension.voice),
+                                    Err(e) => tracing::warn!("Invalid audio level extension: {}", e),
+                                }
+                            }
                             match receiver_queue.try_push(rtp) {
                                 Ok(_) => {
                                     tracing::trace!("Pushed packet to data");
@@ -386,6 +1277,7 @@ impl WebRTCConnection {
                                 }
                             }
                         }
+                        quality_monitor.stream_closed(ssrc);
                         dropped.store(true, Ordering::Relaxed);
                         tracing::info!("Track closed, setting dropped to true");
                     });
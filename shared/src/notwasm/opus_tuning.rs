@@ -0,0 +1,270 @@
+use std::sync::{Arc, Mutex as StdMutex};
+
+use opus::{Application, Bitrate, Channels};
+use webrtc::rtcp::packet::Packet as RtcpPacket;
+use webrtc::rtcp::receiver_report::ReceiverReport;
+
+/// Weight of the newest receiver report in the smoothed loss
+const LOSS_SMOOTHING: f64 = 0.3;
+/// Loss above which in-band FEC is worth its bitrate
+const FEC_LOSS: f64 = 0.01;
+/// Loss from which the bitrate starts going down, and at which it reaches the minimum
+const LOW_LOSS: f64 = 0.02;
+const HIGH_LOSS: f64 = 0.15;
+/// Highest loss the encoder is told to expect, more only wastes bitrate on FEC
+const MAX_EXPECTED_LOSS: i32 = 30;
+
+/**
+ * What the microphone mostly carries, picks the Opus application and the bitrate range.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AudioProfile {
+    #[default]
+    Voice,
+    Music,
+}
+
+impl AudioProfile {
+    /// Profile stored in the audio settings, 0 is voice and 1 is music
+    pub fn from_id(id: i32) -> Self {
+        match id {
+            1 => AudioProfile::Music,
+            _ => AudioProfile::Voice,
+        }
+    }
+
+    pub fn id(&self) -> i32 {
+        match self {
+            AudioProfile::Voice => 0,
+            AudioProfile::Music => 1,
+        }
+    }
+
+    pub fn application(&self) -> Application {
+        match self {
+            AudioProfile::Voice => Application::Voip,
+            AudioProfile::Music => Application::Audio,
+        }
+    }
+
+    /// Music is sent in stereo, voice gains nothing from it
+    pub fn channels(&self) -> Channels {
+        match self {
+            AudioProfile::Voice => Channels::Mono,
+            AudioProfile::Music => Channels::Stereo,
+        }
+    }
+
+    /// Lowest and highest bitrate in bit/s, the highest is used while nothing is lost
+    fn bitrate_range(&self) -> (i32, i32) {
+        match self {
+            AudioProfile::Voice => (16_000, 40_000),
+            AudioProfile::Music => (48_000, 128_000),
+        }
+    }
+}
+
+/// Settings of an Opus encoder, the application and channels can only be changed by creating a new one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpusSettings {
+    pub profile: AudioProfile,
+    pub bitrate: i32,
+    pub fec: bool,
+    /// Whether silent frames are left out
+    pub dtx: bool,
+    /// Packet loss in percent the encoder prepares its FEC for
+    pub expected_loss: i32,
+}
+
+impl OpusSettings {
+    pub fn apply(&self, encoder: &mut opus::Encoder) -> Result<(), opus::Error> {
+        encoder.set_bitrate(Bitrate::Bits(self.bitrate))?;
+        encoder.set_inband_fec(self.fec)?;
+        encoder.set_packet_loss_perc(self.expected_loss)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct TuningState {
+    profile: AudioProfile,
+    /// Smoothed fraction of the packets the receiver lost, None before the first report
+    loss: Option<f64>,
+}
+
+/**
+ * Encoder settings of an outgoing audio track. The profile is chosen by the user,
+ * the bitrate, FEC and expected loss follow the receiver reports of the track.
+ */
+#[derive(Debug, Default)]
+pub struct OpusTuning {
+    state: StdMutex<TuningState>,
+}
+
+impl OpusTuning {
+    pub fn new(profile: AudioProfile) -> Arc<Self> {
+        Arc::new(OpusTuning {
+            state: StdMutex::new(TuningState {
+                profile,
+                loss: None,
+            }),
+        })
+    }
+
+    pub fn set_profile(&self, profile: AudioProfile) {
+        self.state.lock().unwrap().profile = profile;
+    }
+
+    pub fn report_loss(&self, fraction_lost: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.loss = Some(match state.loss {
+            Some(loss) => loss + (fraction_lost - loss) * LOSS_SMOOTHING,
+            None => fraction_lost,
+        });
+    }
+
+    /**
+     * Feeds the loss of the receiver reports in `packets` into the settings.
+     */
+    pub fn report_rtcp(&self, packets: &[Box<dyn RtcpPacket + Send + Sync>]) {
+        for packet in packets {
+            let Some(report) = packet.as_any().downcast_ref::<ReceiverReport>() else {
+                continue;
+            };
+            let loss = report
+                .reports
+                .iter()
+                .map(|report| report.fraction_lost as f64 / 256.0)
+                .reduce(f64::max);
+            if let Some(loss) = loss {
+                self.report_loss(loss);
+            }
+        }
+    }
+
+    pub fn settings(&self) -> OpusSettings {
+        let state = self.state.lock().unwrap();
+        let loss = state.loss.unwrap_or(0.0);
+        let (min, max) = state.profile.bitrate_range();
+        // Down from the highest to the lowest bitrate as the loss goes from low to high
+        let position = ((loss - LOW_LOSS) / (HIGH_LOSS - LOW_LOSS)).clamp(0.0, 1.0);
+        let bitrate = max - ((max - min) as f64 * position) as i32;
+        OpusSettings {
+            profile: state.profile,
+            // Rounded to 4 kbit/s so that small changes of the loss do not touch the encoder
+            bitrate: bitrate / 4000 * 4000,
+            fec: loss > FEC_LOSS,
+            // Pauses in music are part of it
+            dtx: state.profile == AudioProfile::Voice,
+            expected_loss: ((loss * 100.0).round() as i32).clamp(0, MAX_EXPECTED_LOSS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+    use webrtc::rtcp::reception_report::ReceptionReport;
+
+    use super::*;
+
+    /// Receiver report of streams that lost the given fractions, in 1/256
+    fn receiver_report(fractions_lost: &[u8]) -> Box<dyn RtcpPacket + Send + Sync> {
+        Box::new(ReceiverReport {
+            reports: fractions_lost
+                .iter()
+                .map(|&fraction_lost| ReceptionReport {
+                    fraction_lost,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn starts_at_the_highest_bitrate_without_fec() {
+        let settings = OpusTuning::new(AudioProfile::Voice).settings();
+        assert_eq!(
+            settings,
+            OpusSettings {
+                profile: AudioProfile::Voice,
+                bitrate: 40_000,
+                fec: false,
+                dtx: true,
+                expected_loss: 0,
+            }
+        );
+        let settings = OpusTuning::new(AudioProfile::Music).settings();
+        assert_eq!((settings.bitrate, settings.dtx), (128_000, false));
+    }
+
+    #[test]
+    fn lowers_the_bitrate_with_the_loss() {
+        for profile in [AudioProfile::Voice, AudioProfile::Music] {
+            let (min, max) = profile.bitrate_range();
+            let bitrate = |loss: f64| {
+                let tuning = OpusTuning::new(profile);
+                tuning.report_loss(loss);
+                tuning.settings().bitrate
+            };
+            assert_eq!(bitrate(LOW_LOSS), max);
+            assert_eq!(bitrate((LOW_LOSS + HIGH_LOSS) / 2.0), (max + min) / 2);
+            assert_eq!(bitrate(HIGH_LOSS), min);
+            assert_eq!(bitrate(1.0), min);
+        }
+    }
+
+    #[test]
+    fn turns_fec_on_over_the_threshold() {
+        let tuning = OpusTuning::new(AudioProfile::Voice);
+        tuning.report_loss(FEC_LOSS);
+        assert!(!tuning.settings().fec);
+        let tuning = OpusTuning::new(AudioProfile::Voice);
+        tuning.report_loss(0.05);
+        let settings = tuning.settings();
+        assert!(settings.fec);
+        assert_eq!(settings.expected_loss, 5);
+    }
+
+    #[test]
+    fn caps_the_expected_loss() {
+        let tuning = OpusTuning::new(AudioProfile::Voice);
+        tuning.report_loss(0.5);
+        assert_eq!(tuning.settings().expected_loss, MAX_EXPECTED_LOSS);
+    }
+
+    #[test]
+    fn smooths_the_loss() {
+        let tuning = OpusTuning::new(AudioProfile::Voice);
+        tuning.report_loss(0.1);
+        tuning.report_loss(0.0);
+        assert_eq!(tuning.settings().expected_loss, 7);
+    }
+
+    #[test]
+    fn reads_the_worst_loss_of_the_receiver_reports() {
+        let tuning = OpusTuning::new(AudioProfile::Voice);
+        tuning.report_rtcp(&[
+            Box::new(PictureLossIndication::default()),
+            receiver_report(&[13, 64]),
+            receiver_report(&[]),
+        ]);
+        let settings = tuning.settings();
+        assert_eq!(settings.expected_loss, 25);
+        assert_eq!(settings.bitrate, 16_000);
+        assert!(settings.fec);
+    }
+
+    #[test]
+    fn keeps_the_loss_across_profile_changes() {
+        let tuning = OpusTuning::new(AudioProfile::Voice);
+        tuning.report_loss(HIGH_LOSS);
+        tuning.set_profile(AudioProfile::Music);
+        let settings = tuning.settings();
+        assert_eq!(
+            (settings.profile, settings.bitrate, settings.dtx),
+            (AudioProfile::Music, 48_000, false)
+        );
+    }
+}
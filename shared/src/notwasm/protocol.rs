@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+/// Version of the websocket protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version the server accepts in `Hello`. Clients from before the handshake
+/// never send it and get none of the features, see `Feature::legacy`.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/**
+ * Optional parts of the protocol, both sides only use the ones they agreed on in the handshake.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Feature {
+    /// `Resume`, `Resumed` and `FullRefresh`
+    Resume,
+    Heartbeat,
+    /// `Request` and `Response`
+    Rpc,
+    /// `DominantSpeakerChanged`, `SpeakingStarted` and `SpeakingStopped`
+    SpeakerEvents,
+    VoiceTakeover,
+    /// Binary MessagePack frames instead of JSON text, see `Encoding`
+    MessagePack,
+    /// `SetCamera`, `SetVideoSubscriptions` and `CameraChanged`
+    Video,
+    /// `StartScreenShare`, `StopScreenShare`, `WatchScreenShare`, `StopWatchingScreenShare`
+    /// and `ScreenShareChanged`
+    ScreenShare,
+    /// Video published in several layers, and `SetVideoSize`
+    Simulcast,
+    /// `RpcRequest::GetCallQuality`
+    CallQuality,
+    /// `SetVoiceState` and `VoiceStateChanged`
+    VoiceState,
+    /// A feature of a newer build, ignored by this one
+    #[serde(other)]
+    Unknown,
+}
+
+impl Feature {
+    /**
+     * Features this build supports.
+     */
+    pub fn supported() -> HashSet<Feature> {
+        HashSet::from([
+            Feature::Resume,
+            Feature::Heartbeat,
+            Feature::Rpc,
+            Feature::SpeakerEvents,
+            Feature::VoiceTakeover,
+            Feature::MessagePack,
+            Feature::Video,
+            Feature::ScreenShare,
+            Feature::Simulcast,
+            Feature::CallQuality,
+            Feature::VoiceState,
+        ])
+    }
+
+    /**
+     * Features of peers from before the handshake, none. They neither send heartbeats
+     * nor read sequenced messages, and are sent bare JSON messages instead.
+     */
+    pub fn legacy() -> HashSet<Feature> {
+        HashSet::new()
+    }
+
+    /**
+     * Features both sides support, unknown ones are dropped.
+     */
+    pub fn negotiate(theirs: &HashSet<Feature>) -> HashSet<Feature> {
+        Feature::supported().intersection(theirs).copied().collect()
+    }
+}
+
+/// Frames as peers of older protocol versions and newer builds send them
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use super::*;
+    use crate::{
+        Encoding, Frame, RpcError, RpcResponse, SequencedMessage, WebSocketError, WebSocketMessage,
+    };
+
+    const USER_ID: &str = "6b1d9f4e-8c4a-4f61-9f0a-3c8e2f1d7b55";
+
+    /// Sent by servers from before the handshake, bare and without the newer fields
+    const BASELINE_SERVER_FRAMES: &[&str] = &[
+        r#"{"SomeoneJoinedAudioChannel":{"data":{"channel":{"id":"0f6f0c4e-2b1a-4c55-9d7e-1a2b3c4d5e6f","name":"General","type_":"Voice","hidden":false,"server_id":"3c1e5b7a-9d2f-4e8b-a6c4-7f0e1d2c3b4a","created_at":"2025-05-01T12:00:00","updated_at":"2025-05-01T12:00:00"},"user":{"id":"6b1d9f4e-8c4a-4f61-9f0a-3c8e2f1d7b55","username":"alice","slot":0,"boost":null}}}}"#,
+        r#"{"SomeoneLeftAudioChannel":{"data":{"channel":{"id":"0f6f0c4e-2b1a-4c55-9d7e-1a2b3c4d5e6f","name":"General","type_":"Voice","hidden":false,"server_id":"3c1e5b7a-9d2f-4e8b-a6c4-7f0e1d2c3b4a","created_at":"2025-05-01T12:00:00","updated_at":"2025-05-01T12:00:00"},"user":{"id":"6b1d9f4e-8c4a-4f61-9f0a-3c8e2f1d7b55","username":"alice","slot":0,"boost":120}}}}"#,
+        r#"{"WebRTCOffer":{"type":"offer","sdp":"v=0\r\n"}}"#,
+        r#"{"IceCandidate":{"candidate":"candidate:1 1 udp 2122260223 192.168.0.196 46243 typ host","sdpMid":"0","sdpMLineIndex":0,"usernameFragment":null}}"#,
+        r#""DisconnectFromAudioChannel""#,
+        r#"{"Error":{"err":"NotAuthorized"}}"#,
+        r#"{"Error":{"err":"NotFound"}}"#,
+    ];
+
+    /// Sent by clients from before the handshake
+    const BASELINE_CLIENT_FRAMES: &[&str] = &[
+        r#"{"JoinAudioChannel":{"server_id":"3c1e5b7a-9d2f-4e8b-a6c4-7f0e1d2c3b4a","channel_id":"0f6f0c4e-2b1a-4c55-9d7e-1a2b3c4d5e6f"}}"#,
+        r#"{"WebRTCAnswer":{"type":"answer","sdp":"v=0\r\n"}}"#,
+        r#""DisconnectFromAudioChannel""#,
+        r#""Disconnect""#,
+    ];
+
+    #[test]
+    fn parses_baseline_server_frames() {
+        for frame in BASELINE_SERVER_FRAMES {
+            let (seq, message) = SequencedMessage::from_json(frame).unwrap();
+            assert_eq!(seq, 0, "{frame}");
+            assert!(message.is_some(), "{frame}");
+        }
+        let (_, message) = SequencedMessage::from_json(BASELINE_SERVER_FRAMES[0]).unwrap();
+        let Some(WebSocketMessage::SomeoneJoinedAudioChannel { data }) = message else {
+            panic!("wrong variant");
+        };
+        assert_eq!(data.user.id.to_string(), USER_ID);
+        assert_eq!(data.channel.user_limit, None);
+        assert!(!data.user.muted && !data.user.camera && !data.user.screen_share);
+    }
+
+    #[test]
+    fn parses_baseline_client_frames() {
+        for frame in BASELINE_CLIENT_FRAMES {
+            assert!(
+                WebSocketMessage::from_json(frame).unwrap().is_some(),
+                "{frame}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_sequenced_frames() {
+        let frame =
+            format!(r#"{{"seq":12,"message":{{"SpeakingStarted":{{"user_id":"{USER_ID}"}}}}}}"#);
+        let (seq, message) = SequencedMessage::from_json(&frame).unwrap();
+        assert_eq!(seq, 12);
+        assert!(matches!(
+            message,
+            Some(WebSocketMessage::SpeakingStarted { .. })
+        ));
+    }
+
+    #[test]
+    fn skips_unknown_variants() {
+        let unknown = [
+            format!(r#"{{"RaisedHand":{{"user_id":"{USER_ID}","hand":"left"}}}}"#),
+            r#""Shrug""#.to_string(),
+            r#"{"Poll":["a","b"]}"#.to_string(),
+        ];
+        for frame in &unknown {
+            assert!(
+                WebSocketMessage::from_json(frame).unwrap().is_none(),
+                "{frame}"
+            );
+            let sequenced = format!(r#"{{"seq":5,"message":{frame}}}"#);
+            assert_eq!(SequencedMessage::from_json(&sequenced).unwrap().0, 5);
+            assert!(SequencedMessage::from_json(&sequenced).unwrap().1.is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_known_variants() {
+        assert!(WebSocketMessage::from_json(r#"{"SpeakingStarted":{"user_id":3}}"#).is_err());
+    }
+
+    #[test]
+    fn drops_unknown_features() {
+        let frame =
+            r#"{"Hello":{"protocol_version":3,"features":["Resume","Teleport","MessagePack"]}}"#;
+        let Some(WebSocketMessage::Hello {
+            protocol_version,
+            features,
+        }) = WebSocketMessage::from_json(frame).unwrap()
+        else {
+            panic!("wrong variant");
+        };
+        assert_eq!(protocol_version, 3);
+        assert!(features.contains(&Feature::Unknown));
+        assert_eq!(
+            Feature::negotiate(&features),
+            HashSet::from([Feature::Resume, Feature::MessagePack])
+        );
+    }
+
+    #[test]
+    fn legacy_peers_get_no_features() {
+        assert!(Feature::legacy().is_empty());
+        assert!(Feature::negotiate(&Feature::legacy()).is_empty());
+    }
+
+    fn error_of(frame: &str) -> WebSocketError {
+        match WebSocketMessage::from_json(frame).unwrap() {
+            Some(WebSocketMessage::Error { err }) => err,
+            other => panic!("not an error: {other:?}"),
+        }
+    }
+
+    fn rpc_error_of(frame: &str) -> RpcError {
+        match WebSocketMessage::from_json(frame).unwrap() {
+            Some(WebSocketMessage::Response {
+                result: Err(err), ..
+            }) => err,
+            other => panic!("not a failed response: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_known_errors() {
+        assert!(matches!(
+            error_of(r#"{"Error":{"err":"RoomFull"}}"#),
+            WebSocketError::RoomFull
+        ));
+        assert!(matches!(
+            error_of(r#"{"Error":{"err":{"UnsupportedProtocol":{"min_version":2}}}}"#),
+            WebSocketError::UnsupportedProtocol { min_version: 2 }
+        ));
+        assert!(matches!(
+            rpc_error_of(r#"{"Response":{"id":1,"result":{"Err":{"InvalidRequest":"no such server"}}}}"#),
+            RpcError::InvalidRequest(reason) if reason == "no such server"
+        ));
+    }
+
+    #[test]
+    fn falls_back_on_unknown_errors() {
+        let errors = [
+            r#"{"Error":{"err":"TooManyRequests"}}"#,
+            r#"{"Error":{"err":{"Banned":{"until":"2030-01-01T00:00:00"}}}}"#,
+            r#"{"Error":{"err":{"RateLimited":500}}}"#,
+        ];
+        for frame in errors {
+            assert!(
+                matches!(error_of(frame), WebSocketError::Unknown),
+                "{frame}"
+            );
+        }
+        let rpc_errors = [
+            r#"{"Response":{"id":1,"result":{"Err":"Teleported"}}}"#,
+            r#"{"Response":{"id":2,"result":{"Err":{"RateLimited":{"retry_after_ms":500}}}}}"#,
+        ];
+        for frame in rpc_errors {
+            assert!(matches!(rpc_error_of(frame), RpcError::Unknown), "{frame}");
+        }
+    }
+
+    /// Errors a newer build could send
+    #[derive(Serialize)]
+    enum NewerError {
+        Banned { until: u64 },
+    }
+
+    #[derive(Serialize)]
+    enum NewerMessage {
+        Error {
+            err: NewerError,
+        },
+        Response {
+            id: u64,
+            result: Result<RpcResponse, NewerError>,
+        },
+    }
+
+    #[test]
+    fn falls_back_on_unknown_errors_in_msgpack() {
+        let frames = [
+            NewerMessage::Error {
+                err: NewerError::Banned { until: 1 },
+            },
+            NewerMessage::Response {
+                id: 4,
+                result: Err(NewerError::Banned { until: 1 }),
+            },
+        ];
+        for message in frames {
+            let Frame::Binary(bytes) = Encoding::MessagePack.encode(&message).unwrap() else {
+                panic!("MessagePack is written as binary frames");
+            };
+            match WebSocketMessage::from_msgpack(&bytes).unwrap() {
+                Some(WebSocketMessage::Error { err }) => {
+                    assert!(matches!(err, WebSocketError::Unknown))
+                }
+                Some(WebSocketMessage::Response { id, result }) => {
+                    assert_eq!(id, 4);
+                    assert!(matches!(result, Err(RpcError::Unknown)));
+                }
+                other => panic!("unexpected message: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn errors_round_trip() {
+        let err = WebSocketError::UnsupportedProtocol { min_version: 2 };
+        let json = serde_json::to_string(&err).unwrap();
+        assert_eq!(json, r#"{"UnsupportedProtocol":{"min_version":2}}"#);
+        let parsed: WebSocketError = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            parsed,
+            WebSocketError::UnsupportedProtocol { min_version: 2 }
+        ));
+
+        let err = RpcError::Internal("database".to_string());
+        let parsed: RpcError = serde_json::from_str(&serde_json::to_string(&err).unwrap()).unwrap();
+        assert!(matches!(parsed, RpcError::Internal(reason) if reason == "database"));
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::sync::Mutex as StdMutex;
+use std::time::Instant;
+
+use webrtc::rtp::header::Header;
+use webrtc::stats::{StatsReport, StatsReportType};
+
+use crate::models::{ConnectionQuality, JitterBufferStats, TrackDirection, TrackQuality};
+
+/// Sequence number jump after which a stream is considered restarted, as in RFC 3550 A.1
+const MAX_DROPOUT: u16 = 3000;
+/// How far back a sequence number can be and still count as a reordered packet
+const MAX_MISORDER: u16 = 100;
+
+/**
+ * Arrival statistics of a received RTP stream, following RFC 3550 appendix A.
+ * The stats of the peer connection have neither loss nor jitter for received streams.
+ */
+#[derive(Debug)]
+struct ReceivedStream {
+    clock_rate: u32,
+    /// Extended sequence numbers of the first and the highest packet
+    base_seq: u64,
+    max_seq: u64,
+    received: u64,
+    /// Expected and received packets at the previous sample
+    expected_prior: u64,
+    received_prior: u64,
+    /// Relative transit time of the previous packet, in timestamp units
+    transit: Option<u32>,
+    /// Interarrival jitter, in timestamp units
+    jitter: f64,
+}
+
+impl ReceivedStream {
+    fn new(clock_rate: u32, seq: u16) -> Self {
+        ReceivedStream {
+            clock_rate,
+            base_seq: seq as u64,
+            max_seq: seq as u64,
+            received: 0,
+            expected_prior: 0,
+            received_prior: 0,
+            transit: None,
+            jitter: 0.0,
+        }
+    }
+
+    fn update(&mut self, header: &Header, arrival: u32) {
+        let delta = header.sequence_number.wrapping_sub(self.max_seq as u16);
+        if delta < MAX_DROPOUT {
+            self.max_seq += delta as u64;
+        } else if delta < u16::MAX - MAX_MISORDER {
+            // The sender restarted, the old numbers mean nothing anymore
+            *self = ReceivedStream::new(self.clock_rate, header.sequence_number);
+        }
+        self.received += 1;
+
+        let transit = arrival.wrapping_sub(header.timestamp);
+        if let Some(previous) = self.transit {
+            let difference = (transit.wrapping_sub(previous) as i32).unsigned_abs() as f64;
+            self.jitter += (difference - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+    }
+
+    /// Fraction of the packets lost since the previous call, None if none were expected
+    fn interval_loss(&mut self) -> Option<f64> {
+        let expected = self.max_seq - self.base_seq + 1;
+        let expected_interval = expected.saturating_sub(self.expected_prior);
+        let received_interval = self.received.saturating_sub(self.received_prior);
+        self.expected_prior = expected;
+        self.received_prior = self.received;
+        if expected_interval == 0 {
+            return None;
+        }
+        let lost = expected_interval.saturating_sub(received_interval);
+        Some(lost as f64 / expected_interval as f64)
+    }
+
+    fn jitter_ms(&self) -> f64 {
+        self.jitter * 1000.0 / self.clock_rate as f64
+    }
+}
+
+/**
+ * Turns the cumulative stats of a peer connection into loss, jitter, round trip time
+ * and bitrate since the previous sample.
+ */
+#[derive(Debug)]
+pub struct QualityMonitor {
+    started: Instant,
+    /// Received streams keyed by SSRC
+    received: StdMutex<HashMap<u32, ReceivedStream>>,
+    /// Jitter buffers of received audio streams keyed by SSRC
+    jitter_buffers: StdMutex<HashMap<u32, JitterBufferStats>>,
+    /// Bytes of each stream at the previous sample and when it was taken
+    bytes: StdMutex<HashMap<(TrackDirection, u32), (u64, Instant)>>,
+}
+
+impl Default for QualityMonitor {
+    fn default() -> Self {
+        QualityMonitor {
+            started: Instant::now(),
+            received: StdMutex::new(HashMap::new()),
+            jitter_buffers: StdMutex::new(HashMap::new()),
+            bytes: StdMutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl QualityMonitor {
+    /**
+     * Records the arrival of a packet of a received stream.
+     */
+    pub fn packet_received(&self, clock_rate: u32, header: &Header) {
+        // Arrival time in the clock of the stream, only differences between packets matter
+        let arrival = (self.started.elapsed().as_secs_f64() * clock_rate as f64) as u64 as u32;
+        self.received
+            .lock()
+            .unwrap()
+            .entry(header.ssrc)
+            .or_insert_with(|| ReceivedStream::new(clock_rate, header.sequence_number))
+            .update(header, arrival);
+    }
+
+    pub fn stream_closed(&self, ssrc: u32) {
+        self.received.lock().unwrap().remove(&ssrc);
+        self.jitter_buffers.lock().unwrap().remove(&ssrc);
+    }
+
+    pub fn jitter_buffer_updated(&self, ssrc: u32, stats: JitterBufferStats) {
+        self.jitter_buffers.lock().unwrap().insert(ssrc, stats);
+    }
+
+    /**
+     * Computes the quality since the previous sample from a stats report of the connection.
+     */
+    pub fn sample(&self, report: &StatsReport) -> ConnectionQuality {
+        let mut quality = ConnectionQuality::default();
+        let mut received = self.received.lock().unwrap();
+        let jitter_buffers = self.jitter_buffers.lock().unwrap();
+        let mut bytes = self.bytes.lock().unwrap();
+        let mut bitrate = |direction: TrackDirection, ssrc: u32, total: u64, timestamp: Instant| {
+            let previous = bytes.insert((direction, ssrc), (total, timestamp));
+            match previous {
+                Some((previous, previous_timestamp)) if timestamp > previous_timestamp => {
+                    let elapsed = timestamp.duration_since(previous_timestamp).as_secs_f64();
+                    (total.saturating_sub(previous) as f64 * 8.0 / elapsed) as u64
+                }
+                _ => 0,
+            }
+        };
+        for stats in report.reports.values() {
+            match stats {
+                StatsReportType::CandidatePair(pair) if pair.nominated => {
+                    if pair.current_round_trip_time > 0.0 {
+                        quality.rtt_ms = Some(pair.current_round_trip_time * 1000.0);
+                    }
+                    if pair.available_outgoing_bitrate > 0.0 {
+                        quality.available_outgoing_bitrate = Some(pair.available_outgoing_bitrate as u64);
+                    }
+                }
+                StatsReportType::InboundRTP(inbound) => {
+                    let stream = received.get_mut(&inbound.ssrc);
+                    let rtt_ms = report.reports.values().find_map(|stats| match stats {
+                        StatsReportType::RemoteOutboundRTP(remote) if remote.ssrc == inbound.ssrc => {
+                            remote.round_trip_time.map(|rtt| rtt * 1000.0)
+                        }
+                        _ => None,
+                    });
+                    let total = inbound.bytes_received + inbound.header_bytes_received;
+                    quality.tracks.push(TrackQuality {
+                        track_id: inbound.track_identifier.clone(),
+                        kind: inbound.kind.to_owned(),
+                        direction: TrackDirection::Inbound,
+                        ssrc: inbound.ssrc,
+                        jitter_ms: stream.as_ref().map(|stream| stream.jitter_ms()),
+                        packet_loss: stream.and_then(ReceivedStream::interval_loss),
+                        rtt_ms,
+                        bitrate: bitrate(TrackDirection::Inbound, inbound.ssrc, total, inbound.timestamp),
+                        jitter_buffer: jitter_buffers.get(&inbound.ssrc).cloned(),
+                    });
+                }
+                StatsReportType::OutboundRTP(outbound) => {
+                    let remote = report.reports.values().find_map(|stats| match stats {
+                        StatsReportType::RemoteInboundRTP(remote) if remote.ssrc == outbound.ssrc => {
+                            Some(remote)
+                        }
+                        _ => None,
+                    });
+                    let total = outbound.bytes_sent + outbound.header_bytes_sent;
+                    quality.tracks.push(TrackQuality {
+                        track_id: outbound.track_identifier.clone(),
+                        kind: outbound.kind.to_owned(),
+                        direction: TrackDirection::Outbound,
+                        ssrc: outbound.ssrc,
+                        packet_loss: remote.map(|remote| remote.fraction_lost),
+                        jitter_ms: None,
+                        rtt_ms: remote
+                            .and_then(|remote| remote.round_trip_time)
+                            .map(|rtt| rtt * 1000.0),
+                        bitrate: bitrate(TrackDirection::Outbound, outbound.ssrc, total, outbound.timestamp),
+                        jitter_buffer: None,
+                    });
+                }
+                _ => {}
+            }
+        }
+        // Forget the streams that went away
+        bytes.retain(|(direction, ssrc), _| {
+            quality
+                .tracks
+                .iter()
+                .any(|track| track.direction == *direction && track.ssrc == *ssrc)
+        });
+        quality.tracks.sort_by(|a, b| a.track_id.cmp(&b.track_id));
+        quality
+    }
+}
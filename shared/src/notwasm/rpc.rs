@@ -0,0 +1,76 @@
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::models::{ChannelWithUsers, PermissionsOfUser, RoomQuality, Server};
+
+/**
+ * Requests the client can send over the websocket instead of a separate HTTPS request.
+ * Each one is answered with the matching `RpcResponse` variant.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RpcRequest {
+    GetServers,
+    /// Also subscribes the session to the events of the server
+    ListChannels { server_id: Uuid },
+    GetPermissions { server_id: Uuid },
+    /// Connection quality of everyone in a voice channel, needs `PermissionType::ViewCallQuality`
+    GetCallQuality { server_id: Uuid, channel_id: Uuid },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RpcResponse {
+    Servers(Vec<Server>),
+    Channels(Vec<ChannelWithUsers>),
+    Permissions(PermissionsOfUser),
+    /// None when nobody is in the voice channel
+    CallQuality(Option<RoomQuality>),
+}
+
+/// Deserializes any error of a newer protocol version as `Unknown`, whatever its content
+#[derive(Debug, Clone, Serialize, Deserialize, Error)]
+#[serde(remote = "Self")]
+pub enum RpcError {
+    #[error("Not Authorized")]
+    NotAuthorized,
+    #[error("Not Found")]
+    NotFound,
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Internal server error: {0}")]
+    Internal(String),
+    /// Set by the client when no response arrived in time
+    #[error("Request timed out")]
+    Timeout,
+    /// Set by the client when the websocket closed before the response arrived
+    #[error("WebSocket disconnected")]
+    Disconnected,
+    /// Set by the client when the server did not negotiate `Feature::Rpc`
+    #[error("Server does not support websocket requests")]
+    NotSupported,
+    /// An error of a newer protocol version
+    #[error("Unknown error")]
+    Unknown,
+}
+
+impl Serialize for RpcError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RpcError::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RpcError {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OrUnknown {
+            Known(#[serde(deserialize_with = "RpcError::deserialize")] RpcError),
+            Unknown(IgnoredAny),
+        }
+        Ok(match OrUnknown::deserialize(deserializer)? {
+            OrUnknown::Known(err) => err,
+            OrUnknown::Unknown(_) => RpcError::Unknown,
+        })
+    }
+}
@@ -0,0 +1,617 @@
+use webrtc::api::media_engine::{MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9};
+use webrtc::media::Sample;
+use webrtc::rtcp::packet::Packet as RtcpPacket;
+use webrtc::rtcp::payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate;
+use webrtc::rtcp::receiver_report::ReceiverReport;
+use webrtc::rtcp::transport_feedbacks::transport_layer_cc::{
+    PacketStatusChunk, SymbolTypeTcc, TransportLayerCc,
+};
+use webrtc::rtp::packet::Packet;
+
+/// Gap put between the last packet of the previous layer and the first of the next,
+/// one frame at 30 fps in the 90 kHz video clock
+const SWITCH_TIMESTAMP_GAP: u32 = 3000;
+/// Loss above which the estimate goes down, and below which it goes up, as in Google congestion control
+const HIGH_LOSS: f64 = 0.1;
+const LOW_LOSS: f64 = 0.02;
+/// Growth of the estimate for each feedback without loss
+const ESTIMATE_GROWTH: f64 = 1.05;
+
+/**
+ * Simulcast layer of a video track, publishers send the same video in each of them.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Layer {
+    Low,
+    Medium,
+    High,
+}
+
+/// Encoded frame of one layer of a published video track
+#[derive(Debug, Clone)]
+pub struct LayerSample {
+    pub layer: Layer,
+    pub sample: Sample,
+}
+
+impl Layer {
+    pub const ALL: [Layer; 3] = [Layer::Low, Layer::Medium, Layer::High];
+
+    /// RTP stream id of the layer in the SDP and the RID header extension
+    pub fn rid(&self) -> &'static str {
+        match self {
+            Layer::Low => "l",
+            Layer::Medium => "m",
+            Layer::High => "h",
+        }
+    }
+
+    /// Layer of a received track, tracks without simulcast carry the full video
+    pub fn from_rid(rid: &str) -> Self {
+        match rid {
+            "l" => Layer::Low,
+            "m" => Layer::Medium,
+            _ => Layer::High,
+        }
+    }
+
+    /// How many times smaller than the captured video the layer is encoded
+    pub fn scale(&self) -> u32 {
+        match self {
+            Layer::Low => 4,
+            Layer::Medium => 2,
+            Layer::High => 1,
+        }
+    }
+
+    /// Height of the layer for a 720p capture, the size subscribers ask for is compared to it
+    pub fn height(&self) -> u32 {
+        720 / self.scale()
+    }
+
+    /// Bitrate our clients encode the layer with, in bit/s
+    pub fn bitrate(&self) -> u64 {
+        match self {
+            Layer::Low => 150_000,
+            Layer::Medium => 500_000,
+            Layer::High => 1_500_000,
+        }
+    }
+
+    /// Layers a publisher sends, only the full video when simulcast was not negotiated
+    pub fn published(simulcast: bool) -> &'static [Layer] {
+        if simulcast {
+            &Layer::ALL
+        } else {
+            &[Layer::High]
+        }
+    }
+
+    /// Smallest layer at least `height` pixels high
+    fn for_height(height: u32) -> Layer {
+        Layer::ALL
+            .into_iter()
+            .find(|layer| layer.height() >= height)
+            .unwrap_or(Layer::High)
+    }
+}
+
+/**
+ * Picks the layer of a published track one subscriber receives and rewrites the packets,
+ * so that switching layers looks like a single stream to the subscriber.
+ */
+#[derive(Debug)]
+pub struct LayerSelector {
+    /// Largest layer worth sending for the size the subscriber shows the video at
+    requested: Layer,
+    /// Bandwidth estimate of the subscriber in bit/s, None until it sent feedback
+    estimate: Option<u64>,
+    /// Layer to switch to at its next keyframe
+    target: Option<Layer>,
+    /// Layer being forwarded
+    current: Option<Layer>,
+    seq_offset: u16,
+    timestamp_offset: u32,
+    /// Sequence number and timestamp of the last forwarded packet, after rewriting
+    last: Option<(u16, u32)>,
+}
+
+impl Default for LayerSelector {
+    fn default() -> Self {
+        LayerSelector {
+            requested: Layer::High,
+            estimate: None,
+            target: None,
+            current: None,
+            seq_offset: 0,
+            timestamp_offset: 0,
+            last: None,
+        }
+    }
+}
+
+impl LayerSelector {
+    pub fn set_requested_height(&mut self, height: u32) {
+        self.requested = Layer::for_height(height);
+    }
+
+    pub fn estimate(&self) -> Option<u64> {
+        self.estimate
+    }
+
+    pub fn set_estimate(&mut self, estimate: u64) {
+        self.estimate = Some(estimate);
+    }
+
+    /// Layer a keyframe is needed of when the subscriber lost a picture
+    pub fn keyframe_layer(&self) -> Option<Layer> {
+        self.target.or(self.current)
+    }
+
+    /**
+     * Picks the largest of the `available` layers that is not larger than requested and
+     * fits the estimate, or the smallest one if none does.
+     * Returns the new target if it needs a keyframe to be switched to.
+     */
+    pub fn retarget(&mut self, available: &[Layer]) -> Option<Layer> {
+        let fits = |layer: &Layer| {
+            *layer <= self.requested && self.estimate.is_none_or(|estimate| layer.bitrate() <= estimate)
+        };
+        let target = available
+            .iter()
+            .copied()
+            .filter(fits)
+            .max()
+            .or_else(|| available.iter().copied().min());
+        if target == self.target {
+            return None;
+        }
+        self.target = target;
+        target.filter(|target| Some(*target) != self.current)
+    }
+
+    /**
+     * Forgets a layer the publisher stopped sending.
+     */
+    pub fn layer_stopped(&mut self, layer: Layer) {
+        if self.current == Some(layer) {
+            self.current = None;
+        }
+    }
+
+    /**
+     * Returns the packet of `layer` to send to the subscriber, None if it gets another layer.
+     * The target layer replaces the current one at its first keyframe.
+     */
+    pub fn forward(&mut self, layer: Layer, packet: &Packet, keyframe: bool) -> Option<Packet> {
+        if self.current != Some(layer) {
+            if self.target != Some(layer) || !keyframe {
+                return None;
+            }
+            let (seq, timestamp) = (packet.header.sequence_number, packet.header.timestamp);
+            (self.seq_offset, self.timestamp_offset) = match self.last {
+                Some((last_seq, last_timestamp)) => (
+                    last_seq.wrapping_add(1).wrapping_sub(seq),
+                    last_timestamp
+                        .wrapping_add(SWITCH_TIMESTAMP_GAP)
+                        .wrapping_sub(timestamp),
+                ),
+                None => (0, 0),
+            };
+            self.current = Some(layer);
+        }
+        let mut packet = packet.clone();
+        packet.header.sequence_number = packet.header.sequence_number.wrapping_add(self.seq_offset);
+        packet.header.timestamp = packet.header.timestamp.wrapping_add(self.timestamp_offset);
+        self.last = Some((packet.header.sequence_number, packet.header.timestamp));
+        Some(packet)
+    }
+}
+
+/**
+ * Updates the bandwidth estimate of a subscriber with its RTCP feedback. REMB gives it
+ * directly, transport wide feedback and receiver reports lower it while packets get lost
+ * and raise it slowly while they do not.
+ */
+pub fn estimate_bandwidth(
+    estimate: Option<u64>,
+    packets: &[Box<dyn RtcpPacket + Send + Sync>],
+) -> Option<u64> {
+    let mut estimate = estimate;
+    for packet in packets {
+        let packet = packet.as_any();
+        if let Some(remb) = packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+            estimate = Some(remb.bitrate as u64);
+            continue;
+        }
+        let loss = if let Some(feedback) = packet.downcast_ref::<TransportLayerCc>() {
+            transport_feedback_loss(feedback)
+        } else if let Some(report) = packet.downcast_ref::<ReceiverReport>() {
+            report
+                .reports
+                .iter()
+                .map(|report| report.fraction_lost as f64 / 256.0)
+                .reduce(f64::max)
+        } else {
+            None
+        };
+        let Some(loss) = loss else {
+            continue;
+        };
+        let current = estimate.unwrap_or(Layer::High.bitrate()) as f64;
+        let next = if loss > HIGH_LOSS {
+            current * (1.0 - 0.5 * loss)
+        } else if loss < LOW_LOSS {
+            current * ESTIMATE_GROWTH
+        } else {
+            current
+        };
+        // Never below the smallest layer, and not growing without bounds while nothing is lost
+        let next = next.clamp(Layer::Low.bitrate() as f64, 2.0 * Layer::High.bitrate() as f64);
+        estimate = Some(next as u64);
+    }
+    estimate
+}
+
+/// Fraction of the packets the feedback reports as not received
+fn transport_feedback_loss(feedback: &TransportLayerCc) -> Option<f64> {
+    let total = feedback.packet_status_count as u32;
+    if total == 0 {
+        return None;
+    }
+    let received = |symbol: &SymbolTypeTcc| !matches!(symbol, SymbolTypeTcc::PacketNotReceived);
+    let mut received_count = 0u32;
+    for chunk in &feedback.packet_chunks {
+        match chunk {
+            PacketStatusChunk::RunLengthChunk(run) => {
+                if received(&run.packet_status_symbol) {
+                    received_count += run.run_length as u32;
+                }
+            }
+            PacketStatusChunk::StatusVectorChunk(vector) => {
+                received_count += vector.symbol_list.iter().filter(|symbol| received(symbol)).count() as u32;
+            }
+        }
+    }
+    Some(1.0 - received_count.min(total) as f64 / total as f64)
+}
+
+/**
+ * Whether the packet starts a keyframe, layers are only switched there
+ * since the frames of the new layer can not be decoded before one.
+ */
+pub fn is_keyframe(mime_type: &str, payload: &[u8]) -> bool {
+    if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+        is_vp8_keyframe(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+        is_vp9_keyframe(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+        is_h264_keyframe(payload)
+    } else {
+        false
+    }
+}
+
+fn is_vp8_keyframe(payload: &[u8]) -> bool {
+    let Some(&descriptor) = payload.first() else {
+        return false;
+    };
+    // Only the first packet of a frame, start of partition 0, holds the frame header
+    if descriptor & 0x10 == 0 || descriptor & 0x07 != 0 {
+        return false;
+    }
+    let mut offset = 1;
+    if descriptor & 0x80 != 0 {
+        let Some(&extension) = payload.get(offset) else {
+            return false;
+        };
+        offset += 1;
+        // Picture id, one or two bytes
+        if extension & 0x80 != 0 {
+            let Some(&picture_id) = payload.get(offset) else {
+                return false;
+            };
+            offset += if picture_id & 0x80 != 0 { 2 } else { 1 };
+        }
+        // TL0PICIDX
+        if extension & 0x40 != 0 {
+            offset += 1;
+        }
+        // TID and KEYIDX share a byte
+        if extension & 0x30 != 0 {
+            offset += 1;
+        }
+    }
+    // The inverse key frame flag of the frame header
+    payload.get(offset).is_some_and(|header| header & 0x01 == 0)
+}
+
+fn is_vp9_keyframe(payload: &[u8]) -> bool {
+    // Not inter predicted and the beginning of a frame
+    payload
+        .first()
+        .is_some_and(|descriptor| descriptor & 0x40 == 0 && descriptor & 0x08 != 0)
+}
+
+fn is_h264_keyframe(payload: &[u8]) -> bool {
+    const IDR: u8 = 5;
+    const SPS: u8 = 7;
+    const STAP_A: u8 = 24;
+    const FU_A: u8 = 28;
+    let Some(&header) = payload.first() else {
+        return false;
+    };
+    match header & 0x1f {
+        IDR | SPS => true,
+        STAP_A => {
+            let mut offset = 1;
+            while offset + 2 < payload.len() {
+                let size = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+                if matches!(payload[offset + 2] & 0x1f, IDR | SPS) {
+                    return true;
+                }
+                offset += 2 + size;
+            }
+            false
+        }
+        // Start of a fragmented IDR slice
+        FU_A => payload
+            .get(1)
+            .is_some_and(|fu_header| fu_header & 0x80 != 0 && fu_header & 0x1f == IDR),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtcp::reception_report::ReceptionReport;
+    use webrtc::rtcp::transport_feedbacks::transport_layer_cc::{
+        RunLengthChunk, StatusVectorChunk,
+    };
+    use webrtc::rtp::header::Header;
+
+    use super::*;
+
+    fn packet(seq: u16, timestamp: u32) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number: seq,
+                timestamp,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn forwarded(packet: Option<Packet>) -> Option<(u16, u32)> {
+        packet.map(|packet| (packet.header.sequence_number, packet.header.timestamp))
+    }
+
+    #[test]
+    fn switches_layers_only_at_a_keyframe() {
+        let mut selector = LayerSelector::default();
+        assert_eq!(selector.retarget(&Layer::ALL), Some(Layer::High));
+        assert_eq!(
+            forwarded(selector.forward(Layer::High, &packet(100, 1000), false)),
+            None
+        );
+        assert_eq!(
+            forwarded(selector.forward(Layer::Low, &packet(7, 500), true)),
+            None
+        );
+        assert_eq!(
+            forwarded(selector.forward(Layer::High, &packet(101, 1000), true)),
+            Some((101, 1000))
+        );
+        assert_eq!(
+            forwarded(selector.forward(Layer::High, &packet(102, 4000), false)),
+            Some((102, 4000))
+        );
+
+        selector.set_estimate(600_000);
+        assert_eq!(selector.retarget(&Layer::ALL), Some(Layer::Medium));
+        assert_eq!(selector.keyframe_layer(), Some(Layer::Medium));
+        // The current layer goes on until the target has a keyframe
+        assert_eq!(
+            forwarded(selector.forward(Layer::Medium, &packet(5000, 77000), false)),
+            None
+        );
+        assert_eq!(
+            forwarded(selector.forward(Layer::High, &packet(103, 7000), false)),
+            Some((103, 7000))
+        );
+        // Continues the sequence numbers and timestamps of the previous layer
+        assert_eq!(
+            forwarded(selector.forward(Layer::Medium, &packet(5001, 80000), true)),
+            Some((104, 7000 + SWITCH_TIMESTAMP_GAP))
+        );
+        assert_eq!(
+            forwarded(selector.forward(Layer::High, &packet(104, 10000), false)),
+            None
+        );
+        assert_eq!(
+            forwarded(selector.forward(Layer::Medium, &packet(5002, 83000), false)),
+            Some((105, 10000 + SWITCH_TIMESTAMP_GAP))
+        );
+    }
+
+    #[test]
+    fn rewrites_across_the_wraparound() {
+        let mut selector = LayerSelector::default();
+        selector.retarget(&Layer::ALL);
+        selector.forward(Layer::High, &packet(65535, u32::MAX - 1000), true);
+        selector.set_requested_height(100);
+        assert_eq!(selector.retarget(&Layer::ALL), Some(Layer::Low));
+        assert_eq!(
+            forwarded(selector.forward(Layer::Low, &packet(300, 40), true)),
+            Some((0, SWITCH_TIMESTAMP_GAP - 1001))
+        );
+    }
+
+    #[test]
+    fn targets_the_largest_layer_that_fits() {
+        let mut selector = LayerSelector::default();
+        selector.set_requested_height(200);
+        assert_eq!(selector.retarget(&Layer::ALL), Some(Layer::Medium));
+        assert_eq!(selector.retarget(&Layer::ALL), None);
+        // Nothing fits the estimate, the smallest layer still goes through
+        selector.set_estimate(100_000);
+        assert_eq!(selector.retarget(&Layer::ALL), Some(Layer::Low));
+        // Without simulcast there is only the full video
+        assert_eq!(selector.retarget(&[Layer::High]), Some(Layer::High));
+        selector.set_estimate(2_000_000);
+        selector.set_requested_height(720);
+        assert_eq!(selector.retarget(&[Layer::High]), None);
+    }
+
+    #[test]
+    fn needs_no_keyframe_to_go_back_to_the_current_layer() {
+        let mut selector = LayerSelector::default();
+        selector.retarget(&Layer::ALL);
+        selector.forward(Layer::High, &packet(0, 0), true);
+        selector.set_estimate(200_000);
+        assert_eq!(selector.retarget(&Layer::ALL), Some(Layer::Low));
+        selector.set_estimate(2_000_000);
+        assert_eq!(selector.retarget(&Layer::ALL), None);
+        assert_eq!(selector.keyframe_layer(), Some(Layer::High));
+        assert!(selector
+            .forward(Layer::High, &packet(1, 3000), false)
+            .is_some());
+        selector.layer_stopped(Layer::High);
+        assert!(selector
+            .forward(Layer::High, &packet(2, 6000), false)
+            .is_none());
+    }
+
+    fn receiver_report(fraction_lost: u8) -> Box<dyn RtcpPacket + Send + Sync> {
+        Box::new(ReceiverReport {
+            reports: vec![ReceptionReport {
+                fraction_lost,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn takes_the_estimate_from_remb() {
+        let remb: Box<dyn RtcpPacket + Send + Sync> = Box::new(ReceiverEstimatedMaximumBitrate {
+            bitrate: 800_000.0,
+            ..Default::default()
+        });
+        assert_eq!(estimate_bandwidth(Some(200_000), &[remb]), Some(800_000));
+    }
+
+    #[test]
+    fn follows_the_loss_of_receiver_reports() {
+        // Half of the packets lost
+        assert_eq!(
+            estimate_bandwidth(Some(1_000_000), &[receiver_report(128)]),
+            Some(750_000)
+        );
+        assert_eq!(
+            estimate_bandwidth(Some(1_000_000), &[receiver_report(0)]),
+            Some(1_050_000)
+        );
+        // Between the thresholds it stays
+        assert_eq!(
+            estimate_bandwidth(Some(1_000_000), &[receiver_report(13)]),
+            Some(1_000_000)
+        );
+        assert_eq!(estimate_bandwidth(None, &[]), None);
+    }
+
+    #[test]
+    fn keeps_the_estimate_within_the_layers() {
+        let reports = (0..100).map(|_| receiver_report(0)).collect::<Vec<_>>();
+        assert_eq!(
+            estimate_bandwidth(None, &reports),
+            Some(2 * Layer::High.bitrate())
+        );
+        let reports = (0..100).map(|_| receiver_report(255)).collect::<Vec<_>>();
+        assert_eq!(
+            estimate_bandwidth(None, &reports),
+            Some(Layer::Low.bitrate())
+        );
+    }
+
+    #[test]
+    fn counts_the_loss_of_transport_feedback() {
+        let received = SymbolTypeTcc::PacketReceivedSmallDelta;
+        let lost = SymbolTypeTcc::PacketNotReceived;
+        let feedback: Box<dyn RtcpPacket + Send + Sync> = Box::new(TransportLayerCc {
+            packet_status_count: 10,
+            packet_chunks: vec![
+                PacketStatusChunk::RunLengthChunk(RunLengthChunk {
+                    packet_status_symbol: received,
+                    run_length: 4,
+                    ..Default::default()
+                }),
+                PacketStatusChunk::StatusVectorChunk(StatusVectorChunk {
+                    symbol_list: vec![received, lost, lost, lost, lost, lost],
+                    ..Default::default()
+                }),
+            ],
+            ..Default::default()
+        });
+        assert_eq!(
+            estimate_bandwidth(Some(1_000_000), &[feedback]),
+            Some(750_000)
+        );
+        let empty: Box<dyn RtcpPacket + Send + Sync> = Box::new(TransportLayerCc::default());
+        assert_eq!(
+            estimate_bandwidth(Some(1_000_000), &[empty]),
+            Some(1_000_000)
+        );
+    }
+
+    #[test]
+    fn finds_vp8_keyframes() {
+        assert!(is_keyframe("video/vp8", &[0x10, 0x00]));
+        assert!(!is_keyframe(MIME_TYPE_VP8, &[0x10, 0x01]));
+        // Not the start of the frame, or not partition 0
+        assert!(!is_keyframe(MIME_TYPE_VP8, &[0x00, 0x00]));
+        assert!(!is_keyframe(MIME_TYPE_VP8, &[0x11, 0x00]));
+        // Extension with a two byte picture id, TL0PICIDX and TID
+        assert!(is_keyframe(
+            MIME_TYPE_VP8,
+            &[0x90, 0xe0, 0x92, 0x34, 0x05, 0x20, 0x00]
+        ));
+        assert!(!is_keyframe(
+            MIME_TYPE_VP8,
+            &[0x90, 0xe0, 0x92, 0x34, 0x05, 0x20, 0x01]
+        ));
+        // One byte picture id
+        assert!(is_keyframe(MIME_TYPE_VP8, &[0x90, 0x80, 0x12, 0x00]));
+        assert!(!is_keyframe(MIME_TYPE_VP8, &[0x90]));
+        assert!(!is_keyframe(MIME_TYPE_VP8, &[]));
+    }
+
+    #[test]
+    fn finds_vp9_keyframes() {
+        assert!(is_keyframe(MIME_TYPE_VP9, &[0x08]));
+        assert!(!is_keyframe(MIME_TYPE_VP9, &[0x48]));
+        assert!(!is_keyframe(MIME_TYPE_VP9, &[0x00]));
+    }
+
+    #[test]
+    fn finds_h264_keyframes() {
+        assert!(is_keyframe(MIME_TYPE_H264, &[0x65, 0x88]));
+        assert!(is_keyframe(MIME_TYPE_H264, &[0x67, 0x42]));
+        assert!(!is_keyframe(MIME_TYPE_H264, &[0x41, 0x9a]));
+        // STAP-A with an access unit delimiter and a SPS
+        assert!(is_keyframe(
+            MIME_TYPE_H264,
+            &[0x78, 0x00, 0x02, 0x09, 0xf0, 0x00, 0x03, 0x67, 0x42, 0x00]
+        ));
+        assert!(!is_keyframe(
+            MIME_TYPE_H264,
+            &[0x78, 0x00, 0x02, 0x41, 0x9a, 0x00, 0x02, 0x41, 0x9b]
+        ));
+        // FU-A starting an IDR slice, continuing it and starting another slice
+        assert!(is_keyframe(MIME_TYPE_H264, &[0x7c, 0x85, 0x88]));
+        assert!(!is_keyframe(MIME_TYPE_H264, &[0x7c, 0x05, 0x88]));
+        assert!(!is_keyframe(MIME_TYPE_H264, &[0x7c, 0x81, 0x9a]));
+        assert!(!is_keyframe("audio/opus", &[0x65]));
+    }
+}
@@ -24,6 +24,7 @@ diesel::table! {
         server_id -> Uuid,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        user_limit -> Nullable<Int4>,
     }
 }
 